@@ -0,0 +1,402 @@
+extern crate combine;
+#[macro_use]
+extern crate criterion;
+extern crate rustfest;
+
+use combine::Parser;
+use criterion::{black_box, Criterion};
+
+use rustfest::closure::compile_closure;
+use rustfest::prelude::{add, eq, if_};
+#[cfg(feature = "testing")]
+use rustfest::testing::{generate_program, ProgramSpec};
+use rustfest::{
+    eval, expr, hash_string, optimize, parse_program_with, EvalError, IncrementalParser, IntMap,
+    Interpreter, ParseOptions, Value,
+};
+
+use std::borrow::Cow;
+
+include!("programs.rs");
+
+// First we need some helper functions. Besides the prelude, the
+// benchmarks use these two.
+//
+// This one just returns a function so `((whatever))` (equivalent
+// to `(whatever())()`) does something useful. Specifically
+// it just returns itself. We try to do as little work as
+// possible here so that our benchmark is still testing the
+// interpreter and not this function.
+fn callable<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    Ok(Value::InbuiltFunc(callable))
+}
+
+// This just takes anything and returns `Void`. We just
+// want a function that can take any number of arguments
+// but we don't want that function to do anything useful
+// since, again, the benchmark should be of the
+// interpreter's code.
+fn ignore<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    Ok(Value::Void)
+}
+
+// Now we run the benchmarks. The parsing ones are very simple...
+fn parse_deep_nesting(c: &mut Criterion) {
+    c.bench_function("parse_deep_nesting", |b| {
+        b.iter(|| black_box(expr().easy_parse(DEEP_NESTING)))
+    });
+}
+
+fn parse_many_variables(c: &mut Criterion) {
+    c.bench_function("parse_many_variables", |b| {
+        b.iter(|| black_box(expr().easy_parse(MANY_VARIABLES)))
+    });
+}
+
+fn parse_nested_func(c: &mut Criterion) {
+    c.bench_function("parse_nested_func", |b| {
+        b.iter(|| black_box(expr().easy_parse(NESTED_FUNC)))
+    });
+}
+
+fn parse_real_code(c: &mut Criterion) {
+    c.bench_function("parse_real_code", |b| {
+        b.iter(|| black_box(expr().easy_parse(REAL_CODE)))
+    });
+}
+
+fn parse_literals(c: &mut Criterion) {
+    c.bench_function("parse_literals", |b| {
+        b.iter(|| black_box(expr().easy_parse(LITERALS)))
+    });
+}
+
+// Children are shared rather than copied, so this should only cost a
+// couple of reference count bumps however big the program is.
+fn clone_many_variables(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(MANY_VARIABLES).unwrap();
+
+    c.bench_function("clone_many_variables", |b| {
+        b.iter(|| black_box(program.clone()))
+    });
+}
+
+// Changing one digit of a thousand definitions, either by reparsing the
+// whole file or by reparsing just the definition that changed. Each edit
+// writes the same digit, so the source is the same every iteration.
+fn parse_after_edit(c: &mut Criterion) {
+    let src = many_defines(1000);
+    let digit = src.find(" 500)").unwrap() + 1;
+    let mut edited = src.clone();
+    edited.replace_range(digit..digit + 1, "7");
+
+    c.bench_function("parse_after_edit_from_scratch", |b| {
+        b.iter(|| {
+            black_box(parse_program_with(&edited, &ParseOptions::default(), &mut |_| {}))
+        })
+    });
+
+    let mut parser = IncrementalParser::new(&src);
+    c.bench_function("parse_after_edit_incremental", |b| {
+        b.iter(|| black_box(parser.edit(digit..digit + 1, "7").is_ok()))
+    });
+}
+
+// For the benchmarks that run the code we have to do a little more
+// work. We need to put some functions in the global namespace that
+// our testing code needs in order to run.
+fn run_deep_nesting(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(DEEP_NESTING).unwrap();
+
+    let mut env = IntMap::default();
+    env.insert(
+        hash_string("test"),
+        Cow::Owned(Value::InbuiltFunc(callable)),
+    );
+
+    c.bench_function("run_deep_nesting", |b| {
+        b.iter(|| black_box(eval(&program, &mut env)))
+    });
+}
+
+fn run_real_code(c: &mut Criterion) {
+    let mut env = IntMap::default();
+
+    env.insert(hash_string("eq"), Cow::Owned(Value::InbuiltFunc(eq)));
+    env.insert(hash_string("add"), Cow::Owned(Value::InbuiltFunc(add)));
+    env.insert(hash_string("if"), Cow::Owned(Value::ReentrantFunc(if_)));
+
+    let (program, _) = combine::many1::<Vec<_>, _>(expr())
+        .easy_parse(REAL_CODE)
+        .unwrap();
+
+    c.bench_function("run_real_code", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            for line in &program {
+                let _ = black_box(eval(line, &mut env));
+            }
+        })
+    });
+}
+
+// The same again, but with small functions inlined into their call
+// sites first.
+fn run_real_code_inlined(c: &mut Criterion) {
+    let mut env = IntMap::default();
+
+    env.insert(hash_string("eq"), Cow::Owned(Value::InbuiltFunc(eq)));
+    env.insert(hash_string("add"), Cow::Owned(Value::InbuiltFunc(add)));
+    env.insert(hash_string("if"), Cow::Owned(Value::ReentrantFunc(if_)));
+
+    let (mut program, _) = combine::many1::<Vec<_>, _>(expr())
+        .easy_parse(REAL_CODE)
+        .unwrap();
+    optimize::inline(&mut program, 32);
+
+    c.bench_function("run_real_code_inlined", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            for line in &program {
+                let _ = black_box(eval(line, &mut env));
+            }
+        })
+    });
+}
+
+// The same program again, compiled to bytecode up front.
+fn run_real_code_vm(c: &mut Criterion) {
+    let mut interpreter = Interpreter::new();
+
+    interpreter
+        .register(hash_string("eq"), eq)
+        .register(hash_string("add"), add)
+        .register_reentrant(hash_string("if"), if_);
+
+    let env: IntMap<_> = interpreter.env();
+
+    let (program, _) = combine::many1::<Vec<_>, _>(expr())
+        .easy_parse(REAL_CODE)
+        .unwrap();
+    let compiled = interpreter.compile(&program);
+
+    c.bench_function("run_real_code_vm", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            let _ = black_box(compiled.run(&mut env));
+        })
+    });
+}
+
+fn run_many_variables(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(MANY_VARIABLES).unwrap();
+
+    let mut env = IntMap::default();
+
+    env.insert(
+        hash_string("ignore"),
+        Cow::Owned(Value::InbuiltFunc(ignore)),
+    );
+
+    c.bench_function("run_many_variables", |b| {
+        b.iter(|| black_box(eval(&program, &mut env)))
+    });
+}
+
+fn run_many_variables_closure(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(MANY_VARIABLES).unwrap();
+    let compiled = compile_closure(&program);
+
+    let mut env = IntMap::default();
+
+    env.insert(
+        hash_string("ignore"),
+        Cow::Owned(Value::InbuiltFunc(ignore)),
+    );
+
+    c.bench_function("run_many_variables_closure", |b| {
+        b.iter(|| black_box(compiled.eval(&mut env)))
+    });
+}
+
+fn run_nested_func(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(NESTED_FUNC).unwrap();
+    let mut env = IntMap::default();
+
+    c.bench_function("run_nested_func", |b| {
+        b.iter(|| black_box(eval(&program, &mut env)))
+    });
+}
+
+fn run_nested_func_vm(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(NESTED_FUNC).unwrap();
+    let compiled = Interpreter::new().compile(&[program]);
+    let mut env = IntMap::default();
+
+    c.bench_function("run_nested_func_vm", |b| {
+        b.iter(|| black_box(compiled.run(&mut env)))
+    });
+}
+
+// The literals are all evaluated, but only the last one is returned.
+fn run_literals(c: &mut Criterion) {
+    let (program, _) = expr().easy_parse(LITERALS).unwrap();
+    let mut env = IntMap::default();
+
+    c.bench_function("run_literals", |b| {
+        b.iter(|| black_box(eval(&program, &mut env)))
+    });
+}
+
+// Each iteration starts from the same environment, so this measures it
+// growing from the builtins up to 500 more variables.
+fn run_many_defines(c: &mut Criterion) {
+    let mut env = IntMap::default();
+
+    env.insert(hash_string("add"), Cow::Owned(Value::InbuiltFunc(add)));
+
+    let (program, _) = combine::many1::<Vec<_>, _>(expr())
+        .easy_parse(&many_defines(500)[..])
+        .unwrap();
+
+    c.bench_function("run_many_defines", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            for line in &program {
+                let _ = black_box(eval(line, &mut env));
+            }
+        })
+    });
+}
+
+// A hundred thousand calls, none of them nested in another, so this is as
+// much a check that tail calls stay cheap as that they happen at all.
+fn run_tail_recursion(c: &mut Criterion) {
+    let program = rustfest::parse_program(TAIL_RECURSION).unwrap();
+    let mut env = rustfest::prelude::env();
+    let _ = eval(&program[0], &mut env).unwrap();
+
+    c.bench_function("run_tail_recursion", |b| {
+        b.iter(|| black_box(eval(&program[1], &mut env)))
+    });
+}
+
+// The same program run with a hundred different inputs, parsed once up
+// front, and then parsed again for every input the way a host would
+// without `Program`. The difference between the two is the parsing that
+// `Interpreter::prepare` saves.
+fn run_prepared_program(c: &mut Criterion) {
+    let interpreter = rustfest::prelude::interpreter();
+    let program = interpreter.prepare(REAL_CODE).unwrap();
+    let env: IntMap<_> = interpreter.env();
+
+    c.bench_function("run_prepared_program", |b| {
+        b.iter(|| {
+            for n in 0..100 {
+                let mut env = env.clone();
+                let _ = black_box(program.run(&mut env, &[("n", Value::Int(n))]));
+            }
+        })
+    });
+}
+
+fn run_reparsed_program(c: &mut Criterion) {
+    let interpreter = rustfest::prelude::interpreter();
+    let env: IntMap<_> = interpreter.env();
+
+    c.bench_function("run_reparsed_program", |b| {
+        b.iter(|| {
+            for n in 0..100 {
+                let program = interpreter.prepare(REAL_CODE).unwrap();
+                let mut env = env.clone();
+                let _ = black_box(program.run(&mut env, &[("n", Value::Int(n))]));
+            }
+        })
+    });
+}
+
+// An interpreter with the prelude and stdlib ready to run in, made from
+// scratch, and cloned from one made earlier. Both end with an environment,
+// since that's what the clone's baseline is there to save.
+fn build_interpreter(c: &mut Criterion) {
+    c.bench_function("build_interpreter", |b| {
+        b.iter(|| {
+            let mut interpreter = rustfest::prelude::interpreter();
+            let env = rustfest::prelude::env();
+            interpreter.mark_baseline(&env);
+            black_box((interpreter, env))
+        })
+    });
+}
+
+fn clone_interpreter(c: &mut Criterion) {
+    let mut configured = rustfest::prelude::interpreter();
+    configured.mark_baseline(&rustfest::prelude::env());
+
+    c.bench_function("clone_interpreter", |b| {
+        b.iter(|| {
+            let interpreter = configured.clone();
+            let mut env = IntMap::default();
+            interpreter.reset(&mut env);
+            black_box((interpreter, env))
+        })
+    });
+}
+
+// A generated program of about ten thousand nodes, mostly functions
+// calling the ones defined before them. The generator is only there with
+// the `testing` feature, so run this with `--features testing`.
+#[cfg(feature = "testing")]
+fn run_generated(c: &mut Criterion) {
+    let spec = ProgramSpec {
+        defines: 500,
+        ..ProgramSpec::default()
+    };
+    let program = rustfest::parse_program(&generate_program(spec)).unwrap();
+    let env = rustfest::prelude::env();
+
+    c.bench_function("run_generated", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            for line in &program {
+                let _ = black_box(eval(line, &mut env));
+            }
+        })
+    });
+}
+
+criterion_group!(
+    parse,
+    parse_deep_nesting,
+    parse_many_variables,
+    parse_nested_func,
+    parse_real_code,
+    parse_literals,
+    parse_after_edit,
+    clone_many_variables
+);
+criterion_group!(
+    run,
+    run_deep_nesting,
+    run_real_code,
+    run_real_code_inlined,
+    run_real_code_vm,
+    run_many_variables,
+    run_many_variables_closure,
+    run_nested_func,
+    run_nested_func_vm,
+    run_literals,
+    run_many_defines,
+    run_tail_recursion,
+    run_prepared_program,
+    run_reparsed_program,
+    build_interpreter,
+    clone_interpreter
+);
+#[cfg(feature = "testing")]
+criterion_group!(generated, run_generated);
+
+#[cfg(feature = "testing")]
+criterion_main!(parse, run, generated);
+#[cfg(not(feature = "testing"))]
+criterion_main!(parse, run);
@@ -0,0 +1,160 @@
+// Here are our test program strings. Our language looks a lot like Lisp,
+// but it has the important distinction of being totally useless. The unit
+// tests in `src/lib.rs` include this file too, so they check the same
+// programs that the benchmarks measure.
+//
+// This string is used to test the performance when programs include
+// deeply-nested structures. Nesting this deep is unlikely but it's a
+// good test for the parser's performance on nesting in general.
+pub(crate) const DEEP_NESTING: &str = "(((((((((((((((((((((((((((((((((((((((((((((test\
+    )))))))))))))))))))))))))))))))))))))))))))))";
+
+// This string is used to test the performance of when programs include
+// many variables of many different names, and many repetitions of the
+// same name. We'd expect real programs to contain lots of variables and
+// so it's important that we get good performance when parsing and
+// evaluating them.
+pub(crate) const MANY_VARIABLES: &str = r"
+    ((\(a b c d e f g h i j k l m n o p q r s t u v w x y z)
+      (a b c d e f g h i j k l m n o p q r s t u v w x y z)
+      (b c d e f g h i j k l m n o p q r s t u v w x y z)
+      (c d e f g h i j k l m n o p q r s t u v w x y z)
+      (d e f g h i j k l m n o p q r s t u v w x y z)
+      (e f g h i j k l m n o p q r s t u v w x y z)
+      (f g h i j k l m n o p q r s t u v w x y z)
+      (g h i j k l m n o p q r s t u v w x y z)
+      (h i j k l m n o p q r s t u v w x y z)
+      (i j k l m n o p q r s t u v w x y z)
+      (j k l m n o p q r s t u v w x y z)
+      (k l m n o p q r s t u v w x y z)
+      (l m n o p q r s t u v w x y z)
+      (m n o p q r s t u v w x y z)
+      (n o p q r s t u v w x y z)
+      (o p q r s t u v w x y z)
+      (p q r s t u v w x y z)
+      (q r s t u v w x y z)
+      (r s t u v w x y z)
+      (s t u v w x y z)
+      (t u v w x y z)
+      (u v w x y z)
+      (v w x y z)
+      (w x y z)
+      (x y z)
+      (y z)
+      (z))
+        ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore
+        ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore)
+        ";
+
+// This is used to test that function calls aren't unnecessarily
+// expensive. It just passes the same value down and then back up
+// the stack.
+pub(crate) const NESTED_FUNC: &str = r"
+    ((\(val)
+      ((\(val)
+        ((\(val)
+          ((\(val)
+            ((\(val)
+              ((\(val)
+                ((\(val)
+                  ((\(val)
+                    ((\(val)
+                      ((\(val)
+                        ((\(val)
+                          val
+                        ) val)
+                      ) val)
+                    ) val)
+                  ) val)
+                ) val)
+              ) val)
+            ) val)
+          ) val)
+        ) val)
+      ) val)
+    ) #f)
+";
+
+// This is a more realistic program that uses every feature of
+// the language. It's not useful for finding hotspots but it's
+// definitely useful for seeing improvements.
+pub(crate) const REAL_CODE: &str = r"
+(= increment (\(a)
+  (add a 1)))
+(= someval (increment 2))
+(= double (\ (someval)
+  (add someval someval)))
+(= addfive (\ (first second third fourth fifth) (add first second third fourth fifth)))
+(= second (\ (a a) a))
+(= rec (\ (a)
+  ((if (eq a 10)
+       (\() 10)
+       (\() (rec (add a 1)))))))
+(= ne (\ (a b)
+  (not (eq a b))))
+(= not (\ (a)
+  (if a #f)))
+
+(double 5)
+(addfive 1 2 3 4 5)
+(second 1 2)
+(rec 0)
+(ne 1 2)
+someval
+";
+
+// This is mostly literals, which should cost next to nothing to parse or
+// to evaluate. Anything that shows up here is overhead that every program
+// pays.
+pub(crate) const LITERALS: &str = r"
+            ((\()
+               0  1  2  3  4  5  6  7  8  9 10 11 12 13 14 15 16 17 18 19
+              20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39
+              40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59
+              50 51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66 67 68 69
+              70 71 72 73 74 75 76 77 78 79 80 81 82 83 84 85 86 87 88 89
+              90 91 92 93 94 95 96 97 98 99))
+        ";
+
+// This is used to test how evaluation copes with a large environment.
+// It defines `count` variables one after another at the top level, and
+// then adds them all up, so it gives the sum of `0..count`.
+pub(crate) fn many_defines(count: usize) -> String {
+    // Names can only have letters in them, so the number is written in
+    // base 26 with `a` to `z` for digits.
+    let name = |mut i: usize| {
+        let mut name = String::from("v");
+        loop {
+            name.push((b'a' + (i % 26) as u8) as char);
+            i /= 26;
+            if i == 0 {
+                return name;
+            }
+        }
+    };
+
+    let mut src = String::new();
+    for i in 0..count {
+        src.push_str(&format!("(= {} {})\n", name(i), i));
+    }
+    src.push_str("(add");
+    for i in 0..count {
+        src.push_str(&format!(" {}", name(i)));
+    }
+    src.push_str(")\n");
+    src
+}
+
+// Recursion that only ever happens in tail position, so it runs in the
+// same stack however far it goes. The call to `if` isn't a tail call,
+// because `if` is a native, but the call of the lambda it returns is the
+// last form of `count`, and the call to `count` is the last form of that
+// lambda, so both are. There's no subtraction in the prelude, so this
+// counts up to 100000 rather than down from it.
+pub(crate) const TAIL_RECURSION: &str = r"
+(= count (\(n)
+  ((if (eq n 100000)
+       (\() n)
+       (\() (count (add n 1)))))))
+(count 0)
+";
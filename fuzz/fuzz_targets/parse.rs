@@ -0,0 +1,7 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate rustfest;
+
+fuzz_target!(|data: &[u8]| rustfest::parse_fuzz(data));
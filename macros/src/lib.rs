@@ -0,0 +1,354 @@
+//! Programs parsed when the crate using them is compiled, rather than when
+//! it runs.
+//!
+//! `lisp!` takes a program written inline and `lisp_file!` takes the path
+//! of one, relative to the crate's `Cargo.toml`. Either expands to an
+//! expression that builds a `rustfest::Program` directly, so a syntax error
+//! is a compile error and there's no parsing left to do at runtime. The
+//! program's identifiers are given to `SymbolTable::insert` as it's built,
+//! so they're whatever the parser of the `rustfest` being linked would
+//! give them, with or without std.
+//!
+//! These live in a crate of their own since a proc-macro crate can't be
+//! anything else, and `rustfest` can't re-export them because this crate
+//! depends on it for its parser. Add `rustfest-macros` alongside
+//! `rustfest` to use them.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate rustfest_macros;
+//! extern crate rustfest;
+//!
+//! # fn main() {
+//! let program = lisp! { (= x 20) (add x 22) };
+//! let mut env = rustfest::prelude::env();
+//! assert_eq!(program.run(&mut env, &[]).unwrap(), rustfest::Value::Int(42));
+//! # }
+//! ```
+//!
+//! Rust has to split `lisp!`'s input into its own tokens before the macro
+//! sees it, which `\`, `'` and unbalanced quotes can't be. A program that
+//! uses them can be given as a single string literal instead:
+//!
+//! ```
+//! # #[macro_use]
+//! # extern crate rustfest_macros;
+//! # extern crate rustfest;
+//! # fn main() {
+//! let program = lisp!(r"(= twice (\(f x) (f (f x)))) (twice (\(n) (add n 10)) 22)");
+//! # let mut env = rustfest::prelude::env();
+//! # assert_eq!(program.run(&mut env, &[]).unwrap(), rustfest::Value::Int(42));
+//! # }
+//! ```
+//!
+//! ```compile_fail
+//! # #[macro_use]
+//! # extern crate rustfest_macros;
+//! # fn main() {
+//! let program = lisp! { (add 1 ()) };
+//! # }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate rustfest;
+extern crate syn;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use proc_macro2::{Ident, Literal, Span, TokenStream, TokenTree};
+use rustfest::{
+    line_and_column, parse_program_with_symbols, Ast, Lambda, ParseError, ParseOptions, Pattern,
+    Value,
+};
+use syn::LitStr;
+
+/// Parse a program written inline, or in a single string literal, into a
+/// `rustfest::Program`.
+#[proc_macro]
+pub fn lisp(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = TokenStream::from(input);
+    let expanded = match syn::parse2::<LitStr>(input.clone()) {
+        Ok(lit) => expand(&lit.value(), &Origin::Literal(lit.span())),
+        Err(_) => {
+            let (src, trees) = source(input);
+            expand(&src, &Origin::Tokens(trees))
+        }
+    };
+    expanded.into()
+}
+
+/// Parse the program in a file, relative to the crate's `Cargo.toml`, into
+/// a `rustfest::Program`. The crate is rebuilt when the file changes.
+#[proc_macro]
+pub fn lisp_file(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let lit = match syn::parse::<LitStr>(input) {
+        Ok(lit) => lit,
+        Err(error) => return compile_error(error.span(), &error.to_string()).into(),
+    };
+    let span = lit.span();
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let path = PathBuf::from(dir).join(lit.value());
+    let src = match fs::read_to_string(&path) {
+        Ok(src) => src,
+        Err(error) => {
+            let message = format!("couldn't read {}: {}", lit.value(), error);
+            return compile_error(span, &message).into();
+        }
+    };
+
+    let program = expand(&src, &Origin::File(lit.value(), span));
+    let path = path.to_string_lossy();
+    let expanded = quote! {{
+        // Only here so that cargo knows to rebuild when the file changes.
+        const _: &str = include_str!(#path);
+        #program
+    }};
+    expanded.into()
+}
+
+// The source of a program given as Rust tokens, along with where each
+// top-level token tree starts in it. Each tree is written as it was in the
+// file where that's known, so `12a` stays two forms, and trees are joined
+// with spaces except after punctuation that needs what follows it, like
+// the `#` of `#f` or the `:` of a keyword.
+fn source(input: TokenStream) -> (String, Vec<(usize, Span)>) {
+    let mut src = String::new();
+    let mut trees = Vec::new();
+    let mut joined = true;
+
+    for tree in input {
+        if !joined {
+            src.push(' ');
+        }
+        trees.push((src.len(), tree.span()));
+        src.push_str(&tree.span().source_text().unwrap_or_else(|| tree.to_string()));
+        joined = match tree {
+            TokenTree::Punct(ref punct) => "#:',".contains(punct.as_char()),
+            _ => false,
+        };
+    }
+    (src, trees)
+}
+
+// Where a program's source came from, for pointing errors at it.
+enum Origin {
+    // The top-level token trees given to `lisp!`, with where each starts in
+    // the source made from them.
+    Tokens(Vec<(usize, Span)>),
+    Literal(Span),
+    // A path given to `lisp_file!`, as it was written.
+    File(String, Span),
+}
+
+// The expression that builds the program in `src`, or a compile error
+// saying why it doesn't parse. Diagnostics are ignored, as they are by
+// `Program::new`'s other callers.
+fn expand(src: &str, origin: &Origin) -> TokenStream {
+    let (forms, symbols) =
+        match parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}) {
+            Ok(parsed) => parsed,
+            Err(error) => return parse_error(src, origin, &error),
+        };
+
+    let mut names = symbols.iter().collect::<Vec<_>>();
+    names.sort_by_key(|&(_, name)| name);
+    let mut expander = Expander {
+        ids: HashMap::new(),
+    };
+    let mut inserts = Vec::new();
+    for (i, &(id, name)) in names.iter().enumerate() {
+        let local = Ident::new(&format!("id{}", i), Span::call_site());
+        inserts.push(quote! { let #local = symbols.insert(#name); });
+        expander.ids.insert(id, local);
+    }
+    let forms = forms.iter().map(|form| expander.ast(form));
+
+    quote! {{
+        let mut symbols = ::rustfest::SymbolTable::new();
+        #(#inserts)*
+        ::rustfest::Program::new(::std::vec![#(#forms),*], symbols)
+    }}
+}
+
+fn parse_error(src: &str, origin: &Origin, error: &ParseError) -> TokenStream {
+    let position = match *error {
+        ParseError::Syntax { position, .. } => position,
+        _ => None,
+    };
+    // Like the `run` binary, this leaves out the byte offset that the
+    // message starts with in favour of the line and column.
+    let message = error.to_string();
+    let message = message
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("Parse error at"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let at = position.map(|position| line_and_column(src, position));
+
+    match *origin {
+        // The source made from tokens isn't laid out like the code they
+        // came from, so rather than a line and column the error points at
+        // the token tree it's in.
+        Origin::Tokens(ref trees) => {
+            let position = position.unwrap_or(usize::MAX);
+            let span = trees
+                .iter()
+                .take_while(|&&(start, _)| start <= position)
+                .last()
+                .map_or_else(Span::call_site, |&(_, span)| span);
+            compile_error(span, &message)
+        }
+        Origin::Literal(span) => match at {
+            Some((line, column)) => {
+                compile_error(span, &format!("{}:{}: {}", line, column, message))
+            }
+            None => compile_error(span, &message),
+        },
+        Origin::File(ref path, span) => match at {
+            Some((line, column)) => {
+                compile_error(span, &format!("{}:{}:{}: {}", path, line, column, message))
+            }
+            None => compile_error(span, &format!("{}: {}", path, message)),
+        },
+    }
+}
+
+// `syn::Error::to_compile_error` names `::core`, which crates on the 2015
+// edition can't see without an `extern crate core`.
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    quote_spanned!(span=> compile_error!(#message))
+}
+
+struct Expander {
+    // The local that each identifier's id is kept in while the program is
+    // built.
+    ids: HashMap<u64, Ident>,
+}
+
+impl Expander {
+    fn id(&self, id: u64) -> TokenStream {
+        match self.ids.get(&id) {
+            Some(local) => quote!(#local),
+            // The parser names every identifier it makes, so this is only
+            // here to be safe.
+            None => {
+                let id = Literal::u64_suffixed(id);
+                quote!(#id)
+            }
+        }
+    }
+
+    fn ast(&self, ast: &Ast<u64>) -> TokenStream {
+        match *ast {
+            Ast::Lit(ref value) => {
+                let value = self.value(value);
+                quote!(::rustfest::Ast::Lit(#value))
+            }
+            Ast::Variable(id) => {
+                let id = self.id(id);
+                quote!(::rustfest::Ast::Variable(#id))
+            }
+            Ast::Call(ref func, ref args) => {
+                let func = self.ast(func);
+                let args = args.iter().map(|arg| self.ast(arg));
+                quote! {
+                    ::rustfest::Ast::Call(
+                        ::std::rc::Rc::new(#func),
+                        ::std::rc::Rc::<[::rustfest::Ast<u64>]>::from(::std::vec![#(#args),*]),
+                    )
+                }
+            }
+            Ast::Define(id, ref value) => {
+                let id = self.id(id);
+                let value = self.ast(value);
+                quote!(::rustfest::Ast::Define(#id, ::std::rc::Rc::new(#value)))
+            }
+            Ast::Include(ref path) => {
+                let path: &str = path;
+                quote!(::rustfest::Ast::Include(::std::rc::Rc::from(#path)))
+            }
+        }
+    }
+
+    // Only the kinds of value that the parser makes are handled, since
+    // those are the only ones a parsed program can hold.
+    fn value(&self, value: &Value<u64>) -> TokenStream {
+        match *value {
+            Value::Void => quote!(::rustfest::Value::Void),
+            Value::False => quote!(::rustfest::Value::False),
+            Value::Nil => quote!(::rustfest::Value::Nil),
+            Value::Int(i) => {
+                let i = Literal::u64_suffixed(i);
+                quote!(::rustfest::Value::Int(#i))
+            }
+            Value::Str(ref s) => {
+                let s: &str = s;
+                quote!(::rustfest::Value::Str(::std::rc::Rc::new(::std::string::String::from(#s))))
+            }
+            Value::Bytes(ref bytes) => {
+                let bytes = Literal::byte_string(bytes);
+                quote!(::rustfest::Value::Bytes(::std::rc::Rc::new(#bytes.to_vec())))
+            }
+            Value::Keyword(id) => {
+                let id = self.id(id);
+                quote!(::rustfest::Value::Keyword(#id))
+            }
+            Value::Symbol(id) => {
+                let id = self.id(id);
+                quote!(::rustfest::Value::Symbol(#id))
+            }
+            Value::List(ref items) => {
+                let items = items.iter().map(|item| self.value(item));
+                quote!(::rustfest::Value::List(::std::rc::Rc::new(::std::vec![#(#items),*])))
+            }
+            Value::BigInt(ref n) => {
+                let n = n.to_string();
+                quote! {
+                    ::rustfest::Value::BigInt(::std::rc::Rc::new(
+                        ::rustfest::BigInt::from_decimal(#n).unwrap(),
+                    ))
+                }
+            }
+            Value::Function(ref lambda) => {
+                let lambda = self.lambda(lambda);
+                quote!(::rustfest::Value::Function(::std::rc::Rc::new(#lambda)))
+            }
+            ref other => {
+                let message = format!("`{:?}` can't be written in a program", other);
+                quote!(compile_error!(#message))
+            }
+        }
+    }
+
+    fn lambda(&self, lambda: &Lambda<u64>) -> TokenStream {
+        let params = lambda.params.iter().map(|param| self.pattern(param));
+        let defaults = lambda.defaults.iter().map(|default| self.ast(default));
+        let body = lambda.body.iter().map(|form| self.ast(form));
+        quote! {
+            ::rustfest::Lambda {
+                params: ::std::vec![#(#params),*].into_boxed_slice(),
+                defaults: ::std::vec![#(#defaults),*].into_boxed_slice(),
+                body: ::std::vec![#(#body),*].into_boxed_slice(),
+            }
+        }
+    }
+
+    fn pattern(&self, pattern: &Pattern<u64>) -> TokenStream {
+        match *pattern {
+            Pattern::Name(id) => {
+                let id = self.id(id);
+                quote!(::rustfest::Pattern::Name(#id))
+            }
+            Pattern::List(ref items) => {
+                let items = items.iter().map(|item| self.pattern(item));
+                quote!(::rustfest::Pattern::List(::std::vec![#(#items),*].into_boxed_slice()))
+            }
+        }
+    }
+}
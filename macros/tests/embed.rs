@@ -0,0 +1,96 @@
+#[macro_use]
+extern crate rustfest_macros;
+extern crate rustfest;
+extern crate trybuild;
+
+use std::fs;
+
+use rustfest::{parse_program_with_symbols, ParseOptions, Program, Value};
+
+fn run(program: &Program) -> Value<u64> {
+    let mut env = rustfest::prelude::env();
+    program.run(&mut env, &[]).unwrap()
+}
+
+// Check that `program` evaluates to what `src` does when it's parsed at
+// runtime, and names the same identifiers.
+fn same_as_parsed(program: &Program, src: &str) {
+    let (forms, symbols) =
+        parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+    assert_eq!(program.forms().len(), forms.len(), "{:?}", src);
+    for (id, name) in symbols.iter() {
+        assert_eq!(program.symbols().name(id), Some(name), "{:?}", src);
+    }
+    assert_eq!(program.symbols().len(), symbols.len(), "{:?}", src);
+    assert_eq!(run(program), run(&Program::new(forms, symbols)), "{:?}", src);
+}
+
+fn file(path: &str) -> String {
+    fs::read_to_string(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+}
+
+#[test]
+fn files_evaluate_like_parsed_ones() {
+    let programs = [
+        (lisp_file!("../tests/scripts/answer.lisp"), "../tests/scripts/answer.lisp"),
+        (lisp_file!("tests/scripts/init.lisp"), "tests/scripts/init.lisp"),
+    ];
+    for &(ref program, path) in &programs {
+        same_as_parsed(program, &file(path));
+    }
+    assert_eq!(run(&programs[0].0), Value::Int(42));
+}
+
+#[test]
+fn string_literals_evaluate_like_parsed_ones() {
+    let src = r"
+(= increment (\(a)
+  (add a 1)))
+(= someval (increment 2))
+(= rec (\ (a)
+  ((if (eq a 10)
+       (\() 10)
+       (\() (rec (add a 1)))))))
+(= quoted '(f ,someval (g :key 1)))
+(list (rec 0) someval quoted)
+";
+    same_as_parsed(
+        &lisp!(r"
+(= increment (\(a)
+  (add a 1)))
+(= someval (increment 2))
+(= rec (\ (a)
+  ((if (eq a 10)
+       (\() 10)
+       (\() (rec (add a 1)))))))
+(= quoted '(f ,someval (g :key 1)))
+(list (rec 0) someval quoted)
+"),
+        src,
+    );
+}
+
+#[test]
+fn tokens_evaluate_like_parsed_ones() {
+    let program = lisp! {
+        (= x 20)
+        (= a 5)
+        (= big 123456789012345678901234567890)
+        (= greeting "hello {x}")
+        (list (add x 22) #f #b"ab\x01" nil (= k :key) greeting big 12a)
+    };
+    let src = r#"(= x 20)
+(= a 5)
+(= big 123456789012345678901234567890)
+(= greeting "hello {x}")
+(list (add x 22) #f #b"ab\x01" nil (= k :key) greeting big 12 a)"#;
+    same_as_parsed(&program, src);
+
+    assert_eq!(run(&lisp! {}), Value::Void);
+    assert_eq!(run(&lisp!((add 1 2))), Value::Int(3));
+}
+
+#[test]
+fn syntax_errors_fail_to_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
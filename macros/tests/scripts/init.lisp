@@ -0,0 +1,9 @@
+(= twice (\(f x) (f (f x))))
+(= f (\((a (b c)) d) (add a b c d)))
+(= g (\(a (= b (add a 1))) (add a b)))
+(= x 1)
+(= getx (\() x))
+(= x 2)
+(= kind :script)
+(= name "init {(getx)}")
+(list (twice (\(n) (add n 10)) 22) (f (list 1 (list 2 3)) 4) (g 1) (g 1 10) kind name '(a ,x))
@@ -0,0 +1,9 @@
+#[macro_use]
+extern crate rustfest_macros;
+
+fn main() {
+    let program = lisp! {
+        (= x 1)
+        (add x ())
+    };
+}
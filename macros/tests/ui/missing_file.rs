@@ -0,0 +1,6 @@
+#[macro_use]
+extern crate rustfest_macros;
+
+fn main() {
+    lisp_file!("tests/ui/no-such-file.lisp");
+}
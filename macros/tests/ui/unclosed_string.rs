@@ -0,0 +1,6 @@
+#[macro_use]
+extern crate rustfest_macros;
+
+fn main() {
+    lisp!("(= x 1)\n(print \"unclosed)");
+}
@@ -0,0 +1,547 @@
+//! Facts about programs that can be worked out without running them.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::slice;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {Ast, EvalError, Lambda, Pattern, Value};
+
+/// The names that `ast` reads without binding them first, either as a
+/// parameter of a function around the read or with an earlier define.
+///
+/// A read in a function's body counts as bound by anything the scopes
+/// around the function define, even after it, since the function is
+/// assumed to be called once they're all defined. Elsewhere reads happen
+/// in order, so a read before the define of the same name is free. Defines
+/// in a function's body only bind names within it, and whatever an
+/// `include` defines isn't known.
+pub fn free_variables<Id: Clone + Eq + Hash>(ast: &Ast<Id>) -> HashSet<Id> {
+    program_free_variables(slice::from_ref(ast))
+}
+
+/// `free_variables` for a whole program, where each form can use what the
+/// forms before it define.
+pub fn program_free_variables<Id: Clone + Eq + Hash>(program: &[Ast<Id>]) -> HashSet<Id> {
+    let mut free = HashSet::new();
+    let mut bound = HashSet::new();
+    let mut functions = Vec::new();
+    for form in program {
+        visit(form, &mut bound, &mut functions, &mut free);
+    }
+    for lambda in functions {
+        function(lambda, &bound, &mut free);
+    }
+    free
+}
+
+// Reads in the body of a function are checked once everything around it
+// is bound, along with the functions inside it in turn.
+fn function<Id: Clone + Eq + Hash>(
+    lambda: &Lambda<Id>,
+    outer: &HashSet<Id>,
+    free: &mut HashSet<Id>,
+) {
+    let mut bound = outer.clone();
+    let mut functions = Vec::new();
+
+    let required = lambda.required();
+    for param in &lambda.params[..required] {
+        bound.extend(param.names().into_iter().cloned());
+    }
+    // Each default can use the parameters before it.
+    for (param, default) in lambda.params[required..].iter().zip(lambda.defaults.iter()) {
+        visit(default, &mut bound, &mut functions, free);
+        bound.extend(param.names().into_iter().cloned());
+    }
+    for stmt in lambda.body.iter() {
+        visit(stmt, &mut bound, &mut functions, free);
+    }
+
+    for lambda in functions {
+        function(lambda, &bound, free);
+    }
+}
+
+// Checks the reads in `ast` in the order they happen, and puts off the
+// functions it has until the scope they're in is finished.
+fn visit<'a, Id: Clone + Eq + Hash>(
+    ast: &'a Ast<Id>,
+    bound: &mut HashSet<Id>,
+    functions: &mut Vec<&'a Lambda<Id>>,
+    free: &mut HashSet<Id>,
+) {
+    match *ast {
+        Ast::Variable(ref name) => {
+            if !bound.contains(name) {
+                free.insert(name.clone());
+            }
+        }
+        Ast::Define(ref name, ref value) => {
+            visit(value, bound, functions, free);
+            bound.insert(name.clone());
+        }
+        Ast::Call(ref func, ref args) => {
+            visit(func, bound, functions, free);
+            for arg in args.iter() {
+                visit(arg, bound, functions, free);
+            }
+        }
+        Ast::Lit(Value::Function(ref lambda)) => functions.push(lambda),
+        Ast::Lit(_) | Ast::Include(_) => {}
+    }
+}
+
+/// Whether `a` and `b` are the same program up to consistent renaming of
+/// what functions bind, their parameters and the defines in their bodies.
+/// Reads refer to bindings the way `free_variables` has them, and a read in
+/// one has to refer to the binding in the same place as the read in the
+/// other, so `(\(x) (\(y) x))` isn't `(\(y) (\(y) y))`. Free variables and
+/// top-level defines have to have the same names, as do quoted symbols,
+/// which are data.
+///
+/// This walks both at once without recursing, looking at each node once.
+pub fn alpha_eq<Id: Clone + Eq + Hash>(a: &Ast<Id>, b: &Ast<Id>) -> bool {
+    let mut scopes = (Scopes::default(), Scopes::default());
+    // Which of the bindings of each function the walk is in have been
+    // bound so far, by position. The two sides have the same number.
+    let mut frames: Vec<Vec<bool>> = Vec::new();
+    let mut pending = vec![Step::Compare(a, b)];
+    while let Some(step) = pending.pop() {
+        let same = match step {
+            Step::Compare(a, b) => match (a, b) {
+                (Ast::Variable(a), Ast::Variable(b)) => {
+                    match (scopes.0.resolve(a, &frames), scopes.1.resolve(b, &frames)) {
+                        (None, None) => a == b,
+                        (bound_a, bound_b) => bound_a == bound_b,
+                    }
+                }
+                (Ast::Define(n1, v1), Ast::Define(n2, v2)) => {
+                    // The value is read before the name is bound.
+                    pending.push(Step::Bind(n1, n2));
+                    pending.push(Step::Compare(v1, v2));
+                    true
+                }
+                (Ast::Call(f1, a1), Ast::Call(f2, a2)) if a1.len() == a2.len() => {
+                    let args = a1.iter().zip(a2.iter()).rev();
+                    pending.extend(args.map(|(a, b)| Step::Compare(a, b)));
+                    pending.push(Step::Compare(f1, f2));
+                    true
+                }
+                (Ast::Lit(Value::Function(a)), Ast::Lit(Value::Function(b))) => {
+                    let same_shape = a.params.len() == b.params.len()
+                        && a.defaults.len() == b.defaults.len()
+                        && a.body.len() == b.body.len();
+                    let slots_a = scopes.0.enter(a, frames.len());
+                    let slots_b = scopes.1.enter(b, frames.len());
+                    frames.push(vec![false; slots_a]);
+                    pending.push(Step::Leave(a, b));
+                    same_shape && slots_a == slots_b && {
+                        let required = a.required();
+                        let body = a.body.iter().zip(b.body.iter()).rev();
+                        pending.extend(body.map(|(a, b)| Step::Compare(a, b)));
+                        // Each default can use the parameters before it.
+                        let optional = a.params[required..].iter().zip(b.params[required..].iter());
+                        let defaults = a.defaults.iter().zip(b.defaults.iter());
+                        for ((p1, p2), (d1, d2)) in optional.zip(defaults).rev() {
+                            pending.push(Step::Param(p1, p2));
+                            pending.push(Step::Compare(d1, d2));
+                        }
+                        let params = a.params[..required].iter().zip(b.params[..required].iter());
+                        pending.extend(params.rev().map(|(p1, p2)| Step::Param(p1, p2)));
+                        true
+                    }
+                }
+                (Ast::Lit(Value::Function(_)), _) | (_, Ast::Lit(Value::Function(_))) => false,
+                (Ast::Lit(a), Ast::Lit(b)) => a.equal(b),
+                (Ast::Include(a), Ast::Include(b)) => a == b,
+                _ => false,
+            },
+            Step::Bind(a, b) => bind(&scopes, &mut frames, a, b),
+            Step::Param(a, b) => {
+                let mut names_match = true;
+                let shapes_match = patterns_match(a, b, &mut |a, b| {
+                    names_match &= bind(&scopes, &mut frames, a, b);
+                });
+                shapes_match && names_match
+            }
+            Step::Leave(a, b) => {
+                scopes.0.leave(a);
+                scopes.1.leave(b);
+                frames.pop();
+                true
+            }
+        };
+        if !same {
+            return false;
+        }
+    }
+    true
+}
+
+// What's left to do in `alpha_eq`.
+enum Step<'a, Id> {
+    Compare(&'a Ast<Id>, &'a Ast<Id>),
+    // A define or parameter binds these names from here on.
+    Bind(&'a Id, &'a Id),
+    Param(&'a Pattern<Id>, &'a Pattern<Id>),
+    // The walk is done with the bodies of these functions.
+    Leave(&'a Lambda<Id>, &'a Lambda<Id>),
+}
+
+// For one side of `alpha_eq`, where each name is bound in the functions
+// the walk is in, innermost last, as the function's position in the walk
+// and the binding's position in the function.
+struct Scopes<Id> {
+    bindings: HashMap<Id, Vec<(usize, usize)>>,
+}
+
+impl<Id> Default for Scopes<Id> {
+    fn default() -> Self {
+        Scopes { bindings: HashMap::new() }
+    }
+}
+
+impl<Id: Clone + Eq + Hash> Scopes<Id> {
+    // Bind everything `lambda` binds, as the function at `frame`, giving
+    // how many names that is.
+    fn enter(&mut self, lambda: &Lambda<Id>, frame: usize) -> usize {
+        let names = binds(lambda);
+        for (slot, name) in names.iter().enumerate() {
+            self.bindings.entry((*name).clone()).or_default().push((frame, slot));
+        }
+        names.len()
+    }
+
+    fn leave(&mut self, lambda: &Lambda<Id>) {
+        for name in binds(lambda) {
+            if let Some(stack) = self.bindings.get_mut(name) {
+                stack.pop();
+            }
+        }
+    }
+
+    // The binding a read of `name` refers to, or `None` if it's free. The
+    // innermost function's bindings only count once they're bound, but the
+    // walk is in the body of every other function, which sees all of them.
+    fn resolve(&self, name: &Id, frames: &[Vec<bool>]) -> Option<(usize, usize)> {
+        let (&(frame, slot), outer) = self.bindings.get(name)?.split_last()?;
+        if frame + 1 == frames.len() && !frames[frame][slot] {
+            outer.last().copied()
+        } else {
+            Some((frame, slot))
+        }
+    }
+
+    fn slot(&self, name: &Id) -> Option<usize> {
+        self.bindings.get(name)?.last().map(|&(_, slot)| slot)
+    }
+}
+
+// Whether a define or parameter binding `a` binds what binding `b` does,
+// marking it as bound. At the top level that means the same name.
+fn bind<Id: Clone + Eq + Hash>(
+    scopes: &(Scopes<Id>, Scopes<Id>),
+    frames: &mut [Vec<bool>],
+    a: &Id,
+    b: &Id,
+) -> bool {
+    let frame = match frames.last_mut() {
+        Some(frame) => frame,
+        None => return a == b,
+    };
+    match (scopes.0.slot(a), scopes.1.slot(b)) {
+        (Some(a), Some(b)) if a == b => {
+            frame[a] = true;
+            true
+        }
+        _ => false,
+    }
+}
+
+// Whether two patterns have the same shape and `names` holds for each pair
+// of names in the same place. Recurses once per level of nesting, which
+// the parser limits.
+fn patterns_match<Id>(
+    a: &Pattern<Id>,
+    b: &Pattern<Id>,
+    names: &mut dyn FnMut(&Id, &Id),
+) -> bool {
+    match (a, b) {
+        (Pattern::Name(a), Pattern::Name(b)) => {
+            names(a, b);
+            true
+        }
+        (Pattern::List(a), Pattern::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| patterns_match(a, b, names))
+        }
+        _ => false,
+    }
+}
+
+// Every name `lambda` binds, as a parameter or a define in its defaults or
+// body outside any function inside it, each once, in the order they're
+// first bound.
+fn binds<Id: Eq + Hash>(lambda: &Lambda<Id>) -> Vec<&Id> {
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+    let mut bind = |name| {
+        if seen.insert(name) {
+            names.push(name);
+        }
+    };
+    for param in lambda.params.iter() {
+        param.names().into_iter().for_each(&mut bind);
+    }
+    let mut pending = lambda.defaults.iter().chain(lambda.body.iter()).rev().collect::<Vec<_>>();
+    while let Some(ast) = pending.pop() {
+        match *ast {
+            Ast::Define(ref name, ref value) => {
+                bind(name);
+                pending.push(value);
+            }
+            Ast::Call(ref func, ref args) => {
+                pending.extend(args.iter().rev());
+                pending.push(func);
+            }
+            Ast::Variable(_) | Ast::Lit(_) | Ast::Include(_) => {}
+        }
+    }
+    names
+}
+
+/// The size and shape of a program, which is cheap enough to work out
+/// before deciding whether to run it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AstMetrics {
+    /// How many levels below the root the deepest node is, counting the
+    /// body of a function as one level below it.
+    pub depth: usize,
+    pub nodes: usize,
+    pub calls: usize,
+    pub defines: usize,
+    /// How many function literals there are.
+    pub lambdas: usize,
+    /// The most arguments any call is given.
+    pub max_call_arity: usize,
+}
+
+/// Measures `ast` in one pass over its nodes, without recursing.
+pub fn metrics<Id>(ast: &Ast<Id>) -> AstMetrics {
+    let mut metrics = AstMetrics::default();
+    for (depth, node) in ast.iter_with_depth() {
+        metrics.nodes += 1;
+        metrics.depth = metrics.depth.max(depth);
+        match *node {
+            Ast::Call(_, ref args) => {
+                metrics.calls += 1;
+                metrics.max_call_arity = metrics.max_call_arity.max(args.len());
+            }
+            Ast::Define(..) => metrics.defines += 1,
+            Ast::Lit(Value::Function(_)) => metrics.lambdas += 1,
+            Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        }
+    }
+    metrics
+}
+
+/// The largest programs that `eval_with` will run, checked against the
+/// `metrics` of each form it's given before evaluating anything. Each is
+/// `None` for no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AstLimits {
+    /// The deepest a form may be. Unlike `EvalOptions::max_depth`, this is
+    /// about how the code is written rather than how deeply calls nest.
+    pub max_depth: Option<usize>,
+    pub max_nodes: Option<usize>,
+    pub max_calls: Option<usize>,
+    pub max_defines: Option<usize>,
+    pub max_lambdas: Option<usize>,
+    pub max_call_arity: Option<usize>,
+}
+
+impl AstLimits {
+    /// Fails with `EvalError::TooComplex` naming the first limit that
+    /// `metrics` is over.
+    pub fn check<Id>(&self, metrics: &AstMetrics) -> Result<(), EvalError<Id>> {
+        let checks = [
+            (Metric::Depth, metrics.depth, self.max_depth),
+            (Metric::Nodes, metrics.nodes, self.max_nodes),
+            (Metric::Calls, metrics.calls, self.max_calls),
+            (Metric::Defines, metrics.defines, self.max_defines),
+            (Metric::Lambdas, metrics.lambdas, self.max_lambdas),
+            (Metric::CallArity, metrics.max_call_arity, self.max_call_arity),
+        ];
+        for &(metric, got, max) in &checks {
+            match max {
+                Some(max) if got > max => return Err(EvalError::TooComplex { metric, got, max }),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One of the measurements in `AstMetrics`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Metric {
+    Depth,
+    Nodes,
+    Calls,
+    Defines,
+    Lambdas,
+    CallArity,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Metric::Depth => "depth",
+            Metric::Nodes => "number of nodes",
+            Metric::Calls => "number of calls",
+            Metric::Defines => "number of defines",
+            Metric::Lambdas => "number of functions",
+            Metric::CallArity => "number of arguments to a call",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::collections::HashSet;
+
+    use benches::{corpus_env, DEEP_NESTING, MANY_VARIABLES, REAL_CODE};
+    use {eval_with, hash_string, parse_program, EvalError, EvalOptions};
+
+    use super::{alpha_eq, free_variables, metrics, program_free_variables, AstLimits, Metric};
+
+    fn names(names: &[&str]) -> HashSet<u64> {
+        names.iter().map(|name| hash_string(name)).collect()
+    }
+
+    fn free(src: &str) -> HashSet<u64> {
+        program_free_variables(&parse_program(src).unwrap())
+    }
+
+    fn same(a: &str, b: &str) -> bool {
+        alpha_eq(&parse_program(a).unwrap()[0], &parse_program(b).unwrap()[0])
+    }
+
+    #[test]
+    fn real_code_needs_three_builtins() {
+        assert_eq!(free(REAL_CODE), names(&["add", "eq", "if"]));
+    }
+
+    #[test]
+    fn handles_shadowing_and_defines() {
+        // Reads before a define are free, reads after it aren't.
+        assert_eq!(free("(add x 1) (= x 1) x"), names(&["add", "x"]));
+        assert_eq!(free("(= x (add x 1))"), names(&["add", "x"]));
+        // Defines in one argument are seen by the ones after it.
+        assert_eq!(free("(list (= q 1) q)"), names(&["list"]));
+
+        // Parameters shadow whatever is outside.
+        assert_eq!(free(r"(= f (\(add) (add 1)))"), names(&[]));
+        assert_eq!(free(r"(= f (\((a b)) (add a b)))"), names(&["add"]));
+        // Defines in a body don't leak out of it, and a read in a body
+        // before the define is still free.
+        assert_eq!(free(r"(= f (\() (= y 1) y)) y"), names(&["y"]));
+        assert_eq!(free(r"(\() y (= y 1))"), names(&["y"]));
+        // Functions only run once the scope around them is defined.
+        assert_eq!(free(r"(\() (= g (\() z)) (= z 1) (g))"), names(&[]));
+        // Defaults see the parameters before them.
+        assert_eq!(free(r"(\(a (= b a) (= c d)) (list b c))"), names(&["d", "list"]));
+
+        // On its own, a form knows nothing about the rest of the program.
+        let program = parse_program(r"(= x 1) (= f (\() x))").unwrap();
+        assert_eq!(free_variables(&program[1]), names(&["x"]));
+        assert_eq!(program_free_variables(&program), names(&[]));
+    }
+
+    #[test]
+    fn renaming_what_functions_bind_is_alpha_equivalent() {
+        assert!(same(r"(\(x y) (add x y))", r"(\(a b) (add a b))"));
+        assert!(same(r"(\((x y) (= z x)) (list x y z))", r"(\((p q) (= r p)) (list p q r))"));
+        assert!(same(r"(\() (= t 1) (\() t))", r"(\() (= u 1) (\() u))"));
+        assert!(!same(r"(\(x y) (sub x y))", r"(\(y x) (sub x y))"));
+        assert!(!same(r"(\(a a) a)", r"(\(a b) b)"));
+        assert!(!same(r"(\(x) x)", r"(\(x y) x)"));
+
+        let deep = &parse_program(DEEP_NESTING).unwrap()[0];
+        assert!(alpha_eq(deep, deep));
+    }
+
+    #[test]
+    fn free_variables_have_to_match() {
+        assert!(!same(r"(\(x) (add x y))", r"(\(x) (add x z))"));
+        assert!(!same(r"(\(x) (add x 1))", r"(\(add) (add x 1))"));
+        assert!(!same("(= x 1)", "(= y 1)"));
+        assert!(!same("'x", "'y"));
+        // A read before the define in the same body is of something else.
+        assert!(same(r"(\() (list y (= z 1) z))", r"(\() (list y (= w 1) w))"));
+        assert!(!same(r"(\() (list y (= y 1) y))", r"(\() (list z (= z 1) z))"));
+    }
+
+    #[test]
+    fn reads_refer_to_the_same_binding_through_shadowing() {
+        // Mapping names one to one would pair `x` with `y` and then `y`
+        // with `y`, and call these equal.
+        assert!(!same(r"(\(x) (\(y) x))", r"(\(y) (\(y) y))"));
+        assert!(same(r"(\(x) (\(y) x))", r"(\(y) (\(x) y))"));
+        assert!(same(r"(\(x) (\(x) x))", r"(\(a) (\(b) b))"));
+        assert!(!same(r"(\(x) (\(x) x))", r"(\(a) (\(b) a))"));
+        // The inner function's define shadows the outer parameter only for
+        // the reads after it.
+        assert!(same(r"(\(x) (\() (list x (= x 1) x)))", r"(\(a) (\() (list a (= b 1) b)))"));
+        assert!(!same(r"(\(x) (\() (list x (= x 1) x)))", r"(\(a) (\() (list b (= b 1) b)))"));
+    }
+
+    #[test]
+    fn measures_the_bench_programs() {
+        let deep = metrics(&parse_program(DEEP_NESTING).unwrap()[0]);
+        assert_eq!(deep.depth, 45);
+        assert_eq!((deep.nodes, deep.calls), (46, 45));
+        assert!(deep.max_call_arity <= 1);
+
+        let many = metrics(&parse_program(MANY_VARIABLES).unwrap()[0]);
+        assert_eq!(many.max_call_arity, 26);
+        assert_eq!((many.lambdas, many.defines), (1, 0));
+    }
+
+    #[test]
+    fn rejects_programs_over_the_limits() {
+        let program = parse_program(REAL_CODE).unwrap();
+        let deepest = program.iter().map(|form| metrics(form).depth).max().unwrap();
+
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            limits: Some(AstLimits {
+                max_depth: Some(deepest - 1),
+                ..AstLimits::default()
+            }),
+            ..EvalOptions::default()
+        };
+        let results = program
+            .iter()
+            .map(|form| eval_with(form, &mut env, &mut options).err())
+            .collect::<Vec<_>>();
+        let too_deep = Some(EvalError::TooComplex {
+            metric: Metric::Depth,
+            got: deepest,
+            max: deepest - 1,
+        });
+        assert!(results.contains(&too_deep));
+        assert!(results.contains(&None));
+
+        options.limits = Some(AstLimits {
+            max_depth: Some(deepest),
+            ..AstLimits::default()
+        });
+        for form in &program {
+            assert!(eval_with(form, &mut env, &mut options).is_ok());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Integers too big for a `u64`, which `add` and `mul` move on to when a
+//! result doesn't fit, and which literals too long for one are read as.
+//!
+//! Like `Value::Int` these are never negative, and since nothing in the
+//! prelude subtracts or divides them, there's only what it needs here:
+//! adding, multiplying, comparing, and reading and writing them in decimal.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+/// The most digits `BigInt::from_decimal` reads. Reading takes time in the
+/// square of how many there are, so without a limit a long enough literal
+/// would keep the parser busy for as long as it liked.
+pub const MAX_DIGITS: usize = 10_000;
+
+/// A non-negative integer of any size.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BigInt {
+    // Base 2^32, least significant first, with no zeros at the top so that
+    // each number is written one way and the derived `==` is right.
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    /// Reads a number written in decimal, or `None` if `text` is empty, has
+    /// anything but digits in it or is longer than `MAX_DIGITS`.
+    pub fn from_decimal(text: &str) -> Option<BigInt> {
+        if text.is_empty() || text.len() > MAX_DIGITS {
+            return None;
+        }
+        let mut out = BigInt { digits: Vec::new() };
+        // Nine digits at a time, which is as many as always fit in a `u32`.
+        for chunk in text.as_bytes().chunks(9) {
+            let mut value = 0;
+            for &byte in chunk {
+                if !byte.is_ascii_digit() {
+                    return None;
+                }
+                value = value * 10 + u32::from(byte - b'0');
+            }
+            out.mul_add_small(10u32.pow(chunk.len() as u32), value);
+        }
+        Some(out)
+    }
+
+    /// This number as a `u64`, if it fits in one.
+    pub fn to_u64(&self) -> Option<u64> {
+        match self.digits[..] {
+            [] => Some(0),
+            [low] => Some(u64::from(low)),
+            [low, high] => Some(u64::from(high) << 32 | u64::from(low)),
+            _ => None,
+        }
+    }
+
+    // What `Value::heap_size` counts for this.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.digits.capacity() * 4
+    }
+
+    // `self * by + add`, in place.
+    fn mul_add_small(&mut self, by: u32, add: u32) {
+        let mut carry = u64::from(add);
+        for digit in &mut self.digits {
+            let product = u64::from(*digit) * u64::from(by) + carry;
+            *digit = product as u32;
+            carry = product >> 32;
+        }
+        if carry != 0 {
+            self.digits.push(carry as u32);
+        }
+    }
+
+    // The remainder of dividing by `by`, leaving the quotient in `self`.
+    fn div_rem_small(&mut self, by: u32) -> u32 {
+        let mut rem = 0;
+        for digit in self.digits.iter_mut().rev() {
+            let current = rem << 32 | u64::from(*digit);
+            *digit = (current / u64::from(by)) as u32;
+            rem = current % u64::from(by);
+        }
+        self.trim();
+        rem as u32
+    }
+
+    fn trim(&mut self) {
+        while self.digits.last() == Some(&0) {
+            self.digits.pop();
+        }
+    }
+}
+
+impl From<u64> for BigInt {
+    fn from(i: u64) -> Self {
+        let mut out = BigInt {
+            digits: vec![i as u32, (i >> 32) as u32],
+        };
+        out.trim();
+        out
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        let (long, short) = if self.digits.len() >= other.digits.len() {
+            (&self.digits, &other.digits)
+        } else {
+            (&other.digits, &self.digits)
+        };
+
+        let mut digits = Vec::with_capacity(long.len() + 1);
+        let mut carry = 0;
+        for (i, &digit) in long.iter().enumerate() {
+            let sum = u64::from(digit) + u64::from(short.get(i).cloned().unwrap_or(0)) + carry;
+            digits.push(sum as u32);
+            carry = sum >> 32;
+        }
+        if carry != 0 {
+            digits.push(carry as u32);
+        }
+        BigInt { digits }
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    // The way it's done on paper, which is plenty for the sizes that
+    // programs get to.
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut out = BigInt {
+            digits: vec![0; self.digits.len() + other.digits.len()],
+        };
+        for (i, &a) in self.digits.iter().enumerate() {
+            let mut carry = 0;
+            for (j, &b) in other.digits.iter().enumerate() {
+                let product = u64::from(a) * u64::from(b) + u64::from(out.digits[i + j]) + carry;
+                out.digits[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            out.digits[i + other.digits.len()] = carry as u32;
+        }
+        out.trim();
+        out
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.digits
+            .len()
+            .cmp(&other.digits.len())
+            .then_with(|| self.digits.iter().rev().cmp(other.digits.iter().rev()))
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Nine decimal digits at a time, least significant first.
+        let mut rest = self.clone();
+        let mut chunks = Vec::new();
+        while !rest.digits.is_empty() {
+            chunks.push(rest.div_rem_small(1_000_000_000));
+        }
+
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, others)) => {
+                write!(f, "{}", first)?;
+                for chunk in others.iter().rev() {
+                    write!(f, "{:09}", chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+
+    use super::{BigInt, MAX_DIGITS};
+
+    fn big(digits: &str) -> BigInt {
+        BigInt::from_decimal(digits).unwrap()
+    }
+
+    #[test]
+    fn decimal_round_trips() {
+        for digits in &["0", "1", "4294967296", "18446744073709551616", "1000000000000000000000"] {
+            assert_eq!(big(digits).to_string(), *digits);
+        }
+        assert_eq!(big("007").to_string(), "7");
+
+        assert_eq!(BigInt::from_decimal(""), None);
+        assert_eq!(BigInt::from_decimal("12a"), None);
+        assert_eq!(BigInt::from_decimal(&"9".repeat(MAX_DIGITS + 1)), None);
+    }
+
+    #[test]
+    fn arithmetic_carries() {
+        let max = BigInt::from(u64::MAX);
+        assert_eq!(max.to_u64(), Some(u64::MAX));
+        assert_eq!((&max + &BigInt::from(1)).to_string(), "18446744073709551616");
+        assert_eq!((&max * &max).to_string(), "340282366920938463426481119284349108225");
+        assert_eq!((&max * &BigInt::from(0)), BigInt::from(0));
+        assert_eq!((&max + &max).to_u64(), None);
+
+        assert!(big("18446744073709551616") > max);
+        assert!(big("4294967296") < big("18446744073709551616"));
+    }
+}
@@ -0,0 +1,42 @@
+extern crate rustfest;
+extern crate rustyline;
+
+use std::io;
+use std::process;
+
+use rustfest::repl::Repl;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+fn main() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Couldn't start the terminal: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut repl = Repl::new();
+    let mut stdout = io::stdout();
+
+    loop {
+        let line = match editor.readline(repl.prompt()) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Couldn't read input: {}", e);
+                process::exit(1);
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        match repl.feed(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                eprintln!("Couldn't write output: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+extern crate rustfest;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use std::str::FromStr;
+
+use rustfest::report::{render_error, run_source_with};
+use rustfest::{prelude, EvalOptions};
+
+const USAGE: &str = "\
+Usage: run [--fuel N] [--max-depth N] FILE
+
+Runs the program in FILE, or standard input if FILE is `-`, and prints the
+value of its last form.
+
+Options:
+  --fuel N       Stop after evaluating N expressions
+  --max-depth N  Stop if calls nest more than N deep (default 1000)";
+
+const DEFAULT_MAX_DEPTH: usize = 1000;
+
+struct Args {
+    path: String,
+    options: EvalOptions<u64>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut path = None;
+    let mut options = EvalOptions {
+        max_depth: Some(DEFAULT_MAX_DEPTH),
+        ..EvalOptions::default()
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fuel" => options.fuel = Some(number(&arg, args.next())?),
+            "--max-depth" => options.max_depth = Some(number(&arg, args.next())?),
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    match path {
+        Some(path) => Ok(Args { path, options }),
+        None => Err("No file given".to_owned()),
+    }
+}
+
+fn number<T: FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, not `{}`", option, value))
+}
+
+fn read_source(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut src = String::new();
+        io::stdin().read_to_string(&mut src)?;
+        Ok(src)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("{}\n", message);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let name = if args.path == "-" { "<stdin>" } else { &args.path };
+
+    let src = match read_source(&args.path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("{}: error: {}", name, e);
+            process::exit(1);
+        }
+    };
+
+    let mut warn = |diagnostic| eprintln!("{}: warning: {}", name, diagnostic);
+    match run_source_with(&src, &prelude::env(), args.options, &mut warn) {
+        Ok(value) => println!("{}", value),
+        Err(e) => {
+            eprint!("{}", render_error(&e, &src, name));
+            process::exit(1);
+        }
+    }
+}
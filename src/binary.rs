@@ -0,0 +1,679 @@
+//! A compact binary encoding of parsed programs, so they can be parsed once
+//! and shipped somewhere else to run.
+//!
+//! An encoding starts with the magic bytes `RFST` and a little-endian `u16`
+//! format version, followed by the number of nodes and then the nodes
+//! themselves. Each node is a tag byte and its fields, and refers to its
+//! children by their index in the node table, which must be lower than its
+//! own. The last node is the root. Identifiers are their 8-byte
+//! little-endian hashes, strings are a varint length followed by that many
+//! bytes of UTF-8, byte strings are the same without having to be UTF-8,
+//! an integer too big for a `u64` is its decimal digits written as a
+//! string, and every other number is an unsigned LEB128 varint. A
+//! function's parameters are a varint count followed by each parameter,
+//! which is a 0 byte and the name it binds, or a 1 byte and the items of a
+//! list pattern written the same way. They're followed by the defaults of
+//! the last parameters and then the body, each a varint count of children.
+//! A child that's shared between several parents is only written once, and
+//! stays shared once decoded.
+//!
+//! `FORMAT_VERSION` only changes when the format does, not with the crate's
+//! version, and decoding anything with a different version fails.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {Ast, BigInt, Lambda, Pattern, Value, MAX_NESTING};
+
+const MAGIC: &[u8; 4] = b"RFST";
+
+/// The version of the format written by `Ast::to_bytes`.
+pub const FORMAT_VERSION: u16 = 10;
+
+const TAG_VOID: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_INT: u8 = 2;
+const TAG_FUNCTION: u8 = 3;
+const TAG_VARIABLE: u8 = 4;
+const TAG_CALL: u8 = 5;
+const TAG_DEFINE: u8 = 6;
+const TAG_INCLUDE: u8 = 7;
+const TAG_KEYWORD: u8 = 8;
+const TAG_STRING: u8 = 9;
+const TAG_SYMBOL: u8 = 10;
+const TAG_BIG_INT: u8 = 11;
+const TAG_BYTES: u8 = 12;
+const TAG_NIL: u8 = 13;
+
+/// Why a program couldn't be encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncodeError {
+    /// The program contains an `InbuiltFunc`, which has no name to write.
+    NativeFunction,
+    /// The program contains a list or multiple values, which no program
+    /// can write either.
+    List,
+    /// The program contains a partially applied function, which can only
+    /// be built by running a program.
+    Partial,
+    /// The program contains a thunk, which can only be built by running a
+    /// program too.
+    Thunk,
+    /// The program contains a box, which is made by running a program as
+    /// well.
+    Box,
+    /// The program contains a host object, which only the host knows how
+    /// to write.
+    Foreign,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::NativeFunction => write!(f, "Native functions can't be encoded"),
+            EncodeError::List => write!(f, "Lists can't be encoded"),
+            EncodeError::Partial => write!(f, "Partially applied functions can't be encoded"),
+            EncodeError::Thunk => write!(f, "Thunks can't be encoded"),
+            EncodeError::Box => write!(f, "Boxes can't be encoded"),
+            EncodeError::Foreign => write!(f, "Foreign values can't be encoded"),
+        }
+    }
+}
+
+impl error::Error for EncodeError {}
+
+/// Why some bytes couldn't be decoded into a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input doesn't start with the magic bytes.
+    NotAProgram,
+    /// The input was written with a different version of the format.
+    VersionMismatch { found: u16, expected: u16 },
+    /// The input ends in the middle of a node.
+    UnexpectedEnd,
+    /// A node has a tag that doesn't exist.
+    UnknownTag(u8),
+    /// A varint doesn't fit in 64 bits.
+    Overflow,
+    /// A string isn't valid UTF-8.
+    InvalidUtf8,
+    /// A big integer has something other than decimal digits in it, or
+    /// more of them than `bigint::MAX_DIGITS`.
+    BadNumber,
+    /// A list pattern is nested more than `MAX_NESTING` deep, which the
+    /// parser would never have produced.
+    TooDeep,
+    /// A function has defaults for more parameters than it has, or for a
+    /// list pattern.
+    BadDefaults,
+    /// A node refers to a child that isn't before it in the table.
+    BadReference { node: usize, child: usize },
+    /// The node table is empty, so there's no root.
+    Empty,
+    /// There are bytes left over after the last node.
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::NotAProgram => write!(f, "Not an encoded program"),
+            DecodeError::VersionMismatch { found, expected } => write!(
+                f,
+                "Program was encoded with format version {}, expected {}",
+                found, expected
+            ),
+            DecodeError::UnexpectedEnd => write!(f, "Encoded program is truncated"),
+            DecodeError::UnknownTag(tag) => write!(f, "Unknown node tag {}", tag),
+            DecodeError::Overflow => write!(f, "Number is too large"),
+            DecodeError::InvalidUtf8 => write!(f, "String is not valid UTF-8"),
+            DecodeError::BadNumber => write!(f, "Big integer is badly written or too long"),
+            DecodeError::TooDeep => write!(f, "Parameter patterns are nested too deeply"),
+            DecodeError::BadDefaults => {
+                write!(f, "Function has defaults that don't fit its parameters")
+            }
+            DecodeError::BadReference { node, child } => {
+                write!(f, "Node {} refers to node {}, which isn't before it", node, child)
+            }
+            DecodeError::Empty => write!(f, "Encoded program has no nodes"),
+            DecodeError::TrailingBytes => write!(f, "Unexpected bytes after the last node"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl Ast<u64> {
+    /// Encode this program in the format described in the `binary` module.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoder = Encoder {
+            nodes: Vec::new(),
+            indices: HashMap::new(),
+            count: 0,
+        };
+        encoder.encode(self)?;
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        write_varint(&mut out, encoder.count as u64);
+        out.extend(encoder.nodes);
+        Ok(out)
+    }
+
+    /// Decode a program written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut input = Reader { bytes };
+
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::NotAProgram);
+        }
+        let version = input.take(2)?;
+        let version = u16::from_le_bytes([version[0], version[1]]);
+        if version != FORMAT_VERSION {
+            return Err(DecodeError::VersionMismatch {
+                found: version,
+                expected: FORMAT_VERSION,
+            });
+        }
+
+        let count = input.varint()?;
+        // Every node takes at least a byte, so this bounds the allocation.
+        let mut nodes: Vec<Rc<Ast<u64>>> = Vec::with_capacity((count as usize).min(input.bytes.len()));
+
+        for node in 0..count as usize {
+            let child = |input: &mut Reader| match input.varint()? as usize {
+                child if child < node => Ok(child),
+                child => Err(DecodeError::BadReference { node, child }),
+            };
+
+            let ast = match input.byte()? {
+                TAG_VOID => Ast::Lit(Value::Void),
+                TAG_FALSE => Ast::Lit(Value::False),
+                TAG_NIL => Ast::Lit(Value::Nil),
+                TAG_INT => Ast::Lit(Value::Int(input.varint()?)),
+                TAG_BIG_INT => {
+                    let big = BigInt::from_decimal(input.text()?).ok_or(DecodeError::BadNumber)?;
+                    Ast::Lit(Value::BigInt(Rc::new(big)))
+                }
+                TAG_FUNCTION => {
+                    let params = input.patterns(0)?;
+                    let children = |input: &mut Reader| {
+                        (0..input.varint()?)
+                            .map(|_| child(input).map(|i| (*nodes[i]).clone()))
+                            .collect::<Result<Box<[_]>, _>>()
+                    };
+                    let defaults = children(&mut input)?;
+                    let body = children(&mut input)?;
+
+                    let named = |param: &Pattern<u64>| matches!(*param, Pattern::Name(_));
+                    match params.len().checked_sub(defaults.len()) {
+                        Some(required) if params[required..].iter().all(named) => {}
+                        _ => return Err(DecodeError::BadDefaults),
+                    }
+                    Ast::Lit(Value::Function(Rc::new(Lambda { params, defaults, body })))
+                }
+                TAG_VARIABLE => Ast::Variable(input.ident()?),
+                TAG_KEYWORD => Ast::Lit(Value::Keyword(input.ident()?)),
+                TAG_SYMBOL => Ast::Lit(Value::Symbol(input.ident()?)),
+                TAG_CALL => {
+                    let func = nodes[child(&mut input)?].clone();
+                    let args = (0..input.varint()?)
+                        .map(|_| child(&mut input).map(|i| (*nodes[i]).clone()))
+                        .collect::<Result<Rc<[_]>, _>>()?;
+                    Ast::Call(func, args)
+                }
+                TAG_DEFINE => {
+                    let name = input.ident()?;
+                    let value = nodes[child(&mut input)?].clone();
+                    Ast::Define(name, value)
+                }
+                TAG_STRING => Ast::Lit(Value::Str(Rc::new(input.text()?.to_owned()))),
+                TAG_BYTES => Ast::Lit(Value::Bytes(Rc::new(input.bytes()?.to_vec()))),
+                TAG_INCLUDE => Ast::Include(input.text()?.into()),
+                tag => return Err(DecodeError::UnknownTag(tag)),
+            };
+
+            nodes.push(Rc::new(ast));
+        }
+
+        if !input.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+
+        let root = nodes.pop().ok_or(DecodeError::Empty)?;
+        Ok(Rc::try_unwrap(root).unwrap_or_else(|root| (*root).clone()))
+    }
+}
+
+struct Encoder {
+    nodes: Vec<u8>,
+    // The index of every node written so far, by address, so that shared
+    // children are only written once.
+    indices: HashMap<usize, usize>,
+    count: usize,
+}
+
+impl Encoder {
+    // Writes `root` and everything under it, children first. This uses a
+    // worklist rather than recursion, since programs can be very deep.
+    fn encode(&mut self, root: &Ast<u64>) -> Result<(), EncodeError> {
+        let mut pending = vec![(root, false)];
+
+        while let Some((ast, children_written)) = pending.pop() {
+            if self.indices.contains_key(&address(ast)) {
+                continue;
+            }
+
+            if !children_written {
+                pending.push((ast, true));
+                match *ast {
+                    Ast::Lit(Value::Function(ref lambda)) => {
+                        let children = lambda.defaults.iter().chain(lambda.body.iter());
+                        pending.extend(children.rev().map(|stmt| (stmt, false)))
+                    }
+                    Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+                    Ast::Call(ref func, ref args) => {
+                        pending.extend(args.iter().rev().map(|arg| (arg, false)));
+                        pending.push((func, false));
+                    }
+                    Ast::Define(_, ref value) => pending.push((value, false)),
+                }
+                continue;
+            }
+
+            self.write(ast)?;
+            self.indices.insert(address(ast), self.count);
+            self.count += 1;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, ast: &Ast<u64>) -> Result<(), EncodeError> {
+        let indices = &self.indices;
+        let out = &mut self.nodes;
+        let index = |child: &Ast<u64>| indices[&address(child)] as u64;
+
+        match *ast {
+            Ast::Lit(Value::Void) => out.push(TAG_VOID),
+            Ast::Lit(Value::False) => out.push(TAG_FALSE),
+            Ast::Lit(Value::Nil) => out.push(TAG_NIL),
+            Ast::Lit(Value::Int(i)) => {
+                out.push(TAG_INT);
+                write_varint(out, i);
+            }
+            Ast::Lit(Value::BigInt(ref big)) => {
+                let digits = big.to_string();
+                out.push(TAG_BIG_INT);
+                write_varint(out, digits.len() as u64);
+                out.extend_from_slice(digits.as_bytes());
+            }
+            Ast::Lit(Value::Function(ref lambda)) => {
+                out.push(TAG_FUNCTION);
+                write_patterns(out, &lambda.params);
+                write_varint(out, lambda.defaults.len() as u64);
+                for default in lambda.defaults.iter() {
+                    write_varint(out, index(default));
+                }
+                write_varint(out, lambda.body.len() as u64);
+                for stmt in lambda.body.iter() {
+                    write_varint(out, index(stmt));
+                }
+            }
+            Ast::Lit(Value::InbuiltFunc(_))
+            | Ast::Lit(Value::ReentrantFunc(_))
+            | Ast::Lit(Value::HostFunc(_)) => return Err(EncodeError::NativeFunction),
+            Ast::Lit(Value::List(_)) | Ast::Lit(Value::Values(_)) => return Err(EncodeError::List),
+            Ast::Lit(Value::Partial(_)) => return Err(EncodeError::Partial),
+            Ast::Lit(Value::Thunk(_)) => return Err(EncodeError::Thunk),
+            Ast::Lit(Value::Box(_)) => return Err(EncodeError::Box),
+            Ast::Lit(Value::Foreign(_)) => return Err(EncodeError::Foreign),
+            Ast::Variable(name) => {
+                out.push(TAG_VARIABLE);
+                out.extend_from_slice(&name.to_le_bytes());
+            }
+            Ast::Lit(Value::Keyword(name)) => {
+                out.push(TAG_KEYWORD);
+                out.extend_from_slice(&name.to_le_bytes());
+            }
+            Ast::Lit(Value::Symbol(name)) => {
+                out.push(TAG_SYMBOL);
+                out.extend_from_slice(&name.to_le_bytes());
+            }
+            Ast::Lit(Value::Str(ref text)) => {
+                out.push(TAG_STRING);
+                write_varint(out, text.len() as u64);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Ast::Lit(Value::Bytes(ref bytes)) => {
+                out.push(TAG_BYTES);
+                write_varint(out, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Ast::Call(ref func, ref args) => {
+                out.push(TAG_CALL);
+                write_varint(out, index(func));
+                write_varint(out, args.len() as u64);
+                for arg in args.iter() {
+                    write_varint(out, index(arg));
+                }
+            }
+            Ast::Define(name, ref value) => {
+                out.push(TAG_DEFINE);
+                out.extend_from_slice(&name.to_le_bytes());
+                write_varint(out, index(value));
+            }
+            Ast::Include(ref path) => {
+                out.push(TAG_INCLUDE);
+                write_varint(out, path.len() as u64);
+                out.extend_from_slice(path.as_bytes());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn address(ast: &Ast<u64>) -> usize {
+    ast as *const Ast<u64> as usize
+}
+
+// Recurses once per level of nesting of list patterns.
+fn write_patterns(out: &mut Vec<u8>, patterns: &[Pattern<u64>]) {
+    write_varint(out, patterns.len() as u64);
+    for pattern in patterns {
+        match *pattern {
+            Pattern::Name(name) => {
+                out.push(0);
+                out.extend_from_slice(&name.to_le_bytes());
+            }
+            Pattern::List(ref items) => {
+                out.push(1);
+                write_patterns(out, items);
+            }
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.varint()?;
+        if len > self.bytes.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        self.take(len as usize)
+    }
+
+    fn text(&mut self) -> Result<&'a str, DecodeError> {
+        ::std::str::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn ident(&mut self) -> Result<u64, DecodeError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // The parameters of a function, or the items of a list pattern nested
+    // `depth` deep in them.
+    fn patterns(&mut self, depth: usize) -> Result<Box<[Pattern<u64>]>, DecodeError> {
+        if depth > MAX_NESTING {
+            return Err(DecodeError::TooDeep);
+        }
+
+        (0..self.varint()?)
+            .map(|_| match self.byte()? {
+                0 => self.ident().map(Pattern::Name),
+                1 => self.patterns(depth + 1).map(Pattern::List),
+                tag => Err(DecodeError::UnknownTag(tag)),
+            })
+            .collect()
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::Overflow);
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(DecodeError::Overflow)
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use benches::{corpus, same_ast};
+    use {hash_string, parse_program, Ast, Lambda, Value};
+
+    use super::{DecodeError, EncodeError, FORMAT_VERSION};
+
+    #[test]
+    fn corpus_round_trips() {
+        for (name, program) in corpus() {
+            for form in &program {
+                let bytes = form.to_bytes().unwrap();
+                let decoded = Ast::from_bytes(&bytes).unwrap();
+                assert!(same_ast(form, &decoded), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_children_are_written_once() {
+        let shared = Rc::new(Ast::Call(
+            Rc::new(Ast::Variable(hash_string("f"))),
+            vec![Ast::Lit(Value::Int(300))].into(),
+        ));
+        let program = Ast::Call(shared.clone(), vec![Ast::Define(hash_string("x"), shared)].into());
+
+        let decoded = Ast::from_bytes(&program.to_bytes().unwrap()).unwrap();
+        assert!(same_ast(&program, &decoded));
+        match decoded {
+            Ast::Call(ref func, ref args) => match args[0] {
+                Ast::Define(_, ref value) => assert!(Rc::ptr_eq(func, value)),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn includes_round_trip() {
+        let program = Ast::Call(
+            Rc::new(Ast::Variable(hash_string("f"))),
+            vec![Ast::Include("lib/µ.lisp".into())].into(),
+        );
+
+        let bytes = program.to_bytes().unwrap();
+        assert!(same_ast(&program, &Ast::from_bytes(&bytes).unwrap()));
+
+        let start = bytes.iter().position(|&b| b == b'l').unwrap();
+        let mut corrupt = bytes.clone();
+        corrupt[start + 4] = 0xff;
+        assert_eq!(Ast::from_bytes(&corrupt).err(), Some(DecodeError::InvalidUtf8));
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let program = parse_program(r#"(f "plain µ" "x is {x}" "")"#).unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let program = parse_program(r#"(f #b"\xff\x00 not UTF-8" #b"" nil)"#).unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn big_ints_round_trip() {
+        let program = parse_program("(f 18446744073709551616 1234567890123456789012345678901)");
+        let program = program.unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn quoted_code_round_trips() {
+        let program = parse_program(r"'(f 'x ,y (\(a (= b 1)) a))").unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+    }
+
+    #[test]
+    fn patterns_round_trip() {
+        let program = parse_program(r"(\((a (b)) () c) (add a b c))").unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+
+        let list = Ast::Lit(Value::List(Rc::new(vec![Value::Int(1)])));
+        assert_eq!(list.to_bytes().err(), Some(EncodeError::List));
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        let program = parse_program(r"(\(a (= b (add a 1)) (= c 3)) (add a b c))").unwrap();
+
+        let bytes = program[0].to_bytes().unwrap();
+        assert!(same_ast(&program[0], &Ast::from_bytes(&bytes).unwrap()));
+
+        // More defaults than parameters.
+        let lambda = Lambda {
+            params: Box::new([]),
+            defaults: vec![Ast::Lit(Value::Int(1))].into(),
+            body: Box::new([]),
+        };
+        let bytes = Ast::Lit(Value::Function(Rc::new(lambda))).to_bytes().unwrap();
+        assert_eq!(Ast::from_bytes(&bytes).err(), Some(DecodeError::BadDefaults));
+    }
+
+    #[test]
+    fn deep_programs_round_trip_on_a_small_stack() {
+        use std::thread;
+
+        let worker = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let name = hash_string("a");
+                let mut calls = Ast::Variable(name);
+                for _ in 0..200_000 {
+                    calls = Ast::Call(Rc::new(calls), vec![Ast::Variable(name)].into());
+                }
+
+                let decoded = Ast::from_bytes(&calls.to_bytes().unwrap()).unwrap();
+                let mut depth = 0;
+                let mut ast = &decoded;
+                while let Ast::Call(ref func, _) = *ast {
+                    depth += 1;
+                    ast = func;
+                }
+                assert_eq!(depth, 200_000);
+            })
+            .unwrap();
+
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_natives_and_other_versions() {
+        fn native(_: &[&Value<u64>]) -> Result<Value<u64>, ::EvalError<u64>> {
+            Ok(Value::Void)
+        }
+
+        let program = Ast::Lit(Value::Function(Rc::new(Lambda::new(
+            vec![],
+            vec![Ast::Lit(Value::InbuiltFunc(native))],
+        ))));
+        assert_eq!(program.to_bytes().err(), Some(EncodeError::NativeFunction));
+
+        let mut bytes = Ast::Variable(hash_string("a")).to_bytes().unwrap();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Ast::from_bytes(&bytes).err(),
+            Some(DecodeError::VersionMismatch {
+                found: FORMAT_VERSION + 1,
+                expected: FORMAT_VERSION,
+            })
+        );
+
+        assert_eq!(Ast::from_bytes(b"nope").err(), Some(DecodeError::NotAProgram));
+    }
+
+    #[test]
+    fn corrupt_input_is_an_error_not_a_panic() {
+        // A fixed xorshift sequence, so failures can be reproduced.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for (_, program) in corpus() {
+            for form in &program {
+                let bytes = form.to_bytes().unwrap();
+
+                for len in 0..bytes.len() {
+                    assert!(Ast::from_bytes(&bytes[..len]).is_err());
+                }
+
+                for _ in 0..200 {
+                    let mut corrupt = bytes.clone();
+                    for _ in 0..1 + random() % 4 {
+                        let i = random() as usize % corrupt.len();
+                        corrupt[i] = random() as u8;
+                    }
+                    // Some corruptions are still valid programs, which is
+                    // fine as long as nothing panics.
+                    let _ = Ast::from_bytes(&corrupt);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,777 @@
+//! A bytecode backend for hot programs.
+//!
+//! Every function body is compiled once into a flat `Chunk` of instructions,
+//! which the `Vm` runs with an explicit value stack instead of recursing
+//! through the AST. Scoping is the same dynamic scoping that `eval` gets by
+//! cloning the environment on every call: a function's parameters live in
+//! stack slots, and any other name is looked up through the frames of every
+//! function that is still running before falling back to the global
+//! environment.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {
+    first_keyword, promoted, single_values, Arguments, Ast, EvalError, IncludeError, Lambda,
+    NativeFn, Pattern, TruthPolicy, Value,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instr<Id> {
+    /// Push a constant from the chunk's constant table.
+    PushConst(usize),
+    /// Push a parameter of the running function.
+    LoadLocal(usize),
+    /// Push whatever a name is bound to in the innermost scope that has it.
+    LoadGlobal(Id),
+    /// Rebind a parameter of the running function to the top of the stack,
+    /// leaving the value there.
+    StoreLocal(usize),
+    /// Bind a name in the innermost scope to the top of the stack, leaving
+    /// the value there.
+    Define(Id),
+    /// Call the function that is below this many arguments on the stack.
+    Call(usize),
+    /// `Call`, where the arguments from the second number on are keyword
+    /// arguments.
+    CallNamed(usize, usize),
+    /// If the call gave the optional parameter with this index an argument
+    /// by keyword, push it and jump to the `Bind` after its default.
+    Given(usize, usize),
+    /// Bind the next parameter of the running function, which had no
+    /// argument, to the top of the stack, leaving the value there.
+    Bind,
+    /// Discard the top of the stack.
+    Pop,
+    /// Return the top of the stack to the caller.
+    Ret,
+    /// An `include`, which the VM can't follow, so this always fails.
+    Include,
+}
+
+impl<Id> Instr<Id> {
+    // Whether this finishes an expression. Each expression compiles to
+    // exactly one of these, so counting them counts what `eval_with` does.
+    pub(crate) fn is_expression(&self) -> bool {
+        !matches!(*self, Instr::Given(..) | Instr::Bind | Instr::Pop | Instr::Ret)
+    }
+}
+
+/// The compiled form of one function body, or of a whole program.
+pub struct Chunk<Id> {
+    params: Box<[Id]>,
+    // Where to start running when this many of the optional parameters
+    // were passed, so only the defaults of the rest are evaluated. The last
+    // is the start of the body.
+    starts: Box<[usize]>,
+    code: Vec<Instr<Id>>,
+    constants: Vec<Value<Id>>,
+}
+
+impl<Id> Chunk<Id> {
+    pub fn code(&self) -> &[Instr<Id>] {
+        &self.code
+    }
+}
+
+// Function values are matched to their chunks by the address of their
+// `Lambda`. Every table also keeps it alive (through the program's constants
+// or the VM's cache), so an address can't be reused while it's still a key.
+type FunctionKey = usize;
+
+fn function_key<Id>(lambda: &Rc<Lambda<Id>>) -> FunctionKey {
+    Rc::as_ptr(lambda) as usize
+}
+
+pub struct CompiledProgram<Id> {
+    entry: Rc<Chunk<Id>>,
+    functions: HashMap<FunctionKey, Rc<Chunk<Id>>>,
+    // What reentrant natives like `if` are told is true.
+    truth: TruthPolicy<Id>,
+}
+
+impl<Id: Clone + Debug + Eq + Hash> CompiledProgram<Id> {
+    pub fn entry(&self) -> &Chunk<Id> {
+        &self.entry
+    }
+
+    /// Run the whole program, returning the value of its last form.
+    pub fn run<'a, S: BuildHasher>(
+        &self,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+    ) -> Result<Value<Id>, EvalError<Id>> {
+        Vm::new(self).run(env)
+    }
+}
+
+pub(crate) fn compile<Id>(
+    program: &[Ast<Id>],
+    natives: &HashMap<Id, NativeFn<Id>>,
+    truth: TruthPolicy<Id>,
+) -> CompiledProgram<Id>
+where
+    Id: Clone + Eq + Hash,
+{
+    // A native can be called directly as long as nothing in the program
+    // could be shadowing it when the call happens.
+    let mut bound = HashSet::new();
+    for form in program {
+        collect_binders(form, &mut bound);
+    }
+    let direct = natives
+        .iter()
+        .filter(|&(name, _)| !bound.contains(name))
+        .map(|(name, &func)| (name.clone(), func))
+        .collect();
+
+    let mut compiler = Compiler {
+        direct,
+        functions: HashMap::new(),
+    };
+    let entry = compiler.chunk(Box::new([]), &[], program);
+
+    CompiledProgram {
+        entry: Rc::new(entry),
+        functions: compiler.functions,
+        truth,
+    }
+}
+
+fn collect_binders<Id: Clone + Eq + Hash>(ast: &Ast<Id>, out: &mut HashSet<Id>) {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            out.extend(lambda.names().into_iter().cloned());
+            for stmt in lambda.defaults.iter().chain(lambda.body.iter()) {
+                collect_binders(stmt, out);
+            }
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        Ast::Call(ref func, ref args) => {
+            collect_binders(func, out);
+            for arg in args.iter() {
+                collect_binders(arg, out);
+            }
+        }
+        Ast::Define(ref name, ref value) => {
+            out.insert(name.clone());
+            collect_binders(value, out);
+        }
+    }
+}
+
+// The names a function's parameters bind, which are its stack slots.
+fn params<Id: Clone>(lambda: &Lambda<Id>) -> Box<[Id]> {
+    lambda.names().into_iter().cloned().collect()
+}
+
+struct Compiler<Id> {
+    direct: HashMap<Id, NativeFn<Id>>,
+    functions: HashMap<FunctionKey, Rc<Chunk<Id>>>,
+}
+
+impl<Id: Clone + Eq + Hash> Compiler<Id> {
+    fn chunk(&mut self, params: Box<[Id]>, defaults: &[Ast<Id>], body: &[Ast<Id>]) -> Chunk<Id> {
+        let mut chunk = Chunk {
+            params,
+            starts: Box::new([]),
+            code: Vec::new(),
+            constants: Vec::new(),
+        };
+
+        let mut starts = Vec::with_capacity(defaults.len() + 1);
+        for (i, default) in defaults.iter().enumerate() {
+            starts.push(chunk.code.len());
+            let given = chunk.code.len();
+            chunk.code.push(Instr::Given(i, 0));
+            self.expr(default, &mut chunk);
+            chunk.code[given] = Instr::Given(i, chunk.code.len());
+            chunk.code.push(Instr::Bind);
+        }
+        starts.push(chunk.code.len());
+        chunk.starts = starts.into();
+
+        for (i, stmt) in body.iter().enumerate() {
+            if i > 0 {
+                chunk.code.push(Instr::Pop);
+            }
+            self.expr(stmt, &mut chunk);
+        }
+
+        if body.is_empty() {
+            chunk.constants.push(Value::Void);
+            chunk.code.push(Instr::PushConst(0));
+        }
+
+        chunk.code.push(Instr::Ret);
+        chunk
+    }
+
+    fn expr(&mut self, ast: &Ast<Id>, chunk: &mut Chunk<Id>) {
+        match *ast {
+            Ast::Lit(ref value) => {
+                if let Value::Function(ref lambda) = *value {
+                    let key = function_key(lambda);
+                    if !self.functions.contains_key(&key) {
+                        let compiled = self.chunk(params(lambda), &lambda.defaults, &lambda.body);
+                        self.functions.insert(key, Rc::new(compiled));
+                    }
+                }
+
+                chunk.constants.push(value.clone());
+                chunk.code.push(Instr::PushConst(chunk.constants.len() - 1));
+            }
+            Ast::Variable(ref name) => {
+                // With duplicate parameter names the last one wins, as in `eval`.
+                if let Some(slot) = chunk.params.iter().rposition(|p| p == name) {
+                    chunk.code.push(Instr::LoadLocal(slot));
+                } else if let Some(&func) = self.direct.get(name) {
+                    chunk.constants.push(Value::InbuiltFunc(func));
+                    chunk.code.push(Instr::PushConst(chunk.constants.len() - 1));
+                } else {
+                    chunk.code.push(Instr::LoadGlobal(name.clone()));
+                }
+            }
+            Ast::Call(ref func, ref args) => {
+                self.expr(func, chunk);
+                for arg in args.iter() {
+                    self.expr(arg, chunk);
+                }
+                match first_keyword(args) {
+                    Some(positional) => chunk.code.push(Instr::CallNamed(args.len(), positional)),
+                    None => chunk.code.push(Instr::Call(args.len())),
+                }
+            }
+            Ast::Define(ref name, ref value) => {
+                self.expr(value, chunk);
+                match chunk.params.iter().rposition(|p| p == name) {
+                    Some(slot) => chunk.code.push(Instr::StoreLocal(slot)),
+                    None => chunk.code.push(Instr::Define(name.clone())),
+                }
+            }
+            Ast::Include(_) => chunk.code.push(Instr::Include),
+        }
+    }
+}
+
+// What `Vm::execute` checks as it goes. `Vm::run` uses `Unpaused`, whose
+// checks compile away to nothing, and `eval_resumable` uses `EvalOptions`.
+pub(crate) trait Pause<Id> {
+    // Whether to stop before running `instr`, if it finishes an expression.
+    fn step(&mut self, instr: &Instr<Id>) -> Result<bool, EvalError<Id>>;
+    // Whether a native failing with `Yielded` stops the program, rather
+    // than failing it.
+    fn yields(&self) -> bool;
+}
+
+struct Unpaused;
+
+impl<Id> Pause<Id> for Unpaused {
+    fn step(&mut self, _: &Instr<Id>) -> Result<bool, EvalError<Id>> {
+        Ok(false)
+    }
+
+    fn yields(&self) -> bool {
+        false
+    }
+}
+
+// How `Vm::execute` stopped.
+pub(crate) enum Exit<Id> {
+    Returned(Value<Id>),
+    // A native yielded this. Whatever it's resumed with goes on the stack
+    // as what the call gave.
+    Yielded(Value<Id>),
+    // The next instruction is run when it's resumed.
+    OutOfFuel,
+}
+
+struct Frame<Id> {
+    chunk: Rc<Chunk<Id>>,
+    pc: usize,
+    // Where the function's arguments start on the stack.
+    base: usize,
+    // How many parameters were actually passed an argument.
+    bound: usize,
+    // The arguments that a call with keywords gave the optional
+    // parameters, until `Given` takes them.
+    given: Vec<Option<Value<Id>>>,
+    // Names defined in the function body that aren't parameters.
+    defines: Vec<(Id, Value<Id>)>,
+}
+
+pub struct Vm<'p, Id: 'p> {
+    program: &'p CompiledProgram<Id>,
+    // Functions that didn't come from `program`, e.g. ones the host put in
+    // the environment, compiled the first time they're called.
+    compiled: HashMap<FunctionKey, Rc<Chunk<Id>>>,
+    // The bodies of those functions, so their addresses stay unique.
+    pinned: Vec<Rc<Lambda<Id>>>,
+    stack: Vec<Value<Id>>,
+    frames: Vec<Frame<Id>>,
+}
+
+impl<'p, Id: Clone + Debug + Eq + Hash> Vm<'p, Id> {
+    pub fn new(program: &'p CompiledProgram<Id>) -> Self {
+        Vm {
+            program,
+            compiled: HashMap::new(),
+            pinned: Vec::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn run<'a, S: BuildHasher>(
+        &mut self,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+    ) -> Result<Value<Id>, EvalError<Id>> {
+        self.enter();
+        let result = self.execute(env, &mut Unpaused);
+
+        // Anything left over belongs to the calls that were abandoned.
+        self.stack.clear();
+        self.frames.clear();
+
+        result.map(|exit| match exit {
+            Exit::Returned(value) => value,
+            Exit::Yielded(_) | Exit::OutOfFuel => unreachable!("`Unpaused` never stops"),
+        })
+    }
+
+    fn enter(&mut self) {
+        self.frames.push(Frame {
+            chunk: self.program.entry.clone(),
+            pc: 0,
+            base: 0,
+            bound: 0,
+            given: Vec::new(),
+            defines: Vec::new(),
+        });
+    }
+
+    // Runs from where the innermost frame left off.
+    fn execute<'a, S: BuildHasher, P: Pause<Id>>(
+        &mut self,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+        pause: &mut P,
+    ) -> Result<Exit<Id>, EvalError<Id>> {
+        let (mut chunk, mut pc) = {
+            let frame = self.frames.last().unwrap();
+            (frame.chunk.clone(), frame.pc)
+        };
+
+        loop {
+            let instr = &chunk.code[pc];
+            if pause.step(instr)? {
+                self.frames.last_mut().unwrap().pc = pc;
+                return Ok(Exit::OutOfFuel);
+            }
+            pc += 1;
+
+            match *instr {
+                Instr::PushConst(i) => self.stack.push(chunk.constants[i].clone()),
+                Instr::LoadLocal(slot) => {
+                    let value = {
+                        let frame = self.frames.last().unwrap();
+                        if slot < frame.bound {
+                            self.stack[frame.base + slot].clone()
+                        } else {
+                            self.lookup(&chunk.params[slot], env)?
+                        }
+                    };
+                    self.stack.push(value);
+                }
+                Instr::LoadGlobal(ref name) => {
+                    let value = self.lookup(name, env)?;
+                    self.stack.push(value);
+                }
+                Instr::StoreLocal(slot) => {
+                    let value = self.stack.last().unwrap().clone();
+                    let frame = self.frames.last_mut().unwrap();
+                    if slot < frame.bound {
+                        self.stack[frame.base + slot] = value;
+                    } else {
+                        frame.defines.push((chunk.params[slot].clone(), value));
+                    }
+                }
+                Instr::Define(ref name) => {
+                    let value = self.stack.last().unwrap().clone();
+                    if self.frames.len() == 1 {
+                        env.insert(name.clone(), Cow::Owned(value));
+                    } else {
+                        let frame = self.frames.last_mut().unwrap();
+                        frame.defines.push((name.clone(), value));
+                    }
+                }
+                Instr::Call(argc) | Instr::CallNamed(argc, _) => {
+                    let callee = self.stack.len() - argc - 1;
+                    let mut argc = argc;
+                    let mut positional = match *instr {
+                        Instr::CallNamed(_, positional) => Some(positional),
+                        _ => None,
+                    };
+
+                    // A partially applied function takes the place of its
+                    // function, with the arguments it has going first.
+                    if let Value::Partial(partial) = self.stack[callee].clone() {
+                        let given = partial.args();
+                        self.stack.splice(callee + 1..callee + 1, given.iter().cloned());
+                        self.stack[callee] = partial.func().clone();
+                        argc += given.len();
+                        positional = positional.map(|i| i + given.len());
+                    }
+
+                    let function = match self.stack[callee] {
+                        Value::Function(ref lambda) => Some(lambda.clone()),
+                        Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => None,
+                        _ => return Err(EvalError::NotAFunction),
+                    };
+
+                    match function {
+                        Some(lambda) => {
+                            let callee_chunk = self.chunk_for(&lambda);
+
+                            if let Some(positional) = positional {
+                                let args = self.stack.split_off(callee + 1);
+                                let Arguments { required, optional } =
+                                    lambda.arrange(args, Some(positional), Value::as_keyword)?;
+                                let args = lambda.bind(required)?;
+                                let bound = args.len();
+                                self.stack.extend(args);
+
+                                // Every optional parameter is checked for an
+                                // argument in turn, so the call starts at the
+                                // first.
+                                let start = callee_chunk.starts[0];
+                                self.frames.last_mut().unwrap().pc = pc;
+                                self.frames.push(Frame {
+                                    chunk: callee_chunk.clone(),
+                                    pc: start,
+                                    base: callee + 1,
+                                    bound,
+                                    given: optional,
+                                    defines: Vec::new(),
+                                });
+
+                                chunk = callee_chunk;
+                                pc = start;
+                                continue;
+                            }
+
+                            let params = &lambda.params;
+                            lambda.check_arity(argc);
+
+                            let mut bound = argc.min(params.len());
+                            self.stack.truncate(callee + 1 + bound);
+                            // Each slot holds one name, so list patterns are
+                            // taken apart into theirs.
+                            if params.iter().any(|param| matches!(*param, Pattern::List(_))) {
+                                let args = lambda.bind(self.stack.split_off(callee + 1))?;
+                                bound = args.len();
+                                self.stack.extend(args);
+                            }
+
+                            // If a required argument is missing, no defaults
+                            // are evaluated.
+                            let starts = &callee_chunk.starts;
+                            let start = match argc.checked_sub(lambda.required()) {
+                                Some(given) => starts[given.min(starts.len() - 1)],
+                                None => starts[starts.len() - 1],
+                            };
+
+                            self.frames.last_mut().unwrap().pc = pc;
+                            self.frames.push(Frame {
+                                chunk: callee_chunk.clone(),
+                                pc: start,
+                                base: callee + 1,
+                                bound,
+                                given: Vec::new(),
+                                defines: Vec::new(),
+                            });
+
+                            chunk = callee_chunk;
+                            pc = start;
+                        }
+                        None => {
+                            let result = {
+                                let func = &self.stack[callee];
+                                let args = self.stack[callee + 1..].iter().collect::<Vec<_>>();
+                                single_values(&args)
+                                    .and_then(|()| promoted(func.call_bare(&args, self.program.truth)?, &args, false))
+                            };
+                            self.stack.truncate(callee);
+                            match result {
+                                Err(EvalError::Yielded(value)) if pause.yields() => {
+                                    self.frames.last_mut().unwrap().pc = pc;
+                                    return Ok(Exit::Yielded(value));
+                                }
+                                result => self.stack.push(result?),
+                            }
+                        }
+                    }
+                }
+                // The default is already in the parameter's slot, since
+                // everything the call left on the stack belongs to the
+                // parameters before it.
+                Instr::Given(i, end) => {
+                    let frame = self.frames.last_mut().unwrap();
+                    if let Some(value) = frame.given.get_mut(i).and_then(Option::take) {
+                        self.stack.push(value);
+                        pc = end;
+                    }
+                }
+                Instr::Bind => self.frames.last_mut().unwrap().bound += 1,
+                Instr::Pop => {
+                    self.stack.pop();
+                }
+                Instr::Include => {
+                    return Err(EvalError::Include(Box::new(IncludeError::Unsupported)))
+                }
+                Instr::Ret => {
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+
+                    match self.frames.last() {
+                        Some(caller) => {
+                            self.stack.truncate(frame.base - 1);
+                            self.stack.push(result);
+                            chunk = caller.chunk.clone();
+                            pc = caller.pc;
+                        }
+                        None => return Ok(Exit::Returned(result)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn chunk_for(&mut self, lambda: &Rc<Lambda<Id>>) -> Rc<Chunk<Id>> {
+        let key = function_key(lambda);
+
+        if let Some(chunk) = self.program.functions.get(&key) {
+            return chunk.clone();
+        }
+
+        if !self.compiled.contains_key(&key) {
+            // We know nothing about where this function came from, so every
+            // name it uses has to be looked up.
+            let mut compiler = Compiler {
+                direct: HashMap::new(),
+                functions: HashMap::new(),
+            };
+            let chunk = compiler.chunk(params(lambda), &lambda.defaults, &lambda.body);
+            self.compiled.extend(compiler.functions);
+            self.compiled.insert(key, Rc::new(chunk));
+            self.pinned.push(lambda.clone());
+        }
+
+        self.compiled[&key].clone()
+    }
+
+    // Finds a name by walking every running function from the innermost
+    // outwards, which is exactly what is visible in the cloned scope that
+    // `eval` would have built.
+    fn lookup<'a, S: BuildHasher>(
+        &self,
+        name: &Id,
+        env: &HashMap<Id, Cow<'a, Value<Id>>, S>,
+    ) -> Result<Value<Id>, EvalError<Id>> {
+        for frame in self.frames.iter().rev() {
+            if let Some((_, value)) = frame.defines.iter().rev().find(|(n, _)| n == name) {
+                return Ok(value.clone());
+            }
+
+            if let Some(slot) = frame.chunk.params[..frame.bound].iter().rposition(|p| p == name) {
+                return Ok(self.stack[frame.base + slot].clone());
+            }
+        }
+
+        match env.get(name) {
+            Some(value) => Ok((**value).clone()),
+            _ => Err(EvalError::UnboundVariable(name.clone())),
+        }
+    }
+}
+
+/// A program stopped partway through, which owns everything that a `Vm`
+/// running it would borrow, for `Continuation`s to keep.
+pub(crate) struct Paused<Id> {
+    program: Rc<CompiledProgram<Id>>,
+    compiled: HashMap<FunctionKey, Rc<Chunk<Id>>>,
+    pinned: Vec<Rc<Lambda<Id>>>,
+    stack: Vec<Value<Id>>,
+    frames: Vec<Frame<Id>>,
+}
+
+impl<Id: Clone + Debug + Eq + Hash> Paused<Id> {
+    /// `program`, stopped before its first instruction.
+    pub(crate) fn new(program: CompiledProgram<Id>) -> Self {
+        let program = Rc::new(program);
+        let mut vm = Vm::new(&program);
+        vm.enter();
+        Paused {
+            compiled: vm.compiled,
+            pinned: vm.pinned,
+            stack: vm.stack,
+            frames: vm.frames,
+            program,
+        }
+    }
+
+    /// Push `value` as what the native that yielded gave.
+    pub(crate) fn reply(&mut self, value: Value<Id>) {
+        self.stack.push(value);
+    }
+
+    /// Carry on until the program returns or `pause` stops it.
+    pub(crate) fn run<'a, S: BuildHasher, P: Pause<Id>>(
+        &mut self,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+        pause: &mut P,
+    ) -> Result<Exit<Id>, EvalError<Id>> {
+        let program = self.program.clone();
+        let mut vm = Vm {
+            program: &program,
+            compiled: mem::take(&mut self.compiled),
+            pinned: mem::take(&mut self.pinned),
+            stack: mem::take(&mut self.stack),
+            frames: mem::take(&mut self.frames),
+        };
+        let result = vm.execute(env, pause);
+
+        self.compiled = vm.compiled;
+        self.pinned = vm.pinned;
+        self.stack = vm.stack;
+        self.frames = vm.frames;
+        result
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+    use std::rc::Rc;
+
+    use combine::Parser;
+
+    use benches::{corpus, corpus_interpreter, same_result};
+    use {eval, expr, hash_string, Ast, EvalError, IntMap, Lambda, Value};
+
+    use super::Instr;
+
+    fn parse(src: &str) -> Vec<Ast<u64>> {
+        ::combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(src)
+            .unwrap()
+            .0
+    }
+
+    fn assert_same_results(program: &[Ast<u64>]) {
+        let interpreter = corpus_interpreter();
+        let mut tree_env: IntMap<_> = interpreter.env();
+        let mut vm_env: IntMap<_> = interpreter.env();
+
+        for form in program {
+            let expected = eval(form, &mut tree_env).map(|v| v.into_owned());
+            let actual = interpreter.compile(::std::slice::from_ref(form)).run(&mut vm_env);
+            assert!(same_result(&expected, &actual));
+        }
+
+        let mut keys = tree_env.keys().collect::<Vec<_>>();
+        let mut vm_keys = vm_env.keys().collect::<Vec<_>>();
+        keys.sort();
+        vm_keys.sort();
+        assert_eq!(keys, vm_keys);
+
+        // Compiled as a whole, the last value must match too.
+        let mut whole_env: IntMap<_> = interpreter.env();
+        let actual = interpreter.compile(program).run(&mut whole_env);
+        let mut tree_env: IntMap<_> = interpreter.env();
+        let mut expected = Ok(Value::Void);
+        for form in program {
+            expected = eval(form, &mut tree_env).map(|v| v.into_owned());
+            if expected.is_err() {
+                break;
+            }
+        }
+        assert!(same_result(&expected, &actual));
+    }
+
+    #[test]
+    fn vm_matches_eval_on_corpus() {
+        for (_, program) in corpus() {
+            assert_same_results(&program);
+        }
+    }
+
+    #[test]
+    fn vm_matches_eval_on_scoping_edge_cases() {
+        assert_same_results(&parse(
+            r"
+            (= a 1)
+            (= shadow (\(a) (= a (add a 1)) a))
+            (shadow 5)
+            a
+            (= dup (\(a a) a))
+            (dup 1 2)
+            (= outer (\(b) ((\() (add a b)))))
+            (outer 10)
+            (= local (\() (= c 7) ((\() c))))
+            (local)
+            (= arity (\(a b) a))
+            (arity 3)
+            (= defines (\() (add (= d 2) d)))
+            (defines)
+            (= x (add a 1))
+            (= x (add x 1))
+            (add (= y 5) y)
+            ((\(a b) b) (= z 3) z)
+            ((\(a) (= a (add a 10))) 1)
+            ",
+        ));
+    }
+
+    #[test]
+    fn calls_natives_directly_unless_rebound() {
+        let interpreter = corpus_interpreter();
+
+        let direct = interpreter.compile(&parse("(add 1 2)"));
+        assert_eq!(direct.entry().code()[0], Instr::PushConst(0));
+
+        let rebound = interpreter.compile(&parse(r"(= f (\(add) (add 1 2))) (add 1 2)"));
+        assert!(rebound.entry().code().contains(&Instr::LoadGlobal(hash_string("add"))));
+    }
+
+    #[test]
+    fn compiles_foreign_functions_on_first_call() {
+        let interpreter = corpus_interpreter();
+        let body = parse("(add a 1)");
+        let mut env: IntMap<_> = interpreter.env();
+        env.insert(
+            hash_string("inc"),
+            Cow::Owned(Value::Function(Rc::new(Lambda::new(vec![hash_string("a")], body)))),
+        );
+
+        let program = interpreter.compile(&parse("(inc (inc 1))"));
+        assert!(program.run(&mut env) == Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn vm_matches_eval_on_errors() {
+        assert_same_results(&parse("(= a 1) missing (a) (add 1 (missing))"));
+        assert_same_results(&parse("(add 18446744073709551615 1) (mul 18446744073709551616 2)"));
+
+        let interpreter = corpus_interpreter();
+        let mut env: IntMap<_> = interpreter.env();
+        let program = interpreter.compile(&parse(r"((\(f) (f)) 5)"));
+        assert!(program.run(&mut env) == Err(EvalError::NotAFunction));
+        let program = interpreter.compile(&parse(r"((\() nope))"));
+        assert!(program.run(&mut env) == Err(EvalError::UnboundVariable(hash_string("nope"))));
+    }
+}
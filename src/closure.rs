@@ -0,0 +1,446 @@
+//! Compilation of programs into trees of Rust closures.
+//!
+//! Each AST node becomes a boxed closure that evaluates its children by
+//! calling their closures directly, so the per-node `match` and the pointer
+//! chasing through `Box<Ast>` are paid once at compile time rather than on
+//! every evaluation. Scoping is dynamic, exactly as in `eval`: parameters
+//! are read from the innermost call's arguments, and any other name is looked
+//! up through every call that is still running before the global
+//! environment.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {
+    first_keyword, promoted, single_values, Arguments, Ast, EvalError, IncludeError, Lambda,
+    TruthPolicy, Value,
+};
+
+type Closure<Id> = Box<dyn Fn(&mut Frame<Id>) -> Result<Value<Id>, EvalError<Id>>>;
+
+// Function values are matched to their compiled bodies by the address of
+// their `Lambda`, which the compiled body keeps alive.
+type FunctionKey = usize;
+
+fn function_key<Id>(lambda: &Rc<Lambda<Id>>) -> FunctionKey {
+    Rc::as_ptr(lambda) as usize
+}
+
+struct Body<Id> {
+    // One for each optional parameter, in order.
+    defaults: Vec<Closure<Id>>,
+    stmts: Vec<Closure<Id>>,
+    // The names the parameters bind, in the order `Lambda::bind` gives
+    // their values.
+    params: Box<[Id]>,
+    _source: Rc<Lambda<Id>>,
+}
+
+type Functions<Id> = RefCell<HashMap<FunctionKey, Rc<Body<Id>>>>;
+
+/// The global environment, as seen by compiled code.
+trait Globals<Id> {
+    fn get(&self, name: &Id) -> Option<Value<Id>>;
+    fn define(&mut self, name: Id, value: Value<Id>);
+}
+
+impl<'a, Id: Clone + Eq + Hash, S: BuildHasher> Globals<Id> for HashMap<Id, Cow<'a, Value<Id>>, S> {
+    fn get(&self, name: &Id) -> Option<Value<Id>> {
+        HashMap::get(self, name).map(|value| (**value).clone())
+    }
+
+    fn define(&mut self, name: Id, value: Value<Id>) {
+        self.insert(name, Cow::Owned(value));
+    }
+}
+
+struct Scope<Id> {
+    body: Rc<Body<Id>>,
+    args: Vec<Value<Id>>,
+    // Names defined in the function body that aren't parameters.
+    defines: Vec<(Id, Value<Id>)>,
+}
+
+/// The state of one evaluation of a `CompiledExpr`.
+pub struct Frame<'e, Id: 'e> {
+    globals: &'e mut (dyn Globals<Id> + 'e),
+    functions: &'e Functions<Id>,
+    scopes: Vec<Scope<Id>>,
+    stack: Vec<Value<Id>>,
+}
+
+impl<'e, Id: Clone + Debug + Eq + Hash + 'static> Frame<'e, Id> {
+    fn lookup(&self, name: &Id) -> Result<Value<Id>, EvalError<Id>> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((_, value)) = scope.defines.iter().rev().find(|(n, _)| n == name) {
+                return Ok(value.clone());
+            }
+
+            // With duplicate parameter names the last one wins, as in `eval`.
+            let bound = &scope.body.params[..scope.args.len()];
+            if let Some(slot) = bound.iter().rposition(|p| p == name) {
+                return Ok(scope.args[slot].clone());
+            }
+        }
+
+        self.globals
+            .get(name)
+            .ok_or_else(|| EvalError::UnboundVariable(name.clone()))
+    }
+
+    fn define(&mut self, name: Id, value: Value<Id>) {
+        match self.scopes.last_mut() {
+            Some(scope) => match scope.body.params[..scope.args.len()].iter().rposition(|p| *p == name) {
+                Some(slot) => scope.args[slot] = value,
+                None => scope.defines.push((name, value)),
+            },
+            None => self.globals.define(name, value),
+        }
+    }
+
+    // Calls `func` with the arguments on the stack above `base`, of which
+    // those from `positional` on are keyword arguments.
+    fn call(
+        &mut self,
+        func: Value<Id>,
+        base: usize,
+        positional: Option<usize>,
+    ) -> Result<Value<Id>, EvalError<Id>> {
+        match func {
+            Value::Function(lambda) => {
+                let compiled = self.function(&lambda);
+
+                let args = self.stack.split_off(base);
+                let Arguments { required, optional } =
+                    lambda.arrange(args, positional, Value::as_keyword)?;
+                let args = lambda.bind(required)?;
+                self.scopes.push(Scope {
+                    body: compiled.clone(),
+                    args,
+                    defines: Vec::new(),
+                });
+
+                // Defaults are evaluated in the new scope, after the
+                // parameters before them.
+                let mut out = Ok(Value::Void);
+                for (default, given) in compiled.defaults.iter().zip(optional) {
+                    let value = match given {
+                        Some(value) => value,
+                        None => match default(self) {
+                            Ok(value) => value,
+                            Err(e) => {
+                                out = Err(e);
+                                break;
+                            }
+                        },
+                    };
+                    self.scopes.last_mut().unwrap().args.push(value);
+                }
+
+                if out.is_ok() {
+                    for stmt in &compiled.stmts {
+                        out = stmt(self);
+                        if out.is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                self.scopes.pop();
+                out
+            }
+            // `Partial::new` never nests partials, so this only goes one
+            // level deep.
+            Value::Partial(partial) => {
+                let given = partial.args();
+                self.stack.splice(base..base, given.iter().cloned());
+                self.call(partial.func().clone(), base, positional.map(|i| i + given.len()))
+            }
+            // Reentrant natives are told that values are true the way
+            // `eval` would, since compiled expressions have no options.
+            func @ (Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_)) => {
+                let out = {
+                    let arg_refs = self.stack[base..].iter().collect::<Vec<_>>();
+                    single_values(&arg_refs).and_then(|()| {
+                        let out = func.call_bare(&arg_refs, TruthPolicy::SchemeLike)?;
+                        promoted(out, &arg_refs, false)
+                    })
+                };
+                self.stack.truncate(base);
+                out
+            }
+            _ => {
+                self.stack.truncate(base);
+                Err(EvalError::NotAFunction)
+            }
+        }
+    }
+
+    // Finds the compiled body for a function value, compiling it now if it
+    // didn't come from the program (for example if the host created it).
+    fn function(&self, lambda: &Rc<Lambda<Id>>) -> Rc<Body<Id>> {
+        let key = function_key(lambda);
+
+        if let Some(compiled) = self.functions.borrow().get(&key) {
+            return compiled.clone();
+        }
+
+        let mut compiler = Compiler {
+            functions: HashMap::new(),
+        };
+        let compiled = compiler.function(lambda);
+        let mut functions = self.functions.borrow_mut();
+        functions.extend(compiler.functions);
+        functions.insert(key, compiled.clone());
+        compiled
+    }
+}
+
+/// A program compiled by `compile_closure`, ready to be evaluated any number
+/// of times.
+pub struct CompiledExpr<Id> {
+    root: Closure<Id>,
+    functions: Functions<Id>,
+}
+
+impl<Id: Clone + Debug + Eq + Hash + 'static> CompiledExpr<Id> {
+    pub fn eval<'a, S: BuildHasher>(
+        &self,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+    ) -> Result<Value<Id>, EvalError<Id>> {
+        let mut frame = Frame {
+            globals: env,
+            functions: &self.functions,
+            scopes: Vec::new(),
+            stack: Vec::new(),
+        };
+
+        (self.root)(&mut frame)
+    }
+}
+
+/// Compile `program` once into closures that can then be evaluated with the
+/// same results as `eval`.
+pub fn compile_closure<Id>(program: &Ast<Id>) -> CompiledExpr<Id>
+where
+    Id: Clone + Debug + Eq + Hash + 'static,
+{
+    let mut compiler = Compiler {
+        functions: HashMap::new(),
+    };
+    let root = compiler.expr(program, &[]);
+
+    CompiledExpr {
+        root,
+        functions: RefCell::new(compiler.functions),
+    }
+}
+
+struct Compiler<Id> {
+    functions: HashMap<FunctionKey, Rc<Body<Id>>>,
+}
+
+impl<Id: Clone + Debug + Eq + Hash + 'static> Compiler<Id> {
+    fn function(&mut self, lambda: &Rc<Lambda<Id>>) -> Rc<Body<Id>> {
+        let key = function_key(lambda);
+
+        if let Some(compiled) = self.functions.get(&key) {
+            return compiled.clone();
+        }
+
+        let params = lambda.names().into_iter().cloned().collect::<Box<[_]>>();
+        let compiled = Rc::new(Body {
+            defaults: lambda
+                .defaults
+                .iter()
+                .map(|default| self.expr(default, &params))
+                .collect(),
+            stmts: lambda
+                .body
+                .iter()
+                .map(|stmt| self.expr(stmt, &params))
+                .collect(),
+            params,
+            _source: lambda.clone(),
+        });
+        self.functions.insert(key, compiled.clone());
+        compiled
+    }
+
+    // `params` are the parameters of the function whose body `ast` is in,
+    // which can be read straight out of the innermost scope.
+    fn expr(&mut self, ast: &Ast<Id>, params: &[Id]) -> Closure<Id> {
+        match *ast {
+            Ast::Lit(ref value) => {
+                if let Value::Function(ref lambda) = *value {
+                    self.function(lambda);
+                }
+
+                let value = value.clone();
+                Box::new(move |_| Ok(value.clone()))
+            }
+            Ast::Variable(ref name) => {
+                let name = name.clone();
+
+                match params.iter().rposition(|p| *p == name) {
+                    Some(slot) => Box::new(move |frame| {
+                        {
+                            let scope = frame.scopes.last().unwrap();
+                            if slot < scope.args.len() && scope.defines.is_empty() {
+                                return Ok(scope.args[slot].clone());
+                            }
+                        }
+                        frame.lookup(&name)
+                    }),
+                    None => Box::new(move |frame| frame.lookup(&name)),
+                }
+            }
+            Ast::Call(ref func, ref args) => {
+                let positional = first_keyword(args);
+                let func = self.expr(func, params);
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg, params))
+                    .collect::<Vec<_>>();
+
+                Box::new(move |frame| {
+                    let func = func(frame)?;
+
+                    // Arguments go on a shared stack rather than a fresh `Vec`
+                    // per call, since most calls are to builtins.
+                    let base = frame.stack.len();
+                    for arg in &args {
+                        match arg(frame) {
+                            Ok(value) => frame.stack.push(value),
+                            Err(e) => {
+                                frame.stack.truncate(base);
+                                return Err(e);
+                            }
+                        }
+                    }
+
+                    frame.call(func, base, positional)
+                })
+            }
+            Ast::Define(ref name, ref value) => {
+                let name = name.clone();
+                let value = self.expr(value, params);
+
+                Box::new(move |frame| {
+                    let value = value(frame)?;
+                    frame.define(name.clone(), value.clone());
+                    Ok(value)
+                })
+            }
+            Ast::Include(_) => {
+                Box::new(|_| Err(EvalError::Include(Box::new(IncludeError::Unsupported))))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+    use std::rc::Rc;
+
+    use combine::Parser;
+
+    use benches::{corpus, corpus_env, same_result};
+    use {eval, expr, hash_string, Ast, EvalError, IntMap, Lambda, Value};
+
+    use super::compile_closure;
+
+    fn parse(src: &str) -> Vec<Ast<u64>> {
+        ::combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(src)
+            .unwrap()
+            .0
+    }
+
+    fn assert_same_results(program: &[Ast<u64>]) {
+        let mut tree_env: IntMap<_> = corpus_env();
+        let mut closure_env: IntMap<_> = corpus_env();
+
+        for form in program {
+            let expected = eval(form, &mut tree_env).map(|v| v.into_owned());
+            let actual = compile_closure(form).eval(&mut closure_env);
+            assert!(same_result(&expected, &actual));
+        }
+    }
+
+    #[test]
+    fn closures_match_eval_on_corpus() {
+        for (_, program) in corpus() {
+            assert_same_results(&program);
+        }
+    }
+
+    #[test]
+    fn closures_match_eval_on_scoping_edge_cases() {
+        assert_same_results(&parse(
+            r"
+            (= a 1)
+            (= shadow (\(a) (= a (add a 1)) a))
+            (shadow 5)
+            a
+            (= dup (\(a a) a))
+            (dup 1 2)
+            (= outer (\(b) ((\() (add a b)))))
+            (outer 10)
+            (= local (\() (= c 7) ((\() c))))
+            (local)
+            (= arity (\(a b) a))
+            (arity 3)
+            (= defines (\() (add (= d 2) d)))
+            (defines)
+            (= x (add a 1))
+            (= x (add x 1))
+            (add (= y 5) y)
+            ((\(a b) b) (= z 3) z)
+            ((\(a) (= a (add a 10))) 1)
+            ",
+        ));
+    }
+
+    #[test]
+    fn closures_match_eval_on_errors() {
+        assert_same_results(&parse(r"(= a 1) missing (a) (add 1 (missing)) ((\(f) (f)) 5)"));
+        assert_same_results(&parse("(add 18446744073709551615 1) (mul 18446744073709551616 2)"));
+
+        let mut env: IntMap<_> = corpus_env();
+        let compiled = compile_closure(&parse(r"((\() nope))")[0]);
+        assert!(compiled.eval(&mut env) == Err(EvalError::UnboundVariable(hash_string("nope"))));
+    }
+
+    #[test]
+    fn compiled_once_evaluates_many_times() {
+        let program = parse(r"((\(a b) (add a b)) x 1)");
+        let compiled = compile_closure(&program[0]);
+
+        let mut env: IntMap<_> = corpus_env();
+        for i in 0..10 {
+            env.insert(hash_string("x"), Cow::Owned(Value::Int(i)));
+            assert!(compiled.eval(&mut env) == Ok(Value::Int(i + 1)));
+        }
+    }
+
+    #[test]
+    fn compiles_foreign_functions_on_first_call() {
+        let body = parse("(add a 1)");
+        let mut env: IntMap<_> = corpus_env();
+        env.insert(
+            hash_string("inc"),
+            Cow::Owned(Value::Function(Rc::new(Lambda::new(vec![hash_string("a")], body)))),
+        );
+
+        let compiled = compile_closure(&parse("(inc (inc 1))")[0]);
+        assert!(compiled.eval(&mut env) == Ok(Value::Int(3)));
+    }
+}
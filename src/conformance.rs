@@ -0,0 +1,178 @@
+//! Data-driven tests of the language's semantics.
+//!
+//! Each `.lisp` file in a directory is a program, and its expected result
+//! is either given by a `; expect: ...` line in the file or is the contents
+//! of a sibling `.expected` file. The program is run against the prelude
+//! and the value of its last form is written out with `Display`. A program
+//! that fails to run is expected to give `error: ` followed by the error.
+//!
+//! The language has no comments, so lines starting with `;` are removed
+//! before the program is parsed.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use {eval, parse_program_with, prelude, ParseOptions};
+
+const EXPECT: &str = "; expect:";
+
+/// A program whose result wasn't what was expected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub file: PathBuf,
+    /// The expected result, or `None` if the program doesn't say.
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.file.display())?;
+        match self.expected {
+            Some(ref expected) => writeln!(f, "  expected: {}", expected)?,
+            None => writeln!(f, "  expected: (no expectation given)")?,
+        }
+        write!(f, "    actual: {}", self.actual)
+    }
+}
+
+/// Run every program in `dir`, returning how many there were if they all
+/// gave the expected result.
+pub fn run_conformance<P: AsRef<Path>>(dir: P) -> Result<usize, Vec<Failure>> {
+    let dir = dir.as_ref();
+    let failure = |file: &Path, actual: String| Failure {
+        file: file.to_owned(),
+        expected: None,
+        actual,
+    };
+
+    let entries = fs::read_dir(dir)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect());
+    let mut files: Vec<PathBuf> = match entries {
+        Ok(files) => files,
+        Err(e) => return Err(vec![failure(dir, format!("could not read directory: {}", e))]),
+    };
+    files.retain(|file| file.extension() == Some("lisp".as_ref()));
+    files.sort();
+
+    let mut failures = Vec::new();
+
+    for file in &files {
+        let src = match fs::read_to_string(file) {
+            Ok(src) => src,
+            Err(e) => {
+                failures.push(failure(file, format!("could not read file: {}", e)));
+                continue;
+            }
+        };
+
+        let expected = match expectation(file, &src) {
+            Ok(expected) => expected,
+            Err(e) => {
+                failures.push(failure(file, format!("could not read expectation: {}", e)));
+                continue;
+            }
+        };
+        let actual = run(&src);
+
+        if expected.as_ref() != Some(&actual) {
+            failures.push(Failure {
+                file: file.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(files.len())
+    } else {
+        Err(failures)
+    }
+}
+
+fn expectation(file: &Path, src: &str) -> Result<Option<String>, ::std::io::Error> {
+    let inline = src
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix(EXPECT));
+    if let Some(expected) = inline {
+        return Ok(Some(expected.trim().to_owned()));
+    }
+
+    let sibling = file.with_extension("expected");
+    if sibling.exists() {
+        fs::read_to_string(sibling).map(|expected| Some(expected.trim().to_owned()))
+    } else {
+        Ok(None)
+    }
+}
+
+fn run(src: &str) -> String {
+    let code = src
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(';'))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let program = match parse_program_with(&code, &ParseOptions::default(), &mut |_| {}) {
+        Ok(program) => program,
+        Err(e) => return format!("parse error: {}", e),
+    };
+
+    let mut env = prelude::env();
+    let mut last = None;
+    for form in &program {
+        match eval(form, &mut env) {
+            Ok(value) => last = Some(value.into_owned()),
+            Err(e) => return format!("error: {}", e),
+        }
+    }
+
+    match last {
+        Some(value) => value.to_string(),
+        None => "parse error: empty program".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::run_conformance;
+
+    #[test]
+    fn programs_give_their_expected_results() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+
+        match run_conformance(&dir) {
+            Ok(count) => assert!(count >= 12, "only found {} programs", count),
+            Err(failures) => {
+                let report = failures.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+                panic!("{} programs failed:\n{}", failures.len(), report.join("\n"));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs-failing");
+        let failures = run_conformance(&dir).unwrap_err();
+
+        let report = failures.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            report,
+            vec![
+                format!(
+                    "{}\n  expected: 1\n    actual: error: Attempted to call a non-function",
+                    dir.join("wrong-error.lisp").display()
+                ),
+                format!(
+                    "{}\n  expected: 3\n    actual: 2",
+                    dir.join("wrong-sum.lisp").display()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,464 @@
+//! Conversions between values and Rust types, for host functions that
+//! would rather take a `u64` than match on a `Value` themselves.
+//!
+//! `Interpreter::register_fn` uses these to turn a closure such as
+//! `|a: u64, b: u64| a * b` into a function that programs can call: each
+//! argument is converted with `FromValue`, and the result with `IntoValue`.
+//! The arguments of a registered closure have to own what they hold, so it
+//! takes a `String` rather than a `&str`, although `FromValue` can borrow
+//! from a value when it's called directly.
+
+use std::any::{self, Any};
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::rc::Rc;
+
+use {EvalError, HostFn, Value};
+
+/// A Rust type that can be made into a value.
+pub trait IntoValue<Id> {
+    fn into_value(self) -> Value<Id>;
+}
+
+/// A Rust type that can be read out of a value, borrowing from it for `'a`
+/// if it needs to.
+pub trait FromValue<'a, Id>: Sized {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError>;
+}
+
+/// A value was converted to a type it isn't, such as a string to a `u64`.
+/// Both are named the way `type_name` names values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TypeError {
+    pub expected: &'static str,
+    pub found: &'static str,
+}
+
+impl TypeError {
+    fn new<Id>(expected: &'static str, found: &Value<Id>) -> Self {
+        TypeError {
+            expected,
+            found: type_name(found),
+        }
+    }
+
+    /// This as the error of a native given the wrong type of argument at
+    /// `index`.
+    pub fn at<Id>(self, index: usize) -> EvalError<Id> {
+        EvalError::WrongType {
+            index,
+            error: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected {}, got {}", self.expected, self.found)
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for TypeError {}
+
+/// What kind of value this is, as a word for error messages. Every kind of
+/// function is a `function` except natives, which are `native`, and a
+/// foreign value is its name if it was given one.
+pub fn type_name<Id>(value: &Value<Id>) -> &'static str {
+    match *value {
+        Value::Void => "void",
+        Value::False => "false",
+        Value::Nil => "nil",
+        Value::Int(_) | Value::BigInt(_) => "int",
+        Value::Function(_) | Value::Partial(_) => "function",
+        Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => "native",
+        Value::List(_) => "list",
+        Value::Values(_) => "values",
+        Value::Keyword(_) => "keyword",
+        Value::Symbol(_) => "symbol",
+        Value::Str(_) => "string",
+        Value::Bytes(_) => "bytes",
+        Value::Thunk(_) => "thunk",
+        Value::Box(_) => "box",
+        Value::Foreign(ref foreign) => foreign.name().unwrap_or("foreign"),
+    }
+}
+
+/// The kinds of value that programs can tell apart with `typeof` and the
+/// type predicates, such as `isint`. These are coarser than `Value`'s
+/// variants, the way `type_name` is: a `BigInt` is an int, a `Partial` is a
+/// function, and every kind of native is a builtin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    Void,
+    False,
+    Nil,
+    Int,
+    Function,
+    Builtin,
+    List,
+    Values,
+    Keyword,
+    Symbol,
+    String,
+    Bytes,
+    Thunk,
+    Box,
+    Foreign,
+}
+
+impl ValueType {
+    /// Every type, in the order they're declared. The prelude registers a
+    /// predicate for each of these.
+    pub const ALL: [ValueType; 15] = [
+        ValueType::Void,
+        ValueType::False,
+        ValueType::Nil,
+        ValueType::Int,
+        ValueType::Function,
+        ValueType::Builtin,
+        ValueType::List,
+        ValueType::Values,
+        ValueType::Keyword,
+        ValueType::Symbol,
+        ValueType::String,
+        ValueType::Bytes,
+        ValueType::Thunk,
+        ValueType::Box,
+        ValueType::Foreign,
+    ];
+
+    /// The type of `value`. There's deliberately no catch-all arm here, so
+    /// a new variant of `Value` doesn't compile until it's given a type,
+    /// just as this doesn't with `Foreign` left out:
+    ///
+    /// ```compile_fail
+    /// # use rustfest::{Value, ValueType};
+    /// fn of<Id>(value: &Value<Id>) -> ValueType {
+    ///     match *value {
+    ///         Value::Void => ValueType::Void,
+    ///         Value::False => ValueType::False,
+    ///         Value::Nil => ValueType::Nil,
+    ///         Value::Int(_) | Value::BigInt(_) => ValueType::Int,
+    ///         Value::Function(_) | Value::Partial(_) => ValueType::Function,
+    ///         Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+    ///             ValueType::Builtin
+    ///         }
+    ///         Value::List(_) => ValueType::List,
+    ///         Value::Values(_) => ValueType::Values,
+    ///         Value::Keyword(_) => ValueType::Keyword,
+    ///         Value::Symbol(_) => ValueType::Symbol,
+    ///         Value::Str(_) => ValueType::String,
+    ///         Value::Bytes(_) => ValueType::Bytes,
+    ///         Value::Thunk(_) => ValueType::Thunk,
+    ///         Value::Box(_) => ValueType::Box,
+    ///     }
+    /// }
+    /// ```
+    pub fn of<Id>(value: &Value<Id>) -> ValueType {
+        match *value {
+            Value::Void => ValueType::Void,
+            Value::False => ValueType::False,
+            Value::Nil => ValueType::Nil,
+            Value::Int(_) | Value::BigInt(_) => ValueType::Int,
+            Value::Function(_) | Value::Partial(_) => ValueType::Function,
+            Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+                ValueType::Builtin
+            }
+            Value::List(_) => ValueType::List,
+            Value::Values(_) => ValueType::Values,
+            Value::Keyword(_) => ValueType::Keyword,
+            Value::Symbol(_) => ValueType::Symbol,
+            Value::Str(_) => ValueType::String,
+            Value::Bytes(_) => ValueType::Bytes,
+            Value::Thunk(_) => ValueType::Thunk,
+            Value::Box(_) => ValueType::Box,
+            Value::Foreign(_) => ValueType::Foreign,
+        }
+    }
+
+    /// The name of the symbol that `typeof` gives.
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::Void => "void",
+            ValueType::False => "false",
+            ValueType::Nil => "nil",
+            ValueType::Int => "int",
+            ValueType::Function => "function",
+            ValueType::Builtin => "builtin",
+            ValueType::List => "list",
+            ValueType::Values => "values",
+            ValueType::Keyword => "keyword",
+            ValueType::Symbol => "symbol",
+            ValueType::String => "string",
+            ValueType::Bytes => "bytes",
+            ValueType::Thunk => "thunk",
+            ValueType::Box => "box",
+            ValueType::Foreign => "foreign",
+        }
+    }
+}
+
+/// The object in `value`, for natives that take a `Value::Foreign` holding
+/// a `T`. Anything else is an error expecting `T`, named the way
+/// `std::any::type_name` names it.
+pub fn foreign<Id, T: Any>(value: &Value<Id>) -> Result<&T, TypeError> {
+    value
+        .downcast_foreign()
+        .ok_or_else(|| TypeError::new(any::type_name::<T>(), value))
+}
+
+impl<Id> IntoValue<Id> for Value<Id> {
+    fn into_value(self) -> Value<Id> {
+        self
+    }
+}
+
+impl<'a, Id: Clone> FromValue<'a, Id> for Value<Id> {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        Ok(value.clone())
+    }
+}
+
+impl<'a, Id> FromValue<'a, Id> for &'a Value<Id> {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        Ok(value)
+    }
+}
+
+/// `void`, which is what something done for its effect gives.
+impl<Id> IntoValue<Id> for () {
+    fn into_value(self) -> Value<Id> {
+        Value::Void
+    }
+}
+
+impl<Id> IntoValue<Id> for u64 {
+    fn into_value(self) -> Value<Id> {
+        Value::Int(self)
+    }
+}
+
+/// Only an `Int`: a `BigInt` is too big for a `u64` whenever the crate makes
+/// one.
+impl<'a, Id> FromValue<'a, Id> for u64 {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        match *value {
+            Value::Int(i) => Ok(i),
+            _ => Err(TypeError::new("int", value)),
+        }
+    }
+}
+
+/// `void` for true and `#f` for false, the way `eq` answers.
+impl<Id> IntoValue<Id> for bool {
+    fn into_value(self) -> Value<Id> {
+        if self {
+            Value::Void
+        } else {
+            Value::False
+        }
+    }
+}
+
+/// Whatever the value, the way conditionals read it, so this never fails.
+impl<'a, Id: 'static> FromValue<'a, Id> for bool {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        Ok(value.is_truthy())
+    }
+}
+
+impl<Id> IntoValue<Id> for String {
+    fn into_value(self) -> Value<Id> {
+        Value::Str(Rc::new(self))
+    }
+}
+
+impl<Id> IntoValue<Id> for &str {
+    fn into_value(self) -> Value<Id> {
+        Value::Str(Rc::new(self.to_owned()))
+    }
+}
+
+impl<'a, Id> FromValue<'a, Id> for String {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        <&str>::from_value(value).map(str::to_owned)
+    }
+}
+
+impl<'a, Id> FromValue<'a, Id> for &'a str {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        match *value {
+            Value::Str(ref text) => Ok(text),
+            _ => Err(TypeError::new("string", value)),
+        }
+    }
+}
+
+impl<Id> IntoValue<Id> for &[u8] {
+    fn into_value(self) -> Value<Id> {
+        Value::Bytes(Rc::new(self.to_vec()))
+    }
+}
+
+impl<'a, Id> FromValue<'a, Id> for &'a [u8] {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        match *value {
+            Value::Bytes(ref bytes) => Ok(bytes),
+            _ => Err(TypeError::new("bytes", value)),
+        }
+    }
+}
+
+/// `nil` for `None`.
+impl<Id, T: IntoValue<Id>> IntoValue<Id> for Option<T> {
+    fn into_value(self) -> Value<Id> {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+/// `None` for `nil`, and anything else converted to `T`.
+impl<'a, Id, T: FromValue<'a, Id>> FromValue<'a, Id> for Option<T> {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        match *value {
+            Value::Nil => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<Id, T: IntoValue<Id>> IntoValue<Id> for Vec<T> {
+    fn into_value(self) -> Value<Id> {
+        Value::List(Rc::new(self.into_iter().map(IntoValue::into_value).collect()))
+    }
+}
+
+/// A list, with each of its elements converted to `T`. An element that
+/// isn't one is the error.
+impl<'a, Id, T: FromValue<'a, Id>> FromValue<'a, Id> for Vec<T> {
+    fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+        match *value {
+            Value::List(ref items) => items.iter().map(T::from_value).collect(),
+            _ => Err(TypeError::new("list", value)),
+        }
+    }
+}
+
+// Tuples are lists of their elements, and only lists of exactly as many
+// convert back.
+macro_rules! tuple {
+    ($count:tt; $($name:ident $index:tt),*) => {
+        impl<Id, $($name: IntoValue<Id>),*> IntoValue<Id> for ($($name,)*) {
+            fn into_value(self) -> Value<Id> {
+                Value::List(Rc::new(vec![$(self.$index.into_value()),*]))
+            }
+        }
+
+        impl<'a, Id, $($name: FromValue<'a, Id>),*> FromValue<'a, Id> for ($($name,)*) {
+            fn from_value(value: &'a Value<Id>) -> Result<Self, TypeError> {
+                match *value {
+                    Value::List(ref items) if items.len() == $count => {
+                        Ok(($($name::from_value(&items[$index])?,)*))
+                    }
+                    _ => Err(TypeError::new(concat!("list of ", $count), value)),
+                }
+            }
+        }
+    };
+}
+
+tuple!(1; A 0);
+tuple!(2; A 0, B 1);
+tuple!(3; A 0, B 1, C 2);
+tuple!(4; A 0, B 1, C 2, D 3);
+
+/// Closures that `Interpreter::register_fn` can register: those of up to
+/// four arguments whose types are `FromValue`, returning something that's
+/// `IntoValue`. `Args` is the tuple of the argument types, which tells the
+/// implementations for each number of arguments apart.
+pub trait HostFunction<Id, Args> {
+    fn into_host(self) -> HostFn<Id>;
+}
+
+// Argument `index` of `args`, converted to `T`.
+fn argument<'a, Id, T: FromValue<'a, Id>>(
+    args: &[&'a Value<Id>],
+    index: usize,
+) -> Result<T, EvalError<Id>> {
+    T::from_value(args[index]).map_err(|error| error.at(index))
+}
+
+macro_rules! host_function {
+    ($count:tt; $($name:ident $index:tt),*) => {
+        impl<Id, F, R, $($name),*> HostFunction<Id, ($($name,)*)> for F
+        where
+            Id: 'static,
+            F: Fn($($name),*) -> R + Send + Sync + 'static,
+            R: IntoValue<Id>,
+            $($name: for<'a> FromValue<'a, Id>,)*
+        {
+            fn into_host(self) -> HostFn<Id> {
+                HostFn::new(move |args: &[&Value<Id>]| {
+                    if args.len() != $count {
+                        return Err(EvalError::ArgumentCount {
+                            min: $count,
+                            max: $count,
+                            got: args.len(),
+                        });
+                    }
+                    Ok(self($(argument::<Id, $name>(args, $index)?),*).into_value())
+                })
+            }
+        }
+    };
+}
+
+host_function!(0;);
+host_function!(1; A 0);
+host_function!(2; A 0, B 1);
+host_function!(3; A 0, B 1, C 2);
+host_function!(4; A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+    use std::rc::Rc;
+
+    use super::{FromValue, IntoValue, TypeError};
+    use Value;
+
+    fn list(items: Vec<Value<u64>>) -> Value<u64> {
+        Value::List(Rc::new(items))
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        let value: Value<u64> = (3, "three", vec![Some(1), None]).into_value();
+        assert_eq!(value.to_string(), "(list 3 \"three\" (list 1 nil))");
+
+        let (i, text, items) = <(u64, &str, Vec<Option<u64>>)>::from_value(&value).unwrap();
+        assert_eq!((i, text, items), (3, "three", vec![Some(1), None]));
+        assert_eq!(<Option<u64>>::from_value(&Value::<u64>::Nil), Ok(None));
+        assert!(bool::from_value(&Value::<u64>::Int(0)).unwrap());
+        let no: Value<u64> = false.into_value();
+        assert!(!bool::from_value(&no).unwrap());
+    }
+
+    #[test]
+    fn mismatches_name_both_types() {
+        let error = |expected, found| TypeError { expected, found };
+        let text: Value<u64> = "1".into_value();
+        assert_eq!(u64::from_value(&text), Err(error("int", "string")));
+        let void = Value::<u64>::Void;
+        assert_eq!(<Vec<u64>>::from_value(&void).unwrap_err(), error("list", "void"));
+        let empty = list(vec![]);
+        assert_eq!(<(u64,)>::from_value(&empty).unwrap_err(), error("list of 1", "list"));
+        assert_eq!(<&[u8]>::from_value(&empty).unwrap_err(), error("bytes", "list"));
+        assert_eq!(error("int", "nil").to_string(), "Expected int, got nil");
+    }
+}
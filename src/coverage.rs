@@ -0,0 +1,805 @@
+//! Tracking which parts of a program were evaluated, so that a tool can
+//! point out code that never ran, such as the branch of an `if` that was
+//! never taken.
+//!
+//! Every node of a program gets a `NodeId`, numbered in the order the nodes
+//! are written in the source, and the span of source it was parsed from.
+//! Evaluating with `EvalOptions::coverage` set marks each node as it's
+//! reached. Nodes are recognised by their address, so the program must not
+//! be moved or cloned between being parsed and being evaluated.
+
+use std::collections::HashMap;
+#[cfg(feature = "parse")]
+use std::collections::HashSet;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+#[cfg(feature = "parse")]
+use {parse_program_with, Diagnostic, ParseError, ParseOptions};
+use {Ast, Value};
+
+/// Identifies a node of a program that coverage is being tracked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub usize);
+
+/// A range of byte offsets in the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Which nodes of a program have been evaluated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    ids: HashMap<usize, NodeId>,
+    spans: Vec<Span>,
+    parents: Vec<Option<NodeId>>,
+    covered: Vec<u64>,
+}
+
+impl Coverage {
+    /// Parse `src` like `parse_program_with`, along with a `Coverage` for
+    /// the program where nothing has been evaluated yet. If the span of
+    /// every node can't be found, that's a `ParseError::Syntax` with no
+    /// position, rather than coverage that points at the wrong source.
+    #[cfg(feature = "parse")]
+    pub fn parse(
+        src: &str,
+        options: &ParseOptions,
+        diagnostics: &mut dyn FnMut(Diagnostic),
+    ) -> Result<(Vec<Ast<u64>>, Coverage), ParseError> {
+        let program = parse_program_with(src, options, diagnostics)?;
+        let coverage = Coverage::of(&program, src)?;
+        Ok((program, coverage))
+    }
+
+    /// A `Coverage` for `program`, which was parsed from `src`, for when
+    /// it's been parsed some other way than by `Coverage::parse`. Failing to
+    /// find every node's span is the same error as it is there.
+    pub fn of(program: &[Ast<u64>], src: &str) -> Result<Coverage, ParseError> {
+        let mut ids = HashMap::new();
+        let mut parents = Vec::new();
+        // Uses a worklist rather than recursion, since programs can be deep.
+        let mut pending = program.iter().rev().map(|form| (form, None)).collect::<Vec<_>>();
+        while let Some((ast, parent)) = pending.pop() {
+            let id = NodeId(parents.len());
+            ids.insert(ast as *const Ast<u64> as usize, id);
+            parents.push(parent);
+
+            let first = pending.len();
+            each_child(ast, |child| pending.push((child, Some(id))));
+            // Visit the children in order.
+            pending[first..].reverse();
+        }
+
+        let mut scanned = Vec::with_capacity(parents.len());
+        let mut scanner = Scanner {
+            src,
+            pos: 0,
+            quotes: HashSet::new(),
+        };
+        while scanner.skip_whitespace() < src.len() {
+            scanner.expr(&mut scanned);
+        }
+
+        // Every node of a quote shares its span, since the code that builds
+        // it isn't written anywhere.
+        let mut spans = Vec::with_capacity(parents.len());
+        let mut scanned = scanned.into_iter();
+        let mut quote = None;
+        for id in 0..parents.len() {
+            if let Some(root) = quote {
+                if is_inside(&parents, NodeId(id), root) {
+                    let span = spans[root.0];
+                    spans.push(span);
+                    continue;
+                }
+                quote = None;
+            }
+            let span = scanned.next().ok_or_else(mismatch)?;
+            if scanner.quotes.contains(&span.start) {
+                quote = Some(NodeId(id));
+            }
+            spans.push(span);
+        }
+        let in_bounds = |span: &Span| {
+            span.start <= span.end && src.is_char_boundary(span.start) && src.is_char_boundary(span.end)
+        };
+        if scanned.next().is_some() || !spans.iter().all(in_bounds) {
+            return Err(mismatch());
+        }
+
+        let covered = vec![0; parents.len().div_ceil(64)];
+        Ok(Coverage { ids, spans, parents, covered })
+    }
+
+    pub fn is_covered(&self, id: NodeId) -> bool {
+        self.covered[id.0 / 64] & (1 << (id.0 % 64)) != 0
+    }
+
+    pub fn span(&self, id: NodeId) -> Span {
+        self.spans[id.0]
+    }
+
+    /// How many nodes the program has.
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// The spans of every node that wasn't evaluated, leaving out those
+    /// inside another such node.
+    pub fn uncovered_spans<'a>(&'a self) -> impl Iterator<Item = Span> + 'a {
+        (0..self.len())
+            .map(NodeId)
+            .filter(move |&id| {
+                !self.is_covered(id) && self.parents[id.0].is_none_or(|parent| self.is_covered(parent))
+            })
+            .map(move |id| self.span(id))
+    }
+
+    /// Count everything that `other` saw evaluated as evaluated here too.
+    /// Both must be for the same program.
+    pub fn merge(&mut self, other: &Coverage) {
+        assert_eq!(self.spans, other.spans, "coverage is for a different program");
+        for (word, other) in self.covered.iter_mut().zip(&other.covered) {
+            *word |= other;
+        }
+    }
+
+    pub(crate) fn mark<Id>(&mut self, ast: &Ast<Id>) {
+        if let Some(NodeId(id)) = self.node_at(ast as *const Ast<Id> as usize) {
+            self.covered[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    // The node that was parsed to `address`, if it's part of the program.
+    pub(crate) fn node_at(&self, address: usize) -> Option<NodeId> {
+        self.ids.get(&address).cloned()
+    }
+
+    /// The innermost node of `program` whose span has the byte `offset` in
+    /// it, along with the nodes it's inside. `program` must be the one that
+    /// was parsed with this, and not moved since, or there's no such node.
+    ///
+    /// Spans include their first byte but not the byte after their last,
+    /// so an offset where one node ends and another starts, as with `x` in
+    /// `(f(g)x)`, is in the one that starts there, like a cursor just
+    /// before it. Whitespace between the parts of a form is in the form,
+    /// and whitespace between top-level forms isn't in any node. Names
+    /// that are bound rather than used, like `x` in `(= x 1)` and the
+    /// parameters of a lambda, aren't nodes of their own, so they're in
+    /// the definition or the lambda. Every node of a quote has the quote's
+    /// span, so anything in one is the quote itself.
+    pub fn node_at_offset<'a>(
+        &self,
+        program: &'a [Ast<u64>],
+        offset: usize,
+    ) -> Option<NodePath<'a>> {
+        // Of the nodes with the smallest span, the first is the outermost.
+        let innermost = (0..self.len())
+            .filter(|&id| self.spans[id].start <= offset && offset < self.spans[id].end)
+            .min_by_key(|&id| self.spans[id].end - self.spans[id].start)?;
+
+        let mut ids = vec![NodeId(innermost)];
+        while let Some(parent) = self.parents[ids[ids.len() - 1].0] {
+            ids.push(parent);
+        }
+        ids.reverse();
+
+        let mut nodes = Vec::with_capacity(ids.len());
+        for &id in &ids {
+            let mut found = None;
+            let mut find = |ast: &'a Ast<u64>| {
+                if self.node_at(ast as *const Ast<u64> as usize) == Some(id) {
+                    found = Some(ast);
+                }
+            };
+            match nodes.last() {
+                Some(&parent) => each_child(parent, &mut find),
+                None => program.iter().for_each(&mut find),
+            }
+            nodes.push(found?);
+        }
+
+        Some(NodePath { ids, nodes })
+    }
+}
+
+/// A node of a program and the nodes it's inside, found by
+/// `Coverage::node_at_offset`. Both lists start with a top-level form and
+/// end with the node itself.
+#[derive(Clone)]
+pub struct NodePath<'a> {
+    pub ids: Vec<NodeId>,
+    pub nodes: Vec<&'a Ast<u64>>,
+}
+
+impl<'a> NodePath<'a> {
+    pub fn id(&self) -> NodeId {
+        self.ids[self.ids.len() - 1]
+    }
+
+    pub fn node(&self) -> &'a Ast<u64> {
+        self.nodes[self.nodes.len() - 1]
+    }
+}
+
+// Calls `f` with each child of `ast`, in the order they're numbered in.
+fn each_child<'a, F: FnMut(&'a Ast<u64>)>(ast: &'a Ast<u64>, mut f: F) {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            lambda.defaults.iter().chain(lambda.body.iter()).for_each(f)
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        Ast::Call(ref func, ref args) => {
+            f(func);
+            args.iter().for_each(f);
+        }
+        Ast::Define(_, ref value) => f(value),
+    }
+}
+
+#[cfg(feature = "parse")]
+fn mismatch() -> ParseError {
+    ParseError::Syntax {
+        message: "couldn't find where every part of the program is in the source".to_owned(),
+        position: None,
+    }
+}
+
+#[cfg(feature = "parse")]
+fn is_inside(parents: &[Option<NodeId>], mut id: NodeId, ancestor: NodeId) -> bool {
+    while let Some(parent) = parents[id.0] {
+        if parent == ancestor {
+            return true;
+        }
+        id = parent;
+    }
+    false
+}
+
+// Finds the span of every node in the same order that `Coverage::parse`
+// numbers them, which is the order they're written in. This only has to
+// cope with source that has already parsed successfully, but it stops at
+// the end of the source rather than panicking if it reads it differently
+// from the parser, so that `Coverage::parse` can report that instead.
+#[cfg(feature = "parse")]
+struct Scanner<'a> {
+    src: &'a str,
+    pos: usize,
+    // Where each quote starts. A quote's nodes aren't scanned, since
+    // there's no source for most of them.
+    quotes: HashSet<usize>,
+}
+
+#[cfg(feature = "parse")]
+impl<'a> Scanner<'a> {
+    fn rest(&self) -> &'a str {
+        self.src.get(self.pos..).unwrap_or("")
+    }
+
+    // Whether there's more before `close`, or the end of the source.
+    fn before(&self, close: char) -> bool {
+        !self.rest().is_empty() && !self.rest().starts_with(close)
+    }
+
+    fn skip_while<F: Fn(char) -> bool>(&mut self, f: F) -> usize {
+        let rest = self.rest();
+        self.pos += rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos
+    }
+
+    fn skip_whitespace(&mut self) -> usize {
+        self.skip_while(char::is_whitespace)
+    }
+
+    // Recurses once per level of nesting, which the parser limits.
+    fn expr(&mut self, spans: &mut Vec<Span>) {
+        let start = self.skip_whitespace();
+        let index = spans.len();
+        spans.push(Span { start, end: start });
+
+        if self.rest().starts_with("#f") {
+            self.pos += 2;
+        } else if self.rest().starts_with("#b") {
+            // Bytes, which can't be interpolated.
+            self.pos += 3;
+            while self.before('"') {
+                // An escape is a backslash and the character after it.
+                let mut chars = self.rest().chars();
+                if chars.next() == Some('\\') {
+                    chars.next();
+                }
+                self.pos = self.src.len() - chars.as_str().len();
+            }
+            self.pos += 1;
+        } else if self.rest().starts_with(|c: char| c.is_ascii_digit()) {
+            self.skip_while(|c| c.is_ascii_digit());
+        } else if self.rest().starts_with('"') {
+            self.string(spans);
+        } else if self.rest().starts_with('\'') {
+            self.pos += 1;
+            self.expr(&mut Vec::new());
+            self.quotes.insert(start);
+        } else if self.rest().starts_with(',') {
+            // `,x` is a call to `unquote`, whose name is the comma.
+            spans.push(Span { start, end: start + 1 });
+            self.pos += 1;
+            self.expr(spans);
+        } else if self.rest().starts_with(':') {
+            self.pos += 1;
+            self.skip_while(char::is_alphabetic);
+        } else if self.rest().starts_with(char::is_alphabetic) {
+            self.skip_while(char::is_alphabetic);
+        } else {
+            // A parenthesised form.
+            self.pos += 1;
+            self.skip_whitespace();
+            // Nodes that come after the rest of the form's.
+            let mut after = Vec::new();
+            if self.rest().starts_with('\\') {
+                self.pos += 1;
+                self.skip_whitespace();
+                self.params(spans);
+            } else if self.rest().starts_with('=') {
+                self.pos += 1;
+                self.skip_whitespace();
+                self.skip_while(char::is_alphabetic);
+            } else if self.rest().starts_with("let-values") {
+                // A call to a lambda whose body is the rest of the form,
+                // named by `let-values`. The value it's called with is
+                // written first but is the call's last node.
+                spans.push(Span { start: self.pos, end: self.pos + 10 });
+                self.pos += 10;
+                self.skip_whitespace();
+                self.pos += 1;
+                self.skip_whitespace();
+                self.pattern();
+                self.expr(&mut after);
+                self.skip_whitespace();
+                self.pos += 1;
+                self.skip_whitespace();
+            } else if self.keyword("delay") {
+                // A call to `delay` with a lambda that has the expression
+                // as its body. The lambda is spanned by the expression.
+                spans.push(Span { start: self.pos, end: self.pos + 5 });
+                self.pos += 5;
+                self.lambda(spans);
+                self.skip_whitespace();
+            } else if self.keyword("try") {
+                // A call to `try` with a lambda that has the expression as
+                // its body, and one for the handler, which is spanned by
+                // the `(catch ...)` it's written as.
+                spans.push(Span { start: self.pos, end: self.pos + 3 });
+                self.pos += 3;
+                self.lambda(spans);
+                let index = spans.len();
+                let start = self.skip_whitespace();
+                spans.push(Span { start, end: start });
+                self.pos += 1;
+                self.skip_whitespace();
+                self.pos += 5;
+                self.skip_whitespace();
+                self.pattern();
+                self.skip_whitespace();
+                while self.before(')') {
+                    self.expr(spans);
+                    self.skip_whitespace();
+                }
+                self.pos += 1;
+                spans[index].end = self.pos;
+                self.skip_whitespace();
+            } else if self.rest().starts_with("include") && self.rest()[7..].trim_start().starts_with('"') {
+                // The path is the only thing in an include, and isn't a node.
+                self.skip_while(|c| c != '"');
+                self.pos += 1;
+                self.skip_while(|c| c != '"');
+                self.pos += 1;
+                self.skip_whitespace();
+            }
+
+            while self.before(')') {
+                self.expr(spans);
+                self.skip_whitespace();
+            }
+            spans.append(&mut after);
+            self.pos += 1;
+        }
+
+        spans[index].end = self.pos;
+    }
+
+    // Whether the form goes on with the special form `name`, rather than a
+    // call to something whose name starts with it, such as `delayed`.
+    fn keyword(&self, name: &str) -> bool {
+        self.rest().starts_with(name) && !self.rest()[name.len()..].starts_with(char::is_alphabetic)
+    }
+
+    // An expression that the parser wraps in a lambda with no parameters,
+    // which is spanned by the expression.
+    fn lambda(&mut self, spans: &mut Vec<Span>) {
+        let index = spans.len();
+        let start = self.skip_whitespace();
+        spans.push(Span { start, end: start });
+        self.expr(spans);
+        spans[index].end = self.pos;
+    }
+
+    // A string literal. One with `{expr}`s in it is a call to `concat`, whose
+    // name is given the opening quote as its span, with the text between
+    // the interpolations as its other arguments.
+    fn string(&mut self, spans: &mut Vec<Span>) {
+        let quote = self.pos;
+        let first = spans.len();
+        let mut text = None;
+        let mut interpolated = false;
+        self.pos += 1;
+
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                break;
+            }
+            if rest.starts_with('"') || (rest.starts_with('{') && !rest.starts_with("{{")) {
+                if let Some(start) = text.take() {
+                    spans.push(Span { start, end: self.pos });
+                }
+                self.pos += 1;
+                if rest.starts_with('"') {
+                    break;
+                }
+                interpolated = true;
+                self.expr(spans);
+                self.skip_whitespace();
+                self.pos += 1;
+            } else {
+                text.get_or_insert(self.pos);
+                // Escapes are all two ASCII characters.
+                self.pos += if rest.starts_with(['\\', '{', '}']) {
+                    2
+                } else {
+                    rest.chars().next().map_or(1, char::len_utf8)
+                };
+            }
+        }
+
+        if interpolated {
+            spans.insert(first, Span { start: quote, end: quote + 1 });
+        } else {
+            spans.truncate(first);
+        }
+    }
+
+    // A parameter list, whose defaults are nodes that come before the body.
+    fn params(&mut self, spans: &mut Vec<Span>) {
+        self.pos += 1;
+        loop {
+            self.skip_whitespace();
+            if !self.before(')') {
+                self.pos += 1;
+                return;
+            }
+            if !self.rest().starts_with('(') {
+                self.pattern();
+                continue;
+            }
+
+            let inside = self.rest()[1..].trim_start();
+            if inside.starts_with('=') {
+                // `(= name default)`
+                self.pos = self.src.len() - inside.len() + 1;
+                self.skip_whitespace();
+                self.skip_while(char::is_alphabetic);
+                self.expr(spans);
+                self.skip_whitespace();
+                self.pos += 1;
+            } else {
+                self.pattern();
+            }
+        }
+    }
+
+    // A pattern, which has no nodes in it.
+    fn pattern(&mut self) {
+        if !self.rest().starts_with('(') {
+            // A name, or `,name` in a quote.
+            let start = self.pos;
+            if self.skip_while(|c| c == ',' || c.is_alphabetic()) == start {
+                self.pos = self.src.len();
+            }
+            return;
+        }
+
+        let mut depth = 0;
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 1 => {
+                    self.pos += i + 1;
+                    return;
+                }
+                ')' => depth -= 1,
+                _ => {}
+            }
+        }
+        self.pos = self.src.len();
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use benches::{corpus_env, REAL_CODE};
+    use {eval_with, hash_string, Ast, EvalOptions, ParseOptions, Value};
+
+    use std::borrow::Cow;
+    use std::collections::HashSet;
+
+    use super::{Coverage, NodeId, Scanner, Span};
+
+    #[test]
+    fn spans_match_the_source() {
+        let src = "(= x 1)\n  (add x\t(\\(( a (b)) c ( = d (add c 1) ))  a 12) #f)";
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [
+                "(= x 1)",
+                "1",
+                "(add x\t(\\(( a (b)) c ( = d (add c 1) ))  a 12) #f)",
+                "add",
+                "x",
+                "(\\(( a (b)) c ( = d (add c 1) ))  a 12)",
+                "(add c 1)",
+                "add",
+                "c",
+                "1",
+                "a",
+                "12",
+                "#f",
+            ]
+        );
+
+        // Everything in a quote has the quote's span, but an unquote outside
+        // one is an ordinary call.
+        let src = r"(f '(g ,x (\(,y) y)) ,z)";
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        let quote = r"'(g ,x (\(,y) y))";
+        assert_eq!(text[..3], [src, "f", quote]);
+        assert!(text[3..text.len() - 3].iter().all(|&span| span == quote));
+        assert_eq!(text[text.len() - 3..], [",z", ",", "z"]);
+
+        // `let-values` reads as a call to a lambda, which is spanned by the
+        // keyword, with the values coming after the body.
+        let src = "(let-values ((q (r)) (f 1)) (add q r))";
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(text, [src, "let-values", "(add q r)", "add", "q", "r", "(f 1)", "f", "1"]);
+
+        // `delay` calls the native with a lambda, which is spanned by the
+        // expression in it.
+        let src = "(delay (f 1))";
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(text, [src, "delay", "(f 1)", "(f 1)", "f", "1"]);
+
+        // Bytes are a literal like any other.
+        let src = r#"(f #b"a\"b" #b"" 1)"#;
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(text, [src, "f", r#"#b"a\"b""#, r#"#b"""#, "1"]);
+
+        // So does `try`, with the handler spanned by its `catch`.
+        let src = "(try (f 1) ( catch (e) (g e) 2 ))";
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [src, "try", "(f 1)", "(f 1)", "f", "1", "( catch (e) (g e) 2 )", "(g e)", "g", "e", "2"]
+        );
+
+        let (_, coverage) = Coverage::parse(REAL_CODE, &ParseOptions::default(), &mut |_| {}).unwrap();
+        assert!(coverage.len() > 50);
+
+        // Interpolating calls `concat`, which is spanned by the opening quote.
+        let src = r#"(f "a \" {{" "x {y} {"z {1}"}µ")"#;
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let text = (0..coverage.len())
+            .map(|i| {
+                let span = coverage.span(NodeId(i));
+                &src[span.start..span.end]
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            text,
+            [
+                src,
+                "f",
+                r#""a \" {{""#,
+                r#""x {y} {"z {1}"}µ""#,
+                "\"",
+                "x ",
+                "y",
+                " ",
+                r#""z {1}""#,
+                "\"",
+                "z ",
+                "1",
+                "µ",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_node_at_an_offset() {
+        let src = "(= x 1)\n\n(f (\\(a) (g (\\(b) (h (\\(c) (add c zed)))))) '(q r))(f(g)x)";
+        let (program, coverage) =
+            Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let at = |offset| {
+            coverage.node_at_offset(&program, offset).map(|path| {
+                path.ids
+                    .iter()
+                    .map(|&id| {
+                        let span = coverage.span(id);
+                        &src[span.start..span.end]
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // The first byte, and the name being defined, are the definition.
+        assert_eq!(at(0).unwrap(), ["(= x 1)"]);
+        assert_eq!(at(3).unwrap(), ["(= x 1)"]);
+        // Whitespace between forms isn't in any of them, and neither is the
+        // end of the source.
+        assert_eq!(at(7), None);
+        assert_eq!(at(8), None);
+        assert_eq!(at(src.len()), None);
+
+        // Three lambdas deep, in the middle of a name.
+        let zed = src.find("zed").unwrap() + 1;
+        let path = coverage.node_at_offset(&program, zed).unwrap();
+        assert!(matches!(*path.node(), Ast::Variable(id) if id == hash_string("zed")));
+        assert_eq!(path.id(), path.ids[path.ids.len() - 1]);
+        assert_eq!(path.nodes.len(), path.ids.len());
+        let second = src.lines().nth(2).unwrap();
+        let second = &second[..second.find("(f(g)x)").unwrap()];
+        assert_eq!(
+            at(zed).unwrap(),
+            [
+                second,
+                r"(\(a) (g (\(b) (h (\(c) (add c zed))))))",
+                r"(g (\(b) (h (\(c) (add c zed)))))",
+                r"(\(b) (h (\(c) (add c zed))))",
+                r"(h (\(c) (add c zed)))",
+                r"(\(c) (add c zed))",
+                "(add c zed)",
+                "zed",
+            ]
+        );
+        // Whitespace inside a call, and a parameter, are in the call and
+        // the lambda.
+        let space = src.find(" zed").unwrap();
+        assert_eq!(at(space).unwrap().last(), Some(&"(add c zed)"));
+        let param = src.find("(c)").unwrap() + 1;
+        assert_eq!(at(param).unwrap().last(), Some(&r"(\(c) (add c zed))"));
+        // Anything in a quote is the quote.
+        let r = src.find("r)").unwrap();
+        assert_eq!(at(r).unwrap().last(), Some(&"'(q r)"));
+
+        // Between two nodes, the one that starts there wins, up to the last
+        // byte of the source.
+        let x = src.len() - 2;
+        assert_eq!(at(x).unwrap(), ["(f(g)x)", "x"]);
+        assert_eq!(at(x - 1).unwrap(), ["(f(g)x)", "(g)"]);
+        assert_eq!(at(src.len() - 1).unwrap(), ["(f(g)x)"]);
+    }
+
+    #[test]
+    fn misreading_the_source_is_not_a_panic() {
+        // The scanner only sees source that parsed, but if it reads some
+        // differently from the parser it has to stop rather than panic or
+        // loop, so that the difference can be reported.
+        for &src in &["(", "(\\(1", "(\\((a", "\"abc {", "#b\"a\\", "(try 1 (catch", "(f \"µ", "é"] {
+            let mut scanner = Scanner {
+                src,
+                pos: 0,
+                quotes: HashSet::new(),
+            };
+            while scanner.skip_whitespace() < src.len() {
+                scanner.expr(&mut Vec::new());
+            }
+        }
+
+        // A program other than the one that was parsed has no nodes in it.
+        let (program, coverage) = Coverage::parse("(f 1)", &ParseOptions::default(), &mut |_| {}).unwrap();
+        assert!(coverage.node_at_offset(&program, 1).is_some());
+        assert!(coverage.node_at_offset(&program.clone(), 1).is_none());
+    }
+
+    #[test]
+    fn reports_the_branch_that_never_ran() {
+        let src = r"
+            (= choose (\(flag) ((if flag (\() (add 1 2)) (\() 3)))))
+            (choose yes)";
+        let parse = || Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let run = |program: &[Ast<u64>], coverage: &mut Option<Coverage>, yes| {
+            let mut options = EvalOptions {
+                coverage: coverage.take(),
+                ..EvalOptions::default()
+            };
+            let mut env = corpus_env();
+            env.insert(hash_string("yes"), Cow::Owned(yes));
+            for form in program {
+                eval_with(form, &mut env, &mut options).unwrap();
+            }
+            *coverage = options.coverage;
+        };
+        let uncovered = |coverage: &Option<Coverage>| {
+            coverage
+                .as_ref()
+                .unwrap()
+                .uncovered_spans()
+                .map(|Span { start, end }| &src[start..end])
+                .collect::<Vec<_>>()
+        };
+
+        let (program, coverage) = parse();
+        let mut coverage = Some(coverage);
+        run(&program, &mut coverage, Value::False);
+        assert_eq!(uncovered(&coverage), ["(add 1 2)"]);
+        assert!(coverage.as_ref().unwrap().is_covered(NodeId(0)));
+
+        // The other branch, run on its own, misses the first one instead.
+        let (other_program, other) = parse();
+        let mut other = Some(other);
+        run(&other_program, &mut other, Value::Void);
+        assert_eq!(uncovered(&other), ["3"]);
+
+        // Running the program again with another input adds to its coverage.
+        let mut both = coverage.clone();
+        run(&program, &mut both, Value::Void);
+        assert_eq!(uncovered(&both), Vec::<&str>::new());
+
+        // And so does merging coverage from separate runs.
+        coverage.as_mut().unwrap().merge(other.as_ref().unwrap());
+        assert_eq!(coverage, both);
+    }
+}
@@ -0,0 +1,428 @@
+//! Hooks for stepping through evaluation one node at a time.
+//!
+//! A `Debugger` attached through `EvalOptions::debugger` hears about every
+//! node as `eval_with` starts and finishes evaluating it, in the order they
+//! are evaluated, and decides whether evaluation goes on. It can also watch
+//! variables, to hear whenever they're defined.
+
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use snapshot::sorted_by_name;
+use {Ast, EvalError, Scope, SymbolTable, Value};
+
+/// What a `Debugger` wants to happen after it's told about a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Evaluate the node, reporting each node inside it as usual.
+    Continue,
+    /// Evaluate the node without reporting anything inside it, including
+    /// the bodies of any functions it calls. Its exit is still reported.
+    StepOver,
+    /// Stop, with `eval_with` returning `EvalError::Aborted`.
+    Abort,
+}
+
+pub trait Debugger<Id> {
+    /// Called before `node` is evaluated, with the variables it can see.
+    fn on_enter(&mut self, node: &Ast<Id>, env: &EnvView<Id>) -> Step;
+
+    /// Called once `node` has been evaluated. Nodes that fail report their
+    /// error, and then so does every node they were inside of.
+    fn on_exit(&mut self, node: &Ast<Id>, result: Result<&Value<Id>, &EvalError<Id>>);
+
+    /// Called when a variable passed to `Debugging::watch` is defined, with
+    /// the value it had before in the scope it's defined in, if any.
+    /// Binding a function's parameters doesn't count.
+    fn on_define(&mut self, name: &Id, old: Option<&Value<Id>>, new: &Value<Id>) {
+        let _ = (name, old, new);
+    }
+}
+
+/// Read-only access to the variables in scope at a node.
+pub struct EnvView<'a, Id: 'a> {
+    bindings: &'a dyn Bindings<Id>,
+}
+
+impl<'a, Id> EnvView<'a, Id> {
+    pub fn get(&self, name: &Id) -> Option<&Value<Id>> {
+        self.bindings.get(name)
+    }
+
+    /// Every variable and its value, in no particular order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&Id, &Value<Id>)> + '_> {
+        self.bindings.iter()
+    }
+}
+
+impl<'a> EnvView<'a, u64> {
+    /// Every variable and its value, with names from `symbols`, in order of
+    /// name as `snapshot::sorted_by_name` gives them.
+    pub fn sorted_by_name(&self, symbols: &SymbolTable) -> Vec<(String, &Value<u64>)> {
+        sorted_by_name(self.iter().map(|(&name, value)| (name, value)), symbols)
+    }
+}
+
+// Lets `EnvView` hide which hasher the environment uses, and how it's
+// split between calls.
+trait Bindings<Id> {
+    fn get(&self, name: &Id) -> Option<&Value<Id>>;
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Id, &Value<Id>)> + '_>;
+}
+
+impl<'s, 'b, Id: Clone + Eq + Hash, S: BuildHasher> Bindings<Id> for Scope<'s, 'b, Id, S> {
+    fn get(&self, name: &Id) -> Option<&Value<Id>> {
+        Scope::get(self, name).map(|value| value.as_ref())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&Id, &Value<Id>)> + '_> {
+        Box::new(Scope::iter(self).map(|(name, value)| (name, value.as_ref())))
+    }
+}
+
+/// A `Debugger` attached to `EvalOptions`, along with how far into the
+/// program evaluation has got.
+pub struct Debugging<Id> {
+    debugger: Box<dyn Debugger<Id>>,
+    watched: Vec<Id>,
+    // How many nodes are being evaluated, and how many there were when the
+    // debugger asked to step over one.
+    depth: usize,
+    stepping_over: Option<usize>,
+}
+
+impl<Id> Debugging<Id> {
+    pub fn new<D: Debugger<Id> + 'static>(debugger: D) -> Self {
+        Debugging {
+            debugger: Box::new(debugger),
+            watched: Vec::new(),
+            depth: 0,
+            stepping_over: None,
+        }
+    }
+
+    /// Call `on_define` whenever `name` is defined from now on.
+    pub fn watch(&mut self, name: Id) -> &mut Self {
+        self.watched.push(name);
+        self
+    }
+
+    pub(crate) fn enter<S: BuildHasher>(
+        &mut self,
+        node: &Ast<Id>,
+        env: &Scope<Id, S>,
+    ) -> Result<(), EvalError<Id>>
+    where
+        Id: Clone + Eq + Hash,
+    {
+        if self.stepping_over.is_none() {
+            match self.debugger.on_enter(node, &EnvView { bindings: env }) {
+                Step::Continue => {}
+                Step::StepOver => self.stepping_over = Some(self.depth),
+                Step::Abort => return Err(EvalError::Aborted),
+            }
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub(crate) fn exit(&mut self, node: &Ast<Id>, result: Result<&Value<Id>, &EvalError<Id>>) {
+        self.depth -= 1;
+
+        if self.stepping_over == Some(self.depth) {
+            self.stepping_over = None;
+        }
+        if self.stepping_over.is_none() {
+            self.debugger.on_exit(node, result);
+        }
+    }
+
+    pub(crate) fn define(&mut self, name: &Id, old: Option<&Value<Id>>, new: &Value<Id>)
+    where
+        Id: PartialEq,
+    {
+        if self.watched.contains(name) {
+            self.debugger.on_define(name, old, new);
+        }
+    }
+
+    // Forget where evaluation was, after it stopped partway through.
+    pub(crate) fn reset(&mut self) {
+        self.depth = 0;
+        self.stepping_over = None;
+    }
+}
+
+impl<Id> fmt::Debug for Debugging<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Debugging")
+            .field("depth", &self.depth)
+            .field("stepping_over", &self.stepping_over)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use benches::{corpus_env, REAL_CODE};
+    use print::to_source;
+    use {
+        eval_with, hash_string, parse_program_with, parse_program_with_symbols, prelude, Ast,
+        EvalError, EvalOptions, ParseOptions, SymbolTable, Value,
+    };
+
+    use super::{Debugger, Debugging, EnvView, Step};
+
+    // Writes down every callback, and answers each `on_enter` with the next
+    // of `steps`, or `Continue` once they run out.
+    struct Recorder {
+        symbols: SymbolTable,
+        steps: Vec<Step>,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Debugger<u64> for Recorder {
+        fn on_enter(&mut self, node: &Ast<u64>, env: &EnvView<u64>) -> Step {
+            let x = match env.get(&hash_string("x")) {
+                Some(x) => format!(" with x = {}", x),
+                None => String::new(),
+            };
+            let src = to_source(node, &self.symbols).unwrap();
+            self.log.borrow_mut().push(format!("enter {}{}", src, x));
+
+            if self.steps.is_empty() {
+                Step::Continue
+            } else {
+                self.steps.remove(0)
+            }
+        }
+
+        fn on_exit(&mut self, node: &Ast<u64>, result: Result<&Value<u64>, &EvalError<u64>>) {
+            let src = to_source(node, &self.symbols).unwrap();
+            let result = match result {
+                Ok(value) => value.to_string(),
+                Err(e) => e.to_string(),
+            };
+            self.log.borrow_mut().push(format!("exit {} = {}", src, result));
+        }
+    }
+
+    fn debug(src: &str, steps: Vec<Step>) -> (Vec<String>, Result<(), EvalError<u64>>, Vec<u64>) {
+        let (program, mut symbols) =
+            parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        symbols.insert("add");
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut options = EvalOptions {
+            debugger: Some(Debugging::new(Recorder {
+                symbols: symbols.clone(),
+                steps,
+                log: log.clone(),
+            })),
+            ..EvalOptions::default()
+        };
+
+        let mut env = corpus_env();
+        let result = program
+            .iter()
+            .try_for_each(|form| eval_with(form, &mut env, &mut options).map(|_| ()));
+        let mut defined = symbols
+            .iter()
+            .filter(|&(id, _)| env.contains_key(&id) && id != hash_string("add"))
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        defined.sort();
+
+        let log = log.borrow().clone();
+        (log, result, defined)
+    }
+
+    #[test]
+    fn sees_nodes_in_evaluation_order() {
+        let (log, result, _) = debug("(= x (add 1 2)) x", vec![]);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            log,
+            [
+                "enter (= x (add 1 2))",
+                "enter (add 1 2)",
+                "enter add",
+                "exit add = <native>",
+                "enter 1",
+                "exit 1 = 1",
+                "enter 2",
+                "exit 2 = 2",
+                "exit (add 1 2) = 3",
+                "exit (= x (add 1 2)) = 3",
+                "enter x with x = 3",
+                "exit x = 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_over_nodes() {
+        let src = r"(= f (\(a) (add a a))) (add (f 1) 2)";
+        let steps = vec![Step::Continue, Step::Continue, Step::Continue, Step::Continue, Step::StepOver];
+        let (log, result, _) = debug(src, steps);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            log,
+            [
+                r"enter (= f (\(a) (add a a)))",
+                r"enter (\(a) (add a a))",
+                r"exit (\(a) (add a a)) = <function>",
+                r"exit (= f (\(a) (add a a))) = <function>",
+                "enter (add (f 1) 2)",
+                "enter add",
+                "exit add = <native>",
+                "enter (f 1)",
+                "exit (f 1) = 2",
+                "enter 2",
+                "exit 2 = 2",
+                "exit (add (f 1) 2) = 4",
+            ]
+        );
+    }
+
+    // Writes down what `EnvView::sorted_by_name` gives at each read of `z`.
+    struct Lister {
+        symbols: SymbolTable,
+        log: Rc<RefCell<Vec<Vec<String>>>>,
+    }
+
+    impl Debugger<u64> for Lister {
+        fn on_enter(&mut self, node: &Ast<u64>, env: &EnvView<u64>) -> Step {
+            if let Ast::Variable(name) = *node {
+                if name == hash_string("z") {
+                    let bindings = env.sorted_by_name(&self.symbols).into_iter();
+                    let shown = bindings.map(|(name, value)| format!("{} = {}", name, value));
+                    self.log.borrow_mut().push(shown.collect());
+                }
+            }
+            Step::Continue
+        }
+
+        fn on_exit(&mut self, _: &Ast<u64>, _: Result<&Value<u64>, &EvalError<u64>>) {}
+    }
+
+    #[test]
+    fn lists_variables_in_order_of_name() {
+        let list = |src: &str| {
+            let (program, symbols) =
+                parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+            let log = Rc::new(RefCell::new(Vec::new()));
+            let mut options = EvalOptions {
+                debugger: Some(Debugging::new(Lister { symbols, log: log.clone() })),
+                ..EvalOptions::default()
+            };
+            let mut env = corpus_env();
+            for form in &program {
+                eval_with(form, &mut env, &mut options).unwrap();
+            }
+            let log = log.borrow().clone();
+            log
+        };
+
+        let forwards = list(r"(= a 1) (= b 2) (= c 3) ((\(z b) z) 4 5)");
+        let backwards = list(r"(= c 3) (= b 2) (= a 1) ((\(b z) z) 5 4)");
+        assert_eq!(forwards, backwards);
+        let mine = forwards[0].iter().filter(|line| line.as_bytes()[1] == b' ');
+        let mine = mine.collect::<Vec<_>>();
+        assert_eq!(mine, ["a = 1", "b = 5", "c = 3", "z = 4"]);
+        let mut sorted = forwards[0].clone();
+        sorted.sort();
+        assert_eq!(forwards[0], sorted);
+    }
+
+    #[test]
+    fn aborting_stops_evaluation() {
+        let (log, result, defined) =
+            debug("(= a 1) (= b 2) (= c 3)", vec![Step::Continue, Step::Continue, Step::Abort]);
+
+        assert_eq!(result, Err(EvalError::Aborted));
+        assert_eq!(log, ["enter (= a 1)", "enter 1", "exit 1 = 1", "exit (= a 1) = 1", "enter (= b 2)"]);
+        assert_eq!(defined, [hash_string("a")]);
+    }
+
+    // A watched variable's name, old value and new value.
+    type Definition<Id> = (Id, Option<String>, String);
+
+    // Only listens for watched variables, writing down each definition.
+    struct Watcher<Id> {
+        log: Rc<RefCell<Vec<Definition<Id>>>>,
+    }
+
+    impl<Id: Clone> Debugger<Id> for Watcher<Id> {
+        fn on_enter(&mut self, _: &Ast<Id>, _: &EnvView<Id>) -> Step {
+            Step::Continue
+        }
+
+        fn on_exit(&mut self, _: &Ast<Id>, _: Result<&Value<Id>, &EvalError<Id>>) {}
+
+        fn on_define(&mut self, name: &Id, old: Option<&Value<Id>>, new: &Value<Id>) {
+            let old = old.map(|old| old.to_string());
+            self.log.borrow_mut().push((name.clone(), old, new.to_string()));
+        }
+    }
+
+    #[test]
+    fn watches_definitions() {
+        let program = parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut debugger = Debugging::new(Watcher { log: log.clone() });
+        debugger.watch(hash_string("someval")).watch(hash_string("nothing"));
+        let mut options = EvalOptions {
+            debugger: Some(debugger),
+            ..EvalOptions::default()
+        };
+
+        let mut env = corpus_env();
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+
+        // `double` has a parameter called `someval`, which doesn't count.
+        assert_eq!(*log.borrow(), [(hash_string("someval"), None, "3".to_owned())]);
+    }
+
+    #[test]
+    fn watches_named_identifiers() {
+        let define = |value| Ast::Define("x", Rc::new(value));
+        let program = [
+            define(Ast::Lit(Value::Int(1))),
+            define(Ast::Call(
+                Rc::new(Ast::Variable("add")),
+                vec![Ast::Variable("x"), Ast::Lit(Value::Int(1))].into(),
+            )),
+            Ast::Define("y", Rc::new(Ast::Variable("x"))),
+        ];
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut debugger = Debugging::new(Watcher { log: log.clone() });
+        debugger.watch("x");
+        let mut options = EvalOptions {
+            debugger: Some(debugger),
+            ..EvalOptions::default()
+        };
+
+        let mut env = HashMap::new();
+        env.insert("add", Cow::Owned(Value::InbuiltFunc(prelude::add)));
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+
+        assert_eq!(
+            *log.borrow(),
+            [("x", None, "1".to_owned()), ("x", Some("1".to_owned()), "2".to_owned())]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Export of programs as Graphviz graphs, for looking at what the parser
+//! produced.
+//!
+//! Each node of the tree gets its own graph node, numbered in the order
+//! they're written out, so the same program always gives the same output.
+//! Calls are boxes, defines are diamonds, function literals are double
+//! octagons, includes are notes and other literals and variables are
+//! ellipses. Edges are labelled with the role of the child, which for the
+//! default of a parameter names the parameter. Children shared
+//! between several parents are drawn once per parent.
+
+use std::fmt::Write;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {Ast, Pattern, SymbolTable, Value};
+
+/// Render `ast` in the DOT language. Identifiers are shown by name if
+/// `symbols` has them, and as their hash otherwise.
+pub fn to_dot(ast: &Ast<u64>, symbols: Option<&SymbolTable>) -> String {
+    let name = |id: u64| match symbols.and_then(|symbols| symbols.name(id)) {
+        Some(name) => name.to_owned(),
+        None => format!("#{:x}", id),
+    };
+
+    let mut out = String::from("digraph ast {\n");
+    let mut next = 1;
+    // Uses a worklist rather than recursion, since programs can be deep.
+    let mut pending = vec![(0, ast)];
+
+    while let Some((id, ast)) = pending.pop() {
+        let (shape, label) = match *ast {
+            Ast::Lit(ref value) => match *value {
+                Value::Void => ("ellipse", "void".to_owned()),
+                Value::False => ("ellipse", "#f".to_owned()),
+                Value::Nil => ("ellipse", "nil".to_owned()),
+                Value::Int(i) => ("ellipse", i.to_string()),
+                Value::BigInt(ref big) => ("ellipse", big.to_string()),
+                Value::Function(ref lambda) => {
+                    let params = lambda.params.iter().map(|p| pattern(p, &name)).collect::<Vec<_>>();
+                    ("doubleoctagon", format!("\\({})", params.join(" ")))
+                }
+                Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+                    ("ellipse", "native".to_owned())
+                }
+                Value::Partial(_) => ("ellipse", "partial".to_owned()),
+                Value::Thunk(_) => ("ellipse", "thunk".to_owned()),
+                Value::Box(_) => ("ellipse", "box".to_owned()),
+                Value::Foreign(_) => ("ellipse", value.to_string()),
+                Value::Str(_) | Value::Bytes(_) => ("ellipse", value.to_string()),
+                Value::List(_) | Value::Values(_) => ("ellipse", value.to_string()),
+                Value::Keyword(id) => ("ellipse", format!(":{}", name(id))),
+                Value::Symbol(id) => ("ellipse", format!("'{}", name(id))),
+            },
+            Ast::Variable(id) => ("ellipse", name(id)),
+            Ast::Call(..) => ("box", "call".to_owned()),
+            Ast::Define(id, _) => ("diamond", format!("= {}", name(id))),
+            Ast::Include(ref path) => ("note", format!("include \"{}\"", path)),
+        };
+        let _ = writeln!(out, "    n{} [shape={}, label=\"{}\"];", id, shape, escape(&label));
+
+        let mut children = Vec::new();
+        match *ast {
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let optional = &lambda.params[lambda.required()..];
+                for (param, default) in optional.iter().zip(lambda.defaults.iter()) {
+                    children.push((format!("default {}", pattern(param, &name)), default));
+                }
+                for (i, stmt) in lambda.body.iter().enumerate() {
+                    children.push((format!("body {}", i), stmt));
+                }
+            }
+            Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+            Ast::Call(ref func, ref args) => {
+                children.push(("function".to_owned(), &**func));
+                for (i, arg) in args.iter().enumerate() {
+                    children.push((format!("arg {}", i), arg));
+                }
+            }
+            Ast::Define(_, ref value) => children.push(("value".to_owned(), &**value)),
+        }
+
+        let first = pending.len();
+        for (role, child) in children {
+            let _ = writeln!(out, "    n{} -> n{} [label=\"{}\"];", id, next, role);
+            pending.push((next, child));
+            next += 1;
+        }
+        // Visit the children in order.
+        pending[first..].reverse();
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+fn pattern(pattern: &Pattern<u64>, name: &dyn Fn(u64) -> String) -> String {
+    match *pattern {
+        Pattern::Name(id) => name(id),
+        Pattern::List(ref items) => {
+            let items = items.iter().map(|item| self::pattern(item, name)).collect::<Vec<_>>();
+            format!("({})", items.join(" "))
+        }
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use benches::MANY_VARIABLES;
+    use {parse_program_with_symbols, ParseOptions};
+
+    use super::to_dot;
+
+    #[test]
+    fn exports_a_small_program() {
+        let options = ParseOptions::default();
+        let (program, symbols) =
+            parse_program_with_symbols(r"(= inc (\(x) (add x 1 #f)))", &options, &mut |_| {})
+                .unwrap();
+
+        assert_eq!(
+            to_dot(&program[0], Some(&symbols)),
+            r##"digraph ast {
+    n0 [shape=diamond, label="= inc"];
+    n0 -> n1 [label="value"];
+    n1 [shape=doubleoctagon, label="\\(x)"];
+    n1 -> n2 [label="body 0"];
+    n2 [shape=box, label="call"];
+    n2 -> n3 [label="function"];
+    n2 -> n4 [label="arg 0"];
+    n2 -> n5 [label="arg 1"];
+    n2 -> n6 [label="arg 2"];
+    n3 [shape=ellipse, label="add"];
+    n4 [shape=ellipse, label="x"];
+    n5 [shape=ellipse, label="1"];
+    n6 [shape=ellipse, label="#f"];
+}
+"##
+        );
+
+        let anonymous = to_dot(&program[0], None);
+        assert!(anonymous.contains(&format!("label=\"= #{:x}\"", ::hash_string("inc"))));
+    }
+
+    #[test]
+    fn labels_defaults_with_their_parameter() {
+        let options = ParseOptions::default();
+        let (program, symbols) =
+            parse_program_with_symbols(r"(\(a (= b a)) b)", &options, &mut |_| {}).unwrap();
+        let dot = to_dot(&program[0], Some(&symbols));
+
+        assert!(dot.contains("n0 [shape=doubleoctagon, label=\"\\\\(a b)\"];"), "{}", dot);
+        assert!(dot.contains("n0 -> n1 [label=\"default b\"];"), "{}", dot);
+        assert!(dot.contains("n1 [shape=ellipse, label=\"a\"];"), "{}", dot);
+        assert!(dot.contains("n0 -> n2 [label=\"body 0\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn many_variables_exports_valid_dot() {
+        let options = ParseOptions::default();
+        let (program, symbols) =
+            parse_program_with_symbols(MANY_VARIABLES, &options, &mut |_| {}).unwrap();
+        let dot = to_dot(&program[0], Some(&symbols));
+
+        let mut lines = dot.lines();
+        assert_eq!(lines.next(), Some("digraph ast {"));
+        assert_eq!(lines.next_back(), Some("}"));
+        assert_eq!(dot.matches('{').count(), dot.matches('}').count());
+
+        let mut nodes = 0;
+        for line in lines {
+            let line = line.trim_start();
+            assert!(line.starts_with('n') && line.ends_with("\"];"), "{}", line);
+
+            let label = &line[line.find("label=\"").expect(line) + 7..line.len() - 3];
+            assert!(!label.replace("\\\\", "").replace("\\\"", "").contains('"'), "{}", line);
+
+            if !line.contains("->") {
+                nodes += 1;
+            }
+        }
+        assert!(nodes > 100);
+        assert!(dot.contains("label=\"= a\"") || dot.contains("label=\"a\""));
+    }
+}
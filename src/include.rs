@@ -0,0 +1,342 @@
+//! Following `(include "path")` forms, which evaluate the top-level forms
+//! of another file in the environment of the include, as though they had
+//! been written in its place.
+//!
+//! Only `eval_with` follows includes, and only if `EvalOptions::includes`
+//! is set, so by default a program can't read any files. Without the
+//! `parse` feature there's no `Includes`, and every include fails as
+//! `Disabled`.
+
+use std::error;
+use std::fmt;
+#[cfg(feature = "parse")]
+use std::fs;
+#[cfg(feature = "parse")]
+use std::path::PathBuf;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+#[cfg(feature = "parse")]
+use {parse_program, Ast};
+use ParseError;
+
+/// Where `eval_with` finds the files that a program includes.
+#[cfg(feature = "parse")]
+pub struct Includes<Id> {
+    base: PathBuf,
+    /// How many includes may be in progress at once, counting the one
+    /// being started.
+    pub max_depth: usize,
+    parse: fn(&str) -> Result<Vec<Ast<Id>>, ParseError>,
+    // The files currently being included, outermost first, as their
+    // resolved path and the path they were included as.
+    stack: Vec<(PathBuf, String)>,
+}
+
+#[cfg(feature = "parse")]
+impl Includes<u64> {
+    /// Resolve included paths relative to `base`, allowing up to 16 nested
+    /// includes.
+    pub fn new<P: Into<PathBuf>>(base: P) -> Self {
+        Includes {
+            base: base.into(),
+            max_depth: 16,
+            parse: parse_program,
+            stack: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "parse")]
+impl<Id> Includes<Id> {
+    // Start including `path`, returning its forms to be evaluated before a
+    // matching call to `leave`.
+    pub(crate) fn enter(&mut self, path: &str) -> Result<Vec<Ast<Id>>, IncludeError> {
+        if self.stack.len() >= self.max_depth {
+            return Err(IncludeError::TooDeep {
+                path: path.to_owned(),
+                max: self.max_depth,
+            });
+        }
+
+        let read_error = |e: ::std::io::Error| IncludeError::Read {
+            path: path.to_owned(),
+            message: e.to_string(),
+        };
+        let resolved = fs::canonicalize(self.base.join(path)).map_err(read_error)?;
+        if let Some(start) = self.stack.iter().position(|(open, _)| *open == resolved) {
+            let mut cycle = self.stack[start..]
+                .iter()
+                .map(|(_, name)| name.clone())
+                .collect::<Vec<_>>();
+            cycle.push(path.to_owned());
+            return Err(IncludeError::Cycle(cycle));
+        }
+
+        let src = fs::read_to_string(&resolved).map_err(read_error)?;
+        let forms = (self.parse)(&src).map_err(|error| IncludeError::Parse {
+            path: path.to_owned(),
+            error,
+        })?;
+
+        self.stack.push((resolved, path.to_owned()));
+        Ok(forms)
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    // Forget the includes in progress, after evaluation stopped partway
+    // through them.
+    pub(crate) fn reset(&mut self) {
+        self.stack.clear();
+    }
+}
+
+#[cfg(feature = "parse")]
+impl<Id> fmt::Debug for Includes<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Includes")
+            .field("base", &self.base)
+            .field("max_depth", &self.max_depth)
+            .field("stack", &self.stack)
+            .finish()
+    }
+}
+
+/// Why an `include` couldn't be followed. Paths are as they were written
+/// in the program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IncludeError {
+    /// `EvalOptions::includes` isn't set.
+    Disabled,
+    /// The program was run by something other than `eval_with`.
+    Unsupported,
+    Read { path: String, message: String },
+    Parse { path: String, error: ParseError },
+    /// Each file includes the next, and the last is the same as the first.
+    Cycle(Vec<String>),
+    /// Including `path` would have gone over `Includes::max_depth`.
+    TooDeep { path: String, max: usize },
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IncludeError::Disabled => write!(f, "Including files is disabled"),
+            IncludeError::Unsupported => write!(f, "Including files is only supported by `eval_with`"),
+            IncludeError::Read { ref path, ref message } => {
+                write!(f, "Couldn't read included file `{}`: {}", path, message)
+            }
+            IncludeError::Parse { ref path, ref error } => {
+                write!(f, "Couldn't parse included file `{}`: {}", path, error)
+            }
+            IncludeError::Cycle(ref paths) => {
+                write!(f, "Files include each other: {}", paths.join(" -> "))
+            }
+            IncludeError::TooDeep { ref path, max } => {
+                write!(f, "Including `{}` would nest includes more than {} deep", path, max)
+            }
+        }
+    }
+}
+
+impl error::Error for IncludeError {}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use benches::corpus_env;
+    use bytecode::Vm;
+    use closure::compile_closure;
+    use coverage::{Coverage, NodeId};
+    use print::to_source;
+    use {
+        eval, eval_with, parse_program, parse_program_with_symbols, EvalError, EvalOptions,
+        Interpreter, ParseOptions, Value,
+    };
+
+    use super::{IncludeError, Includes};
+
+    // A fresh directory of source files, deleted again when it's dropped.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = env::temp_dir().join(format!("rustfest-include-{}-{}", process::id(), name));
+            for &(path, src) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, src).unwrap();
+            }
+            Fixture(dir)
+        }
+
+        fn options(&self) -> EvalOptions<u64> {
+            EvalOptions {
+                includes: Some(Includes::new(&self.0)),
+                ..EvalOptions::default()
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run(src: &str, options: &mut EvalOptions<u64>) -> Result<Value<u64>, EvalError<u64>> {
+        let program = parse_program(src).unwrap();
+        let mut env = corpus_env();
+        let mut out = Value::Void;
+        for form in &program {
+            out = eval_with(form, &mut env, options)?.into_owned();
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn includes_definitions() {
+        let fixture = Fixture::new("simple", &[("lib.lisp", r"(= double (\(x) (add x x))) (= ten 10)")]);
+        let mut options = fixture.options();
+
+        assert!(matches!(
+            run(r#"(include "lib.lisp") (double ten)"#, &mut options),
+            Ok(Value::Int(20))
+        ));
+        // An include evaluates to the file's last form.
+        assert!(matches!(run(r#"(include "lib.lisp")"#, &mut options), Ok(Value::Int(10))));
+    }
+
+    #[test]
+    fn includes_nest() {
+        let fixture = Fixture::new(
+            "nested",
+            &[
+                ("main.lisp", r#"(include "lib/one.lisp") (= three (add one two))"#),
+                ("lib/one.lisp", r#"(= one 1) (include "lib/two.lisp")"#),
+                ("lib/two.lisp", "(= two (add one 1))"),
+            ],
+        );
+
+        assert!(matches!(
+            run(r#"(include "main.lisp") three"#, &mut fixture.options()),
+            Ok(Value::Int(3))
+        ));
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let fixture = Fixture::new(
+            "cycle",
+            &[("a.lisp", r#"(include "b.lisp")"#), ("b.lisp", r#"(= b 1) (include "a.lisp")"#)],
+        );
+        let mut options = fixture.options();
+
+        let err = run(r#"(include "a.lisp")"#, &mut options).err();
+        assert_eq!(
+            err,
+            Some(EvalError::Include(Box::new(IncludeError::Cycle(vec![
+                "a.lisp".to_owned(),
+                "b.lisp".to_owned(),
+                "a.lisp".to_owned(),
+            ]))))
+        );
+        assert_eq!(err.unwrap().to_string(), "Files include each other: a.lisp -> b.lisp -> a.lisp");
+
+        // The failed include doesn't count against the next program.
+        assert!(matches!(
+            run(r#"(include "b.lisp")"#, &mut options).err(),
+            Some(EvalError::Include(ref e)) if matches!(**e, IncludeError::Cycle(_))
+        ));
+    }
+
+    #[test]
+    fn limits_include_depth() {
+        let fixture = Fixture::new(
+            "depth",
+            &[
+                ("a.lisp", r#"(include "b.lisp")"#),
+                ("b.lisp", r#"(include "c.lisp")"#),
+                ("c.lisp", "3"),
+            ],
+        );
+        let mut options = fixture.options();
+        options.includes.as_mut().unwrap().max_depth = 2;
+
+        assert_eq!(
+            run(r#"(include "a.lisp")"#, &mut options).err(),
+            Some(EvalError::Include(Box::new(IncludeError::TooDeep {
+                path: "c.lisp".to_owned(),
+                max: 2,
+            })))
+        );
+        assert!(matches!(run(r#"(include "b.lisp")"#, &mut options), Ok(Value::Int(3))));
+    }
+
+    #[test]
+    fn errors_name_the_included_file() {
+        let fixture = Fixture::new("errors", &[("broken.lisp", "(add 1")]);
+        let mut options = fixture.options();
+
+        let err = run(r#"(include "missing.lisp")"#, &mut options).err().unwrap();
+        assert!(matches!(err, EvalError::Include(ref e) if matches!(**e, IncludeError::Read { .. })));
+        let message = err.to_string();
+        assert!(message.starts_with("Couldn't read included file `missing.lisp`: "), "{}", message);
+
+        let err = run(r#"(include "broken.lisp")"#, &mut options).err().unwrap();
+        assert!(matches!(err, EvalError::Include(ref e) if matches!(**e, IncludeError::Parse { .. })));
+        let message = err.to_string();
+        assert!(message.starts_with("Couldn't parse included file `broken.lisp`: "), "{}", message);
+    }
+
+    #[test]
+    fn refuses_to_include_when_disabled() {
+        // The file exists, but nothing is allowed to read it.
+        let _fixture = Fixture::new("disabled", &[("lib.lisp", "1")]);
+        let src = r#"(include "lib.lisp")"#;
+        let disabled = Some(EvalError::Include(Box::new(IncludeError::Disabled)));
+
+        assert_eq!(run(src, &mut EvalOptions::default()).err(), disabled);
+
+        let program = parse_program(src).unwrap();
+        assert_eq!(eval(&program[0], &mut corpus_env()).err(), disabled);
+
+        let unsupported = Some(EvalError::Include(Box::new(IncludeError::Unsupported)));
+        assert_eq!(compile_closure(&program[0]).eval(&mut corpus_env()).err(), unsupported);
+        let compiled = Interpreter::new().compile(&program);
+        assert_eq!(Vm::new(&compiled).run(&mut corpus_env()).err(), unsupported);
+    }
+
+    #[test]
+    fn include_is_only_special_with_a_path() {
+        let src = r#"(include "a b.lisp") (include x) ( include  "c.lisp" )"#;
+        let (program, symbols) =
+            parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        assert!(matches!(program[0], ::Ast::Include(ref path) if &**path == "a b.lisp"));
+        assert!(matches!(program[1], ::Ast::Call(..)));
+        assert!(matches!(program[2], ::Ast::Include(ref path) if &**path == "c.lisp"));
+
+        let printed = program
+            .iter()
+            .map(|form| to_source(form, &symbols).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(printed, [r#"(include "a b.lisp")"#, "(include x)", r#"(include "c.lisp")"#]);
+
+        let (_, coverage) = Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let spans = (0..coverage.len())
+            .map(|i| coverage.span(NodeId(i)))
+            .map(|span| &src[span.start..span.end])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [r#"(include "a b.lisp")"#, "(include x)", "include", "x", r#"( include  "c.lisp" )"#]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Parsing a program again after a small edit, without parsing all of it.
+//!
+//! Top-level forms don't affect how each other parse, so an
+//! `IncrementalParser` remembers where each one is in the source and only
+//! reparses the ones an edit touches, keeping the rest as they were and
+//! moving their spans along. Forms next to the edit with nothing between
+//! them and it are reparsed too, since `a` and `b` are two forms but `ab`
+//! is one. If the reparsed text doesn't split into whole forms, such as
+//! when a parenthesis has just been opened, or it fails to parse, the
+//! whole source is parsed again instead, so the result and any error are
+//! always the same as parsing the source from scratch. The one exception
+//! is identifier collisions, which are only looked for between names in
+//! the forms being parsed.
+
+use std::ops::Range;
+
+use coverage::Span;
+use tokens::{tokenize, TokenKind};
+use {parse_program_with, Ast, ParseError, ParseOptions};
+
+/// A program along with what's needed to reparse it quickly after an edit.
+pub struct IncrementalParser {
+    src: String,
+    program: Result<Vec<Ast<u64>>, ParseError>,
+    // Where each top-level form is, or `None` when the source didn't split
+    // into forms and the next edit has to parse it all.
+    spans: Option<Vec<Span>>,
+}
+
+impl IncrementalParser {
+    /// Parse `src` from scratch. Diagnostics are ignored.
+    pub fn new(src: &str) -> Self {
+        let mut parser = IncrementalParser {
+            src: src.to_owned(),
+            program: Ok(Vec::new()),
+            spans: None,
+        };
+        parser.parse_all();
+        parser
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// The program as of the last edit, or why it couldn't be parsed.
+    pub fn program(&self) -> Result<&[Ast<u64>], &ParseError> {
+        self.program.as_ref().map(|program| &program[..])
+    }
+
+    /// Replace `range` of the source with `replacement` and reparse it. Like
+    /// `String::replace_range`, this panics if `range` isn't on character
+    /// boundaries in the source.
+    pub fn edit(
+        &mut self,
+        range: Range<usize>,
+        replacement: &str,
+    ) -> Result<&[Ast<u64>], &ParseError> {
+        let mut spans = match self.spans.take() {
+            Some(spans) if self.program.is_ok() => spans,
+            _ => {
+                self.src.replace_range(range, replacement);
+                self.parse_all();
+                return self.program();
+            }
+        };
+
+        // The forms the edit touches, and then any next to those.
+        let mut first = spans.iter().take_while(|span| span.end < range.start).count();
+        let mut last = first + spans[first..]
+            .iter()
+            .take_while(|span| span.start <= range.end)
+            .count();
+        let mut start = spans.get(first).map_or(range.start, |span| span.start.min(range.start));
+        let mut end = match last {
+            0 => range.end,
+            _ => spans[last - 1].end.max(range.end),
+        };
+        while first > 0 && spans[first - 1].end == start {
+            first -= 1;
+            start = spans[first].start;
+        }
+        while last < spans.len() && spans[last].start == end {
+            end = spans[last].end;
+            last += 1;
+        }
+
+        let removed = range.end - range.start;
+        self.src.replace_range(range, replacement);
+        let end = end + replacement.len() - removed;
+
+        let text = &self.src[start..end];
+        let reparsed = split(text).and_then(|new_spans| {
+            let program = parse_program_with(text, &ParseOptions::default(), &mut |_| {});
+            match program {
+                Ok(program) if program.len() == new_spans.len() => Some((program, new_spans)),
+                _ => None,
+            }
+        });
+        let (forms, new_spans) = match reparsed {
+            Some(reparsed) => reparsed,
+            None => {
+                self.parse_all();
+                return self.program();
+            }
+        };
+
+        let added = new_spans.len();
+        let new_spans = new_spans.into_iter().map(|span| Span {
+            start: span.start + start,
+            end: span.end + start,
+        });
+        spans.splice(first..last, new_spans);
+        for span in &mut spans[first + added..] {
+            span.start = span.start + replacement.len() - removed;
+            span.end = span.end + replacement.len() - removed;
+        }
+        self.spans = Some(spans);
+
+        if let Ok(ref mut program) = self.program {
+            program.splice(first..last, forms);
+        }
+        self.program()
+    }
+
+    fn parse_all(&mut self) {
+        self.program = parse_program_with(&self.src, &ParseOptions::default(), &mut |_| {});
+        self.spans = split(&self.src);
+    }
+}
+
+// Where each top-level form of `src` is, going by its tokens, or `None` if
+// it doesn't split into whole forms. A quote or unquote belongs to the form
+// after it.
+fn split(src: &str) -> Option<Vec<Span>> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    let mut depth = 0;
+
+    for token in tokenize(src) {
+        match token.kind {
+            TokenKind::Whitespace => continue,
+            TokenKind::Error => return None,
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth == 0 => return None,
+            TokenKind::Close => depth -= 1,
+            _ => {}
+        }
+        let form_start = *start.get_or_insert(token.span.start);
+        let prefix = matches!(token.kind, TokenKind::Quote | TokenKind::Unquote);
+        if depth == 0 && !prefix {
+            spans.push(Span {
+                start: form_start,
+                end: token.span.end,
+            });
+            start = None;
+        }
+    }
+
+    if start.is_some() {
+        return None;
+    }
+    Some(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::{split, IncrementalParser};
+    use benches::{many_defines, same_ast, MANY_VARIABLES};
+    use {parse_program_with, ParseOptions};
+
+    fn same_as_from_scratch(parser: &IncrementalParser) {
+        let expected = parse_program_with(parser.src(), &ParseOptions::default(), &mut |_| {});
+        match (parser.program(), expected) {
+            (Ok(program), Ok(expected)) => {
+                assert_eq!(program.len(), expected.len(), "{:?}", parser.src());
+                for (a, b) in program.iter().zip(expected.iter()) {
+                    assert!(same_ast(a, b), "{:?}", parser.src());
+                }
+            }
+            (Err(error), Err(expected)) => assert_eq!(*error, expected),
+            (program, expected) => panic!(
+                "{:?} gave {:?} rather than {:?}",
+                parser.src(),
+                program.err(),
+                expected.err()
+            ),
+        }
+    }
+
+    #[test]
+    fn splits_into_top_level_forms() {
+        let src = "  (f (g)) 12a '(x ,y)\n\"s\" #f#f";
+        let forms = split(src)
+            .unwrap()
+            .iter()
+            .map(|span| &src[span.start..span.end])
+            .collect::<Vec<_>>();
+        assert_eq!(forms, ["(f (g))", "12", "a", "'(x ,y)", "\"s\"", "#f", "#f"]);
+
+        assert_eq!(split("(f"), None);
+        assert_eq!(split("f)"), None);
+        assert_eq!(split("'"), None);
+        assert_eq!(split("%"), None);
+    }
+
+    #[test]
+    fn edits_only_reparse_what_they_touch() {
+        let mut parser = IncrementalParser::new("(= a 1) (= b 2)\n(= c 3)");
+        parser.edit(13..14, "20").unwrap();
+        assert_eq!(parser.src(), "(= a 1) (= b 20)\n(= c 3)");
+        same_as_from_scratch(&parser);
+
+        // Joining two names makes one form out of two.
+        let mut parser = IncrementalParser::new("a b c");
+        parser.edit(1..2, "").unwrap();
+        assert_eq!(parser.program().unwrap().len(), 2);
+        same_as_from_scratch(&parser);
+
+        // An unclosed parenthesis fails like it does from scratch, and
+        // closing it again makes the program whole.
+        let mut parser = IncrementalParser::new("(add 1 2) (add 3 4)");
+        assert!(parser.edit(8..9, "").is_err());
+        same_as_from_scratch(&parser);
+        parser.edit(8..8, ")").unwrap();
+        same_as_from_scratch(&parser);
+    }
+
+    #[test]
+    fn random_edits_match_parsing_from_scratch() {
+        const REPLACEMENTS: &[&str] = &[
+            "", "", " ", "\n", "a", "xy", "7", "42", "(", ")", "(add 1 2)", "'", ",", "\"", "\"s\"",
+            "#f", ":k", "(= v 1)", "(\\(q) q)", "%",
+        ];
+        let src = format!("{}{}", MANY_VARIABLES, many_defines(20));
+        let mut parser = IncrementalParser::new(&src);
+        let mut rng = StdRng::seed_from_u64(194);
+
+        for _ in 0..300 {
+            let len = parser.src().len();
+            let start = rng.gen_range(0..=len);
+            let end = (start + rng.gen_range(0..4)).min(len);
+            let replacement = REPLACEMENTS[rng.gen_range(0..REPLACEMENTS.len())];
+            let removed = parser.src()[start..end].to_owned();
+            let failed = parser.edit(start..end, replacement).is_err();
+            same_as_from_scratch(&parser);
+
+            // Edits that break the program are undone, so that every edit is
+            // made to one that parses, as most would be in an editor.
+            if failed {
+                parser.edit(start..start + replacement.len(), &removed).unwrap();
+                same_as_from_scratch(&parser);
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Counts of the work `eval` does, for tests that pin them so that a
+//! change that makes evaluation do more can't hide behind a fast machine.
+//!
+//! This is built for the crate's own tests and with the `bench-instrument`
+//! feature, and costs nothing otherwise. The counts are kept per thread, so
+//! tests running in parallel don't disturb each other's numbers.
+//! Allocations are only counted once `CountingAlloc` is the global
+//! allocator, which the crate's tests make it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// How often each kind of work was done, either since the thread started
+/// or, from `measure`, while running something.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CountersSnapshot {
+    /// Variables read, whether they were bound or not.
+    pub lookups: u64,
+    /// Scopes made for a call, an include or a native that evaluates code,
+    /// on top of the globals.
+    pub scopes: u64,
+    /// Calls to `Value::clone`, which copies the value and adds a reference
+    /// to anything it shares.
+    pub value_clones: u64,
+    /// Blocks allocated on the heap.
+    pub allocations: u64,
+}
+
+impl CountersSnapshot {
+    const ZERO: CountersSnapshot = CountersSnapshot {
+        lookups: 0,
+        scopes: 0,
+        value_clones: 0,
+        allocations: 0,
+    };
+
+    /// Whether each count is within `percent` percent of the one in
+    /// `expected`.
+    pub fn is_close_to(&self, expected: &CountersSnapshot, percent: u64) -> bool {
+        let close = |got: u64, expected: u64| got.abs_diff(expected) * 100 <= expected * percent;
+        close(self.lookups, expected.lookups)
+            && close(self.scopes, expected.scopes)
+            && close(self.value_clones, expected.value_clones)
+            && close(self.allocations, expected.allocations)
+    }
+
+    fn since(&self, before: &CountersSnapshot) -> CountersSnapshot {
+        CountersSnapshot {
+            lookups: self.lookups - before.lookups,
+            scopes: self.scopes - before.scopes,
+            value_clones: self.value_clones - before.value_clones,
+            allocations: self.allocations - before.allocations,
+        }
+    }
+}
+
+thread_local! {
+    static COUNTERS: Cell<CountersSnapshot> = const { Cell::new(CountersSnapshot::ZERO) };
+}
+
+/// Everything counted on this thread so far.
+pub fn snapshot() -> CountersSnapshot {
+    COUNTERS.with(Cell::get)
+}
+
+/// Runs `f`, along with what was counted while it ran.
+pub fn measure<T, F: FnOnce() -> T>(f: F) -> (T, CountersSnapshot) {
+    let before = snapshot();
+    let out = f();
+    (out, snapshot().since(&before))
+}
+
+pub(crate) fn record<F: FnOnce(&mut CountersSnapshot)>(f: F) {
+    // Allocations can happen while the thread is being torn down, when
+    // there's nowhere left to count them.
+    let _ = COUNTERS.try_with(|counters| {
+        let mut snapshot = counters.get();
+        f(&mut snapshot);
+        counters.set(snapshot);
+    });
+}
+
+/// The system allocator, counting each allocation. Install it with
+/// `#[global_allocator]` to have `allocations` counted.
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(|counters| counters.allocations += 1);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+
+    use benches::{ignore, MANY_VARIABLES, NESTED_FUNC, REAL_CODE};
+    use prelude::{add, eq, if_};
+    use {eval, hash_string, parse_program, parse_program_with, IntMap, ParseOptions, Value};
+
+    use super::{measure, CountersSnapshot};
+
+    // These pin what one iteration of the benchmark with the same name
+    // does. If a change brings them down, lower them to match. If it
+    // raises them, make sure it's worth it first.
+    const TOLERANCE: u64 = 5;
+
+    fn check(name: &str, got: CountersSnapshot, expected: CountersSnapshot) {
+        assert!(got.is_close_to(&expected, TOLERANCE), "{}: {:?}", name, got);
+    }
+
+    #[test]
+    fn run_real_code() {
+        let mut env = IntMap::default();
+        env.insert(hash_string("eq"), Cow::Owned(Value::InbuiltFunc(eq)));
+        env.insert(hash_string("add"), Cow::Owned(Value::InbuiltFunc(add)));
+        env.insert(hash_string("if"), Cow::Owned(Value::ReentrantFunc(if_)));
+        // REAL_CODE has a duplicate parameter, which we don't need to hear
+        // about.
+        let program = parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+        let ((), counters) = measure(|| {
+            let mut env = env.clone();
+            for line in &program {
+                let _ = eval(line, &mut env);
+            }
+        });
+        check(
+            "run_real_code",
+            counters,
+            CountersSnapshot {
+                lookups: 88,
+                scopes: 28,
+                value_clones: 91,
+                allocations: 103,
+            },
+        );
+    }
+
+    #[test]
+    fn run_nested_func() {
+        let program = parse_program(NESTED_FUNC).unwrap();
+        let mut env = IntMap::default();
+
+        let ((), counters) = measure(|| {
+            let _ = eval(&program[0], &mut env);
+        });
+        check(
+            "run_nested_func",
+            counters,
+            CountersSnapshot {
+                lookups: 11,
+                scopes: 11,
+                value_clones: 32,
+                allocations: 12,
+            },
+        );
+    }
+
+    #[test]
+    fn run_many_variables() {
+        let program = parse_program(MANY_VARIABLES).unwrap();
+        let mut env = IntMap::default();
+        env.insert(hash_string("ignore"), Cow::Owned(Value::InbuiltFunc(ignore)));
+
+        let ((), counters) = measure(|| {
+            let _ = eval(&program[0], &mut env);
+        });
+        check(
+            "run_many_variables",
+            counters,
+            CountersSnapshot {
+                lookups: 377,
+                scopes: 1,
+                value_clones: 377,
+                allocations: 105,
+            },
+        );
+    }
+}
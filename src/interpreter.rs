@@ -0,0 +1,493 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+#[cfg(feature = "parse")]
+use std::mem;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use bytecode::{self, CompiledProgram};
+use convert::HostFunction;
+use macros::Gensyms;
+use rng::Rng;
+#[cfg(feature = "parse")]
+use program::Program;
+#[cfg(feature = "parse")]
+use {output, report};
+#[cfg(feature = "parse")]
+use snapshot::{EnvDiff, EnvSnapshot};
+#[cfg(feature = "parse")]
+use {ParseError, SymbolTable};
+use {Ast, EvalError, EvalOptions, ParseOptions, TruthPolicy, Value};
+
+/// A function implemented in Rust. Returning an error aborts the program
+/// the same way as any other evaluation error.
+pub type NativeFn<Id> = fn(&[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>>;
+
+/// A native function that can evaluate code, in the environment and under
+/// the limits of the call to it. Only `eval` and `eval_with` can call
+/// these; the other backends fail with `EvalError::Unsupported`.
+pub type ReentrantFn<Id> =
+    fn(&mut dyn Evaluator<Id>, &[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>>;
+
+/// A native function that draws on the interpreter's `Rng`, such as the
+/// prelude's `random`. Registering one with `Interpreter::register_rng`
+/// binds it to the interpreter's generator, and each clone of the
+/// interpreter binds it to a generator of its own.
+pub type RngFn<Id> = fn(&Rng, &[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>>;
+
+type HostClosure<Id> =
+    dyn Fn(&[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>> + Send + Sync;
+
+/// A Rust closure that programs can call, usually made by
+/// `Interpreter::register_fn`. Unlike a `NativeFn` it can hold state, but
+/// it has to be `Send` and `Sync` so that `sync` values can share it.
+/// Copies share the closure, and are equal to each other.
+pub struct HostFn<Id> {
+    // Boxed again so that the `Arc` is a thin pointer and values stay small.
+    func: Arc<Box<HostClosure<Id>>>,
+}
+
+impl<Id> HostFn<Id> {
+    pub fn new<F>(func: F) -> Self
+    where
+        F: Fn(&[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>> + Send + Sync + 'static,
+    {
+        HostFn {
+            func: Arc::new(Box::new(func)),
+        }
+    }
+
+    pub fn call(&self, args: &[&Value<Id>]) -> Result<Value<Id>, EvalError<Id>> {
+        (self.func)(args)
+    }
+
+    // Where the closure is, which is the same for every copy.
+    pub(crate) fn addr(&self) -> *const () {
+        Arc::as_ptr(&self.func) as *const ()
+    }
+}
+
+impl<Id> Clone for HostFn<Id> {
+    fn clone(&self) -> Self {
+        HostFn {
+            func: self.func.clone(),
+        }
+    }
+}
+
+/// What a `ReentrantFn` is given to evaluate code with.
+pub trait Evaluator<Id> {
+    /// Evaluate `ast` in the caller's environment, so anything it defines
+    /// stays defined after the call.
+    fn eval(&mut self, ast: &Ast<Id>) -> Result<Value<Id>, EvalError<Id>>;
+    /// Call `func` with `args`.
+    fn call(&mut self, func: &Value<Id>, args: &[Value<Id>]) -> Result<Value<Id>, EvalError<Id>>;
+    /// Which values the caller treats as true, which a native that branches
+    /// on a value should go by rather than `Value::is_truthy`.
+    fn truth(&self) -> TruthPolicy<Id> {
+        TruthPolicy::SchemeLike
+    }
+}
+
+/// The `Evaluator` that the backends other than `eval` and `eval_with` give
+/// to reentrant natives. They can't evaluate code on a native's behalf, so
+/// a native that only asks which values are true, like `if`, works, and
+/// the rest fail with `EvalError::Unsupported`.
+pub(crate) struct Bare<Id> {
+    pub(crate) truth: TruthPolicy<Id>,
+}
+
+impl<Id> Evaluator<Id> for Bare<Id> {
+    fn eval(&mut self, _: &Ast<Id>) -> Result<Value<Id>, EvalError<Id>> {
+        Err(EvalError::Unsupported)
+    }
+
+    fn call(&mut self, _: &Value<Id>, _: &[Value<Id>]) -> Result<Value<Id>, EvalError<Id>> {
+        Err(EvalError::Unsupported)
+    }
+
+    fn truth(&self) -> TruthPolicy<Id> {
+        self.truth
+    }
+}
+
+/// Sort the arguments a native function was given into one per name in
+/// `names`, for natives that take keyword arguments. Arguments are given
+/// positionally up to the first keyword, and after that as `:name value`
+/// pairs; names that weren't given anything are `None`.
+pub fn keyword_args<'a, Id: Clone + PartialEq>(
+    args: &[&'a Value<Id>],
+    names: &[Id],
+) -> Result<Vec<Option<&'a Value<Id>>>, EvalError<Id>> {
+    let positional = args
+        .iter()
+        .position(|arg| arg.as_keyword().is_some())
+        .unwrap_or(args.len());
+    let names = names.iter().map(Some).collect::<Vec<_>>();
+    ::line_up(args.to_vec(), positional, &names, |arg| arg.as_keyword())
+}
+
+/// The native functions that programs can call, and the ways of running
+/// programs against them.
+///
+/// Cloning one is cheap, since clones share what's registered and the
+/// baseline until one of them changes it, when it gets a copy of its own.
+/// So a host can configure an interpreter once and clone it for each
+/// request, and nothing registered in a clone shows up in the original.
+/// A clone's `rng` and `gensyms` are its own, and start again from the
+/// seed and from 0, so nothing a program does with one clone's changes
+/// the numbers or symbols another's programs get.
+pub struct Interpreter<Id> {
+    natives: Rc<HashMap<Id, NativeFn<Id>>>,
+    reentrant: Rc<HashMap<Id, ReentrantFn<Id>>>,
+    // Including the natives in `rng_natives`, bound to `rng`.
+    hosts: Rc<HashMap<Id, HostFn<Id>>>,
+    rng_natives: Rc<HashMap<Id, RngFn<Id>>>,
+    // What `reset` puts an environment back to, if not what `env` makes.
+    baseline: Option<Rc<HashMap<Id, Value<Id>>>>,
+    truth: TruthPolicy<Id>,
+    rng: Rng,
+    // What `reset` starts `rng` again from.
+    rng_seed: u64,
+    gensyms: Gensyms,
+}
+
+// Written out so that the clone's natives in `rng_natives` are bound to a
+// generator of its own.
+impl<Id: Clone + Eq + Hash + 'static> Clone for Interpreter<Id> {
+    fn clone(&self) -> Self {
+        let rng = Rng::new(self.rng_seed);
+        let mut hosts = self.hosts.clone();
+        if !self.rng_natives.is_empty() {
+            let hosts = Rc::make_mut(&mut hosts);
+            for (name, &func) in self.rng_natives.iter() {
+                hosts.insert(name.clone(), bind_rng(&rng, func));
+            }
+        }
+
+        Interpreter {
+            natives: self.natives.clone(),
+            reentrant: self.reentrant.clone(),
+            hosts,
+            rng_natives: self.rng_natives.clone(),
+            baseline: self.baseline.clone(),
+            truth: self.truth,
+            rng,
+            rng_seed: self.rng_seed,
+            gensyms: Gensyms::new(),
+        }
+    }
+}
+
+fn bind_rng<Id: 'static>(rng: &Rng, func: RngFn<Id>) -> HostFn<Id> {
+    let rng = rng.clone();
+    HostFn::new(move |args| func(&rng, args))
+}
+
+impl<Id: Clone + Debug + Eq + Hash> Interpreter<Id> {
+    pub fn new() -> Self {
+        Interpreter {
+            natives: Rc::new(HashMap::new()),
+            reentrant: Rc::new(HashMap::new()),
+            hosts: Rc::new(HashMap::new()),
+            rng_natives: Rc::new(HashMap::new()),
+            baseline: None,
+            truth: TruthPolicy::SchemeLike,
+            rng: Rng::new(0),
+            rng_seed: 0,
+            gensyms: Gensyms::new(),
+        }
+    }
+
+    pub fn register(&mut self, name: Id, func: NativeFn<Id>) -> &mut Self {
+        Rc::make_mut(&mut self.natives).insert(name, func);
+        self
+    }
+
+    pub fn register_reentrant(&mut self, name: Id, func: ReentrantFn<Id>) -> &mut Self {
+        Rc::make_mut(&mut self.reentrant).insert(name, func);
+        self
+    }
+
+    /// Register a Rust closure of up to four arguments, converting them
+    /// with `FromValue` and its result with `IntoValue`, so that
+    /// `|a: u64, b: u64| a * b` can be called as `(name 2 3)`. Calling it
+    /// with the wrong number of arguments fails with
+    /// `EvalError::ArgumentCount`, and with an argument that doesn't
+    /// convert with `EvalError::WrongType`.
+    pub fn register_fn<Args, F: HostFunction<Id, Args>>(&mut self, name: Id, func: F) -> &mut Self {
+        self.register_host(name, func.into_host())
+    }
+
+    /// Register a closure that takes its arguments as they are, for when
+    /// `register_fn` can't convert them, or it has errors of its own.
+    pub fn register_host(&mut self, name: Id, func: HostFn<Id>) -> &mut Self {
+        if self.rng_natives.contains_key(&name) {
+            Rc::make_mut(&mut self.rng_natives).remove(&name);
+        }
+        Rc::make_mut(&mut self.hosts).insert(name, func);
+        self
+    }
+
+    /// Register a native that's given this interpreter's `rng` along with
+    /// its arguments, as a host closure.
+    pub fn register_rng(&mut self, name: Id, func: RngFn<Id>) -> &mut Self
+    where
+        Id: 'static,
+    {
+        self.register_host(name.clone(), bind_rng(&self.rng, func));
+        Rc::make_mut(&mut self.rng_natives).insert(name, func);
+        self
+    }
+
+    /// Start the generator that `rng` gives from `seed`, now and whenever
+    /// `reset` is called. It starts from 0 until this is called.
+    pub fn with_rng_seed(&mut self, seed: u64) -> &mut Self {
+        self.rng_seed = seed;
+        self.rng.seed(seed);
+        self
+    }
+
+    /// The generator behind the prelude's `random`, and whatever else is
+    /// registered with `register_rng`.
+    pub fn rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    /// What macros expanded with `Macros::with_gensyms` count their
+    /// `gensym` symbols with, which `reset` starts again from 0.
+    pub fn gensyms(&self) -> &Gensyms {
+        &self.gensyms
+    }
+
+    /// Make `policy` decide which values conditionals treat as true, in
+    /// programs run with `options` or the `eval_str` methods. It can't be
+    /// changed while they run.
+    pub fn set_truth(&mut self, policy: TruthPolicy<Id>) -> &mut Self {
+        self.truth = policy;
+        self
+    }
+
+    pub fn truth(&self) -> TruthPolicy<Id> {
+        self.truth
+    }
+
+    /// Options for `eval_with` that evaluate under this interpreter's
+    /// `TruthPolicy`, and are otherwise the defaults.
+    pub fn options(&self) -> EvalOptions<Id> {
+        EvalOptions {
+            truth: self.truth,
+            ..EvalOptions::default()
+        }
+    }
+
+    pub fn natives(&self) -> &HashMap<Id, NativeFn<Id>> {
+        &self.natives
+    }
+
+    pub fn reentrant(&self) -> &HashMap<Id, ReentrantFn<Id>> {
+        &self.reentrant
+    }
+
+    pub fn hosts(&self) -> &HashMap<Id, HostFn<Id>> {
+        &self.hosts
+    }
+
+    /// A global namespace containing every registered native function.
+    pub fn env<'a, S: BuildHasher + Default>(&self) -> HashMap<Id, Cow<'a, Value<Id>>, S> {
+        self.registered().map(|(name, value)| (name, Cow::Owned(value))).collect()
+    }
+
+    fn registered(&self) -> impl Iterator<Item = (Id, Value<Id>)> + '_ {
+        let natives = self
+            .natives
+            .iter()
+            .map(|(name, &func)| (name.clone(), Value::InbuiltFunc(func)));
+        let reentrant = self
+            .reentrant
+            .iter()
+            .map(|(name, &func)| (name.clone(), Value::ReentrantFunc(func)));
+        let hosts = self
+            .hosts
+            .iter()
+            .map(|(name, func)| (name.clone(), Value::HostFunc(func.clone())));
+        natives.chain(reentrant).chain(hosts)
+    }
+
+    /// Make what's bound in `env` now what `reset` puts environments back
+    /// to, such as after defining the stdlib or a host's own functions in
+    /// it. Until this is called, `reset` gives what `env` does.
+    ///
+    /// A host closure bound under a name that one is registered as here is
+    /// left for `reset` to bind this interpreter's own, so that an `env`
+    /// made by another interpreter, such as `prelude::env`, doesn't bring
+    /// in closures over that one's state, like its `rng`.
+    pub fn mark_baseline<'a, S: BuildHasher>(&mut self, env: &HashMap<Id, Cow<'a, Value<Id>>, S>) {
+        let baseline = env
+            .iter()
+            .filter(|&(name, value)| {
+                !(matches!(**value, Value::HostFunc(_)) && self.hosts.contains_key(name))
+            })
+            .map(|(name, value)| (name.clone(), (**value).clone()));
+        self.baseline = Some(Rc::new(baseline.collect()));
+    }
+
+    /// Put `env` back the way it was at the baseline, dropping everything
+    /// bound since and rebinding anything that was redefined, and give
+    /// back the memory it grew into. Natives registered after the baseline
+    /// was marked are bound too. The natives are the ones registered, so a
+    /// host closure keeps whatever state it holds, but `rng` starts again
+    /// from its seed and `gensyms` from 0. What `EvalOptions` remembers is
+    /// reset with `EvalOptions::reset`.
+    pub fn reset<'a, S: BuildHasher>(&self, env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>) {
+        self.rng.seed(self.rng_seed);
+        self.gensyms.reset();
+        env.clear();
+        env.extend(self.registered().map(|(name, value)| (name, Cow::Owned(value))));
+        if let Some(ref baseline) = self.baseline {
+            let baseline = baseline.iter();
+            env.extend(baseline.map(|(name, value)| (name.clone(), Cow::Owned(value.clone()))));
+        }
+        env.shrink_to_fit();
+    }
+
+    /// Compile `program` to bytecode for the `bytecode::Vm`.
+    ///
+    /// Natives that the program never rebinds are called directly rather
+    /// than looked up, so the compiled program must be run in an
+    /// environment created by `env`.
+    pub fn compile(&self, program: &[Ast<Id>]) -> CompiledProgram<Id> {
+        bytecode::compile(program, &self.natives, self.truth)
+    }
+}
+
+impl Interpreter<u64> {
+    /// Options for parsing programs that will run in an environment created
+    /// by `env`, so that redefining any of the natives is reported.
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            builtins: self
+                .natives
+                .keys()
+                .chain(self.reentrant.keys())
+                .chain(self.hosts.keys())
+                .cloned()
+                .collect(),
+            ..ParseOptions::default()
+        }
+    }
+
+    /// Parse `src` into a `Program` that can be run any number of times in
+    /// an environment created by `env`. Diagnostics are dropped.
+    #[cfg(feature = "parse")]
+    pub fn prepare(&self, src: &str) -> Result<Program, ParseError> {
+        let (forms, symbols) =
+            ::parse_program_with_symbols(src, &self.parse_options(), &mut |_| {})?;
+        Ok(Program::new(forms, symbols))
+    }
+
+    /// Parse and run `src` in `env`, giving the value of its last form, how
+    /// `env` changed, and the names of the identifiers in `src`, which are
+    /// every name that can have changed. What a program that fails defines
+    /// before it fails is left in `env`, as with `eval`.
+    #[cfg(feature = "parse")]
+    pub fn eval_str_diffed<'a, S: BuildHasher + Default>(
+        &self,
+        src: &str,
+        env: &mut HashMap<u64, Cow<'a, Value<u64>>, S>,
+    ) -> Result<(Value<u64>, EnvDiff, SymbolTable), report::Error> {
+        let before = EnvSnapshot::new(env);
+        let (value, symbols) = self.eval_str(src, env)?;
+        let diff = before.diff(&EnvSnapshot::new(env));
+        Ok((value, diff, symbols))
+    }
+
+    /// Parse and run `src` in `env`, giving the value of its last form and
+    /// what it printed, which doesn't go to the `output` sink. What was
+    /// printed before an error is given too.
+    #[cfg(feature = "parse")]
+    pub fn eval_str_captured<'a, S: BuildHasher + Default>(
+        &self,
+        src: &str,
+        env: &mut HashMap<u64, Cow<'a, Value<u64>>, S>,
+    ) -> (Result<Value<u64>, report::Error>, String) {
+        output::capture(|| self.eval_str(src, env).map(|(value, _)| value))
+    }
+
+    #[cfg(feature = "parse")]
+    fn eval_str<'a, S: BuildHasher + Default>(
+        &self,
+        src: &str,
+        env: &mut HashMap<u64, Cow<'a, Value<u64>>, S>,
+    ) -> Result<(Value<u64>, SymbolTable), report::Error> {
+        let program = self.prepare(src)?;
+
+        // Values can borrow from the program, which is gone once this
+        // returns, so they're copied out of the environment it ran in.
+        let mut running: HashMap<_, Cow<_>, S> = mem::take(env);
+        let result = program.run_with(&mut running, &[], &mut self.options());
+        let after = running.into_iter().map(|(name, value)| (name, Cow::Owned(value.into_owned())));
+        env.extend(after);
+
+        let value = result.map_err(|error| report::Error::Eval {
+            error,
+            span: None,
+            suggestion: None,
+        })?;
+        Ok((value, program.symbols().clone()))
+    }
+}
+
+impl<Id: Clone + Debug + Eq + Hash> Default for Interpreter<Id> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use {eval, hash_string, parse_program, prelude, IntMap, Value};
+
+    #[test]
+    fn clones_share_until_they_change() {
+        let mut original = prelude::interpreter();
+        let mut env = prelude::env();
+        original.mark_baseline(&env);
+
+        let mut copy = original.clone();
+        assert_eq!(Rc::strong_count(&original.natives), 2);
+        assert_eq!(Rc::strong_count(&original.rng_natives), 2);
+        // Except for the hosts, which include `random` bound to each one's
+        // own generator.
+        assert!(!Rc::ptr_eq(&original.hosts, &copy.hosts));
+        assert!(Rc::ptr_eq(original.baseline.as_ref().unwrap(), copy.baseline.as_ref().unwrap()));
+
+        copy.register(hash_string("plus"), prelude::add)
+            .register_fn(hash_string("triple"), |n: u64| n * 3);
+        assert!(!Rc::ptr_eq(&original.natives, &copy.natives));
+        assert!(Rc::ptr_eq(&original.reentrant, &copy.reentrant));
+        assert!(!original.natives.contains_key(&hash_string("plus")));
+        assert!(!original.hosts.contains_key(&hash_string("triple")));
+
+        // What's defined in the clone's environment stays there, even once
+        // it's the clone's baseline.
+        let program = parse_program("(= x (triple (plus 1 1)))").unwrap();
+        let mut copied: IntMap<_> = IntMap::default();
+        copy.reset(&mut copied);
+        assert!(eval(&program[0], &mut copied).unwrap().into_owned() == Value::Int(6));
+        copy.mark_baseline(&copied);
+        assert!(!Rc::ptr_eq(original.baseline.as_ref().unwrap(), copy.baseline.as_ref().unwrap()));
+
+        original.reset(&mut env);
+        assert!(!env.contains_key(&hash_string("x")));
+        assert!(env.contains_key(&hash_string("not")));
+        copy.reset(&mut copied);
+        assert!(*copied[&hash_string("x")] == Value::Int(6));
+    }
+}
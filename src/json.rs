@@ -0,0 +1,172 @@
+//! Conversion between values and JSON, for hosts that pass data in and out
+//! of programs as JSON.
+//!
+//! The only data the language has are integers, strings, `False`, `Void`,
+//! `Nil` and lists of them, so that's all that can be converted. Integers
+//! map to JSON numbers, strings to strings, `False` to `false`, `Nil` to
+//! `null` and lists to arrays. Multiple values become arrays too, and come
+//! back as lists. JSON `true` becomes `Void`, since that's what `eq`
+//! returns for true, and `Void` becomes `null` as JSON has nothing closer,
+//! which means `true` comes back as `null`. Objects,
+//! numbers that aren't unsigned integers, functions, keywords, symbols and
+//! bytes have no counterpart, so they're rejected, and so are integers too
+//! big for a `u64`, which JSON numbers don't go up to.
+
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+use serde_json::{self, Number};
+
+use Value;
+
+/// Why a value couldn't be converted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonError {
+    /// Functions aren't data, and nor are thunks.
+    Function,
+    /// Keywords are only known by the hash of their name.
+    Keyword,
+    /// Neither are symbols.
+    Symbol,
+    /// A `BigInt` too big to be a JSON number.
+    TooBig,
+    /// JSON strings are text, and bytes needn't be.
+    Bytes,
+    /// A box is a place to keep a value rather than a value.
+    Box,
+    /// Only the host knows what's in a foreign value.
+    Foreign,
+    /// A JSON value of a kind the language doesn't have, such as an object
+    /// or a negative number. The string is the kind that was found.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Function => write!(f, "Functions can't be converted to JSON"),
+            JsonError::Keyword => write!(f, "Keywords can't be converted to JSON"),
+            JsonError::Symbol => write!(f, "Symbols can't be converted to JSON"),
+            JsonError::TooBig => write!(f, "Integers over 64 bits can't be converted to JSON"),
+            JsonError::Bytes => write!(f, "Bytes can't be converted to JSON"),
+            JsonError::Box => write!(f, "Boxes can't be converted to JSON"),
+            JsonError::Foreign => write!(f, "Foreign values can't be converted to JSON"),
+            JsonError::Unsupported(kind) => write!(f, "JSON {} can't be converted to a value", kind),
+        }
+    }
+}
+
+impl error::Error for JsonError {}
+
+/// Convert a value for the host, failing if it's a function, thunk, box,
+/// foreign value, keyword, symbol or bytes, or a list with one in it.
+pub fn value_to_json<Id>(value: &Value<Id>) -> Result<serde_json::Value, JsonError> {
+    match *value {
+        Value::Void | Value::Nil => Ok(serde_json::Value::Null),
+        Value::False => Ok(serde_json::Value::Bool(false)),
+        Value::Int(i) => Ok(serde_json::Value::Number(Number::from(i))),
+        Value::BigInt(ref big) => match big.to_u64() {
+            Some(i) => Ok(serde_json::Value::Number(Number::from(i))),
+            None => Err(JsonError::TooBig),
+        },
+        Value::Function(_)
+        | Value::InbuiltFunc(_)
+        | Value::ReentrantFunc(_)
+        | Value::HostFunc(_)
+        | Value::Partial(_)
+        | Value::Thunk(_) => Err(JsonError::Function),
+        Value::Keyword(_) => Err(JsonError::Keyword),
+        Value::Symbol(_) => Err(JsonError::Symbol),
+        Value::Str(ref text) => Ok(serde_json::Value::String(text.to_string())),
+        Value::Bytes(_) => Err(JsonError::Bytes),
+        Value::Box(_) => Err(JsonError::Box),
+        Value::Foreign(_) => Err(JsonError::Foreign),
+        Value::List(ref items) | Value::Values(ref items) => items
+            .iter()
+            .map(value_to_json)
+            .collect::<Result<_, _>>()
+            .map(serde_json::Value::Array),
+    }
+}
+
+/// Convert JSON from the host, failing if the language has nothing to
+/// represent it with.
+pub fn json_to_value<Id>(json: &serde_json::Value) -> Result<Value<Id>, JsonError> {
+    match *json {
+        serde_json::Value::Null => Ok(Value::Nil),
+        serde_json::Value::Bool(true) => Ok(Value::Void),
+        serde_json::Value::Bool(false) => Ok(Value::False),
+        serde_json::Value::Number(ref n) => match n.as_u64() {
+            Some(i) => Ok(Value::Int(i)),
+            None if n.is_i64() => Err(JsonError::Unsupported("negative numbers")),
+            None => Err(JsonError::Unsupported("floating point numbers")),
+        },
+        serde_json::Value::String(ref text) => Ok(Value::Str(Rc::new(text.clone()))),
+        serde_json::Value::Array(ref items) => items
+            .iter()
+            .map(json_to_value)
+            .collect::<Result<_, _>>()
+            .map(|items| Value::List(Rc::new(items))),
+        serde_json::Value::Object(_) => Err(JsonError::Unsupported("objects")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use serde_json;
+
+    use benches::corpus_env;
+    use {BigInt, Lambda, Value};
+
+    use super::{json_to_value, value_to_json, JsonError};
+
+    #[test]
+    fn data_round_trips() {
+        for json in &[
+            "null",
+            "false",
+            "0",
+            "18446744073709551615",
+            r#""a \"quoted\" {string}""#,
+            r#"[1, [], [null, [false, "x"]]]"#,
+        ] {
+            let json: serde_json::Value = serde_json::from_str(json).unwrap();
+            let value = json_to_value::<u64>(&json).unwrap();
+            assert_eq!(value_to_json(&value), Ok(json));
+        }
+    }
+
+    #[test]
+    fn lossy_and_unsupported_values() {
+        let convert = |json| json_to_value::<u64>(&serde_json::from_str(json).unwrap());
+
+        assert!(convert("true") == Ok(Value::Void));
+        assert_eq!(value_to_json(&convert("true").unwrap()), Ok(serde_json::Value::Null));
+
+        assert!(convert("-1") == Err(JsonError::Unsupported("negative numbers")));
+        assert!(convert("1.5") == Err(JsonError::Unsupported("floating point numbers")));
+        assert!(convert("1.0") == Err(JsonError::Unsupported("floating point numbers")));
+        assert!(convert("[1, [-2]]") == Err(JsonError::Unsupported("negative numbers")));
+        assert!(convert(r#"{"a": 1}"#) == Err(JsonError::Unsupported("objects")));
+
+        let lambda = Value::Function(Rc::new(Lambda::<u64>::new(vec![], vec![])));
+        assert_eq!(value_to_json(&lambda), Err(JsonError::Function));
+        let list = Value::List(Rc::new(vec![Value::Int(1), lambda]));
+        assert_eq!(value_to_json(&list), Err(JsonError::Function));
+        assert_eq!(value_to_json(&Value::Keyword(1)), Err(JsonError::Keyword));
+        assert_eq!(value_to_json(&Value::Symbol(1)), Err(JsonError::Symbol));
+        let big = BigInt::from_decimal("18446744073709551616").unwrap();
+        assert_eq!(value_to_json(&Value::<u64>::BigInt(Rc::new(big))), Err(JsonError::TooBig));
+        let bytes = Value::<u64>::Bytes(Rc::new(b"text".to_vec()));
+        assert_eq!(value_to_json(&bytes), Err(JsonError::Bytes));
+        let values = Value::Values(Rc::new(vec![Value::<u64>::Int(1), Value::Void]));
+        assert_eq!(value_to_json(&values), Ok(serde_json::from_str("[1, null]").unwrap()));
+        assert!(convert("null") == Ok(Value::Nil));
+        for native in corpus_env().values() {
+            assert_eq!(value_to_json(native), Err(JsonError::Function));
+        }
+    }
+}
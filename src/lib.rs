@@ -1,478 +1,6180 @@
-#![cfg_attr(test, feature(test))]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "parse")]
 #[macro_use]
 extern crate combine;
+#[cfg(not(feature = "std"))]
+extern crate hashbrown;
+#[cfg(feature = "std")]
 extern crate intmap;
+#[cfg(feature = "parse")]
+extern crate stacker;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(any(all(test, feature = "parse"), feature = "testing"))]
+extern crate rand;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(all(test, target_arch = "wasm32"))]
+extern crate wasm_bindgen_test;
+
+// Without std, `std::` paths resolve to this instead, which has what the
+// crate uses from `core` and `alloc`.
+#[cfg(not(feature = "std"))]
+#[path = "no_std.rs"]
+mod std;
+
+// Without std there's nowhere to print the warnings that evaluation gives,
+// so they're dropped.
+#[cfg(not(feature = "std"))]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+// Counts an event in `instrument`, when it's built.
+macro_rules! instrument {
+    ($counter:ident) => {{
+        #[cfg(any(all(test, feature = "std"), feature = "bench-instrument"))]
+        ::instrument::record(|counters| counters.$counter += 1);
+    }};
+}
 
+use std::any::Any;
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::cell::{OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt::{self, Debug};
 use std::hash::{BuildHasher, Hash, Hasher};
+use std::iter;
+use std::mem;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
 use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use coverage::Span;
+use memo::{Memo, MemoKey, Recall};
+
+pub mod analysis;
+pub mod bigint;
+pub mod binary;
+pub mod bytecode;
+pub mod closure;
+#[cfg(feature = "parse")]
+pub mod conformance;
+pub mod convert;
+pub mod coverage;
+pub mod debugger;
+pub mod dot;
+pub mod include;
+#[cfg(feature = "parse")]
+pub mod incremental;
+#[cfg(any(all(test, feature = "std"), feature = "bench-instrument"))]
+pub mod instrument;
+mod interpreter;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod macros;
+pub mod memo;
+pub mod optimize;
+#[cfg(feature = "std")]
+pub mod output;
+#[cfg(feature = "parse")]
+pub mod parser;
+#[cfg(feature = "std")]
+pub mod pool;
+pub mod prelude;
+pub mod print;
+pub mod program;
+#[cfg(feature = "parse")]
+pub mod repl;
+#[cfg(feature = "parse")]
+pub mod report;
+pub mod rename;
+pub mod resume;
+pub mod rng;
+pub mod snapshot;
+pub mod sync;
+pub mod tokens;
+pub mod trace;
+pub mod truth;
+#[cfg(any(all(test, feature = "parse"), feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use analysis::{AstLimits, AstMetrics, Metric};
+pub use bigint::BigInt;
+pub use convert::{FromValue, IntoValue, TypeError, ValueType};
+pub use coverage::Coverage;
+pub use debugger::Debugging;
+pub use include::IncludeError;
+#[cfg(feature = "parse")]
+pub use include::Includes;
+#[cfg(feature = "parse")]
+pub use incremental::IncrementalParser;
+pub use interpreter::{
+    keyword_args, Evaluator, HostFn, Interpreter, NativeFn, ReentrantFn, RngFn,
+};
+pub use macros::{expand, ExpandError, Macros};
+#[cfg(feature = "parse")]
+pub use parser::{
+    expr, parse_bytes, parse_fuzz, parse_program, parse_program_with, parse_program_with_symbols,
+    parse_recovering,
+};
+pub use program::Program;
+pub use rename::{rename, RenameConflict, RenameReport, RenameScope};
+pub use resume::{eval_resumable, Continuation, Outcome};
+pub use snapshot::{EnvDiff, EnvSnapshot};
+pub use tokens::{is_complete, tokenize, CompleteStatus, Token, TokenKind};
+pub use trace::{TraceEvent, TraceKind, Tracer};
+pub use truth::TruthPolicy;
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Ast<Ident> {
     Lit(Value<Ident>),
     Variable(Ident),
-    Call(Box<Ast<Ident>>, Vec<Ast<Ident>>),
-    Define(Ident, Box<Ast<Ident>>),
+    /// A function and its arguments, evaluated in that order in the
+    /// caller's scope, so in `(f (= x 1) x)` the second argument is 1
+    /// whatever `f` is. `eval` describes this in full.
+    Call(Rc<Ast<Ident>>, Rc<[Ast<Ident>]>),
+    Define(Ident, Rc<Ast<Ident>>),
+    /// `(include "path")`, which evaluates the forms of another file.
+    Include(Rc<str>),
 }
 
-#[derive(Clone)]
+/// With the `serde` feature, values and programs can be serialized as long
+/// as they don't contain an `InbuiltFunc`, which fails to serialize since
+/// there's no way to name it. Shared children are written out once per
+/// reference, so they come back as separate copies.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Value<Ident> {
     Void,
     False,
     Int(u64),
-    Function(Rc<[Ident]>, Rc<[Ast<Ident>]>),
-    InbuiltFunc(fn(&[&Value<Ident>]) -> Value<Ident>),
+    Function(Rc<Lambda<Ident>>),
+    #[cfg_attr(feature = "serde", serde(skip))]
+    InbuiltFunc(NativeFn<Ident>),
+    /// A native that evaluates code, such as `eval`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    ReentrantFunc(ReentrantFn<Ident>),
+    /// A Rust closure, registered with `Interpreter::register_fn`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    HostFunc(HostFn<Ident>),
+    /// Built by the `list` native, and taken apart by list patterns in
+    /// function parameters. This is a `Vec` rather than a slice so that the
+    /// `Rc` is a thin pointer and values stay small.
+    List(Rc<Vec<Value<Ident>>>),
+    /// `:name`, which evaluates to itself. In the arguments of a call it
+    /// gives the argument after it to the parameter called `name`.
+    Keyword(Ident),
+    /// A function with some of its arguments already given, built by the
+    /// `curry` native.
+    Partial(Rc<Partial<Ident>>),
+    /// `"text"`. Like `List`, this is a `String` rather than a `str` so that
+    /// the `Rc` is a thin pointer.
+    Str(Rc<String>),
+    /// `'name`, a name as data rather than a variable to look up. Quoted
+    /// code is made of these and lists, which is how macros see their
+    /// arguments.
+    Symbol(Ident),
+    /// Several results at once, built by the `values` native and taken
+    /// apart by `let-values`. Natives only take single values, so passing
+    /// one of these to a native is an error rather than quietly using the
+    /// first value. A `Vec` for the same reason as `List`.
+    Values(Rc<Vec<Value<Ident>>>),
+    /// An integer too big for `Int`, written as a literal or made by `add`
+    /// or `mul` when `EvalOptions::auto_promote` lets them. One of these is
+    /// equal to the `Int` with the same value, if there is one, but those
+    /// the crate makes never fit in an `Int`.
+    BigInt(Rc<BigInt>),
+    /// `#b"text"`, binary data rather than text. `\xNN` in the literal is
+    /// the byte `NN` in hex, and anything else is its UTF-8 bytes. A `Vec`
+    /// for the same reason as `List`.
+    Bytes(Rc<Vec<u8>>),
+    /// `nil`, for when there's no value to give: nothing found, or nothing
+    /// there. `Void` is the result of something done for its effect, such
+    /// as a define, and is true, while `Nil` is false like `False`. The two
+    /// are never equal.
+    Nil,
+    /// `(delay expr)`, which evaluates `expr` the first time it's given to
+    /// `force` and keeps the result for every time after that. Scoping is
+    /// dynamic, so like a lambda it captures nothing, and `expr` sees the
+    /// variables of wherever it's first forced.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Thunk(Rc<Thunk<Ident>>),
+    /// A place holding a value, made by `box` and changed by `boxset`.
+    /// Everything holding the same box sees what's written to it. Cloning a
+    /// box clones the `Rc` rather than what's in it, so a box in a scope
+    /// that the environment copies is still the same box.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Box(Rc<RefCell<Value<Ident>>>),
+    /// A host object, such as a connection or a game entity, that programs
+    /// can hold and pass around but not look inside. Made by
+    /// `Value::foreign` and read by natives with `Value::downcast_foreign`.
+    /// Cloning one clones the `Rc`, and it's only equal to its clones. A
+    /// `Foreign` rather than a `dyn Any` so that the `Rc` is a thin pointer.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Foreign(Rc<Foreign>),
 }
 
-fn hash_string(x: &str) -> u64 {
-    use std::collections::hash_map::DefaultHasher;
-    let mut h = DefaultHasher::new();
-    x.hash(&mut h);
-    h.finish()
+// Written out rather than derived so that `instrument` can count clones.
+impl<Ident: Clone> Clone for Value<Ident> {
+    fn clone(&self) -> Self {
+        instrument!(value_clones);
+        match *self {
+            Value::Void => Value::Void,
+            Value::False => Value::False,
+            Value::Int(i) => Value::Int(i),
+            Value::Function(ref lambda) => Value::Function(lambda.clone()),
+            Value::InbuiltFunc(func) => Value::InbuiltFunc(func),
+            Value::ReentrantFunc(func) => Value::ReentrantFunc(func),
+            Value::HostFunc(ref func) => Value::HostFunc(func.clone()),
+            Value::List(ref items) => Value::List(items.clone()),
+            Value::Keyword(ref name) => Value::Keyword(name.clone()),
+            Value::Partial(ref partial) => Value::Partial(partial.clone()),
+            Value::Str(ref text) => Value::Str(text.clone()),
+            Value::Symbol(ref name) => Value::Symbol(name.clone()),
+            Value::Values(ref items) => Value::Values(items.clone()),
+            Value::BigInt(ref big) => Value::BigInt(big.clone()),
+            Value::Bytes(ref bytes) => Value::Bytes(bytes.clone()),
+            Value::Nil => Value::Nil,
+            Value::Thunk(ref thunk) => Value::Thunk(thunk.clone()),
+            Value::Box(ref cell) => Value::Box(cell.clone()),
+            Value::Foreign(ref foreign) => Value::Foreign(foreign.clone()),
+        }
+    }
 }
 
-impl<Id> PartialEq for Value<Id> {
-    fn eq(&self, other: &Self) -> bool {
-        use Value::*;
+impl<Ident> Value<Ident> {
+    /// Whether conditionals treat this value as true under the default
+    /// `TruthPolicy`, where only `False` and `Nil` are false: `Void`, `0`
+    /// and every function are true, as in Scheme where everything but `#f`
+    /// is true. A native that branches on a value should ask the policy its
+    /// `Evaluator` gives instead, as the `truth` module describes, and
+    /// anything else should ask this rather than matching on `False` itself.
+    pub fn is_truthy(&self) -> bool {
+        TruthPolicy::SchemeLike.is_true(self)
+    }
 
-        match (self, other) {
-            (&Void, &Void) => true,
-            (&False, &False) => true,
-            (&Int(a), &Int(b)) => a == b,
-            _ => false,
+    /// Roughly how many bytes this value keeps on the heap, leaving out the
+    /// value itself. Whatever is shared behind an `Rc` is counted once,
+    /// however often it's reached, and a function counts the top-level
+    /// nodes of its code but not the nodes inside them.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size_in(&mut HashSet::new())
+    }
+
+    // `heap_size`, leaving out anything shared that's in `seen` and adding
+    // what's counted to it. This doesn't recurse, since lists built while
+    // running can be nested more deeply than the parser allows.
+    pub(crate) fn heap_size_in(&self, seen: &mut HashSet<*const ()>) -> usize {
+        // The two reference counts beside every value behind an `Rc`.
+        const COUNTS: usize = 2 * mem::size_of::<usize>();
+
+        let mut size = 0;
+        let mut pending = vec![self];
+        while let Some(value) = pending.pop() {
+            match value.shared() {
+                Some(ptr) if seen.insert(ptr) => {}
+                _ => continue,
+            }
+            size += COUNTS;
+            match *value {
+                Value::List(ref items) | Value::Values(ref items) => {
+                    size += mem::size_of::<Vec<Value<Ident>>>();
+                    size += items.capacity() * mem::size_of::<Value<Ident>>();
+                    pending.extend(items.iter());
+                }
+                Value::Str(ref text) => size += mem::size_of::<String>() + text.capacity(),
+                Value::BigInt(ref big) => size += mem::size_of::<BigInt>() + big.heap_size(),
+                Value::Bytes(ref bytes) => size += mem::size_of::<Vec<u8>>() + bytes.capacity(),
+                Value::Function(ref lambda) => {
+                    size += mem::size_of::<Lambda<Ident>>();
+                    size += lambda.params.len() * mem::size_of::<Pattern<Ident>>();
+                    let nodes = lambda.defaults.len() + lambda.body.len();
+                    size += nodes * mem::size_of::<Ast<Ident>>();
+                }
+                Value::Partial(ref partial) => {
+                    size += mem::size_of::<Partial<Ident>>();
+                    size += partial.args.len() * mem::size_of::<Value<Ident>>();
+                    pending.push(&partial.func);
+                    pending.extend(partial.args.iter());
+                }
+                Value::Thunk(ref thunk) => {
+                    size += mem::size_of::<Thunk<Ident>>();
+                    pending.push(&thunk.func);
+                    pending.extend(thunk.value());
+                }
+                // What's in a box can't be borrowed for longer than this
+                // arm, so this one recurses, once per box inside a box.
+                Value::Box(ref cell) => {
+                    size += mem::size_of::<RefCell<Value<Ident>>>();
+                    size += cell.borrow().heap_size_in(seen);
+                }
+                // What the object keeps is up to the host, so only the
+                // wrapper around it is counted.
+                Value::Foreign(_) => size += mem::size_of::<Foreign>(),
+                _ => {}
+            }
         }
+        size
     }
-}
 
-pub fn eval<'b, Id: Clone + Debug + Eq + Hash, S: BuildHasher + Clone>(
-    program: &'b Ast<Id>,
-    variables: &mut HashMap<Id, Cow<'b, Value<Id>>, S>,
-) -> Cow<'b, Value<Id>> {
-    use self::Ast::*;
-    use self::Value::*;
+    // Where this value's `Rc` points, if it has one.
+    pub(crate) fn shared(&self) -> Option<*const ()> {
+        match *self {
+            Value::List(ref items) | Value::Values(ref items) => {
+                Some(Rc::as_ptr(items) as *const ())
+            }
+            Value::Str(ref text) => Some(Rc::as_ptr(text) as *const ()),
+            Value::BigInt(ref big) => Some(Rc::as_ptr(big) as *const ()),
+            Value::Bytes(ref bytes) => Some(Rc::as_ptr(bytes) as *const ()),
+            Value::Function(ref lambda) => Some(Rc::as_ptr(lambda) as *const ()),
+            Value::Partial(ref partial) => Some(Rc::as_ptr(partial) as *const ()),
+            Value::Thunk(ref thunk) => Some(Rc::as_ptr(thunk) as *const ()),
+            Value::Box(ref cell) => Some(Rc::as_ptr(cell) as *const ()),
+            Value::Foreign(ref foreign) => Some(Rc::as_ptr(foreign) as *const ()),
+            _ => None,
+        }
+    }
 
-    match *program {
-        Lit(ref val) => Cow::Borrowed(val),
-        Variable(ref name) => {
-            match variables.get(name) {
-                Some(v) => v.clone(),
-                _ => panic!("Variable does not exist: {:?}", name),
-            }
-        }
-        Call(ref func, ref arguments) => {
-            let func = eval(&*func, variables);
-
-            match *func.as_ref() {
-                Function(ref args, ref body) => {
-                    // Start a new scope, so all variables defined in the body of the
-                    // function don't leak into the surrounding scope.
-                    let mut new_scope = variables.clone();
-
-                    if arguments.len() != args.len() {
-                        println!(
-                            "Called function with incorrect number of arguments (expected {}, got \
-                            {})",
-                            args.len(),
-                            arguments.len()
-                        );
-                    }
+    // Calls this if it's an `InbuiltFunc` or a `HostFunc`, which every
+    // backend calls the same way, with arguments that `single_values` has
+    // already checked.
+    pub(crate) fn call_native(
+        &self,
+        args: &[&Value<Ident>],
+    ) -> Result<Value<Ident>, EvalError<Ident>> {
+        match *self {
+            Value::InbuiltFunc(func) => func(args),
+            Value::HostFunc(ref func) => func.call(args),
+            _ => Err(EvalError::NotAFunction),
+        }
+    }
 
-                    for (name, val) in args.into_iter().zip(arguments) {
-                        let val = eval(&val, variables);
-                        new_scope.insert(name.clone(), val);
-                    }
+    // `call_native` for the backends that can't reenter, which also call
+    // reentrant natives, giving them an evaluator that only answers which
+    // values are true under `truth`.
+    pub(crate) fn call_bare(
+        &self,
+        args: &[&Value<Ident>],
+        truth: TruthPolicy<Ident>,
+    ) -> Result<Value<Ident>, EvalError<Ident>> {
+        match *self {
+            Value::ReentrantFunc(func) => func(&mut interpreter::Bare { truth }, args),
+            _ => self.call_native(args),
+        }
+    }
 
-                    let mut out = Cow::Owned(Void);
+    pub(crate) fn as_keyword(&self) -> Option<&Ident> {
+        match *self {
+            Value::Keyword(ref name) => Some(name),
+            _ => None,
+        }
+    }
+}
 
-                    for stmt in body.iter() {
-                        out = eval(&stmt, &mut new_scope);
-                    }
+/// Values as a program would write them, with functions shown as
+/// `<function>` and `<native>` since they have no name, and keywords as
+/// `<keyword>` and `<symbol>` since their names aren't kept. Strings are
+/// quoted, with the characters that would end or interpolate them escaped.
+/// Bytes are written in hex, but only the first `BYTES_SHOWN` of them, with
+/// how many there are after that, so that a big buffer can't flood a log.
+/// A box is shown with what it holds now, as `(box 1)`, and as `(box ...)`
+/// wherever it turns up inside itself. A foreign value is `<foreign>`, or
+/// `<foreign name>` if it was given a name.
+impl<Ident> fmt::Display for Value<Ident> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.show(f, &mut Vec::new())
+    }
+}
 
-                    Cow::Owned(out.into_owned())
+impl<Ident> Value<Ident> {
+    // `Display`, where `open` is the boxes whose contents are being shown.
+    fn show(&self, f: &mut fmt::Formatter, open: &mut Vec<*const ()>) -> fmt::Result {
+        match *self {
+            Value::Void => write!(f, "void"),
+            Value::False => write!(f, "#f"),
+            Value::Nil => write!(f, "nil"),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(ref big) => write!(f, "{}", big),
+            Value::Function(_) | Value::Partial(_) => write!(f, "<function>"),
+            Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+                write!(f, "<native>")
+            }
+            Value::Keyword(_) => write!(f, "<keyword>"),
+            Value::Symbol(_) => write!(f, "<symbol>"),
+            Value::Thunk(_) => write!(f, "<thunk>"),
+            Value::Foreign(ref foreign) => write!(f, "{}", foreign),
+            Value::Str(ref text) => write!(f, "{}", Quoted(text)),
+            Value::Bytes(ref bytes) => write!(f, "{}", BytesPreview(bytes)),
+            Value::List(ref items) => {
+                write!(f, "(list")?;
+                for item in items.iter() {
+                    write!(f, " ")?;
+                    item.show(f, open)?;
+                }
+                write!(f, ")")
+            }
+            Value::Values(ref items) => {
+                write!(f, "(values")?;
+                for item in items.iter() {
+                    write!(f, " ")?;
+                    item.show(f, open)?;
+                }
+                write!(f, ")")
+            }
+            Value::Box(ref cell) => {
+                let ptr = Rc::as_ptr(cell) as *const ();
+                if open.contains(&ptr) {
+                    return write!(f, "(box ...)");
                 }
-                InbuiltFunc(ref func) => {
-                    let args = arguments
-                        .iter()
-                        .map(|ast| eval(ast, variables))
-                        .collect::<Vec<_>>();
+                open.push(ptr);
+                write!(f, "(box ")?;
+                cell.borrow().show(f, open)?;
+                open.pop();
+                write!(f, ")")
+            }
+        }
+    }
+}
 
-                    let arg_refs = args.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+// Shown the way `Display` shows it, so that errors carrying values can be
+// printed with `{:?}`.
+impl<Ident> fmt::Debug for Value<Ident> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
 
-                    let res = func(&arg_refs);
+/// A call waiting for the rest of its arguments. Calling it calls `func`
+/// with `args` followed by the arguments of the call, so mistakes in the
+/// number of arguments are reported against `func`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Partial<Ident> {
+    func: Value<Ident>,
+    args: Box<[Value<Ident>]>,
+}
 
-                    Cow::Owned(res)
-                }
-                _ => panic!("Attempted to call a non-function"),
-            }
+impl<Ident: Clone> Partial<Ident> {
+    /// Give `func` the first of its arguments. If `func` is itself partially
+    /// applied, the arguments are added to the ones it already has, so
+    /// `func()` is never a `Partial`.
+    pub fn new(func: Value<Ident>, args: Vec<Value<Ident>>) -> Self {
+        match func {
+            Value::Partial(ref inner) => Partial {
+                func: inner.func.clone(),
+                args: inner.args.iter().cloned().chain(args).collect(),
+            },
+            func => Partial {
+                func,
+                args: args.into(),
+            },
         }
-        Define(ref name, ref value) => {
-            let value = eval(&*value, variables);
+    }
+}
+
+impl<Ident> Partial<Ident> {
+    pub fn func(&self) -> &Value<Ident> {
+        &self.func
+    }
+
+    pub fn args(&self) -> &[Value<Ident>] {
+        &self.args
+    }
+}
 
-            variables.insert(name.clone(), value);
+/// A value that hasn't been worked out yet, and is worked out at most once.
+pub struct Thunk<Ident> {
+    func: Value<Ident>,
+    value: OnceCell<Value<Ident>>,
+}
 
-            Cow::Owned(Void)
+impl<Ident> Thunk<Ident> {
+    /// A thunk that calls `func` with no arguments when it's first forced.
+    pub fn new(func: Value<Ident>) -> Self {
+        Thunk {
+            func,
+            value: OnceCell::new(),
         }
     }
+
+    pub fn func(&self) -> &Value<Ident> {
+        &self.func
+    }
+
+    /// What the thunk was forced to, if it has been.
+    pub fn value(&self) -> Option<&Value<Ident>> {
+        self.value.get()
+    }
+
+    // Keeps `value` as what the thunk was forced to, unless forcing it
+    // again from inside `func` already did, and returns whichever is kept.
+    pub(crate) fn set(&self, value: Value<Ident>) -> &Value<Ident> {
+        let _ = self.value.set(value);
+        self.value.get().expect("the value was just set")
+    }
 }
 
-#[derive(Clone, Default)]
-struct U64Hasher(pub u64);
+/// A host object inside a `Value::Foreign`.
+pub struct Foreign {
+    name: Option<&'static str>,
+    object: Box<dyn Any>,
+}
 
-impl BuildHasher for U64Hasher {
-    type Hasher = Self;
-    fn build_hasher(&self) -> Self {
-        U64Hasher(self.0)
+impl Foreign {
+    /// What the object was named by `Value::foreign_named`, if anything.
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// The object, if it's a `T`.
+    pub fn downcast<T: Any>(&self) -> Option<&T> {
+        self.object.downcast_ref()
     }
 }
 
-impl Hasher for U64Hasher {
-    fn finish(&self) -> u64 {
-        self.0
+impl fmt::Display for Foreign {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "<foreign {}>", name),
+            None => write!(f, "<foreign>"),
+        }
+    }
+}
+
+impl<Ident> Value<Ident> {
+    /// A `Foreign` holding `object`, for natives to hand to programs.
+    pub fn foreign<T: Any>(object: T) -> Self {
+        Value::Foreign(Rc::new(Foreign {
+            name: None,
+            object: Box::new(object),
+        }))
     }
 
-    fn write(&mut self, _bytes: &[u8]) {
-        unimplemented!()
+    /// A `Foreign` holding `object`, shown as `<foreign name>` and called
+    /// `name` in type errors.
+    pub fn foreign_named<T: Any>(name: &'static str, object: T) -> Self {
+        Value::Foreign(Rc::new(Foreign {
+            name: Some(name),
+            object: Box::new(object),
+        }))
     }
 
-    fn write_u64(&mut self, i: u64) {
-        self.0 = i
+    /// The object in this value, if it's a `Foreign` holding a `T`.
+    pub fn downcast_foreign<T: Any>(&self) -> Option<&T> {
+        match *self {
+            Value::Foreign(ref foreign) => foreign.downcast(),
+            _ => None,
+        }
+    }
+}
+
+impl<Ident: Clone> Value<Ident> {
+    /// A copy of this value that shares nothing that can change with it,
+    /// which is what `copy` gives programs. Each box is copied along with
+    /// what it holds now, and each thunk along with what it was forced to,
+    /// so that writing to one in the copy leaves the original alone. A box
+    /// or thunk that turns up more than once, including inside itself, is
+    /// copied once and turns up the same number of times in the copy.
+    ///
+    /// What can't change is shared: strings, bytes, big integers, function
+    /// bodies and natives. So are foreign values, which there's no way to
+    /// copy, and a thunk reached again from inside its own function, which
+    /// can't be made until the function is. Lists are copied however deep
+    /// they are, with a worklist rather than recursion.
+    pub fn deep_clone(&self) -> Value<Ident> {
+        let mut copied = HashMap::new();
+        let mut out = Vec::new();
+        let mut pending = vec![DeepClone::Visit(self.clone())];
+        while let Some(step) = pending.pop() {
+            match step {
+                DeepClone::Visit(value) => match value {
+                    Value::List(ref items) | Value::Values(ref items) => {
+                        let is_list = matches!(value, Value::List(_));
+                        pending.push(DeepClone::List(is_list, items.len()));
+                        pending.extend(items.iter().rev().cloned().map(DeepClone::Visit));
+                    }
+                    Value::Partial(ref partial) => {
+                        pending.push(DeepClone::Partial(partial.args.len()));
+                        pending.extend(partial.args.iter().rev().cloned().map(DeepClone::Visit));
+                        pending.push(DeepClone::Visit(partial.func.clone()));
+                    }
+                    Value::Box(ref cell) => {
+                        let key = Rc::as_ptr(cell) as *const ();
+                        if let Some(copy) = copied.get(&key) {
+                            out.push(Option::clone(copy).unwrap_or_else(|| value.clone()));
+                            continue;
+                        }
+                        let copy = Rc::new(RefCell::new(Value::Void));
+                        copied.insert(key, Some(Value::Box(copy.clone())));
+                        out.push(Value::Box(copy.clone()));
+                        pending.push(DeepClone::FillBox(copy));
+                        pending.push(DeepClone::Visit(cell.borrow().clone()));
+                    }
+                    Value::Thunk(ref thunk) => {
+                        let key = Rc::as_ptr(thunk) as *const ();
+                        if let Some(copy) = copied.get(&key) {
+                            out.push(Option::clone(copy).unwrap_or_else(|| value.clone()));
+                            continue;
+                        }
+                        // Not made yet, until its function is copied.
+                        copied.insert(key, None);
+                        pending.push(DeepClone::Thunk(key, thunk.value().cloned()));
+                        pending.push(DeepClone::Visit(thunk.func.clone()));
+                    }
+                    value => out.push(value),
+                },
+                DeepClone::List(is_list, len) => {
+                    let items = out.split_off(out.len() - len);
+                    out.push(if is_list {
+                        Value::List(Rc::new(items))
+                    } else {
+                        Value::Values(Rc::new(items))
+                    });
+                }
+                DeepClone::Partial(len) => {
+                    let args = out.split_off(out.len() - len).into_boxed_slice();
+                    let func = out.pop().expect("the function was copied");
+                    out.push(Value::Partial(Rc::new(Partial { func, args })));
+                }
+                DeepClone::FillBox(copy) => {
+                    *copy.borrow_mut() = out.pop().expect("the contents were copied");
+                }
+                DeepClone::Thunk(key, value) => {
+                    let func = out.pop().expect("the function was copied");
+                    let copy = Rc::new(Thunk::new(func));
+                    copied.insert(key, Some(Value::Thunk(copy.clone())));
+                    out.push(Value::Thunk(copy.clone()));
+                    if let Some(value) = value {
+                        pending.push(DeepClone::FillThunk(copy));
+                        pending.push(DeepClone::Visit(value));
+                    }
+                }
+                DeepClone::FillThunk(copy) => {
+                    copy.set(out.pop().expect("the value was copied"));
+                }
+            }
+        }
+        out.pop().expect("the value was copied")
     }
 }
 
-type IntMap<V> = HashMap<u64, V, U64Hasher>;
+// What's left to do in `Value::deep_clone`, which keeps the copies made so
+// far on a stack, taking them off as the values holding them are made.
+enum DeepClone<Ident> {
+    Visit(Value<Ident>),
+    // A list if true, or else values, of the last this many copies.
+    List(bool, usize),
+    // A partial of the copy before the last this many, which are its
+    // arguments.
+    Partial(usize),
+    FillBox(Rc<RefCell<Value<Ident>>>),
+    // The thunk at this address, and what it was forced to.
+    Thunk(*const (), Option<Value<Ident>>),
+    FillThunk(Rc<Thunk<Ident>>),
+}
 
-parser! {
-    pub fn expr['a, I]()(I) -> Ast<u64> where [
-         I: combine::Stream<Item = char, Range = &'a str> +
-         combine::RangeStreamOnce
-    ] {
-        use combine::parser::char::*;
-        use combine::parser::range::*;
-        use combine::*;
+// Text as a string literal that parses back to it.
+pub(crate) struct Quoted<'a>(pub(crate) &'a str);
 
-        macro_rules! white {
-            ($prs:expr) => {
-                between(
-                    skip_many(satisfy(char::is_whitespace)),
-                    skip_many(satisfy(char::is_whitespace)),
-                    $prs,
-                )
-            };
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '{' => write!(f, "{{{{")?,
+                '}' => write!(f, "}}}}")?,
+                c => write!(f, "{}", c)?,
+            }
         }
+        write!(f, "\"")
+    }
+}
+
+/// How many bytes `Value::Bytes` shows of itself when displayed.
+pub const BYTES_SHOWN: usize = 32;
 
-        let lambda = char('\\');
-        let eq = char('=');
-        let flse = white!(string("#f")).map(|_| Ast::Lit(::Value::False));
-        let ident = || white!(take_while1(|c: char| c.is_alphabetic())).map(hash_string);
-        let function = (
-            white!(lambda),
-            white!(between(char('('), char(')'), many::<Vec<_>, _>(ident()))),
-            many::<Vec<_>, _>(expr()),
-        ).map(|(_, a, b)| Ast::Lit(::Value::Function(a.into(), b.into())));
-        let define = (white!(eq), ident(), expr()).map(|(_, a, b)| Ast::Define(a, Box::new(b)));
-        let lit_num = many1::<String, _>(digit())
-            .map(|i| Ast::Lit(::Value::Int(i.parse().expect("Parsing integer failed"))));
-        let call = (expr(), many(expr())).map(|(func, args)| Ast::Call(Box::new(func), args));
+// Bytes as a literal that parses back to them, with every byte in hex.
+pub(crate) struct QuotedBytes<'a>(pub(crate) &'a [u8]);
 
-        white!(choice!(
-            flse,
-            lit_num,
-            ident().map(Ast::Variable),
-            between(char('('), char(')'), choice!(function, define, call))
-        ))
+impl<'a> fmt::Display for QuotedBytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#b\"")?;
+        for byte in self.0 {
+            write!(f, "\\x{:02x}", byte)?;
+        }
+        write!(f, "\"")
     }
 }
 
-#[cfg(test)]
-mod benches {
-    extern crate test;
+// Bytes the way `Display` shows them, cut short after `BYTES_SHOWN`.
+pub(crate) struct BytesPreview<'a>(pub(crate) &'a [u8]);
 
-    use combine::Parser;
+impl<'a> fmt::Display for BytesPreview<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() > BYTES_SHOWN {
+            write!(f, "{}... ({} bytes)", QuotedBytes(&self.0[..BYTES_SHOWN]), self.0.len())
+        } else {
+            write!(f, "{}", QuotedBytes(self.0))
+        }
+    }
+}
 
-    use self::test::{black_box, Bencher};
+/// The parameters and body of a user-defined function. This lives behind a
+/// single `Rc` so that function values are no bigger than an integer.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Lambda<Ident> {
+    pub params: Box<[Pattern<Ident>]>,
+    /// The defaults of the last `defaults.len()` parameters, which may be
+    /// left out of a call and are always plain names. Each default is
+    /// evaluated when its argument is missing, at the start of the call
+    /// once the parameters before it are bound.
+    pub defaults: Box<[Ast<Ident>]>,
+    pub body: Box<[Ast<Ident>]>,
+}
 
-    use super::{eval, expr, hash_string, IntMap, Value};
+impl<Ident> Lambda<Ident> {
+    /// A function whose parameters are all plain names.
+    pub fn new<P, B>(params: P, body: B) -> Self
+    where
+        P: IntoIterator<Item = Ident>,
+        B: Into<Box<[Ast<Ident>]>>,
+    {
+        Lambda {
+            params: params.into_iter().map(Pattern::Name).collect(),
+            defaults: Box::new([]),
+            body: body.into(),
+        }
+    }
 
-    use std::borrow::Cow;
+    /// How many arguments a call has to pass.
+    pub fn required(&self) -> usize {
+        self.params.len() - self.defaults.len()
+    }
 
-    // First we need some helper functions. These are used with the `InbuiltFunc`
-    // constructor and act as native functions, similar to how you'd add functions
-    // to the global namespace in Lua.
-    //
-    // This one simply sums the arguments.
-    fn add<T>(variables: &[&Value<T>]) -> Value<T> {
-        let mut out = 0u64;
+    // Calls with a surprising number of arguments still go ahead, binding
+    // what they can, but they're almost certainly a mistake.
+    pub(crate) fn check_arity(&self, argc: usize) {
+        if self.defaults.is_empty() && argc != self.params.len() {
+            println!(
+                "Called function with incorrect number of arguments (expected {}, got {})",
+                self.params.len(),
+                argc
+            );
+        } else if argc < self.required() || argc > self.params.len() {
+            println!(
+                "Called function with incorrect number of arguments (expected {} to {}, got {})",
+                self.required(),
+                self.params.len(),
+                argc
+            );
+        }
+    }
+
+    // Lines the arguments of a call up with the parameters. Without
+    // keywords that's by position, and a call with too few or too many
+    // arguments only gets a warning. With them, the first `positional` are
+    // by position and the rest are `:name value` pairs, and between them
+    // they have to give every required parameter an argument.
+    pub(crate) fn arrange<V, F>(
+        &self,
+        mut args: Vec<V>,
+        positional: Option<usize>,
+        keyword: F,
+    ) -> Result<Arguments<V>, EvalError<Ident>>
+    where
+        Ident: Clone + PartialEq,
+        F: Fn(&V) -> Option<&Ident>,
+    {
+        let required = self.required();
 
-        for v in variables {
-            match *v {
-                Value::Int(i) => out += i,
-                _ => println!("Tried to add a non-int"),
+        let positional = match positional {
+            Some(positional) => positional,
+            None => {
+                self.check_arity(args.len());
+                let optional = if args.len() >= required {
+                    let given = args.split_off(required);
+                    given
+                        .into_iter()
+                        .map(Some)
+                        .chain(iter::repeat_with(|| None))
+                        .take(self.defaults.len())
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                return Ok(Arguments { required: args, optional });
             }
+        };
+
+        // Only plain names can be given by keyword.
+        let names = self
+            .params
+            .iter()
+            .map(|param| match *param {
+                Pattern::Name(ref name) => Some(name),
+                Pattern::List(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let mut slots = line_up(args, positional, &names, keyword)?;
+
+        let optional = slots.split_off(required);
+        let required = slots
+            .into_iter()
+            .zip(self.params.iter())
+            .map(|(slot, param)| {
+                slot.ok_or_else(|| match *param {
+                    Pattern::Name(ref name) => EvalError::MissingArgument(name.clone()),
+                    Pattern::List(_) => EvalError::ArgumentCount {
+                        min: required,
+                        max: self.params.len(),
+                        got: positional,
+                    },
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Arguments { required, optional })
+    }
+
+    /// Every name that the parameters bind, from left to right.
+    pub fn names(&self) -> Vec<&Ident> {
+        let mut out = Vec::new();
+        for param in self.params.iter() {
+            param.push_names(&mut out);
+        }
+        out
+    }
+
+    // Matches `args` against the parameters, giving the value of each of
+    // `names` in the same order. Extra arguments are ignored, and missing
+    // ones leave the parameters after them unbound.
+    pub(crate) fn bind(&self, args: Vec<Value<Ident>>) -> Result<Vec<Value<Ident>>, EvalError<Ident>>
+    where
+        Ident: Clone,
+    {
+        if self.params.iter().all(|param| matches!(*param, Pattern::Name(_))) {
+            return Ok(args);
+        }
+
+        let mut out = Vec::with_capacity(args.len());
+        for (param, arg) in self.params.iter().zip(args) {
+            param.destructure(arg, &mut out)?;
+        }
+        Ok(out)
+    }
+}
+
+// The arguments of a call, lined up with the parameters of the function.
+pub(crate) struct Arguments<V> {
+    // For the required parameters, from the left. There are fewer if a
+    // call without keywords didn't pass them all.
+    pub(crate) required: Vec<V>,
+    // For each optional parameter, if the call gave it an argument. This
+    // is empty if any required argument is missing, since then none of the
+    // defaults are evaluated.
+    pub(crate) optional: Vec<Option<V>>,
+}
+
+// Puts each of `args` in the slot of the parameter it's for, out of those
+// called `names`: the first `positional` by position, and the rest in
+// `:name value` pairs. Extra positional arguments are dropped.
+pub(crate) fn line_up<V, Id, F>(
+    args: Vec<V>,
+    positional: usize,
+    names: &[Option<&Id>],
+    keyword: F,
+) -> Result<Vec<Option<V>>, EvalError<Id>>
+where
+    Id: Clone + PartialEq,
+    F: Fn(&V) -> Option<&Id>,
+{
+    let mut slots = iter::repeat_with(|| None).take(names.len()).collect::<Vec<_>>();
+    let mut args = args.into_iter();
+
+    for (i, arg) in args.by_ref().take(positional).enumerate() {
+        if let Some(slot) = slots.get_mut(i) {
+            *slot = Some(arg);
+        }
+    }
+
+    while let Some(key) = args.next() {
+        let name = keyword(&key).ok_or(EvalError::ExpectedKeyword)?.clone();
+        // With duplicate parameter names the last one wins, as in `eval`.
+        let slot = match names.iter().rposition(|param| *param == Some(&name)) {
+            Some(slot) => slot,
+            None => return Err(EvalError::UnknownKeyword(name)),
+        };
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(EvalError::MissingArgument(name)),
+        };
+        if slots[slot].is_some() {
+            return Err(EvalError::DuplicateArgument(name));
+        }
+        slots[slot] = Some(value);
+    }
+
+    Ok(slots)
+}
+
+// The number that `digits` is written as an integer literal, an `Int` if
+// it fits and a `BigInt` if not, or `None` if it isn't one or it's longer
+// than `bigint::MAX_DIGITS`.
+pub(crate) fn int_literal<Id>(digits: &str) -> Option<Value<Id>> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match digits.parse() {
+        Ok(i) => Some(Value::Int(i)),
+        Err(_) => BigInt::from_decimal(digits).map(|big| Value::BigInt(Rc::new(big))),
+    }
+}
+
+// Where the keyword arguments of a call start, if it has any.
+pub(crate) fn first_keyword<Id>(args: &[Ast<Id>]) -> Option<usize> {
+    args.iter().position(|arg| matches!(*arg, Ast::Lit(Value::Keyword(_))))
+}
+
+/// A parameter of a function, as written between its parentheses.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Pattern<Ident> {
+    /// Binds the whole argument.
+    Name(Ident),
+    /// `(a b)`, which takes apart an argument that must be a list with
+    /// exactly one element per pattern, matching each element against its
+    /// pattern.
+    List(Box<[Pattern<Ident>]>),
+}
+
+// Patterns are walked recursively, which is fine since the parser limits
+// how deeply they nest.
+impl<Ident> Pattern<Ident> {
+    /// Every name that the pattern binds, from left to right.
+    pub fn names(&self) -> Vec<&Ident> {
+        let mut out = Vec::new();
+        self.push_names(&mut out);
+        out
+    }
+
+    fn push_names<'a>(&'a self, out: &mut Vec<&'a Ident>) {
+        match *self {
+            Pattern::Name(ref name) => out.push(name),
+            Pattern::List(ref items) => items.iter().for_each(|item| item.push_names(out)),
         }
+    }
 
-        Value::Int(out)
+    /// The same pattern with every name replaced by `f` of it, called from
+    /// left to right.
+    pub fn map<J, F: FnMut(&Ident) -> J>(&self, f: &mut F) -> Pattern<J> {
+        match *self {
+            Pattern::Name(ref name) => Pattern::Name(f(name)),
+            Pattern::List(ref items) => Pattern::List(items.iter().map(|item| item.map(f)).collect()),
+        }
     }
 
-    // This one checks the arguments for equality. I used `Void` to represent true
-    // and `False` to represent false. This is mostly inspired by scheme, where
-    // everything is true except for `#f`.
-    fn eq<T>(variables: &[&Value<T>]) -> Value<T> {
-        let mut iter_vars = variables.into_iter();
-        if let Some(last) = iter_vars.next() {
-            for v in iter_vars {
-                if v != last {
-                    return Value::False;
+    // Pushes the value of each of `names` onto `out`, in the same order.
+    pub(crate) fn destructure(
+        &self,
+        value: Value<Ident>,
+        out: &mut Vec<Value<Ident>>,
+    ) -> Result<(), EvalError<Ident>>
+    where
+        Ident: Clone,
+    {
+        let items = match *self {
+            Pattern::Name(_) => {
+                out.push(value);
+                return Ok(());
+            }
+            Pattern::List(ref items) => items,
+        };
+
+        // Multiple values are taken apart the same way as a list, which is
+        // what `let-values` relies on.
+        match value {
+            Value::List(ref values) | Value::Values(ref values) if values.len() == items.len() => {
+                for (item, value) in items.iter().zip(values.iter()) {
+                    item.destructure(value.clone(), out)?;
                 }
+                Ok(())
             }
+            Value::List(ref values) | Value::Values(ref values) => {
+                Err(EvalError::PatternMismatch {
+                    expected: items.len(),
+                    got: Some(values.len()),
+                })
+            }
+            _ => Err(EvalError::PatternMismatch {
+                expected: items.len(),
+                got: None,
+            }),
+        }
+    }
+}
 
-            Value::Void
-        } else {
-            Value::Void
+/// The identifier that the parser gives `x`, hashed with `IdentHasher`.
+pub fn hash_string(x: &str) -> u64 {
+    hash_string_with::<IdentHasher>(x)
+}
+
+/// Hash `x` the way `hash_string` does, but with a hasher of your choice.
+/// A host that parses programs for a build with a different `IdentHasher`
+/// can use this with `Ast::map_idents` to give them the identifiers that
+/// build expects.
+pub fn hash_string_with<H: Hasher + Default>(x: &str) -> u64 {
+    let mut h = H::default();
+    x.hash(&mut h);
+    h.finish()
+}
+
+/// The hasher behind `hash_string`. With std this is std's `DefaultHasher`,
+/// and without it, where there's no SipHash to use, it's `Fnv64`. Programs
+/// hashed by one kind of build won't find the natives of the other.
+pub type IdentHasher = BuildIdentHasher;
+
+#[cfg(feature = "std")]
+type BuildIdentHasher = std::collections::hash_map::DefaultHasher;
+#[cfg(not(feature = "std"))]
+type BuildIdentHasher = Fnv64;
+
+/// 64-bit FNV-1a, which is small and needs nothing from std.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Fnv64(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
         }
     }
 
-    // This version of `if` doesn't lazily evaluate its branches, unlike every
-    // other programming language in existence. To do lazy evaluation you make
-    // the `then` and `else` branches return functions and then call the
-    // functions.
-    fn if_<T: Clone>(variables: &[&Value<T>]) -> Value<T> {
-        use std::ops::Deref;
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
 
-        let mut iter = variables.into_iter();
-        let (first, second, third) = (
-            iter.next().expect("No condition for if"),
-            iter.next().expect("No body for if"),
-            iter.next(),
-        );
-        assert!(iter.next().is_none(), "Too many arguments supplied to `if`");
+impl<Ident> Ast<Ident> {
+    /// Every node of this tree in pre-order, starting with this one, and
+    /// including the defaults and bodies of function literals.
+    pub fn iter(&self) -> impl Iterator<Item = &Ast<Ident>> {
+        self.iter_with_depth().map(|(_, ast)| ast)
+    }
 
-        match **first {
-            Value::False => third.map(Deref::deref).cloned().unwrap_or(Value::Void),
-            _ => (*second).clone(),
+    /// Like `iter`, along with how far below this node each one is. The
+    /// defaults and body of a function literal are one below it.
+    pub fn iter_with_depth(&self) -> impl Iterator<Item = (usize, &Ast<Ident>)> {
+        Nodes {
+            pending: vec![(0, self)],
         }
     }
+}
+
+impl<Id: Clone> Ast<Id> {
+    /// The same program over another kind of identifier, with every
+    /// variable, defined name, parameter, keyword and symbol replaced by `f`
+    /// of it, including those in function literals. `f` is called in the
+    /// order the identifiers are written.
+    ///
+    /// # Panics
+    ///
+    /// If the program has a native function as a literal, since natives
+    /// only take values over the identifiers they were written for. Parsed
+    /// programs never do.
+    pub fn map_idents<J, F: FnMut(Id) -> J>(self, mut f: F) -> Ast<J> {
+        map_idents(&self, &mut f)
+    }
+}
 
-    // Here are our test program strings. Our language looks a lot like Lisp,
-    // but it has the important distinction of being totally useless.
-    //
-    // This string is used to test the performance when programs include
-    // deeply-nested structures. Nesting this deep is unlikely but it's a
-    // good test for the parser's performance on nesting in general.
-    const DEEP_NESTING: &str = "(((((((((((((((((((((((((((((((((((((((((((((test\
-    )))))))))))))))))))))))))))))))))))))))))))))";
-
-    // This string is used to test the performance of when programs include
-    // many variables of many different names, and many repetitions of the
-    // same name. We'd expect real programs to contain lots of variables and
-    // so it's important that we get good performance when parsing and
-    // evaluating them.
-    const MANY_VARIABLES: &str = r"
-    ((\(a b c d e f g h i j k l m n o p q r s t u v w x y z)
-      (a b c d e f g h i j k l m n o p q r s t u v w x y z)
-      (b c d e f g h i j k l m n o p q r s t u v w x y z)
-      (c d e f g h i j k l m n o p q r s t u v w x y z)
-      (d e f g h i j k l m n o p q r s t u v w x y z)
-      (e f g h i j k l m n o p q r s t u v w x y z)
-      (f g h i j k l m n o p q r s t u v w x y z)
-      (g h i j k l m n o p q r s t u v w x y z)
-      (h i j k l m n o p q r s t u v w x y z)
-      (i j k l m n o p q r s t u v w x y z)
-      (j k l m n o p q r s t u v w x y z)
-      (k l m n o p q r s t u v w x y z)
-      (l m n o p q r s t u v w x y z)
-      (m n o p q r s t u v w x y z)
-      (n o p q r s t u v w x y z)
-      (o p q r s t u v w x y z)
-      (p q r s t u v w x y z)
-      (q r s t u v w x y z)
-      (r s t u v w x y z)
-      (s t u v w x y z)
-      (t u v w x y z)
-      (u v w x y z)
-      (v w x y z)
-      (w x y z)
-      (x y z)
-      (y z)
-      (z))
-        ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore
-        ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore)
-        ";
-
-    // This is used to test that function calls aren't unnecessarily
-    // expensive. It just passes the same value down and then back up
-    // the stack.
-    const NESTED_FUNC: &str = r"
-    ((\(val)
-      ((\(val)
-        ((\(val)
-          ((\(val)
-            ((\(val)
-              ((\(val)
-                ((\(val)
-                  ((\(val)
-                    ((\(val)
-                      ((\(val)
-                        ((\(val)
-                          val
-                        ) val)
-                      ) val)
-                    ) val)
-                  ) val)
-                ) val)
-              ) val)
-            ) val)
-          ) val)
-        ) val)
-      ) val)
-    ) #f)
-";
-
-    // This is a more realistic program that uses every feature of
-    // the language. It's not useful for finding hotspots but it's
-    // definitely useful for seeing improvements.
-    const REAL_CODE: &str = r"
-(= increment (\(a)
-  (add a 1)))
-(= someval (increment 2))
-(= double (\ (someval)
-  (add someval someval)))
-(= addfive (\ (first second third fourth fifth) (add first second third fourth fifth)))
-(= second (\ (a a) a))
-(= rec (\ (a)
-  ((if (eq a 10)
-       (\() 10)
-       (\() (rec (add a 1)))))))
-(= ne (\ (a b)
-  (not (eq a b))))
-(= not (\ (a)
-  (if a #f)))
-
-(double 5)
-(addfive 1 2 3 4 5)
-(second 1 2)
-(rec 0)
-(ne 1 2)
-someval
-";
-
-    // Now we run the benchmarks. The parsing ones are very simple...
-    #[bench]
-    fn parse_deep_nesting(b: &mut Bencher) {
-        b.iter(|| black_box(expr().easy_parse(DEEP_NESTING)))
-    }
-
-    #[bench]
-    fn parse_many_variables(b: &mut Bencher) {
-        b.iter(|| black_box(expr().easy_parse(MANY_VARIABLES)))
-    }
-
-    #[bench]
-    fn parse_nested_func(b: &mut Bencher) {
-        b.iter(|| black_box(expr().easy_parse(NESTED_FUNC)))
-    }
-
-    #[bench]
-    fn parse_real_code(b: &mut Bencher) {
-        b.iter(|| black_box(expr().easy_parse(REAL_CODE)))
-    }
-
-    // We only test parsing for this one. We could test the speed of
-    // evaluating these expressions too but I personally prefer to
-    // keep the benchmarks few and representative.
-    #[bench]
-    fn parse_literals(b: &mut Bencher) {
-        let program_text = r"
-            ((\()
-               0  1  2  3  4  5  6  7  8  9 10 11 12 13 14 15 16 17 18 19
-              20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39
-              40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59
-              50 51 52 53 54 55 56 57 58 59 60 61 62 63 64 65 66 67 68 69
-              70 71 72 73 74 75 76 77 78 79 80 81 82 83 84 85 86 87 88 89
-              90 91 92 93 94 95 96 97 98 99))
-        ";
-
-        b.iter(|| black_box(expr().easy_parse(program_text)))
-    }
-
-    // For the benchmarks that run the code we have to do a little more
-    // work. We need to put some functions in the global namespace that
-    // our testing code needs in order to run.
-    #[bench]
-    fn run_deep_nesting(b: &mut Bencher) {
-
-        // This just returns a function so `((whatever))` (equivalent
-        // to `(whatever())()`) does something useful. Specifically
-        // it just returns itself. We try to do as little work as
-        // possible here so that our benchmark is still testing the
-        // interpreter and not this function.
-        fn callable<T>(_: &[&Value<T>]) -> Value<T> {
-            Value::InbuiltFunc(callable)
-        }
-
-        let (program, _) = expr().easy_parse(DEEP_NESTING).unwrap();
-
-        let mut env = IntMap::default();
-        env.insert(
-            hash_string("test"),
-            Cow::Owned(Value::InbuiltFunc(callable)),
-        );
+// Builds each node after its children using a stack of converted nodes,
+// rather than recursing, since programs can be deep.
+fn map_idents<Id: Clone, J>(ast: &Ast<Id>, f: &mut dyn FnMut(Id) -> J) -> Ast<J> {
+    enum Task<'a, Id: 'a, J> {
+        Visit(&'a Ast<Id>),
+        Call { args: usize },
+        Define(J),
+        Function {
+            params: Box<[Pattern<J>]>,
+            defaults: usize,
+            body: usize,
+        },
+    }
 
-        b.iter(|| black_box(eval(&program, &mut env)));
+    let mut tasks = vec![Task::Visit(ast)];
+    let mut done = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Visit(ast) => match *ast {
+                Ast::Lit(Value::Function(ref lambda)) => {
+                    tasks.push(Task::Function {
+                        params: lambda
+                            .params
+                            .iter()
+                            .map(|param| param.map(&mut |name: &Id| f(name.clone())))
+                            .collect(),
+                        defaults: lambda.defaults.len(),
+                        body: lambda.body.len(),
+                    });
+                    let children = lambda.defaults.iter().chain(lambda.body.iter());
+                    tasks.extend(children.rev().map(Task::Visit));
+                }
+                Ast::Lit(ref value) => done.push(Ast::Lit(map_value_idents(value, f))),
+                Ast::Variable(ref name) => done.push(Ast::Variable(f(name.clone()))),
+                Ast::Call(ref func, ref args) => {
+                    tasks.push(Task::Call { args: args.len() });
+                    tasks.extend(args.iter().rev().map(Task::Visit));
+                    tasks.push(Task::Visit(func));
+                }
+                Ast::Define(ref name, ref value) => {
+                    tasks.push(Task::Define(f(name.clone())));
+                    tasks.push(Task::Visit(value));
+                }
+                Ast::Include(ref path) => done.push(Ast::Include(path.clone())),
+            },
+            Task::Call { args } => {
+                let args = done.split_off(done.len() - args);
+                let func = done.pop().expect("a call has a function");
+                done.push(Ast::Call(Rc::new(func), args.into()));
+            }
+            Task::Define(name) => {
+                let value = done.pop().expect("a define has a value");
+                done.push(Ast::Define(name, Rc::new(value)));
+            }
+            Task::Function {
+                params,
+                defaults,
+                body,
+            } => {
+                let body = done.split_off(done.len() - body);
+                let defaults = done.split_off(done.len() - defaults);
+                done.push(Ast::Lit(Value::Function(Rc::new(Lambda {
+                    params,
+                    defaults: defaults.into(),
+                    body: body.into(),
+                }))));
+            }
+        }
     }
 
-    #[bench]
-    fn run_real_code(b: &mut Bencher) {
+    done.pop().expect("every node was converted")
+}
+
+// Only functions can make values deep, and those are handed back to
+// `map_idents`.
+fn map_value_idents<Id: Clone, J>(value: &Value<Id>, f: &mut dyn FnMut(Id) -> J) -> Value<J> {
+    match *value {
+        Value::Void => Value::Void,
+        Value::False => Value::False,
+        Value::Nil => Value::Nil,
+        Value::Int(i) => Value::Int(i),
+        Value::Function(_) => match map_idents(&Ast::Lit(value.clone()), f) {
+            Ast::Lit(Value::Function(ref lambda)) => Value::Function(lambda.clone()),
+            _ => unreachable!("a function maps to a function"),
+        },
+        Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+            panic!("native functions can't change the type of their identifiers")
+        }
+        Value::List(ref items) => Value::List(Rc::new(map_values_idents(items, f))),
+        Value::Values(ref items) => Value::Values(Rc::new(map_values_idents(items, f))),
+        Value::Keyword(ref name) => Value::Keyword(f(name.clone())),
+        Value::Symbol(ref name) => Value::Symbol(f(name.clone())),
+        Value::Str(ref text) => Value::Str(text.clone()),
+        Value::BigInt(ref big) => Value::BigInt(big.clone()),
+        Value::Bytes(ref bytes) => Value::Bytes(bytes.clone()),
+        Value::Partial(ref partial) => {
+            let func = map_value_idents(&partial.func, f);
+            Value::Partial(Rc::new(Partial {
+                func,
+                args: map_values_idents(&partial.args, f).into(),
+            }))
+        }
+        // Only the function is mapped, since what a thunk was forced to can
+        // contain the thunk itself.
+        Value::Thunk(ref thunk) => {
+            Value::Thunk(Rc::new(Thunk::new(map_value_idents(&thunk.func, f))))
+        }
+        // A new box, since the old one can't hold the new identifiers. One
+        // that holds itself would never finish, but a box can only be made
+        // by running a program, so a parsed program never has one.
+        Value::Box(ref cell) => {
+            Value::Box(Rc::new(RefCell::new(map_value_idents(&cell.borrow(), f))))
+        }
+        // The object has no identifiers in it, so it's still the same one.
+        Value::Foreign(ref foreign) => Value::Foreign(foreign.clone()),
+    }
+}
 
-        let mut env = IntMap::default();
+fn map_values_idents<Id: Clone, J>(
+    values: &[Value<Id>],
+    f: &mut dyn FnMut(Id) -> J,
+) -> Vec<Value<J>> {
+    values.iter().map(|value| map_value_idents(value, f)).collect()
+}
 
-        env.insert(hash_string("eq"), Cow::Owned(Value::InbuiltFunc(eq)));
-        env.insert(hash_string("add"), Cow::Owned(Value::InbuiltFunc(add)));
-        env.insert(hash_string("if"), Cow::Owned(Value::InbuiltFunc(if_)));
+// Keeps a stack of the nodes still to be visited rather than recursing,
+// since programs can be deep.
+struct Nodes<'a, Ident: 'a> {
+    pending: Vec<(usize, &'a Ast<Ident>)>,
+}
 
-        let (program, _) = ::combine::many1::<Vec<_>, _>(expr())
-            .easy_parse(REAL_CODE)
-            .unwrap();
+impl<'a, Ident> Iterator for Nodes<'a, Ident> {
+    type Item = (usize, &'a Ast<Ident>);
 
-        b.iter(|| {
-            let mut env = env.clone();
-            for line in &program {
-                black_box(eval(line, &mut env));
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, ast) = self.pending.pop()?;
+
+        let first = self.pending.len();
+        match *ast {
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let children = lambda.defaults.iter().chain(lambda.body.iter());
+                self.pending.extend(children.map(|child| (depth + 1, child)));
             }
-        });
+            Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+            Ast::Call(ref func, ref args) => {
+                self.pending.push((depth + 1, func));
+                self.pending.extend(args.iter().map(|arg| (depth + 1, arg)));
+            }
+            Ast::Define(_, ref value) => self.pending.push((depth + 1, value)),
+        }
+        // Visit the children in order.
+        self.pending[first..].reverse();
+
+        Some((depth, ast))
     }
+}
 
-    #[bench]
-    fn run_many_variables(b: &mut Bencher) {
+// Dropping an `Ast` the obvious way recurses once per level of nesting, so a
+// deep enough program would overflow the stack after parsing successfully.
+// Instead we move every child we own the last reference to out into a
+// worklist before it can be dropped, which leaves each node with nothing to
+// recurse into. Children that are shared elsewhere just lose a reference.
+impl<Ident> Drop for Ast<Ident> {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        take_children(self, &mut pending);
 
-        // This just takes anything and returns `Void`. We just
-        // want a function that can take any number of arguments
-        // but we don't want that function to do anything useful
-        // since, again, the benchmark should be of the
-        // interpreter's code.
-        fn ignore<T>(_: &[&Value<T>]) -> Value<T> {
-            Value::Void
+        while let Some(mut ast) = pending.pop() {
+            take_children(&mut ast, &mut pending);
         }
+    }
+}
 
-        let (program, _) = expr().easy_parse(MANY_VARIABLES).unwrap();
+fn take_children<Ident>(ast: &mut Ast<Ident>, out: &mut Vec<Ast<Ident>>) {
+    use std::mem;
 
-        let mut env = IntMap::default();
+    // Leaves can't recurse, so they're left to be dropped in place. This
+    // also stops us from taking the placeholders we leave behind.
+    let mut take = |child: &mut Ast<Ident>| match *child {
+        Ast::Lit(Value::Function(_)) | Ast::Call(..) | Ast::Define(..) => {
+            out.push(mem::replace(child, Ast::Lit(Value::Void)))
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+    };
 
-        env.insert(
-            hash_string("ignore"),
-            Cow::Owned(Value::InbuiltFunc(ignore)),
-        );
+    match *ast {
+        Ast::Lit(Value::Function(ref mut lambda)) => {
+            // Other copies of the function may still be using the body.
+            if let Some(lambda) = Rc::get_mut(lambda) {
+                lambda.defaults.iter_mut().chain(lambda.body.iter_mut()).for_each(take);
+            }
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        Ast::Call(ref mut func, ref mut args) => {
+            if let Some(func) = Rc::get_mut(func) {
+                take(func);
+            }
+            if let Some(args) = Rc::get_mut(args) {
+                args.iter_mut().for_each(take);
+            }
+        }
+        Ast::Define(_, ref mut value) => {
+            if let Some(value) = Rc::get_mut(value) {
+                take(value);
+            }
+        }
+    }
+}
+
+/// Functions are equal only if they are the same function: a `Function` is
+/// equal to copies of itself, an `InbuiltFunc` to the same native
+/// function, and a `HostFunc` to copies of the same closure. Two lambdas
+/// that happen to be written the same way are still different functions,
+/// since comparing their bodies could be arbitrarily expensive;
+/// `Value::equal` compares them structurally instead. The same
+/// goes for partially applied functions. Thunks are only equal to copies of
+/// themselves, whether or not they've been forced, boxes to copies of
+/// themselves, whatever they hold, and foreign values to copies of
+/// themselves. Lists are equal if their elements are, keywords and symbols
+/// if their names are, strings if their text is and bytes if their bytes
+/// are.
+/// Integers are equal if their values are, whether they're an `Int` or a
+/// `BigInt`. `Void`, `False` and `Nil` are each only equal to themselves.
+impl<Id: PartialEq> PartialEq for Value<Id> {
+    fn eq(&self, other: &Self) -> bool {
+        use std::ptr;
+        use Value::*;
+
+        match (self, other) {
+            (&Void, &Void) => true,
+            (&False, &False) => true,
+            (&Nil, &Nil) => true,
+            (&Int(a), &Int(b)) => a == b,
+            (BigInt(a), BigInt(b)) => a == b,
+            (&Int(a), BigInt(b)) | (BigInt(b), &Int(a)) => b.to_u64() == Some(a),
+            (Function(a), Function(b)) => Rc::ptr_eq(a, b),
+            (Partial(a), Partial(b)) => Rc::ptr_eq(a, b),
+            (Thunk(a), Thunk(b)) => Rc::ptr_eq(a, b),
+            (Box(a), Box(b)) => Rc::ptr_eq(a, b),
+            (Foreign(a), Foreign(b)) => Rc::ptr_eq(a, b),
+            (&InbuiltFunc(a), &InbuiltFunc(b)) => ptr::fn_addr_eq(a, b),
+            (&ReentrantFunc(a), &ReentrantFunc(b)) => ptr::fn_addr_eq(a, b),
+            (HostFunc(a), HostFunc(b)) => a.addr() == b.addr(),
+            (List(a), List(b)) => a == b,
+            (Values(a), Values(b)) => a == b,
+            (Keyword(a), Keyword(b)) => a == b,
+            (Symbol(a), Symbol(b)) => a == b,
+            (Str(a), Str(b)) => a == b,
+            (Bytes(a), Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// Values are only ordered against values of the same kind: ints by size,
+// whether they're an `Int` or a `BigInt`, strings by their characters,
+// bytes by their bytes and lists element by element. Anything else is only
+// ordered against itself, the same way `==` compares it.
+impl<Id: PartialEq> PartialOrd for Value<Id> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (&Value::Int(a), &Value::Int(b)) => a.partial_cmp(&b),
+            (Value::BigInt(a), Value::BigInt(b)) => a.partial_cmp(b),
+            (&Value::Int(a), Value::BigInt(b)) => BigInt::from(a).partial_cmp(b),
+            (Value::BigInt(a), &Value::Int(b)) => (**a).partial_cmp(&BigInt::from(b)),
+            (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.partial_cmp(b),
+            (Value::List(a), Value::List(b)) | (Value::Values(a), Value::Values(b)) => {
+                a.partial_cmp(b)
+            }
+            _ if self == other => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+impl<Id: Eq> Eq for Value<Id> {}
+
+// Agrees with `==`, so functions hash by which function they are rather
+// than by how they're written, and can be keys alongside data. Thunks,
+// boxes and foreign values hash the same way, so forcing or changing one
+// doesn't change its hash.
+impl<Id: Hash> Hash for Value<Id> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Equal to the `Int` with the same value, so it has to hash like it.
+        if let Value::BigInt(ref big) = *self {
+            if let Some(i) = big.to_u64() {
+                return Value::<Id>::Int(i).hash(state);
+            }
+        }
+
+        ::std::mem::discriminant(self).hash(state);
+        match *self {
+            Value::Void | Value::False | Value::Nil => {}
+            Value::Int(i) => i.hash(state),
+            Value::Function(ref lambda) => Rc::as_ptr(lambda).hash(state),
+            Value::Partial(ref partial) => Rc::as_ptr(partial).hash(state),
+            Value::Thunk(ref thunk) => Rc::as_ptr(thunk).hash(state),
+            Value::Box(ref cell) => Rc::as_ptr(cell).hash(state),
+            Value::Foreign(ref foreign) => Rc::as_ptr(foreign).hash(state),
+            Value::InbuiltFunc(func) => (func as usize).hash(state),
+            Value::ReentrantFunc(func) => (func as usize).hash(state),
+            Value::HostFunc(ref func) => func.addr().hash(state),
+            Value::List(ref items) | Value::Values(ref items) => items.hash(state),
+            Value::Keyword(ref name) | Value::Symbol(ref name) => name.hash(state),
+            Value::Str(ref text) => text.hash(state),
+            Value::Bytes(ref bytes) => bytes.hash(state),
+            Value::BigInt(ref big) => big.hash(state),
+        }
+    }
+}
+
+impl<Id: PartialEq> Value<Id> {
+    /// Structural equality, where `==` is identity. Two lambdas are equal if
+    /// they have the same parameters and bodies that are written the same
+    /// way, so the same text parsed twice gives equal functions. Natives
+    /// still compare by address, since there's nothing else to go on, and
+    /// boxes do too, since what they hold can change.
+    ///
+    /// Only boxes and thunks can hold themselves, and they're compared by
+    /// identity, so there are no cycles to follow, but the lists and bodies
+    /// being compared can be arbitrarily deep, so this walks them with a
+    /// worklist rather than recursing.
+    pub fn equal(&self, other: &Self) -> bool {
+        let mut pending = Vec::new();
+
+        if !values_match(self, other, &mut pending) {
+            return false;
+        }
+
+        while let Some(next) = pending.pop() {
+            let same = match next {
+                Pending::Values(a, b) => values_match(a, b, &mut pending),
+                Pending::Asts(a, b) => match (a, b) {
+                    (Ast::Lit(a), Ast::Lit(b)) => {
+                        pending.push(Pending::Values(a, b));
+                        true
+                    }
+                    (Ast::Variable(a), Ast::Variable(b)) => a == b,
+                    (Ast::Call(f1, a1), Ast::Call(f2, a2)) => {
+                        pending.push(Pending::Asts(f1, f2));
+                        asts_match(a1, a2, &mut pending)
+                    }
+                    (Ast::Define(n1, v1), Ast::Define(n2, v2)) => {
+                        pending.push(Pending::Asts(v1, v2));
+                        n1 == n2
+                    }
+                    (Ast::Include(a), Ast::Include(b)) => a == b,
+                    _ => false,
+                },
+            };
+
+            if !same {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Identity, which is what the `same` native compares by. This is `==`
+    /// except that lists, multiple values, strings and bytes are only the
+    /// same as copies of themselves, rather than of anything holding equal
+    /// elements, so it never looks inside them.
+    pub fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) | (Value::Values(a), Value::Values(b)) => {
+                Rc::ptr_eq(a, b)
+            }
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            (Value::Bytes(a), Value::Bytes(b)) => Rc::ptr_eq(a, b),
+            _ => self == other,
+        }
+    }
+}
+
+// A pair of things `Value::equal` has still to compare.
+enum Pending<'a, Id> {
+    Values(&'a Value<Id>, &'a Value<Id>),
+    Asts(&'a Ast<Id>, &'a Ast<Id>),
+}
+
+// Compares whatever can be compared without looking inside another value
+// or an `Ast`, and leaves the rest in `pending`.
+fn values_match<'a, Id: PartialEq>(
+    a: &'a Value<Id>,
+    b: &'a Value<Id>,
+    pending: &mut Vec<Pending<'a, Id>>,
+) -> bool {
+    match (a, b) {
+        (Value::Function(a), Value::Function(b)) => {
+            Rc::ptr_eq(a, b)
+                || (a.params == b.params
+                    && asts_match(&a.defaults, &b.defaults, pending)
+                    && asts_match(&a.body, &b.body, pending))
+        }
+        (Value::List(a), Value::List(b)) | (Value::Values(a), Value::Values(b)) => {
+            values_all_match(a, b, pending)
+        }
+        (Value::Partial(a), Value::Partial(b)) => {
+            Rc::ptr_eq(a, b) || {
+                pending.push(Pending::Values(&a.func, &b.func));
+                values_all_match(&a.args, &b.args, pending)
+            }
+        }
+        _ => a == b,
+    }
+}
+
+fn values_all_match<'a, Id>(
+    a: &'a [Value<Id>],
+    b: &'a [Value<Id>],
+    pending: &mut Vec<Pending<'a, Id>>,
+) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    pending.extend(a.iter().zip(b).map(|(a, b)| Pending::Values(a, b)));
+    true
+}
+
+fn asts_match<'a, Id>(
+    a: &'a [Ast<Id>],
+    b: &'a [Ast<Id>],
+    pending: &mut Vec<Pending<'a, Id>>,
+) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    pending.extend(a.iter().zip(b).map(|(a, b)| Pending::Asts(a, b)));
+    true
+}
+
+/// Why evaluating a program failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError<Id> {
+    /// A variable was read that isn't bound in any enclosing scope.
+    UnboundVariable(Id),
+    /// Something other than a function was called.
+    NotAFunction,
+    /// A native function was called with a number of arguments outside
+    /// `min..=max`. A `max` of `usize::MAX` means there's no limit.
+    ArgumentCount { min: usize, max: usize, got: usize },
+    /// `eval_with` used up all of its fuel.
+    OutOfFuel,
+    /// `eval_with` nested calls more than `max` deep.
+    TooDeep { max: usize },
+    /// The debugger attached to `eval_with` stopped evaluation.
+    Aborted,
+    /// An `include` couldn't be followed. This is boxed so that it doesn't
+    /// make every other error bigger.
+    Include(Box<IncludeError>),
+    /// A list pattern with `expected` elements was matched against a list
+    /// with `got` elements, or against something that isn't a list if
+    /// `got` is `None`.
+    PatternMismatch { expected: usize, got: Option<usize> },
+    /// A keyword argument named something that isn't a parameter.
+    UnknownKeyword(Id),
+    /// A parameter was given an argument both by position and by keyword,
+    /// or by the same keyword twice.
+    DuplicateArgument(Id),
+    /// A call with keyword arguments gave nothing for a required parameter,
+    /// or ended in a keyword with no argument after it.
+    MissingArgument(Id),
+    /// After the first keyword argument, something other than a keyword
+    /// came where the next one should have been.
+    ExpectedKeyword,
+    /// Code was evaluated from data that doesn't describe any code.
+    NotCode(&'static str),
+    /// A native was given `count` values at once as one of its arguments.
+    MultipleValues { count: usize },
+    /// A native that does arithmetic was given something other than an
+    /// integer.
+    NotAnInt,
+    /// A native that works on lists was given something else.
+    NotAList,
+    /// A native that works on strings was given something else.
+    NotAString,
+    /// A native that works on bytes was given something else.
+    NotBytes,
+    /// A native that works on boxes was given something else.
+    NotABox,
+    /// A function registered with `Interpreter::register_fn` was given an
+    /// argument that doesn't convert to the type it takes. `index` counts
+    /// from 0, though the message counts from 1. The error is boxed like
+    /// `Include`'s.
+    WrongType { index: usize, error: Box<TypeError> },
+    /// A native was asked for the bytes from `start` up to `end` of `len`
+    /// bytes, which aren't all there.
+    OutOfRange { start: u64, end: u64, len: usize },
+    /// Like `OutOfRange`, but for the characters of a string of `len`
+    /// characters.
+    CharsOutOfRange { start: u64, end: u64, len: usize },
+    /// Bytes were read as text but aren't UTF-8, from the byte `at` on.
+    InvalidUtf8 { at: usize },
+    /// Values were sorted that can't be ordered against each other, such
+    /// as an int and a string.
+    Incomparable,
+    DivideByZero,
+    /// `random` was asked for a number below 0, and there are none.
+    EmptyRange,
+    /// `assert` was given something false. `span` is where the asserted
+    /// expression is in the source, which only `report::run_source` knows.
+    AssertionFailed { message: Rc<String>, span: Option<Span> },
+    /// The program called `error`, with `values` being the arguments after
+    /// the message.
+    User { message: Rc<String>, values: Rc<Vec<Value<Id>>> },
+    /// A form given to `eval_with` measured `got` by `metric`, which is over
+    /// the `max` that `EvalOptions::limits` allows.
+    TooComplex { metric: Metric, got: usize, max: usize },
+    /// Defines and natives had made `used` bytes of new values, which is
+    /// over the `limit` that `EvalOptions::max_heap_bytes` allows.
+    MemoryLimitExceeded { used: usize, limit: usize },
+    /// More variables were bound at once than the `limit` that
+    /// `EvalOptions::max_bindings` allows.
+    TooManyBindings { limit: usize },
+    /// A native made an integer too big for `Int` out of arguments that
+    /// weren't, without `EvalOptions::auto_promote` to let it.
+    IntegerOverflow,
+    /// A `ReentrantFunc` asked to evaluate something when it was called by
+    /// something other than `eval` or `eval_with`.
+    Unsupported,
+    /// The program called `yield` with this value. Only `eval_resumable`
+    /// can pause there, so anything else fails with this.
+    Yielded(Value<Id>),
+    /// A `Continuation` was resumed after it had been resumed already.
+    AlreadyResumed,
+    /// A native panicked, and `EvalOptions::catch_native_panics` caught it.
+    /// `name` is the variable it was called through, if any, and `message`
+    /// is what it panicked with, if that was a string.
+    NativePanic { name: Option<Id>, message: String },
+}
+
+// Identifiers are shown with `{:?}`, or with `EvalError::display_with` for
+// those that aren't `Debug`.
+impl<Id: Debug> fmt::Display for EvalError<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_message(f, &|name, f| write!(f, "{:?}", name))
+    }
+}
+
+impl<Id> EvalError<Id> {
+    /// Something that displays this error the way `Display` does, with
+    /// each identifier in it written by `name`.
+    pub fn display_with<F>(&self, name: F) -> DisplayWith<'_, Id, F>
+    where
+        F: Fn(&Id, &mut fmt::Formatter) -> fmt::Result,
+    {
+        DisplayWith { error: self, name }
+    }
+
+    /// Something that displays this error the way `Display` does, with
+    /// `<identifier>` in place of each identifier in it, for identifiers
+    /// that can't be shown.
+    pub fn display_opaque(&self) -> DisplayWith<'_, Id, fn(&Id, &mut fmt::Formatter) -> fmt::Result> {
+        self.display_with(|_, f| write!(f, "<identifier>"))
+    }
+
+    /// Which kind of error this is, as a name that programs can write as a
+    /// symbol. This is what `try` gives its handler along with the message.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            EvalError::UnboundVariable(_) => "unbound",
+            EvalError::NotAFunction => "notafunction",
+            EvalError::ArgumentCount { .. } => "argumentcount",
+            EvalError::OutOfFuel => "outoffuel",
+            EvalError::TooDeep { .. } => "toodeep",
+            EvalError::Aborted => "aborted",
+            EvalError::Include(_) => "include",
+            EvalError::PatternMismatch { .. } => "patternmismatch",
+            EvalError::UnknownKeyword(_) => "unknownkeyword",
+            EvalError::DuplicateArgument(_) => "duplicateargument",
+            EvalError::MissingArgument(_) => "missingargument",
+            EvalError::ExpectedKeyword => "expectedkeyword",
+            EvalError::NotCode(_) => "notcode",
+            EvalError::MultipleValues { .. } => "multiplevalues",
+            EvalError::NotAnInt => "notanint",
+            EvalError::NotAList => "notalist",
+            EvalError::NotAString => "notastring",
+            EvalError::NotBytes => "notbytes",
+            EvalError::NotABox => "notabox",
+            EvalError::WrongType { .. } => "wrongtype",
+            EvalError::OutOfRange { .. } | EvalError::CharsOutOfRange { .. } => "outofrange",
+            EvalError::InvalidUtf8 { .. } => "invalidutf",
+            EvalError::Incomparable => "incomparable",
+            EvalError::DivideByZero => "dividebyzero",
+            EvalError::EmptyRange => "emptyrange",
+            EvalError::AssertionFailed { .. } => "assertion",
+            EvalError::User { .. } => "user",
+            EvalError::TooComplex { .. } => "toocomplex",
+            EvalError::MemoryLimitExceeded { .. } => "memorylimit",
+            EvalError::TooManyBindings { .. } => "toomanybindings",
+            EvalError::IntegerOverflow => "overflow",
+            EvalError::Unsupported => "unsupported",
+            EvalError::Yielded(_) => "yield",
+            EvalError::AlreadyResumed => "resumed",
+            EvalError::NativePanic { .. } => "nativepanic",
+        }
+    }
+
+    /// Whether this is a limit set by the host being reached, or the
+    /// debugger stopping evaluation. These can't be caught by `try`, since
+    /// otherwise a program could carry on past them.
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            *self,
+            EvalError::OutOfFuel
+                | EvalError::TooDeep { .. }
+                | EvalError::Aborted
+                | EvalError::TooComplex { .. }
+                | EvalError::MemoryLimitExceeded { .. }
+                | EvalError::TooManyBindings { .. }
+        )
+    }
+
+    fn write_message(
+        &self,
+        f: &mut fmt::Formatter,
+        name: &dyn Fn(&Id, &mut fmt::Formatter) -> fmt::Result,
+    ) -> fmt::Result {
+        match *self {
+            EvalError::UnboundVariable(ref id) => {
+                write!(f, "Variable does not exist: ")?;
+                name(id, f)
+            }
+            EvalError::NotAFunction => write!(f, "Attempted to call a non-function"),
+            EvalError::ArgumentCount { min, max, got } if min == max => {
+                write!(f, "Expected {} arguments, got {}", min, got)
+            }
+            EvalError::ArgumentCount { min, max, got } if max == usize::MAX => {
+                write!(f, "Expected at least {} arguments, got {}", min, got)
+            }
+            EvalError::ArgumentCount { min, max, got } => {
+                write!(f, "Expected {} to {} arguments, got {}", min, max, got)
+            }
+            EvalError::OutOfFuel => write!(f, "Ran out of fuel"),
+            EvalError::TooDeep { max } => write!(f, "Calls nested more than {} deep", max),
+            EvalError::Aborted => write!(f, "Evaluation was stopped by the debugger"),
+            EvalError::Include(ref e) => write!(f, "{}", e),
+            EvalError::PatternMismatch { expected, got: Some(got) } => write!(
+                f,
+                "Expected a list of {} elements to destructure, got {}",
+                expected, got
+            ),
+            EvalError::PatternMismatch { expected, got: None } => write!(
+                f,
+                "Expected a list of {} elements to destructure, got something else",
+                expected
+            ),
+            EvalError::UnknownKeyword(ref id) => {
+                write!(f, "No parameter is called ")?;
+                name(id, f)
+            }
+            EvalError::DuplicateArgument(ref id) => {
+                write!(f, "Argument ")?;
+                name(id, f)?;
+                write!(f, " was given more than once")
+            }
+            EvalError::MissingArgument(ref id) => {
+                write!(f, "No argument for ")?;
+                name(id, f)
+            }
+            EvalError::ExpectedKeyword => {
+                write!(f, "Expected a keyword before each argument after the first keyword")
+            }
+            EvalError::NotCode(message) => {
+                write!(f, "Can't evaluate data that isn't code: {}", message)
+            }
+            EvalError::MultipleValues { count } => {
+                write!(f, "Expected a single value, got {} values", count)
+            }
+            EvalError::NotAnInt => write!(f, "Expected an integer"),
+            EvalError::NotAList => write!(f, "Expected a list"),
+            EvalError::NotAString => write!(f, "Expected a string"),
+            EvalError::NotBytes => write!(f, "Expected bytes"),
+            EvalError::NotABox => write!(f, "Expected a box"),
+            EvalError::WrongType { index, ref error } => {
+                let (expected, found) = (error.expected, error.found);
+                write!(f, "Expected {} as argument {}, got {}", expected, index + 1, found)
+            }
+            EvalError::OutOfRange { start, end, len } => {
+                write!(f, "Bytes {} to {} are out of range of {} bytes", start, end, len)
+            }
+            EvalError::CharsOutOfRange { start, end, len } => write!(
+                f,
+                "Characters {} to {} are out of range of {} characters",
+                start, end, len
+            ),
+            EvalError::InvalidUtf8 { at } => write!(f, "Invalid UTF-8 at byte {}", at),
+            EvalError::Incomparable => write!(f, "Can't order values of different kinds"),
+            EvalError::DivideByZero => write!(f, "Divided by zero"),
+            EvalError::EmptyRange => write!(f, "There are no numbers below 0 to pick from"),
+            EvalError::TooComplex { metric, got, max } => {
+                write!(f, "The program's {} is {}, over the limit of {}", metric, got, max)
+            }
+            EvalError::MemoryLimitExceeded { used, limit } => {
+                write!(f, "Used {} bytes of memory, over the limit of {}", used, limit)
+            }
+            EvalError::TooManyBindings { limit } => {
+                write!(f, "Bound more than {} variables at once", limit)
+            }
+            EvalError::IntegerOverflow => write!(f, "Integer too big for 64 bits"),
+            EvalError::AssertionFailed { ref message, .. } => {
+                write!(f, "Assertion failed: {}", message)
+            }
+            EvalError::User { ref message, ref values } => {
+                write!(f, "{}", message)?;
+                for value in values.iter() {
+                    write!(f, " {}", value)?;
+                }
+                Ok(())
+            }
+            EvalError::Unsupported => {
+                write!(f, "Natives that evaluate code are only supported by `eval` and `eval_with`")
+            }
+            EvalError::Yielded(ref value) => {
+                write!(f, "Yielded {} outside of `eval_resumable`", value)
+            }
+            EvalError::AlreadyResumed => write!(f, "The continuation was resumed already"),
+            EvalError::NativePanic { name: Some(ref id), ref message } => {
+                write!(f, "Native ")?;
+                name(id, f)?;
+                write!(f, " panicked: {}", message)
+            }
+            EvalError::NativePanic { name: None, ref message } => {
+                write!(f, "A native panicked: {}", message)
+            }
+        }
+    }
+}
+
+impl<Id: Debug> error::Error for EvalError<Id> {}
+
+/// An `EvalError` displayed with `EvalError::display_with`.
+pub struct DisplayWith<'a, Id: 'a, F> {
+    error: &'a EvalError<Id>,
+    name: F,
+}
+
+impl<'a, Id, F> fmt::Display for DisplayWith<'a, Id, F>
+where
+    F: Fn(&Id, &mut fmt::Formatter) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.write_message(f, &self.name)
+    }
+}
+
+/// Evaluate `program`, reading and defining globals in `variables`.
+///
+/// A call evaluates the function and then each argument from left to
+/// right, all in the caller's scope and before anything is bound, whether
+/// the function is a lambda or a native. So a define in one argument is
+/// visible to the arguments after it, and to the caller afterwards. Every
+/// backend evaluates calls in this order.
+///
+/// Scoping is dynamic. A define inside a lambda's body binds the name in
+/// that call's own scope, replacing a parameter of the same name, and the
+/// binding goes when the call returns. Lambdas don't capture anything: a
+/// lambda reads its free variables when it's called, from the scope it's
+/// called in, so one made before a define sees the define if it's called
+/// after it.
+///
+/// A call that is the last form of a lambda's body, and whose function
+/// turns out to be another lambda, is a tail call: it takes the place of
+/// the call it's in rather than nesting inside it, so recursion through
+/// tail calls runs in constant stack however deep it goes. A call in an
+/// argument, a define or a default isn't in tail position, and neither is
+/// a call to a native. `eval_with` makes tail calls too, except while a
+/// debugger is attached, which has to see every call return.
+pub fn eval<'b, Id: Clone + Eq + Hash, S: BuildHasher>(
+    program: &'b Ast<Id>,
+    variables: &mut HashMap<Id, Cow<'b, Value<Id>>, S>,
+) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+    eval_metered(program, &mut Scope::Global(variables), &mut Unlimited, 0)
+}
+
+/// Bounds on how much work `eval_with` may do before giving up, and what
+/// it should record along the way.
+#[derive(Debug)]
+pub struct EvalOptions<Id: Eq + Hash> {
+    /// How many more expressions may be evaluated, or `None` for no limit.
+    /// This counts down as evaluation goes, so the same `EvalOptions` can
+    /// be shared by every form of a program.
+    pub fuel: Option<u64>,
+    /// Whether running out of `fuel` pauses `eval_resumable`, which gives
+    /// back a `Continuation` to carry on with once there's more, rather
+    /// than failing with `OutOfFuel`. `eval_with` can't pause, so it fails
+    /// either way.
+    pub suspend_on_fuel: bool,
+    /// How deeply calls to user-defined functions may nest, or `None` for
+    /// no limit. Tail calls don't nest, as `eval` describes.
+    pub max_depth: Option<usize>,
+    /// Where to count calls to each function, or `None` to not profile.
+    pub profile: Option<Profile<Id>>,
+    /// Where to record which parts of the program were evaluated, or `None`
+    /// to not track coverage.
+    pub coverage: Option<Coverage>,
+    /// What to tell about each node as it's evaluated, or `None` to not
+    /// debug.
+    pub debugger: Option<Debugging<Id>>,
+    /// Where to find included files, or `None` to refuse to include
+    /// anything. Without the `parse` feature nothing can be included.
+    #[cfg(feature = "parse")]
+    pub includes: Option<Includes<Id>>,
+    /// Where to send a trace of evaluation, or `None` to not trace.
+    pub trace: Option<Tracer<Id>>,
+    /// How big a form may be before it's turned away without running any
+    /// of it, or `None` for no limit.
+    pub limits: Option<AstLimits>,
+    /// How many bytes of new values may be made, or `None` for no limit.
+    /// Each new variable counts its binding, and each value a native
+    /// returns counts whatever it has on the heap that its arguments
+    /// didn't, by `Value::heap_size`. Nothing is taken off when values are
+    /// dropped, so this is a bound on how much is allocated.
+    pub max_heap_bytes: Option<usize>,
+    /// How many bytes have been counted against `max_heap_bytes`. Like
+    /// `fuel`, this carries on from one form to the next, and nothing is
+    /// counted while there's no limit.
+    pub heap_used: usize,
+    /// How many variables may be bound at once, or `None` for no limit.
+    /// This counts the globals that evaluation defines, and the parameters
+    /// and defines of every call that hasn't returned yet, but not the
+    /// globals that were there to start with.
+    pub max_bindings: Option<usize>,
+    /// How many globals have been counted against `max_bindings`. Like
+    /// `heap_used`, this carries on from one form to the next, and nothing
+    /// is counted while there's no limit.
+    pub globals_bound: usize,
+    /// Whether `add` and `mul` may go on to a `BigInt` when a result is too
+    /// big for an `Int`, rather than failing with `IntegerOverflow`. A
+    /// `BigInt` that was there already, such as a long literal, can always
+    /// be added to.
+    pub auto_promote: bool,
+    /// What calls to pure functions returned, or `None` to call them every
+    /// time.
+    pub memo: Option<Memo<Id>>,
+    /// Which values conditionals treat as true.
+    pub truth: TruthPolicy<Id>,
+    /// Whether a native that panics fails with `NativePanic` rather than
+    /// unwinding out of `eval_with`. The panic hook still runs, so the
+    /// panic is still printed unless the host has replaced it. This is on
+    /// by default, and does nothing without std, which can't catch panics.
+    pub catch_native_panics: bool,
+}
+
+impl<Id: Eq + Hash> Default for EvalOptions<Id> {
+    fn default() -> Self {
+        EvalOptions {
+            fuel: None,
+            suspend_on_fuel: false,
+            max_depth: None,
+            profile: None,
+            coverage: None,
+            debugger: None,
+            #[cfg(feature = "parse")]
+            includes: None,
+            trace: None,
+            limits: None,
+            max_heap_bytes: None,
+            heap_used: 0,
+            max_bindings: None,
+            globals_bound: 0,
+            auto_promote: false,
+            memo: None,
+            truth: TruthPolicy::SchemeLike,
+            catch_native_panics: cfg!(feature = "std"),
+        }
+    }
+}
+
+impl<Id: Eq + Hash> EvalOptions<Id> {
+    /// Send a `TraceEvent` to `sink` for every call, return, define and
+    /// variable read from now on.
+    pub fn trace<F: FnMut(TraceEvent<Id>) + 'static>(&mut self, sink: F) -> &mut Self {
+        self.trace = Some(Tracer::new(sink));
+        self
+    }
+
+    /// Remember what calls to the functions called `names` return, as the
+    /// `memo` module describes, rather than making the same call twice.
+    pub fn memoize<I: IntoIterator<Item = Id>>(&mut self, names: I) -> &mut Self
+    where
+        Id: Clone,
+    {
+        self.memo = Some(Memo::new(names));
+        self
+    }
+
+    /// Forget what's been recorded so far, for a host that puts its
+    /// environment back with `Interpreter::reset` between runs: memoized
+    /// results, profile counts, and what's been counted against
+    /// `max_heap_bytes` and `max_bindings`. The limits and `fuel` are left
+    /// as they are.
+    pub fn reset(&mut self)
+    where
+        Id: Clone,
+    {
+        if let Some(ref mut memo) = self.memo {
+            memo.clear();
+        }
+        if let Some(ref mut profile) = self.profile {
+            *profile = Profile::new();
+        }
+        self.heap_used = 0;
+        self.globals_bound = 0;
+    }
+
+    // Count `bytes` against `max_heap_bytes`, if there is one.
+    fn charge(&mut self, bytes: usize) -> Result<(), EvalError<Id>> {
+        match self.max_heap_bytes {
+            Some(limit) => {
+                self.heap_used = self.heap_used.saturating_add(bytes);
+                if self.heap_used > limit {
+                    return Err(EvalError::MemoryLimitExceeded { used: self.heap_used, limit });
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Calls counted by `eval_with`. Calls are told apart by the name of the
+/// variable the function was called through, or by which function it was
+/// if it wasn't called through a variable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile<Id: Eq + Hash> {
+    counts: HashMap<Callee<Id>, ProfileEntry<Id>>,
+    // The functions currently being called, innermost last.
+    stack: Vec<Callee<Id>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Callee<Id> {
+    Named(Id),
+    Anonymous(usize),
+}
+
+/// How often one function was called.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileEntry<Id> {
+    /// The variable the function was called through, if any.
+    pub name: Option<Id>,
+    pub calls: u64,
+    /// How many expressions were evaluated in the function's own body, not
+    /// counting those in the functions it called. Natives always have 0.
+    pub nodes: u64,
+}
+
+impl<Id: Clone + Eq + Hash> Profile<Id> {
+    pub fn new() -> Self {
+        Profile {
+            counts: HashMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Every function that was called, most expensive first.
+    pub fn entries(&self) -> Vec<ProfileEntry<Id>> {
+        let mut entries = self.counts.values().cloned().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| ::std::cmp::Reverse((entry.nodes, entry.calls)));
+        entries
+    }
+
+    fn call(&mut self, callee: &Ast<Id>, func: &Value<Id>) {
+        let key = match (callee, func) {
+            (Ast::Variable(name), _) => Callee::Named(name.clone()),
+            (_, Value::Function(lambda)) => Callee::Anonymous(Rc::as_ptr(lambda) as usize),
+            (_, &Value::InbuiltFunc(func)) => Callee::Anonymous(func as usize),
+            (_, &Value::ReentrantFunc(func)) => Callee::Anonymous(func as usize),
+            (_, Value::HostFunc(func)) => Callee::Anonymous(func.addr() as usize),
+            _ => return,
+        };
+
+        let name = match key {
+            Callee::Named(ref name) => Some(name.clone()),
+            Callee::Anonymous(_) => None,
+        };
+        self.counts
+            .entry(key.clone())
+            .or_insert(ProfileEntry {
+                name,
+                calls: 0,
+                nodes: 0,
+            })
+            .calls += 1;
+        self.stack.push(key);
+    }
+}
+
+impl<Id: Clone + Eq + Hash> Default for Profile<Id> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Evaluate `program` like `eval`, but fail with `OutOfFuel`, `TooDeep`,
+/// `MemoryLimitExceeded` or `TooManyBindings` rather than going past the
+/// limits in `options`, and profile it if asked to.
+pub fn eval_with<'b, Id: Clone + Eq + Hash + 'static, S: BuildHasher>(
+    program: &'b Ast<Id>,
+    variables: &mut HashMap<Id, Cow<'b, Value<Id>>, S>,
+    options: &mut EvalOptions<Id>,
+) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+    if let Some(ref limits) = options.limits {
+        limits.check(&analysis::metrics(program))?;
+    }
+    let result = eval_metered(program, &mut Scope::Global(variables), options, 0);
+    // An error leaves the calls it happened in on the stack.
+    if let Some(ref mut profile) = options.profile {
+        profile.stack.clear();
+    }
+    if let Some(ref mut debugger) = options.debugger {
+        debugger.reset();
+    }
+    #[cfg(feature = "parse")]
+    if let Some(ref mut includes) = options.includes {
+        includes.reset();
+    }
+    result
+}
+
+// Keeps track of `EvalOptions` for `eval_metered`. `eval` uses `Unlimited`,
+// whose checks compile away to nothing.
+trait Meter<Id: Clone> {
+    fn step<S: BuildHasher>(&mut self, node: &Ast<Id>, env: &Scope<Id, S>) -> Result<(), EvalError<Id>>
+    where
+        Id: Eq + Hash;
+    fn exit(&mut self, node: &Ast<Id>, result: &Result<Cow<Value<Id>>, EvalError<Id>>);
+    fn define(
+        &mut self,
+        name: &Id,
+        old: Option<&Value<Id>>,
+        new: &Value<Id>,
+    ) -> Result<(), EvalError<Id>>;
+    // A variable was bound in the innermost frame of `scope`, and `new` if
+    // it wasn't bound anywhere in it before.
+    fn bind<S: BuildHasher>(&mut self, scope: &Scope<Id, S>, new: bool) -> Result<(), EvalError<Id>>
+    where
+        Id: Eq + Hash;
+    // A native made `value` out of `args`.
+    fn allocate(&mut self, value: &Value<Id>, args: &[&Value<Id>]) -> Result<(), EvalError<Id>>;
+    // Whether a native may make a `BigInt` out of `Int`s.
+    fn auto_promote(&self) -> bool;
+    // Whether a native that panics fails with `NativePanic`.
+    fn catch_panics(&self) -> bool;
+    // What reentrant natives that branch on a value go by.
+    fn truth(&self) -> TruthPolicy<Id>;
+    fn enter(&mut self, depth: usize) -> Result<(), EvalError<Id>>;
+    fn lookup(&mut self, name: &Id);
+    fn call(&mut self, callee: &Ast<Id>, func: &Value<Id>, depth: usize);
+    fn leave(&mut self, depth: usize);
+    // Whether a tail call may replace the call it's in, which skips `exit`
+    // for the node that made it.
+    fn tail_calls(&self) -> bool;
+    // What calling the lambda `func` through `callee` returned before, or
+    // what to remember the result under.
+    fn recall(
+        &mut self,
+        callee: &Ast<Id>,
+        func: &Value<Id>,
+        args: &Arguments<Cow<Value<Id>>>,
+    ) -> Recall<Id>;
+    fn remember(&mut self, key: MemoKey<Id>, result: &Value<Id>);
+    fn include<'b, S: BuildHasher>(
+        &mut self,
+        path: &str,
+        scope: &mut Scope<'_, 'b, Id, S>,
+        depth: usize,
+    ) -> Result<Cow<'b, Value<Id>>, EvalError<Id>>
+    where
+        Id: Eq + Hash;
+}
+
+struct Unlimited;
+
+impl<Id: Clone> Meter<Id> for Unlimited {
+    #[inline(always)]
+    fn step<S>(&mut self, _: &Ast<Id>, _: &Scope<Id, S>) -> Result<(), EvalError<Id>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn exit(&mut self, _: &Ast<Id>, _: &Result<Cow<Value<Id>>, EvalError<Id>>) {}
+
+    #[inline(always)]
+    fn define(
+        &mut self,
+        _: &Id,
+        _: Option<&Value<Id>>,
+        _: &Value<Id>,
+    ) -> Result<(), EvalError<Id>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn bind<S>(&mut self, _: &Scope<Id, S>, _: bool) -> Result<(), EvalError<Id>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn allocate(&mut self, _: &Value<Id>, _: &[&Value<Id>]) -> Result<(), EvalError<Id>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn auto_promote(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn catch_panics(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    fn truth(&self) -> TruthPolicy<Id> {
+        TruthPolicy::SchemeLike
+    }
+
+    #[inline(always)]
+    fn enter(&mut self, _: usize) -> Result<(), EvalError<Id>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn lookup(&mut self, _: &Id) {}
+
+    #[inline(always)]
+    fn call(&mut self, _: &Ast<Id>, _: &Value<Id>, _: usize) {}
+
+    #[inline(always)]
+    fn leave(&mut self, _: usize) {}
+
+    #[inline(always)]
+    fn tail_calls(&self) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    fn recall(&mut self, _: &Ast<Id>, _: &Value<Id>, _: &Arguments<Cow<Value<Id>>>) -> Recall<Id> {
+        Recall::Skip
+    }
+
+    #[inline(always)]
+    fn remember(&mut self, _: MemoKey<Id>, _: &Value<Id>) {}
+
+    fn include<'b, S>(
+        &mut self,
+        _: &str,
+        _: &mut Scope<'_, 'b, Id, S>,
+        _: usize,
+    ) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+        Err(EvalError::Include(Box::new(IncludeError::Disabled)))
+    }
+}
+
+impl<Id: Clone + Eq + Hash> Meter<Id> for EvalOptions<Id> {
+    fn step<S: BuildHasher>(&mut self, node: &Ast<Id>, env: &Scope<Id, S>) -> Result<(), EvalError<Id>> {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.enter(node, env)?;
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.mark(node);
+        }
+        if let Some(ref mut profile) = self.profile {
+            if let Some(callee) = profile.stack.last() {
+                if let Some(entry) = profile.counts.get_mut(callee) {
+                    entry.nodes += 1;
+                }
+            }
+        }
+
+        match self.fuel {
+            Some(0) => Err(EvalError::OutOfFuel),
+            Some(ref mut fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn exit(&mut self, node: &Ast<Id>, result: &Result<Cow<Value<Id>>, EvalError<Id>>) {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.exit(node, result.as_ref().map(|value| value.as_ref()));
+        }
+    }
+
+    fn define(
+        &mut self,
+        name: &Id,
+        old: Option<&Value<Id>>,
+        new: &Value<Id>,
+    ) -> Result<(), EvalError<Id>> {
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.define(name, old, new);
+        }
+        if let Some(ref mut trace) = self.trace {
+            trace.emit(TraceKind::Define { name: name.clone() });
+        }
+        if let Some(ref mut memo) = self.memo {
+            memo.forget(name);
+        }
+        // The value is either part of the program or was counted when it
+        // was made, so only a new binding takes any more memory.
+        match old {
+            Some(_) => Ok(()),
+            None => self.charge(mem::size_of::<(Id, Cow<Value<Id>>)>()),
+        }
+    }
+
+    fn bind<S: BuildHasher>(
+        &mut self,
+        scope: &Scope<Id, S>,
+        new: bool,
+    ) -> Result<(), EvalError<Id>> {
+        let limit = match self.max_bindings {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if let Scope::Global(_) = *scope {
+            self.globals_bound += usize::from(new);
+        }
+        // The frames of the calls that have returned are gone from the
+        // chain, and so is what they bound.
+        if self.globals_bound + scope.local_bindings() > limit {
+            return Err(EvalError::TooManyBindings { limit });
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, value: &Value<Id>, args: &[&Value<Id>]) -> Result<(), EvalError<Id>> {
+        if self.max_heap_bytes.is_none() || value.shared().is_none() {
+            return Ok(());
+        }
+        let mut seen = args.iter().filter_map(|arg| arg.shared()).collect();
+        let size = value.heap_size_in(&mut seen);
+        self.charge(size)
+    }
+
+    fn auto_promote(&self) -> bool {
+        self.auto_promote
+    }
+
+    fn catch_panics(&self) -> bool {
+        self.catch_native_panics
+    }
+
+    fn truth(&self) -> TruthPolicy<Id> {
+        self.truth
+    }
+
+    fn enter(&mut self, depth: usize) -> Result<(), EvalError<Id>> {
+        match self.max_depth {
+            Some(max) if depth > max => Err(EvalError::TooDeep { max }),
+            _ => Ok(()),
+        }
+    }
+
+    fn lookup(&mut self, name: &Id) {
+        if let Some(ref mut trace) = self.trace {
+            trace.emit(TraceKind::Lookup { name: name.clone() });
+        }
+    }
+
+    fn call(&mut self, callee: &Ast<Id>, func: &Value<Id>, depth: usize) {
+        if let Some(ref mut profile) = self.profile {
+            profile.call(callee, func);
+        }
+        if let Some(ref mut trace) = self.trace {
+            let name = match *callee {
+                Ast::Variable(ref name) => Some(name.clone()),
+                _ => None,
+            };
+            trace.emit(TraceKind::Call { name, depth });
+        }
+    }
+
+    fn leave(&mut self, depth: usize) {
+        if let Some(ref mut profile) = self.profile {
+            profile.stack.pop();
+        }
+        if let Some(ref mut trace) = self.trace {
+            trace.emit(TraceKind::Return { depth });
+        }
+    }
+
+    fn tail_calls(&self) -> bool {
+        self.debugger.is_none()
+    }
+
+    fn recall(
+        &mut self,
+        callee: &Ast<Id>,
+        func: &Value<Id>,
+        args: &Arguments<Cow<Value<Id>>>,
+    ) -> Recall<Id> {
+        match self.memo {
+            Some(ref mut memo) => memo.recall(callee, func, args),
+            None => Recall::Skip,
+        }
+    }
+
+    fn remember(&mut self, key: MemoKey<Id>, result: &Value<Id>) {
+        if let Some(ref mut memo) = self.memo {
+            memo.remember(key, result);
+        }
+    }
+
+    #[cfg(feature = "parse")]
+    fn include<'b, S: BuildHasher>(
+        &mut self,
+        path: &str,
+        scope: &mut Scope<'_, 'b, Id, S>,
+        depth: usize,
+    ) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+        let forms = match self.includes {
+            Some(ref mut includes) => includes.enter(path).map_err(|e| EvalError::Include(Box::new(e)))?,
+            None => return Err(EvalError::Include(Box::new(IncludeError::Disabled))),
+        };
+
+        // The forms are dropped once they've run, so anything they define
+        // is copied out of them.
+        let mut inner = scope.push();
+        let mut out = Value::Void;
+        for form in &forms {
+            out = eval_metered(form, &mut inner, self, depth)?.into_owned();
+        }
+        for (name, value) in inner.into_bindings() {
+            let old = scope.define(name, Cow::Owned(value.into_owned()));
+            self.bind(scope, old.is_none())?;
+        }
+
+        if let Some(ref mut includes) = self.includes {
+            includes.leave();
+        }
+        Ok(Cow::Owned(out))
+    }
+
+    #[cfg(not(feature = "parse"))]
+    fn include<'b, S: BuildHasher>(
+        &mut self,
+        _path: &str,
+        _scope: &mut Scope<'_, 'b, Id, S>,
+        _depth: usize,
+    ) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+        Err(EvalError::Include(Box::new(IncludeError::Disabled)))
+    }
+}
+
+fn bind_param<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    param: &Pattern<Id>,
+    value: Cow<'b, Value<Id>>,
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+) -> Result<(), EvalError<Id>> {
+    match *param {
+        Pattern::Name(ref name) => {
+            let old = scope.define(name.clone(), value);
+            meter.bind(scope, old.is_none())?;
+        }
+        Pattern::List(_) => {
+            let mut parts = Vec::new();
+            param.destructure(value.into_owned(), &mut parts)?;
+            for (name, part) in param.names().into_iter().zip(parts) {
+                let old = scope.define(name.clone(), Cow::Owned(part));
+                meter.bind(scope, old.is_none())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The variables that code is evaluated with. At the bottom is the map given
+// to `eval`, and each call pushes a frame for what its parameters and body
+// bind, rather than copying everything beneath it. Scoping is dynamic, so a
+// lookup starts at the innermost frame and works down through the callers.
+pub(crate) enum Scope<'s, 'b: 's, Id: Clone + 's, S: 's> {
+    Global(&'s mut HashMap<Id, Cow<'b, Value<Id>>, S>),
+    Local(Frame<'s, 'b, Id, S>),
+}
+
+pub(crate) struct Frame<'s, 'b: 's, Id: Clone + 's, S: 's> {
+    // Calls bind a handful of names, so a list is quicker than a map.
+    bindings: Vec<(Id, Cow<'b, Value<Id>>)>,
+    outer: Outer<'s, 'b, Id, S>,
+}
+
+// A scope beneath the innermost frame, which can be read but not defined
+// in.
+enum Outer<'s, 'b: 's, Id: Clone + 's, S: 's> {
+    Global(&'s HashMap<Id, Cow<'b, Value<Id>>, S>),
+    Local(&'s Frame<'s, 'b, Id, S>),
+}
+
+impl<'s, 'b, Id: Clone, S> Clone for Outer<'s, 'b, Id, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'s, 'b, Id: Clone, S> Copy for Outer<'s, 'b, Id, S> {}
+
+impl<'s, 'b, Id, S> Outer<'s, 'b, Id, S>
+where
+    Id: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn get(self, name: &Id) -> Option<&'s Cow<'b, Value<Id>>> {
+        let mut outer = self;
+        loop {
+            match outer {
+                Outer::Global(globals) => return globals.get(name),
+                Outer::Local(frame) => {
+                    if let Some((_, value)) = frame.bindings.iter().find(|(bound, _)| bound == name) {
+                        return Some(value);
+                    }
+                    outer = frame.outer;
+                }
+            }
+        }
+    }
+}
+
+impl<'s, 'b, Id, S> Scope<'s, 'b, Id, S>
+where
+    Id: Clone + Eq + Hash,
+    S: BuildHasher,
+{
+    fn outer(&self) -> Outer<'_, 'b, Id, S> {
+        match *self {
+            Scope::Global(ref globals) => Outer::Global(globals),
+            Scope::Local(ref frame) => Outer::Local(frame),
+        }
+    }
+
+    pub(crate) fn get(&self, name: &Id) -> Option<&Cow<'b, Value<Id>>> {
+        self.outer().get(name)
+    }
+
+    // A new frame on top of this one. What's defined in it can outlive the
+    // program less than what's beneath it.
+    fn push<'c>(&self) -> Scope<'_, 'c, Id, S>
+    where
+        'b: 'c,
+    {
+        self.push_with(Vec::new())
+    }
+
+    // The same, with `bindings` already in the new frame.
+    fn push_with<'c>(&self, bindings: Vec<(Id, Cow<'c, Value<Id>>)>) -> Scope<'_, 'c, Id, S>
+    where
+        'b: 'c,
+    {
+        instrument!(scopes);
+        Scope::Local(Frame {
+            bindings,
+            outer: self.outer(),
+        })
+    }
+
+    // Bind `name` in the innermost frame, returning what it was bound to
+    // before, in whichever frame that was.
+    fn define(&mut self, name: Id, value: Cow<'b, Value<Id>>) -> Option<Cow<'b, Value<Id>>> {
+        match *self {
+            Scope::Global(ref mut globals) => globals.insert(name, value),
+            Scope::Local(ref mut frame) => {
+                if let Some((_, bound)) = frame.bindings.iter_mut().find(|(bound, _)| *bound == name) {
+                    return Some(mem::replace(bound, value));
+                }
+                let old = frame.outer.get(&name).cloned();
+                frame.bindings.push((name, value));
+                old
+            }
+        }
+    }
+
+    // How many variables the frames above the globals bind between them.
+    fn local_bindings(&self) -> usize {
+        let mut count = 0;
+        let mut outer = self.outer();
+        while let Outer::Local(frame) = outer {
+            count += frame.bindings.len();
+            outer = frame.outer;
+        }
+        count
+    }
+
+    // What was defined in this frame, as opposed to beneath it.
+    fn into_bindings(self) -> Vec<(Id, Cow<'b, Value<Id>>)> {
+        match self {
+            Scope::Global(_) => Vec::new(),
+            Scope::Local(frame) => frame.bindings,
+        }
+    }
+
+    /// Every variable in scope, innermost first, leaving out those that
+    /// are shadowed by a frame above them.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Id, &Cow<'b, Value<Id>>)> + '_ {
+        let mut frames = Vec::new();
+        let mut outer = self.outer();
+        let globals = loop {
+            match outer {
+                Outer::Global(globals) => break globals,
+                Outer::Local(frame) => {
+                    frames.push(&frame.bindings[..]);
+                    outer = frame.outer;
+                }
+            }
+        };
+        frames
+            .into_iter()
+            .flat_map(|bindings| bindings.iter().map(|(name, value)| (name, value)))
+            .chain(globals.iter())
+            .filter(move |&(name, value)| {
+                self.get(name).is_some_and(|visible| ptr::eq(visible, value))
+            })
+    }
+}
+
+fn eval_metered<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    program: &'b Ast<Id>,
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+    meter.step(program, scope)?;
+    let result = eval_node(program, scope, meter, depth);
+    meter.exit(program, &result);
+    result
+}
+
+#[inline(always)]
+fn eval_node<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    program: &'b Ast<Id>,
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+    use self::Ast::*;
+
+    match *program {
+        Lit(ref val) => Ok(Cow::Borrowed(val)),
+        Variable(ref name) => {
+            instrument!(lookups);
+            meter.lookup(name);
+            match scope.get(name) {
+                Some(v) => Ok(v.clone()),
+                _ => Err(EvalError::UnboundVariable(name.clone())),
+            }
+        }
+        Call(ref callee, ref arguments) => {
+            let value = eval_metered(callee, scope, meter, depth)?;
+            call_value(value, callee, arguments, scope, meter, depth).map(Cow::Owned)
+        }
+        Define(ref name, ref value) => {
+            // The right-hand side sees the old binding, if there was one.
+            let value = eval_metered(value, scope, meter, depth)?;
+            define(name, value, scope, meter)
+        }
+        Include(ref path) => meter.include(path, scope, depth),
+    }
+}
+
+// Binds `name` to `value` in the innermost frame and tells `meter` about it.
+#[inline(never)]
+fn define<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    name: &Id,
+    value: Cow<'b, Value<Id>>,
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+) -> Result<Cow<'b, Value<Id>>, EvalError<Id>> {
+    let old = scope.define(name.clone(), value.clone());
+    meter.bind(scope, old.is_none())?;
+    meter.define(name, old.as_ref().map(|old| old.as_ref()), &value)?;
+
+    Ok(value)
+}
+
+// Calls `value`, whatever kind of function it is. A partially applied
+// function is called in place of the function it was built from.
+#[inline(always)]
+fn call_value<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    value: Cow<'b, Value<Id>>,
+    callee: &Ast<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Value<Id>, EvalError<Id>> {
+    let func = match *value {
+        Value::Partial(ref partial) => &partial.func,
+        ref func => func,
+    };
+
+    match *func {
+        Value::Function(_) => call_lambda(value, callee, arguments, scope, meter, depth),
+        Value::InbuiltFunc(_) | Value::HostFunc(_) => {
+            call_native(func, callee, &value, arguments, scope, meter, depth)
+        }
+        Value::ReentrantFunc(func) => {
+            call_reentrant(func, callee, &value, arguments, scope, meter, depth)
+        }
+        _ => Err(EvalError::NotAFunction),
+    }
+}
+
+// Each pass of the loop is one call, and a tail call starts the next pass
+// instead of recursing. Its frame starts from the bindings of the call it
+// replaces, which is what it would have seen beneath it anyway, and since
+// nothing is left to run in that call, nothing can tell they were merged.
+// They're copied out of it, because they may borrow from a lambda that's
+// dropped once its body is done with. Everything but the loop itself is
+// kept in the helpers below, since this is on the stack once for every
+// call that isn't a tail call.
+#[inline(never)]
+fn call_lambda<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    value: Cow<'b, Value<Id>>,
+    callee: &Ast<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Value<Id>, EvalError<Id>> {
+    let mut args = match as_lambda(&value) {
+        Some(lambda) => lambda_arguments(lambda, &value, arguments, scope, meter, depth)?,
+        None => return Err(EvalError::NotAFunction),
+    };
+    let mut value = value;
+    let mut callee = Cow::Borrowed(callee);
+    let mut carried = Vec::new();
+    // The memoised calls that return whatever this does.
+    let mut pending = Vec::new();
+
+    let out = loop {
+        if let Some(out) = recall(&value, &callee, &args, &mut pending, meter) {
+            break out;
+        }
+
+        // Start a new scope, so all variables defined in the body of the
+        // function don't leak into the surrounding scope.
+        let mut new_scope = scope.push_with(carried);
+        let body = start_call(&value, &callee, args, &mut new_scope, meter, depth + 1)?;
+
+        let (last, init) = match body.split_last() {
+            Some(split) => split,
+            None => {
+                meter.leave(depth + 1);
+                break Value::Void;
+            }
+        };
+        for stmt in init {
+            eval_metered(stmt, &mut new_scope, meter, depth + 1)?;
+        }
+
+        let next = match *last {
+            Ast::Call(ref callee, ref arguments) if meter.tail_calls() => {
+                tail_call(last, callee, arguments, &mut new_scope, meter, depth + 1)?
+            }
+            _ => {
+                let out = eval_metered(last, &mut new_scope, meter, depth + 1)?;
+                TailCall::Return(out.into_owned())
+            }
+        };
+
+        meter.leave(depth + 1);
+        match next {
+            TailCall::Return(out) => break out,
+            TailCall::Call { value: next, callee: next_callee, args: next_args } => {
+                carried = owned_bindings(new_scope);
+                value = next;
+                callee = Cow::Owned(next_callee);
+                args = next_args;
+            }
+        }
+    };
+
+    if !pending.is_empty() {
+        remember(pending, &out, meter);
+    }
+    Ok(out)
+}
+
+// What calling `value` returned before, if it's memoised and was made with
+// the same arguments. If it's memoised but wasn't, its key goes on
+// `pending` for its result to be remembered.
+#[inline(never)]
+fn recall<Id: Clone + Eq + Hash, M: Meter<Id>>(
+    value: &Value<Id>,
+    callee: &Ast<Id>,
+    args: &Arguments<Cow<Value<Id>>>,
+    pending: &mut Vec<MemoKey<Id>>,
+    meter: &mut M,
+) -> Option<Value<Id>> {
+    let func = match *value {
+        Value::Partial(ref partial) => &partial.func,
+        ref func => func,
+    };
+    match meter.recall(callee, func, args) {
+        Recall::Hit(out) => Some(out),
+        Recall::Miss(key) => {
+            pending.push(key);
+            None
+        }
+        Recall::Skip => None,
+    }
+}
+
+#[inline(never)]
+fn remember<Id: Clone + Eq + Hash, M: Meter<Id>>(
+    pending: Vec<MemoKey<Id>>,
+    out: &Value<Id>,
+    meter: &mut M,
+) {
+    for key in pending {
+        meter.remember(key, out);
+    }
+}
+
+// What a call's last form came to: either what the call returns, or a
+// lambda to call in its place.
+enum TailCall<'b, Id: Clone + 'b> {
+    Return(Value<Id>),
+    Call {
+        value: Cow<'b, Value<Id>>,
+        callee: Ast<Id>,
+        args: Arguments<Cow<'b, Value<Id>>>,
+    },
+}
+
+// Binds the parameters of the lambda `value` calls, and returns its body.
+#[inline(never)]
+fn start_call<'v, 'c, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    value: &'v Value<Id>,
+    callee: &Ast<Id>,
+    args: Arguments<Cow<'c, Value<Id>>>,
+    scope: &mut Scope<'_, 'c, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<&'v [Ast<Id>], EvalError<Id>>
+where
+    'v: 'c,
+{
+    let func = match *value {
+        Value::Partial(ref partial) => &partial.func,
+        ref func => func,
+    };
+    let lambda = match *func {
+        Value::Function(ref lambda) => lambda,
+        _ => return Err(EvalError::NotAFunction),
+    };
+    let Lambda { ref params, ref defaults, ref body } = **lambda;
+    let Arguments { required, optional } = args;
+
+    for (param, val) in params.iter().zip(required) {
+        bind_param(param, val, scope, meter)?;
+    }
+
+    meter.enter(depth)?;
+    meter.call(callee, func, depth);
+
+    // Defaults are evaluated as part of the call, in its scope, so they
+    // can use the parameters before them.
+    let optional_params = params[lambda.required()..].iter().zip(defaults.iter());
+    for ((param, default), given) in optional_params.zip(optional) {
+        let value = match given {
+            Some(value) => value,
+            None => owned(eval_metered(default, scope, meter, depth)?),
+        };
+        bind_param(param, value, scope, meter)?;
+    }
+
+    Ok(body)
+}
+
+// Evaluates `last`, the call `callee(arguments)` that ends a lambda's
+// body, as far as the arguments of the lambda it calls. Anything else is
+// called as usual, from inside the call that `last` ends. `exit` is only
+// called for `last` in that case, since otherwise it hasn't finished.
+#[inline(never)]
+fn tail_call<'b, 'c, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    last: &Ast<Id>,
+    callee: &'c Ast<Id>,
+    arguments: &'c [Ast<Id>],
+    scope: &mut Scope<'_, 'c, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<TailCall<'b, Id>, EvalError<Id>> {
+    meter.step(last, scope)?;
+    let value = eval_metered(callee, scope, meter, depth)?;
+    let args = match as_lambda(&value) {
+        Some(lambda) => lambda_arguments(lambda, &value, arguments, scope, meter, depth)?,
+        None => {
+            let result = call_value(value, callee, arguments, scope, meter, depth).map(Cow::Owned);
+            meter.exit(last, &result);
+            return Ok(TailCall::Return(result?.into_owned()));
+        }
+    };
+
+    Ok(TailCall::Call {
+        value: owned(value),
+        callee: callee.clone(),
+        args: Arguments {
+            required: args.required.into_iter().map(owned).collect(),
+            optional: args.optional.into_iter().map(|arg| arg.map(owned)).collect(),
+        },
+    })
+}
+
+// The lambda that calling `value` runs, if it runs one.
+fn as_lambda<Id>(value: &Value<Id>) -> Option<&Lambda<Id>> {
+    match *value {
+        Value::Function(ref lambda) => Some(lambda),
+        Value::Partial(ref partial) => as_lambda(&partial.func),
+        _ => None,
+    }
+}
+
+fn owned<'b, Id: Clone>(value: Cow<Value<Id>>) -> Cow<'b, Value<Id>> {
+    Cow::Owned(value.into_owned())
+}
+
+#[inline(never)]
+fn owned_bindings<'b, Id: Clone + Eq + Hash, S: BuildHasher>(
+    scope: Scope<Id, S>,
+) -> Vec<(Id, Cow<'b, Value<Id>>)> {
+    scope.into_bindings().into_iter().map(|(name, value)| (name, owned(value))).collect()
+}
+
+// Arguments are evaluated in the caller's scope before the call happens,
+// for builtins and lambdas alike, and follow any that `func` was given by
+// `curry`. These are kept out of `eval_node` so that the stack frame of
+// every call it recurses through stays small.
+#[inline(never)]
+fn eval_arguments<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    func: &Value<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Vec<Cow<'b, Value<Id>>>, EvalError<Id>> {
+    let given = match *func {
+        Value::Partial(ref partial) => &partial.args[..],
+        _ => &[],
+    };
+    given
+        .iter()
+        .map(|value| Ok(Cow::Owned(value.clone())))
+        .chain(arguments.iter().map(|ast| eval_metered(ast, scope, meter, depth)))
+        .collect()
+}
+
+#[inline(never)]
+fn lambda_arguments<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    lambda: &Lambda<Id>,
+    func: &Value<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Arguments<Cow<'b, Value<Id>>>, EvalError<Id>> {
+    let values = eval_arguments(func, arguments, scope, meter, depth)?;
+    let given = values.len() - arguments.len();
+    let positional = first_keyword(arguments).map(|i| i + given);
+    lambda.arrange(values, positional, |value: &Cow<Value<Id>>| value.as_keyword())
+}
+
+// Natives that do arithmetic go on to a `BigInt` whenever a result is too
+// big for an `Int`, so every backend checks what they return against
+// `EvalOptions::auto_promote`, or refuses if it has no options.
+#[inline]
+pub(crate) fn promoted<Id>(
+    out: Value<Id>,
+    args: &[&Value<Id>],
+    allowed: bool,
+) -> Result<Value<Id>, EvalError<Id>> {
+    match out {
+        Value::BigInt(_) if !allowed && !args.iter().any(|arg| has_big_int(arg)) => {
+            Err(EvalError::IntegerOverflow)
+        }
+        out => Ok(out),
+    }
+}
+
+#[inline(never)]
+fn has_big_int<Id>(value: &Value<Id>) -> bool {
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match *value {
+            Value::BigInt(_) => return true,
+            Value::List(ref items) | Value::Values(ref items) => pending.extend(items.iter()),
+            _ => {}
+        }
+    }
+    false
+}
+
+// Natives aren't written to expect `Values`, so every backend checks their
+// arguments for them before calling one.
+#[inline(never)]
+pub(crate) fn single_values<Id>(args: &[&Value<Id>]) -> Result<(), EvalError<Id>> {
+    match args.iter().find_map(|arg| match **arg {
+        Value::Values(ref values) => Some(values.len()),
+        _ => None,
+    }) {
+        Some(count) => Err(EvalError::MultipleValues { count }),
+        None => Ok(()),
+    }
+}
+
+// Kept out of `eval_node` like the argument helpers above, and for the
+// same reason.
+#[inline(never)]
+fn call_native<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    func: &Value<Id>,
+    callee: &Ast<Id>,
+    value: &Value<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Value<Id>, EvalError<Id>> {
+    let args = eval_arguments(value, arguments, scope, meter, depth)?;
+    let arg_refs = args.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+    single_values(&arg_refs)?;
+
+    meter.call(callee, func, depth + 1);
+    meter.leave(depth + 1);
+
+    let out = if meter.catch_panics() {
+        call_catching(func, callee, &arg_refs)?
+    } else {
+        func.call_native(&arg_refs)?
+    };
+    let out = promoted(out, &arg_refs, meter.auto_promote())?;
+    meter.allocate(&out, &arg_refs)?;
+    Ok(out)
+}
+
+// Calls a native, turning a panic into `NativePanic`. Everything the
+// evaluator keeps track of, such as the depth, was settled before the call,
+// so it can carry on afterwards. A native that was part way through
+// changing a box or its own state is left that way, which nothing here
+// can put right.
+#[cfg(feature = "std")]
+fn call_catching<Id: Clone>(
+    func: &Value<Id>,
+    callee: &Ast<Id>,
+    args: &[&Value<Id>],
+) -> Result<Value<Id>, EvalError<Id>> {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(|| func.call_native(args))) {
+        Ok(out) => return out,
+        Err(payload) => payload,
+    };
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_owned(),
+        },
+    };
+    let name = match *callee {
+        Ast::Variable(ref name) => Some(name.clone()),
+        _ => None,
+    };
+    Err(EvalError::NativePanic { name, message })
+}
+
+#[cfg(not(feature = "std"))]
+fn call_catching<Id: Clone>(
+    func: &Value<Id>,
+    _: &Ast<Id>,
+    args: &[&Value<Id>],
+) -> Result<Value<Id>, EvalError<Id>> {
+    func.call_native(args)
+}
+
+// A reentrant native is called like any other, and then whatever it
+// evaluates counts as being inside the call.
+#[inline(never)]
+fn call_reentrant<'b, Id: Clone + Eq + Hash, S: BuildHasher, M: Meter<Id>>(
+    func: ReentrantFn<Id>,
+    callee: &Ast<Id>,
+    value: &Value<Id>,
+    arguments: &'b [Ast<Id>],
+    scope: &mut Scope<'_, 'b, Id, S>,
+    meter: &mut M,
+    depth: usize,
+) -> Result<Value<Id>, EvalError<Id>> {
+    let args = eval_arguments(value, arguments, scope, meter, depth)?;
+    let arg_refs = args.iter().map(|v| v.as_ref()).collect::<Vec<_>>();
+    single_values(&arg_refs)?;
+
+    meter.enter(depth + 1)?;
+    meter.call(callee, &Value::ReentrantFunc(func), depth + 1);
+    let out = func(
+        &mut Reentry {
+            scope,
+            meter,
+            depth: depth + 1,
+        },
+        &arg_refs,
+    )?;
+    meter.leave(depth + 1);
+    meter.allocate(&out, &arg_refs)?;
+
+    Ok(out)
+}
+
+// The `Evaluator` that `eval_metered` gives to reentrant natives.
+struct Reentry<'a, 's: 'a, 'b: 's, Id: Clone + 's, S: 's, M: 'a> {
+    scope: &'a mut Scope<'s, 'b, Id, S>,
+    meter: &'a mut M,
+    depth: usize,
+}
+
+impl<'a, 's, 'b, Id, S, M> Evaluator<Id> for Reentry<'a, 's, 'b, Id, S, M>
+where
+    Id: Clone + Eq + Hash,
+    S: BuildHasher,
+    M: Meter<Id>,
+{
+    fn eval(&mut self, ast: &Ast<Id>) -> Result<Value<Id>, EvalError<Id>> {
+        // `ast` won't outlive the call, so like an include, anything it
+        // defines is copied out of it.
+        let mut inner = self.scope.push();
+        let out = eval_metered(ast, &mut inner, self.meter, self.depth)?.into_owned();
+        for (name, value) in inner.into_bindings() {
+            let old = self.scope.define(name, Cow::Owned(value.into_owned()));
+            self.meter.bind(self.scope, old.is_none())?;
+        }
+        Ok(out)
+    }
+
+    fn call(&mut self, func: &Value<Id>, args: &[Value<Id>]) -> Result<Value<Id>, EvalError<Id>> {
+        let call = Ast::Call(
+            Rc::new(Ast::Lit(func.clone())),
+            args.iter().cloned().map(Ast::Lit).collect::<Vec<_>>().into(),
+        );
+        // Arguments are already values, so nothing the call does can be
+        // seen by the caller.
+        let out = eval_metered(&call, &mut self.scope.push(), self.meter, self.depth)?.into_owned();
+        Ok(out)
+    }
+
+    fn truth(&self) -> TruthPolicy<Id> {
+        self.meter.truth()
+    }
+}
+
+/// The hasher for `IntMap`, whose keys are identifiers that are already
+/// hashes, so a `u64` hashes to itself. Other integers do the same, and
+/// anything written as bytes, like a string, is folded in with FNV-1a.
+/// Each integer replaces what was written before it, so a key made of
+/// several, like a tuple, hashes by the last of them.
+#[derive(Clone, Default)]
+pub struct U64Hasher(pub u64);
+
+impl BuildHasher for U64Hasher {
+    type Hasher = Self;
+    fn build_hasher(&self) -> Self {
+        U64Hasher(self.0)
+    }
+}
+
+impl Hasher for U64Hasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut fnv = Fnv64(Fnv64::default().0 ^ self.0);
+        fnv.write(bytes);
+        self.0 = fnv.finish();
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.0 = u64::from(i)
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = i
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.0 = i as u64
+    }
+}
+
+pub type IntMap<V> = HashMap<u64, V, U64Hasher>;
+
+/// How deeply parenthesised forms can be nested before parsing fails, and
+/// how deeply `Ast::from_bytes` will nest patterns.
+pub const MAX_NESTING: usize = 256;
+
+/// Why a program couldn't be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// The source isn't a well-formed program. This includes `()`, which
+    /// has no function to call and so is always an error rather than, say,
+    /// evaluating to `Void`. `position` is the byte offset of the problem,
+    /// if it's known.
+    Syntax {
+        message: String,
+        position: Option<usize>,
+    },
+    /// Two different identifiers hash to the same value, so they would
+    /// silently refer to the same variable.
+    IdentifierCollision { a: String, b: String, hash: u64 },
+    /// A problem that's only a warning outside of strict mode.
+    Strict(Diagnostic),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Syntax { ref message, .. } => write!(f, "{}", message),
+            ParseError::IdentifierCollision { ref a, ref b, hash } => write!(
+                f,
+                "Identifiers `{}` and `{}` have the same hash ({:#x})",
+                a, b, hash
+            ),
+            ParseError::Strict(ref diagnostic) => write!(f, "{}", diagnostic),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Something suspicious about a program that doesn't stop it from running.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// A function has more than one parameter called `name`, so only the
+    /// last argument passed for it can ever be read. `position` is the byte
+    /// offset of the repeated parameter.
+    DuplicateParameter { name: String, position: usize },
+    /// A define or a parameter binds `name`, which is one of
+    /// `ParseOptions::builtins`, so the native function can't be reached
+    /// while the binding is in scope. `position` is the byte offset of the
+    /// name.
+    ShadowsBuiltin { name: String, position: usize },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnostic::DuplicateParameter { ref name, position } => write!(
+                f,
+                "Parameter `{}` is bound more than once (at byte {})",
+                name, position
+            ),
+            Diagnostic::ShadowsBuiltin { ref name, position } => write!(
+                f,
+                "`{}` shadows a builtin function (at byte {})",
+                name, position
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Check that no two distinct identifiers in the program hash to the
+    /// same value. This costs a table lookup, and an allocation for each
+    /// new name, per identifier parsed.
+    pub detect_collisions: bool,
+    /// Fail with `ParseError::Strict` on the first diagnostic, instead of
+    /// reporting it and carrying on.
+    pub strict: bool,
+    /// The hashed names of the native functions the program will run with.
+    /// Binding any of these is reported as `Diagnostic::ShadowsBuiltin`.
+    /// `Interpreter::parse_options` fills this in from its natives.
+    pub builtins: HashSet<u64>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            detect_collisions: true,
+            strict: false,
+            builtins: HashSet::new(),
+        }
+    }
+}
+
+/// The line and column, both counting from 1, of the byte offset `position`
+/// in `src`. Columns count characters rather than bytes.
+pub fn line_and_column(src: &str, position: usize) -> (usize, usize) {
+    let mut end = position.min(src.len());
+    while !src.is_char_boundary(end) {
+        end -= 1;
+    }
+    let before = &src[..end];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// The source names of hashed identifiers.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    names: HashMap<u64, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    /// Record `name`, returning the identifier that the parser would give
+    /// it.
+    pub fn insert(&mut self, name: &str) -> u64 {
+        let id = hash_string(name);
+        self.names.entry(id).or_insert_with(|| name.to_owned());
+        id
+    }
+
+    pub fn name(&self, id: u64) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// The name of `id`, or its identifier in hex after a `#` if it isn't
+    /// here, which is how listings of bindings show it.
+    pub fn display_name(&self, id: u64) -> String {
+        match self.name(id) {
+            Some(name) => name.to_owned(),
+            None => format!("#{:x}", id),
+        }
+    }
+
+    /// Every identifier and its name, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.names.iter().map(|(&id, name)| (id, name.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod benches {
+    use combine::Parser;
+
+    use bigint;
+    use bytecode::Vm;
+    use closure::compile_closure;
+    use convert::{self, FromValue};
+    use instrument;
+    use prelude;
+    use super::{
+        eval, eval_with, expr, hash_string, keyword_args, line_and_column, parse_bytes, parse_fuzz,
+        parse_program, parse_program_with, parse_program_with_symbols, parse_recovering, Ast,
+        Coverage, Diagnostic, EvalError, BYTES_SHOWN,
+        EvalOptions, IntMap, Interpreter, Lambda, ParseError, ParseOptions, Partial, Profile, Thunk,
+        TypeError, U64Hasher, Value, MAX_NESTING,
+    };
+
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::mem;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    // First we need some helper functions. Besides the prelude, the
+    // benchmarks use these two.
+    //
+    // This one just returns a function so `((whatever))` (equivalent
+    // to `(whatever())()`) does something useful. Specifically
+    // it just returns itself. We try to do as little work as
+    // possible here so that our benchmark is still testing the
+    // interpreter and not this function.
+    pub(crate) fn callable<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+        Ok(Value::InbuiltFunc(callable))
+    }
+
+    // This just takes anything and returns `Void`. We just
+    // want a function that can take any number of arguments
+    // but we don't want that function to do anything useful
+    // since, again, the benchmark should be of the
+    // interpreter's code.
+    pub(crate) fn ignore<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+        Ok(Value::Void)
+    }
+
+    // The test program strings are shared with the benchmarks.
+    include!("../benches/programs.rs");
+
+    // Other ways of running a program (optimisation passes, alternative
+    // backends) are checked against `eval` using the benchmark programs,
+    // since between them they cover every feature of the language. This
+    // returns each program split into its top-level forms.
+    pub(crate) fn corpus() -> Vec<(&'static str, Vec<Ast<u64>>)> {
+        [
+            ("DEEP_NESTING", DEEP_NESTING),
+            ("MANY_VARIABLES", MANY_VARIABLES),
+            ("NESTED_FUNC", NESTED_FUNC),
+            ("REAL_CODE", REAL_CODE),
+        ].iter()
+            .map(|&(name, src)| {
+                // REAL_CODE has a duplicate parameter, which we don't need
+                // to hear about every time.
+                let program = parse_program_with(src, &ParseOptions::default(), &mut |_| {});
+                (name, program.unwrap())
+            })
+            .collect()
+    }
+
+    // Every native function that any of the benchmark programs need.
+    pub(crate) fn corpus_interpreter() -> Interpreter<u64> {
+        let mut interpreter = prelude::interpreter();
+
+        interpreter
+            .register(hash_string("test"), callable)
+            .register(hash_string("ignore"), ignore);
+
+        interpreter
+    }
+
+    pub(crate) fn corpus_env<'a>() -> IntMap<Cow<'a, Value<u64>>> {
+        corpus_interpreter().env()
+    }
+
+    // Backends may each create their own copy of a function, which
+    // `PartialEq` doesn't consider equal, so we settle for checking they're
+    // the same kind. The same goes for boxes, which may also have been
+    // changed since the result being compared was given, and for foreign
+    // values, which natives make anew for each backend. Lists are compared
+    // element by element, since they may hold any of these.
+    pub(crate) fn same_value<T: PartialEq>(a: &Value<T>, b: &Value<T>) -> bool {
+        match (a, b) {
+            (&Value::Function(..), &Value::Function(..)) => true,
+            (&Value::Partial(..), &Value::Partial(..)) => true,
+            (&Value::Box(..), &Value::Box(..)) => true,
+            (&Value::Foreign(..), &Value::Foreign(..)) => true,
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b))
+            }
+            _ => a == b,
+        }
+    }
+
+    pub(crate) fn same_result<T: PartialEq>(
+        a: &Result<Value<T>, EvalError<T>>,
+        b: &Result<Value<T>, EvalError<T>>,
+    ) -> bool {
+        match (a, b) {
+            (Ok(a), Ok(b)) => same_value(a, b),
+            (Err(a), Err(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // Every node of a parsed program is an `Ast`, so anything that makes it
+    // bigger costs us in both parsing and evaluation. These are checked when
+    // the tests are compiled.
+    #[test]
+    fn runs_the_literal_and_define_heavy_programs() {
+        let mut env = corpus_env();
+        let (literals, _) = expr().easy_parse(LITERALS).unwrap();
+        assert_eq!(eval(&literals, &mut env).unwrap().into_owned(), Value::Int(99));
+
+        let program = parse_program(&many_defines(500)).unwrap();
+        assert_eq!(program.len(), 501);
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(results[499], Value::Int(499));
+        assert_eq!(results[500], Value::Int((0..500).sum()));
+    }
+
+    #[test]
+    fn tail_recursion_runs_in_constant_stack() {
+        // Far too little for a hundred thousand nested calls, or even a
+        // thousand. The parser grows its own stack when it needs to.
+        ::std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(|| {
+                let program = parse_program(TAIL_RECURSION).unwrap();
+                let mut env = prelude::env();
+                assert!(eval(&program[0], &mut env).is_ok());
+                assert_eq!(eval(&program[1], &mut env).unwrap().into_owned(), Value::Int(100_000));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    // The loop in TAIL_RECURSION again, except that each step adds one to
+    // what the rest of it returns. The call to `count` is an argument to
+    // `add`, which isn't a tail position, so every step nests inside the
+    // one before it.
+    const NON_TAIL_RECURSION: &str = r"
+    (= count (\(n)
+      ((if (eq n 100000)
+           (\() 0)
+           (\() (add 1 (count (add n 1))))))))
+    (count 0)
+    ";
+
+    #[test]
+    fn non_tail_recursion_hits_the_depth_limit() {
+        let program = parse_program(NON_TAIL_RECURSION).unwrap();
+        let mut env = prelude::env();
+        let mut options = EvalOptions {
+            max_depth: Some(100),
+            ..EvalOptions::default()
+        };
+
+        assert!(eval_with(&program[0], &mut env, &mut options).is_ok());
+        assert_eq!(
+            eval_with(&program[1], &mut env, &mut options).err(),
+            Some(EvalError::TooDeep { max: 100 })
+        );
+    }
+
+    #[test]
+    fn functions_equal_only_themselves() {
+        let mut env = corpus_env();
+        let program = parse_program(
+            r"
+            (= f (\(x) x))
+            (= g (\(x) x))
+            (same f f)
+            (same f g)
+            (same add add)
+            (same add eq)
+            (same f add)
+            ",
+        ).unwrap();
+
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        assert!(results[2] == Value::Void);
+        assert!(results[3] == Value::False);
+        assert!(results[4] == Value::Void);
+        assert!(results[5] == Value::False);
+        assert!(results[6] == Value::False);
+
+        // The same text parsed twice gives two different functions.
+        let (a, _) = expr().easy_parse(r"(\(x) x)").unwrap();
+        let (b, _) = expr().easy_parse(r"(\(x) x)").unwrap();
+        match (&a, &b) {
+            (Ast::Lit(a), Ast::Lit(b)) => {
+                assert!(*a == a.clone());
+                assert!(a != b);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn same_is_identity_for_lists() {
+        let mut env = corpus_env();
+        let program = parse_program(
+            r#"
+            (= xs (list 1 2))
+            (same xs xs)
+            (same (list 1 2) (list 1 2))
+            (eq (list 1 2) (list 1 2))
+            (same "ab" "ab")
+            (same 1 1)
+            "#,
+        ).unwrap();
+
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        assert!(results[1] == Value::Void);
+        assert!(results[2] == Value::False);
+        assert!(results[3] == Value::Void);
+        assert!(results[4] == Value::False);
+        assert!(results[5] == Value::Void);
+    }
+
+    #[test]
+    fn eq_compares_functions_structurally() {
+        let mut env = corpus_env();
+        let program = parse_program(
+            r"
+            (= f (\(x) (add x 1)))
+            (= g (\(x) (add x 1)))
+            (eq f g)
+            (same f g)
+            (eq f f)
+            (same f f)
+            (eq f (\(y) (add y 1)))
+            (eq f (\(x) (add x 2)))
+            (eq f (\(x) (add x 1) x))
+            (eq add add)
+            (eq add eq)
+            (eq 1 1)
+            ",
+        ).unwrap();
+
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .skip(2)
+            .collect::<Vec<_>>();
+
+        let expected = [true, false, true, true, false, false, false, true, false, true]
+            .iter()
+            .map(|&t| if t { Value::Void } else { Value::False })
+            .collect::<Vec<_>>();
+        assert!(results == expected);
+    }
+
+    #[test]
+    fn deep_functions_compare_without_overflowing() {
+        use std::thread;
+
+        let comparer = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let name = hash_string("a");
+                let nest = |innermost| {
+                    let mut lambda = Value::Int(innermost);
+                    for _ in 0..200_000 {
+                        let body = Ast::Call(Rc::new(Ast::Variable(name)), vec![Ast::Lit(lambda)].into());
+                        lambda = Value::Function(Rc::new(Lambda::new(vec![name], vec![body])));
+                    }
+                    lambda
+                };
+
+                let (a, b, c) = (nest(0), nest(0), nest(1));
+                assert!(a.equal(&b));
+                assert!(a != b);
+                assert!(!a.equal(&c));
+            })
+            .unwrap();
+
+        comparer.join().unwrap();
+    }
+
+    #[test]
+    fn deep_lists_compare_without_overflowing() {
+        use std::thread;
+
+        let comparer = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                // Lists, multiple values and partial applications in turn.
+                let nest = |innermost| {
+                    let mut value = Value::<u64>::Int(innermost);
+                    for depth in 0..100_000 {
+                        value = match depth % 3 {
+                            0 => Value::List(Rc::new(vec![value])),
+                            1 => Value::Values(Rc::new(vec![Value::Nil, value])),
+                            _ => Value::Partial(Rc::new(Partial::new(Value::Nil, vec![value]))),
+                        };
+                    }
+                    value
+                };
+
+                let (a, b, c) = (nest(0), nest(0), nest(1));
+                assert!(a.equal(&b));
+                assert!(!a.equal(&c));
+
+                // Values are dropped recursively, so these are taken apart
+                // from the outside in.
+                for value in [a, b, c] {
+                    let mut value = Some(value);
+                    while let Some(outer) = value.take() {
+                        value = match outer {
+                            Value::List(items) | Value::Values(items) => {
+                                Rc::try_unwrap(items).ok().unwrap().pop()
+                            }
+                            Value::Partial(partial) => {
+                                Rc::try_unwrap(partial).ok().unwrap().args.into_vec().pop()
+                            }
+                            _ => None,
+                        };
+                    }
+                }
+            })
+            .unwrap();
+
+        comparer.join().unwrap();
+    }
+
+    #[test]
+    fn evaluates_with_identifiers_that_are_not_debug() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashMap;
+        use std::fmt;
+        use std::hash::BuildHasher;
+
+        // Neither of these can be printed, and the hasher can't be cloned.
+        #[derive(Clone, PartialEq, Eq, Hash)]
+        struct Name(&'static str);
+        struct Hashing;
+
+        impl BuildHasher for Hashing {
+            type Hasher = DefaultHasher;
+            fn build_hasher(&self) -> DefaultHasher {
+                DefaultHasher::new()
+            }
+        }
+
+        // (= id (\(x) x)) (id 5) (id y)
+        let x = Name("x");
+        let id = Ast::Lit(Value::Function(Rc::new(Lambda::new(vec![x.clone()], vec![Ast::Variable(x)]))));
+        let call = |arg| Ast::Call(Rc::new(Ast::Variable(Name("id"))), vec![arg].into());
+        let program = [
+            Ast::Define(Name("id"), Rc::new(id)),
+            call(Ast::Lit(Value::Int(5))),
+            call(Ast::Variable(Name("y"))),
+        ];
+
+        let mut env = HashMap::with_hasher(Hashing);
+        assert!(eval(&program[0], &mut env).is_ok());
+        assert!(eval(&program[1], &mut env).map(Cow::into_owned) == Ok(Value::Int(5)));
+
+        let error = match eval_with(&program[2], &mut env, &mut EvalOptions::default()) {
+            Err(error) => error,
+            Ok(_) => panic!("`y` isn't bound"),
+        };
+        assert_eq!(
+            error.display_opaque().to_string(),
+            "Variable does not exist: <identifier>"
+        );
+        let name = |name: &Name, f: &mut fmt::Formatter| write!(f, "`{}`", name.0);
+        assert_eq!(error.display_with(name).to_string(), "Variable does not exist: `y`");
+    }
+
+    #[test]
+    fn values_hash_the_way_they_compare() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::HashMap;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |value: &Value<u64>| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+        let list = |items: Vec<Value<u64>>| Value::List(Rc::new(items));
+        let text = |text: &str| Value::Str(Rc::new(text.to_owned()));
+
+        let a = list(vec![Value::Int(1), text("a"), list(vec![Value::Void, Value::Symbol(2)])]);
+        let b = list(vec![Value::Int(1), text("a"), list(vec![Value::Void, Value::Symbol(2)])]);
+        assert!(a == b);
+        assert_eq!(hash(&a), hash(&b));
+
+        let function = || {
+            let (program, _) = expr().easy_parse(r"(\(x) x)").unwrap();
+            eval(&program, &mut corpus_env()).unwrap().into_owned()
+        };
+        let (function, twin) = (function(), function());
+        let native = corpus_env()[&hash_string("add")].clone().into_owned();
+
+        // Clippy sees the cell in a thunk, but thunks hash by address, so
+        // forcing one doesn't change its hash.
+        #[allow(clippy::mutable_key_type)]
+        let mut map = HashMap::new();
+        let keys = [
+            Value::Void,
+            Value::False,
+            Value::Int(0),
+            text(""),
+            Value::Keyword(2),
+            Value::Symbol(2),
+            list(vec![]),
+            Value::Values(Rc::new(vec![])),
+            a,
+            function.clone(),
+            native.clone(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            assert!(map.insert(key.clone(), i).is_none());
+        }
+        assert_eq!(map.len(), keys.len());
+        assert_eq!(map.get(&b), Some(&8));
+        assert_eq!(map.get(&text("")), Some(&3));
+
+        // Functions are keyed by identity, so a copy of one finds it but the
+        // same function written again doesn't.
+        assert_eq!(map.get(&function), Some(&9));
+        assert_eq!(map.get(&native), Some(&10));
+        assert!(twin.equal(&function));
+        assert_eq!(map.get(&twin), None);
+    }
+
+    #[test]
+    fn arguments_are_evaluated_left_to_right() {
+        use std::cell::Cell;
+
+        thread_local!(static TICKS: Cell<u64> = const { Cell::new(0) });
+
+        fn tick<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+            Ok(Value::Int(TICKS.with(|ticks| {
+                ticks.set(ticks.get() + 1);
+                ticks.get()
+            })))
+        }
+
+        let mut interpreter = corpus_interpreter();
+        interpreter.register(hash_string("tick"), tick);
+
+        let cases = [
+            (r"((\(a b c) a) (tick) (tick) (tick))", 1),
+            (r"((\(a b c) c) (tick) (tick) (tick))", 3),
+            (r"(if (tick) (tick) (tick))", 2),
+            (r"((if (tick) (\(a) a)) (tick))", 2),
+            (r"((\(a b) b) (= x (tick)) (add x 10))", 11),
+            (r"(add (= x 5) x)", 10),
+        ];
+
+        for &(src, expected) in &cases {
+            let program = parse_program(src).unwrap();
+            let expected = Ok(Value::Int(expected));
+
+            TICKS.with(|ticks| ticks.set(0));
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(eval(&program[0], &mut env).map(Cow::into_owned) == expected, "{}", src);
+
+            TICKS.with(|ticks| ticks.set(0));
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(compile_closure(&program[0]).eval(&mut env) == expected, "{}", src);
+
+            TICKS.with(|ticks| ticks.set(0));
+            let compiled = interpreter.compile(&program);
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(Vm::new(&compiled).run(&mut env) == expected, "{}", src);
+        }
+    }
+
+    #[test]
+    fn defines_in_arguments_are_seen_by_later_arguments() {
+        // `f` and `second` both give back their second argument, one as a
+        // lambda and the other as a native, and `h` calls `f` from tail
+        // position.
+        let src = r"
+            (= f (\(a b) b))
+            (= h (\() (f (= y 2) y)))
+            (f (= x 1) x)
+            (second (= x 3) x)
+            (h)
+            x
+            ";
+        let program = parse_program(src).unwrap();
+        let mut interpreter = corpus_interpreter();
+        interpreter.register(hash_string("second"), |args| Ok(args[1].clone()));
+        let expected = [1, 3, 2, 3].iter().map(|&i| Value::Int(i)).collect::<Vec<_>>();
+
+        let mut env: IntMap<_> = interpreter.env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(results[2..], expected[..]);
+        // Defined in `h`'s scope, so it went when `h` returned.
+        assert!(!env.contains_key(&hash_string("y")));
+
+        let mut env: IntMap<_> = interpreter.env();
+        let results = program
+            .iter()
+            .map(|form| compile_closure(form).eval(&mut env).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results[2..], expected[..]);
+
+        let compiled = interpreter.compile(&program);
+        let mut env: IntMap<_> = interpreter.env();
+        assert_eq!(Vm::new(&compiled).run(&mut env), Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn defines_in_a_body_replace_the_binding_in_its_scope() {
+        let cases = [
+            // Define after capture: `get` reads `a` when it's called.
+            (r"((\(a) (= get (\() a)) (= a 2) (get)) 1)", 2),
+            // Define before capture.
+            (r"((\(a) (= a 2) (= get (\() a)) (get)) 1)", 2),
+            // A lambda called before the define sees the parameter.
+            (r"((\(a) (= get (\() a)) (= b (get)) (= a 2) b) 1)", 1),
+            // A parameter shadows a global, and defining it again in the
+            // body leaves the global alone.
+            (r"((\(a) (= a (add a 10)) a) 2)", 12),
+            (r"((\(a) (= a 5)) 2) a", 1),
+        ];
+        let interpreter = corpus_interpreter();
+
+        for &(src, expected) in &cases {
+            let program = parse_program(&format!("(= a 1) {}", src)).unwrap();
+            let expected = Value::Int(expected);
+
+            let mut env: IntMap<_> = interpreter.env();
+            let mut last = Value::Void;
+            for form in &program {
+                last = eval(form, &mut env).unwrap().into_owned();
+            }
+            assert_eq!(last, expected, "{}", src);
+
+            let mut env: IntMap<_> = interpreter.env();
+            let mut last = Value::Void;
+            for form in &program {
+                last = compile_closure(form).eval(&mut env).unwrap();
+            }
+            assert_eq!(last, expected, "{}", src);
+
+            let compiled = interpreter.compile(&program);
+            let mut env: IntMap<_> = interpreter.env();
+            assert_eq!(Vm::new(&compiled).run(&mut env), Ok(expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn only_false_is_falsey() {
+        let (lambda, _) = expr().easy_parse(r"(\(x) x)").unwrap();
+        let lambda = match lambda {
+            Ast::Lit(ref value) => value.clone(),
+            _ => unreachable!(),
+        };
+
+        assert!(!Value::<u64>::False.is_truthy());
+        assert!(Value::<u64>::Void.is_truthy());
+        assert!(Value::<u64>::Int(0).is_truthy());
+        assert!(Value::<u64>::Int(1).is_truthy());
+        assert!(Value::<u64>::InbuiltFunc(ignore).is_truthy());
+        assert!(lambda.is_truthy());
+
+        let mut env = corpus_env();
+        let program = parse_program("(if 0 1 2) (if ignore 1 2) (if (eq 1 2) 1 2) (if (eq 1 2) 1)").unwrap();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        assert!(results == [Value::Int(1), Value::Int(1), Value::Int(2), Value::Void]);
+    }
+
+    #[test]
+    fn if_reports_wrong_argument_counts() {
+        let interpreter = corpus_interpreter();
+
+        for (src, got) in [("(if 1 2 3 4)", 4), ("(if 1)", 1)] {
+            let program = parse_program(src).unwrap();
+            let expected = Err(EvalError::ArgumentCount { min: 2, max: 3, got });
+
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(eval(&program[0], &mut env).map(Cow::into_owned) == expected);
+
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(compile_closure(&program[0]).eval(&mut env) == expected);
+
+            let compiled = interpreter.compile(&program);
+            let mut env: IntMap<_> = interpreter.env();
+            assert!(Vm::new(&compiled).run(&mut env) == expected);
+        }
+    }
+
+    #[test]
+    fn defines_see_the_old_value_and_return_the_new_one() {
+        let mut env = corpus_env();
+        let program = parse_program(
+            r"
+            (= x 1)
+            (= x (add x 1))
+            x
+            (add (= y 5) 1)
+            y
+            ((\(a) (= a (add a 10))) 1)
+            ((\(a b) b) (= z 3) z)
+            z
+            ",
+        ).unwrap();
+
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        let expected = [1, 2, 2, 6, 5, 11, 3, 3].iter().map(|&i| Value::Int(i)).collect::<Vec<_>>();
+        assert!(results == expected);
+    }
+
+    // Runs `src` with `eval` and both compiled backends, which must agree,
+    // and returns the value of each form.
+    fn run_everywhere(src: &str) -> Vec<Result<Value<u64>, EvalError<u64>>> {
+        run_everywhere_in(src, corpus_interpreter)
+    }
+
+    // `run_everywhere`, with the functions that `interpreter` registers.
+    fn run_everywhere_in(
+        src: &str,
+        interpreter: fn() -> Interpreter<u64>,
+    ) -> Vec<Result<Value<u64>, EvalError<u64>>> {
+        let program = parse_program(src).unwrap();
+
+        let mut env: IntMap<_> = interpreter().env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.into_owned()))
+            .collect::<Vec<_>>();
+
+        let mut closure_env: IntMap<_> = interpreter().env();
+        let mut vm_env: IntMap<_> = interpreter().env();
+        for (form, expected) in program.iter().zip(&results) {
+            assert!(same_result(expected, &compile_closure(form).eval(&mut closure_env)));
+            let compiled = interpreter().compile(::std::slice::from_ref(form));
+            assert!(same_result(expected, &Vm::new(&compiled).run(&mut vm_env)));
+        }
+
+        results
+    }
+
+    #[test]
+    fn parameters_destructure_lists() {
+        let results = run_everywhere(
+            r"
+            (= f (\((a (b c)) d) (add a b c d)))
+            (f (list 1 (list 2 3)) 4)
+            (= swap (\((a b)) (list b a)))
+            (swap (list 1 2))
+            ((\(() x) x) (list) 5)
+            ",
+        );
+
+        assert!(results[1] == Ok(Value::Int(10)));
+        assert_eq!(results[3].as_ref().ok().unwrap().to_string(), "(list 2 1)");
+        assert!(results[4] == Ok(Value::Int(5)));
+    }
+
+    #[test]
+    fn mismatched_arguments_are_errors() {
+        let results = run_everywhere(
+            r"
+            (= f (\((a (b c)) d) (add a b c d)))
+            (f (list 1) 4)
+            (f 5 4)
+            (f (list 1 2) 4)
+            (f (list 1 (list 2 3 4)) 4)
+            ",
+        );
+
+        let mismatch = |expected, got| Err(EvalError::PatternMismatch { expected, got });
+        assert!(results[1] == mismatch(2, Some(1)));
+        assert!(results[2] == mismatch(2, None));
+        assert!(results[3] == mismatch(2, None));
+        assert!(results[4] == mismatch(2, Some(3)));
+
+        assert_eq!(
+            results[1].as_ref().err().unwrap().to_string(),
+            "Expected a list of 2 elements to destructure, got 1"
+        );
+    }
+
+    #[test]
+    fn functions_see_destructured_names_while_the_call_runs() {
+        // Scoping is dynamic, so a function only sees the names a pattern
+        // bound while the call that bound them is still running.
+        let results = run_everywhere(
+            r"
+            (= sum (\((a b)) ((\() (add a b)))))
+            (sum (list 1 2))
+            (= adder (\((a b)) (\() (add a b))))
+            ((adder (list 1 2)))
+            (= a 10)
+            (= b 20)
+            ((adder (list 1 2)))
+            ",
+        );
+
+        assert!(results[1] == Ok(Value::Int(3)));
+        assert!(results[3] == Err(EvalError::UnboundVariable(hash_string("a"))));
+        assert!(results[6] == Ok(Value::Int(30)));
+    }
+
+    #[test]
+    fn optional_parameters_take_their_defaults() {
+        let results = run_everywhere(
+            r"
+            (= f (\(a (= b 10)) (add a b)))
+            (f 1)
+            (f 1 2)
+            (= g (\(a (= b (add a 1)) (= c (add b 1))) (list a b c)))
+            (g 1)
+            (g 1 5)
+            (= h (\((x y) (= z (add x y))) z))
+            (h (list 1 2))
+            (= x 5)
+            ((\((= y x)) y))
+            ",
+        );
+
+        assert!(results[1] == Ok(Value::Int(11)));
+        assert!(results[2] == Ok(Value::Int(3)));
+        assert_eq!(results[4].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        assert_eq!(results[5].as_ref().ok().unwrap().to_string(), "(list 1 5 6)");
+        assert!(results[7] == Ok(Value::Int(3)));
+        // Defaults are evaluated in the callee's scope, which can see the
+        // caller's variables.
+        assert!(results[9] == Ok(Value::Int(5)));
+    }
+
+    #[test]
+    fn defaults_are_only_evaluated_for_missing_arguments() {
+        let results = run_everywhere(
+            r"
+            (= f (\(a (= b nope)) a))
+            (f 1 2)
+            (f 1)
+            (f)
+            ",
+        );
+
+        assert!(results[1] == Ok(Value::Int(1)));
+        assert!(results[2] == Err(EvalError::UnboundVariable(hash_string("nope"))));
+        // Without all the required arguments none of the defaults run.
+        assert!(results[3] == Err(EvalError::UnboundVariable(hash_string("a"))));
+    }
+
+    #[test]
+    fn required_parameters_cant_follow_optional_ones() {
+        for &src in &[r"(\((= a 1) b) b)", r"(\(a (= b 1) (c)) c)"] {
+            match parse_program(src) {
+                Err(ParseError::Syntax { ref message, .. }) => assert!(
+                    message.contains("required parameters can't come after optional ones"),
+                    "{}",
+                    message
+                ),
+                _ => panic!("`{}` parsed", src),
+            }
+        }
+    }
+
+    #[test]
+    fn keywords_name_the_parameter_they_pass() {
+        let results = run_everywhere(
+            r"
+            (= f (\(a b c) (list a b c)))
+            (f :c 3 :a 1 :b 2)
+            (f 1 :c 3 :b 2)
+            (= g (\(a (= b 1) (= c (add b 1))) (list a b c)))
+            (g 1 :c 5)
+            (g :b 4 :a 1)
+            (g 1 2 :c 3)
+            :a
+            ",
+        );
+
+        assert_eq!(results[1].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        assert_eq!(results[2].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        // Optional parameters can be skipped over, and the ones that aren't
+        // given still see the ones that are.
+        assert_eq!(results[4].as_ref().ok().unwrap().to_string(), "(list 1 1 5)");
+        assert_eq!(results[5].as_ref().ok().unwrap().to_string(), "(list 1 4 5)");
+        assert_eq!(results[6].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        assert!(results[7] == Ok(Value::Keyword(hash_string("a"))));
+    }
+
+    #[test]
+    fn bad_keyword_arguments_are_errors() {
+        let results = run_everywhere(
+            r"
+            (= f (\(a (= b 1)) (list a b)))
+            (f 1 :a 2)
+            (f 1 :c 2)
+            (f :b 2)
+            (= g (\((x y)) x))
+            (g :x 1)
+            ",
+        );
+
+        assert!(results[1] == Err(EvalError::DuplicateArgument(hash_string("a"))));
+        assert!(results[2] == Err(EvalError::UnknownKeyword(hash_string("c"))));
+        assert!(results[3] == Err(EvalError::MissingArgument(hash_string("a"))));
+        // Parameters that take a list apart have no name to pass them by.
+        assert!(results[5] == Err(EvalError::UnknownKeyword(hash_string("x"))));
+
+        for &src in &["(f :a)", "(f :a 1 2)", "(f :a :b 1)"] {
+            match parse_program(src) {
+                Err(ParseError::Syntax { ref message, .. }) => assert!(
+                    message.contains("keyword arguments must be `:name value` pairs"),
+                    "{}",
+                    message
+                ),
+                _ => panic!("`{}` parsed", src),
+            }
+        }
+    }
+
+    #[test]
+    fn curry_gives_functions_their_first_arguments() {
+        let results = run_everywhere(
+            r"
+            (= addten (curry add 10))
+            (addten 1 2)
+            (= f (\(a b c) (list a b c)))
+            (= g (curry (curry f 1) 2))
+            (g 3)
+            (g :c 3)
+            ((partial f 1 2 3))
+            (= h (curry if #f 1))
+            (h 2 3)
+            (curry 1)
+            ",
+        );
+
+        assert!(results[1] == Ok(Value::Int(13)));
+        assert_eq!(results[4].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        assert_eq!(results[5].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        assert_eq!(results[6].as_ref().ok().unwrap().to_string(), "(list 1 2 3)");
+        // Too many arguments in total is reported against the function that
+        // was curried.
+        assert!(results[8] == Err(EvalError::ArgumentCount { min: 2, max: 3, got: 4 }));
+        assert!(results[9] == Err(EvalError::NotAFunction));
+    }
+
+    #[test]
+    fn nil_is_not_void() {
+        let results = run_everywhere(
+            r"
+            nil
+            (eq nil nil)
+            (eq (if #f 1) nil)
+            (eq nil (list))
+            (if nil 1 2)
+            (eq nil #f)
+            (null nil)
+            (null (if #f 1))
+            (null (list))
+            (list nil (if #f 1))
+            (= nils 1)
+            nils
+            ",
+        );
+
+        let shown = results.iter().map(|result| result.as_ref().map(Value::to_string));
+        let shown = shown.collect::<Vec<_>>();
+        assert!(results[0] == Ok(Value::Nil));
+        assert_eq!(shown[1], Ok("void".to_owned()));
+        // A one-armed `if` gives `Void`, which isn't `nil`.
+        assert_eq!(shown[2], Ok("#f".to_owned()));
+        assert_eq!(shown[3], Ok("#f".to_owned()));
+        // `nil` is false without being `#f`.
+        assert_eq!(shown[4], Ok("2".to_owned()));
+        assert_eq!(shown[5], Ok("#f".to_owned()));
+        assert_eq!(shown[6], Ok("void".to_owned()));
+        assert_eq!(shown[7], Ok("#f".to_owned()));
+        assert_eq!(shown[8], Ok("#f".to_owned()));
+        assert_eq!(shown[9], Ok("(list nil void)".to_owned()));
+        assert_eq!(shown[11], Ok("1".to_owned()));
+
+        for &src in &["(= nil 1)", r"(\(nil) 1)", r"(\((= nil 1)) 1)", r"(\((a nil)) a)"] {
+            let message = parse_program(src).err().unwrap().to_string();
+            assert!(message.contains("`nil` can't be bound to a value"), "{}", message);
+        }
+    }
+
+    fn host_interpreter() -> Interpreter<u64> {
+        let mut interpreter = corpus_interpreter();
+        interpreter
+            .register_fn(hash_string("hostadd"), |a: u64, b: u64| a + b)
+            .register_fn(hash_string("shout"), |text: String| text.to_uppercase())
+            .register_fn(hash_string("total"), |items: Vec<u64>| items.iter().sum::<u64>())
+            .register_fn(hash_string("orzero"), |n: Option<u64>| n.unwrap_or(0))
+            .register_fn(hash_string("swap"), |(a, b): (u64, String)| (b, a))
+            .register_fn(hash_string("answer"), || 42)
+            .register_fn(hash_string("pick"), |first: bool, a: u64, b: u64, c: u64| {
+                if first {
+                    a
+                } else {
+                    b + c
+                }
+            });
+        interpreter
+    }
+
+    #[test]
+    fn host_functions_convert_their_arguments() {
+        let results = run_everywhere_in(
+            r#"
+            (hostadd 2 3)
+            (shout "hi")
+            (total (list 1 2 3))
+            (orzero nil)
+            (orzero 7)
+            (swap (list 1 "a"))
+            (answer)
+            (pick (eq 1 2) 1 2 3)
+            ((curry hostadd 1) 2)
+            (eq hostadd hostadd)
+            (hostadd 1 "two")
+            (hostadd 1)
+            (total (list 1 "a"))
+            (swap (list 1 2 3))
+            (orzero (list))
+            "#,
+            host_interpreter,
+        );
+
+        let shown = results.iter().map(|result| result.as_ref().map(Value::to_string));
+        let shown = shown.collect::<Vec<_>>();
+        let ok = |text: &str| Ok(text.to_owned());
+        let expected = [
+            "5",
+            "\"HI\"",
+            "6",
+            "0",
+            "7",
+            "(list \"a\" 1)",
+            "42",
+            "5",
+            "3",
+            "void",
+        ];
+        for (shown, expected) in shown.iter().zip(&expected) {
+            assert_eq!(*shown, ok(expected));
+        }
+
+        let wrong = |index, expected, found| {
+            let error = Box::new(TypeError { expected, found });
+            Err(EvalError::WrongType { index, error })
+        };
+        assert!(results[10] == wrong(1, "int", "string"));
+        assert!(results[11] == Err(EvalError::ArgumentCount { min: 2, max: 2, got: 1 }));
+        // An element of a list is blamed on the argument the list is.
+        assert!(results[12] == wrong(0, "int", "string"));
+        assert!(results[13] == wrong(0, "list of 2", "list"));
+        assert!(results[14] == wrong(0, "int", "list"));
+        let message = results[10].as_ref().unwrap_err().to_string();
+        assert_eq!(message, "Expected int as argument 2, got string");
+    }
+
+    #[test]
+    fn reset_goes_back_to_the_baseline() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let mut interpreter = corpus_interpreter();
+        let counted = calls.clone();
+        interpreter.register_fn(hash_string("count"), move || {
+            counted.fetch_add(1, Ordering::SeqCst) + 1
+        });
+
+        let setup = parse_program("(= base 10)").unwrap();
+        let program = parse_program(
+            r#"
+            (= xs (list 1 2 3))
+            (= text (concat "a" "b"))
+            (= twice (\(x) (mul x 2)))
+            (twice 2)
+            (= add 0)
+            (count)
+            "#,
+        )
+        .unwrap();
+        let after = parse_program("(add base (count))").unwrap();
+        let heap = |env: &IntMap<Cow<Value<u64>>>| {
+            env.values().map(|value| value.heap_size()).sum::<usize>()
+        };
+
+        let mut env: IntMap<_> = interpreter.env();
+        eval(&setup[0], &mut env).unwrap();
+        interpreter.mark_baseline(&env);
+        let mut options = EvalOptions {
+            max_heap_bytes: Some(1 << 20),
+            ..EvalOptions::default()
+        };
+        options.memoize(vec![hash_string("twice")]);
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+        let grown = heap(&env);
+        assert!(options.heap_used > 0);
+        assert_eq!(options.memo.as_ref().unwrap().len(), 1);
+
+        interpreter.reset(&mut env);
+        options.reset();
+        assert_eq!(options.heap_used, 0);
+        assert!(options.memo.as_ref().unwrap().is_empty());
+        assert!(heap(&env) < grown);
+        assert!(!env.contains_key(&hash_string("xs")));
+        assert!(!env.contains_key(&hash_string("text")));
+        assert!(*env[&hash_string("base")] == Value::Int(10));
+        // `add` is the native again, and `count` still has its count.
+        assert!(eval(&after[0], &mut env).unwrap().into_owned() == Value::Int(12));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A fresh interpreter goes back to the prelude's baseline, which is
+        // the stdlib on top of the natives.
+        let fresh = corpus_interpreter();
+        fresh.reset(&mut env);
+        assert!(!env.contains_key(&hash_string("base")));
+        assert!(env.contains_key(&hash_string("not")));
+        assert_eq!(env.len(), prelude::env().len() + 2);
+    }
+
+    struct Handle {
+        id: u64,
+    }
+
+    fn makehandle(args: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+        match *args {
+            [id] => {
+                let id = u64::from_value(id).map_err(|error| error.at(0))?;
+                Ok(Value::foreign_named("handle", Handle { id }))
+            }
+            _ => Err(EvalError::ArgumentCount { min: 1, max: 1, got: args.len() }),
+        }
+    }
+
+    fn usehandle(args: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+        match *args {
+            [handle] => {
+                let handle = convert::foreign::<_, Handle>(handle).map_err(|error| error.at(0))?;
+                Ok(Value::Int(handle.id))
+            }
+            _ => Err(EvalError::ArgumentCount { min: 1, max: 1, got: args.len() }),
+        }
+    }
+
+    fn makeother(_: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+        Ok(Value::foreign("not a handle"))
+    }
+
+    fn handle_interpreter() -> Interpreter<u64> {
+        let mut interpreter = corpus_interpreter();
+        interpreter
+            .register(hash_string("makehandle"), makehandle)
+            .register(hash_string("usehandle"), usehandle)
+            .register(hash_string("makeother"), makeother);
+        interpreter
+    }
+
+    #[test]
+    fn foreign_values_round_trip() {
+        let results = run_everywhere_in(
+            r"
+            (= h (makehandle 7))
+            (usehandle h)
+            h
+            (makeother)
+            (eq h h)
+            (eq ((\(x) x) h) h)
+            (eq h (makehandle 7))
+            (usehandle (makeother))
+            (usehandle 1)
+            ",
+            handle_interpreter,
+        );
+
+        let shown = results.iter().map(|result| result.as_ref().map(Value::to_string));
+        let shown = shown.collect::<Vec<_>>();
+        let handle = "<foreign handle>";
+        let expected = [handle, "7", handle, "<foreign>", "void", "void", "#f"];
+        for (shown, expected) in shown.iter().zip(&expected) {
+            assert_eq!(*shown, Ok(expected.to_string()));
+        }
+
+        let wrong = |found| {
+            let expected = ::std::any::type_name::<Handle>();
+            Err(EvalError::WrongType { index: 0, error: Box::new(TypeError { expected, found }) })
+        };
+        assert!(results[7] == wrong("foreign"));
+        assert!(results[8] == wrong("int"));
+
+        // Copies share the object rather than copying it.
+        let handle = Value::<u64>::foreign(Handle { id: 1 });
+        let mut env = corpus_env();
+        env.insert(hash_string("h"), Cow::Owned(handle.clone()));
+        let copy = env.clone()[&hash_string("h")].clone().into_owned();
+        assert!(copy == handle && copy.downcast_foreign::<Handle>().unwrap().id == 1);
+        match handle {
+            Value::Foreign(ref foreign) => assert_eq!(Rc::strong_count(foreign), 3),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn boxes_are_shared() {
+        let results = run_everywhere(
+            r"
+            (= counter (box 0))
+            (= bump (\(b) (boxset b (add (unbox b) 1))))
+            (= inc (curry bump counter))
+            (= read (curry unbox counter))
+            (inc)
+            (inc)
+            (read)
+            ((\(b) (boxset b (add (unbox b) 10))) counter)
+            (unbox counter)
+            (eq counter counter)
+            (eq (box 1) (box 1))
+            (eq (unbox (box 1)) (unbox (box 1)))
+            (box (list 1 counter))
+            (= loop (box 0))
+            (boxset loop (list loop))
+            loop
+            (unbox 1)
+            (boxset counter)
+            ",
+        );
+
+        let shown = results.iter().map(|result| result.as_ref().map(Value::to_string));
+        let shown = shown.collect::<Vec<_>>();
+        // Both partials hold the same box, and so does the lambda's scope.
+        assert_eq!(shown[4], Ok("void".to_owned()));
+        assert_eq!(shown[6], Ok("2".to_owned()));
+        assert_eq!(shown[8], Ok("12".to_owned()));
+        // Boxes are only equal to themselves, whatever they hold.
+        assert_eq!(shown[9], Ok("void".to_owned()));
+        assert_eq!(shown[10], Ok("#f".to_owned()));
+        assert_eq!(shown[11], Ok("void".to_owned()));
+        assert_eq!(shown[12], Ok("(box (list 1 (box 12)))".to_owned()));
+        assert_eq!(shown[15], Ok("(box (list (box ...)))".to_owned()));
+        assert!(results[16] == Err(EvalError::NotABox));
+        assert!(results[17] == Err(EvalError::ArgumentCount { min: 2, max: 2, got: 1 }));
+    }
+
+    #[test]
+    fn copies_share_no_boxes() {
+        let results = run_everywhere(
+            r#"
+            (= inner (box 1))
+            (= original (list inner "text" (list inner 2)))
+            (= copied (copy original))
+            ((\((b s l)) (boxset b 5)) copied)
+            copied
+            original
+            (= loop (box 0))
+            (boxset loop (list loop))
+            (copy loop)
+            (copy 1 2)
+            "#,
+        );
+
+        let shown = results.iter().map(|result| result.as_ref().map(Value::to_string));
+        let shown = shown.collect::<Vec<_>>();
+        // The box turns up twice in the copy, as it did in the original.
+        assert_eq!(shown[4], Ok(r#"(list (box 5) "text" (list (box 5) 2))"#.to_owned()));
+        assert_eq!(shown[5], Ok(r#"(list (box 1) "text" (list (box 1) 2))"#.to_owned()));
+        assert_eq!(shown[8], Ok("(box (list (box ...)))".to_owned()));
+        assert!(results[9] == Err(EvalError::ArgumentCount { min: 1, max: 1, got: 2 }));
+    }
+
+    #[test]
+    fn deep_clones_share_only_what_cant_change() {
+        let text = Rc::new("text".to_owned());
+        let cell = Rc::new(RefCell::new(Value::<u64>::Str(text.clone())));
+        let thunk = Rc::new(Thunk::new(Value::InbuiltFunc(callable)));
+        thunk.set(Value::Box(cell.clone()));
+        let partial = Value::Partial(Rc::new(Partial::new(
+            Value::InbuiltFunc(callable),
+            vec![Value::Box(cell.clone())],
+        )));
+        let original = Value::List(Rc::new(vec![
+            Value::Box(cell.clone()),
+            Value::Thunk(thunk.clone()),
+            partial,
+            Value::Str(text.clone()),
+        ]));
+
+        let copy = original.deep_clone();
+        assert_eq!(copy.to_string(), original.to_string());
+        let items = match copy {
+            Value::List(ref items) => items,
+            _ => unreachable!(),
+        };
+        let copied_cell = match items[0] {
+            Value::Box(ref copied) => copied.clone(),
+            _ => unreachable!(),
+        };
+        assert!(!Rc::ptr_eq(&copied_cell, &cell));
+        // The box is copied once, wherever it turns up.
+        match (&items[1], &items[2]) {
+            (Value::Thunk(copied), Value::Partial(partial)) => {
+                assert!(!Rc::ptr_eq(copied, &thunk));
+                let is_copied_cell = |value: &Value<u64>| match *value {
+                    Value::Box(ref b) => Rc::ptr_eq(b, &copied_cell),
+                    _ => false,
+                };
+                assert!(is_copied_cell(copied.value().unwrap()));
+                assert!(is_copied_cell(&partial.args()[0]));
+            }
+            _ => unreachable!(),
+        }
+        // Strings can't change, so they're shared.
+        match (&items[3], &*copied_cell.borrow()) {
+            (Value::Str(a), Value::Str(b)) => assert!(Rc::ptr_eq(a, &text) && Rc::ptr_eq(b, &text)),
+            _ => unreachable!(),
+        }
+
+        *copied_cell.borrow_mut() = Value::Int(1);
+        assert!(matches!(*cell.borrow(), Value::Str(_)));
+    }
+
+    #[test]
+    fn deep_cloning_deep_lists_does_not_overflow() {
+        use std::thread;
+
+        let cloner = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let cell = Rc::new(RefCell::new(Value::Int(0)));
+                let mut list = Value::Box(cell.clone());
+                for _ in 0..100_000 {
+                    list = Value::List(Rc::new(vec![list]));
+                }
+                let wide = Value::<u64>::List(Rc::new(vec![Value::Int(1); 100_000]));
+
+                let copy = list.deep_clone();
+                assert!(wide.deep_clone().equal(&wide));
+
+                // Values are dropped recursively, so both are taken apart
+                // from the outside in.
+                let innermost = |mut value: Value<u64>| {
+                    let mut depth = 0;
+                    while let Value::List(items) = value {
+                        value = Rc::try_unwrap(items).ok().unwrap().pop().unwrap();
+                        depth += 1;
+                    }
+                    (depth, value)
+                };
+                let (depth, copied) = innermost(copy);
+                assert_eq!(depth, 100_000);
+                match copied {
+                    Value::Box(copied) => assert!(!Rc::ptr_eq(&copied, &cell)),
+                    _ => unreachable!(),
+                }
+                assert_eq!(innermost(list).0, 100_000);
+            })
+            .unwrap();
+
+        cloner.join().unwrap();
+    }
+
+    #[test]
+    fn strings_interpolate_expressions() {
+        let results = run_everywhere(
+            r#"
+            (= x 5)
+            "count is {x}"
+            "sum {(add x 1 2)}!"
+            "{{x}} \"{x}\" \\"
+            "outer {"inner {x}"} {(list 1 #f "s")}"
+            "{ x }"
+            "plain"
+            "#,
+        );
+
+        let text = |i: usize| match results[i] {
+            Ok(Value::Str(ref text)) => text.to_string(),
+            ref other => panic!("{:?}", other.as_ref().map(Value::to_string)),
+        };
+        assert_eq!(text(1), "count is 5");
+        assert_eq!(text(2), "sum 8!");
+        assert_eq!(text(3), "{x} \"5\" \\");
+        // Strings go in as they are, and anything else as it's displayed.
+        assert_eq!(text(4), "outer inner 5 (list 1 #f \"s\")");
+        assert_eq!(text(5), "5");
+        assert_eq!(text(6), "plain");
+
+        let program = parse_program(r#""a" "a {x}""#).unwrap();
+        assert!(matches!(program[0], Ast::Lit(Value::Str(_))));
+        assert!(matches!(program[1], Ast::Call(ref func, _) if matches!(**func, Ast::Variable(_))));
+    }
+
+    #[test]
+    fn errors_inside_interpolations_point_into_the_string() {
+        let src = r#"(= y "total {(add 1 2}")"#;
+        match parse_program(src) {
+            Err(ParseError::Syntax { position, .. }) => assert_eq!(position, src.find('}')),
+            _ => panic!("`{}` parsed", src),
+        }
+
+        for &src in &[r#""a } b""#, r#""a { b""#, r#""{}""#, r#""\q""#] {
+            assert!(parse_program(src).is_err(), "`{}` parsed", src);
+        }
+    }
+
+    #[test]
+    fn bytes_literals_take_escapes() {
+        let program = parse_program(r##"#b"a\x00\xFF\"\\\nµ" #b"" #b"x"#f"##).unwrap();
+        let bytes = |i: usize| match program[i] {
+            Ast::Lit(Value::Bytes(ref bytes)) => bytes.to_vec(),
+            _ => panic!("not bytes"),
+        };
+        assert_eq!(bytes(0), b"a\x00\xff\"\\\n\xc2\xb5");
+        assert_eq!(bytes(1), b"");
+        assert_eq!(bytes(2), b"x");
+        assert!(matches!(program[3], Ast::Lit(Value::False)));
+
+        for &src in &[r#"#b"\xg0""#, r#"#b"\x1""#, r#"#b"\q""#, r#"#b"abc"#, "#b", "#x"] {
+            assert!(parse_program(src).is_err(), "`{}` parsed", src);
+        }
+        let message = parse_program(r#"#b"\x1""#).err().unwrap().to_string();
+        assert!(message.contains("`\\x` must be followed by two hex digits"), "{}", message);
+
+        let src = r#"(eq #b"ab" (stringtobytes "ab")) (eq #b"ab" "ab")"#;
+        let program = parse_program(src).unwrap();
+        let mut env = corpus_env();
+        let results = program.iter().map(|form| eval(form, &mut env).map(Cow::into_owned));
+        let results = results.collect::<Vec<_>>();
+        assert_eq!(results, [Ok(Value::Void), Ok(Value::False)]);
+    }
+
+    #[test]
+    fn bytes_show_a_bounded_preview() {
+        let bytes = |len| Value::<u64>::Bytes(Rc::new((0..len).map(|i| i as u8).collect()));
+
+        let shown = bytes(BYTES_SHOWN).to_string();
+        assert!(shown.starts_with(r#"#b"\x00\x01"#) && shown.ends_with(r#"\x1f""#), "{}", shown);
+        assert_eq!(shown.len(), 4 * BYTES_SHOWN + 4);
+
+        let shown = bytes(1_000_000).to_string();
+        assert!(shown.ends_with(r#"\x1f"... (1000000 bytes)"#), "{}", shown);
+        assert_eq!(shown, format!("{}... (1000000 bytes)", bytes(BYTES_SHOWN)));
+    }
+
+    #[test]
+    fn eval_runs_quoted_code() {
+        let src = "(eval '(add 1 2)) (eval '(= x 5)) (add x 1) (= y 3) (eval '(add ,y y))
+                   (eval '(1 2)) (eval (list)) (eval 1 2)";
+        let program = parse_program(src).unwrap();
+        let mut env = corpus_env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).map(Cow::into_owned))
+            .collect::<Vec<_>>();
+
+        assert!(results[0] == Ok(Value::Int(3)));
+        // A define in evaluated code stays defined.
+        assert!(results[1] == Ok(Value::Int(5)));
+        assert!(results[2] == Ok(Value::Int(6)));
+        assert!(results[4] == Ok(Value::Int(6)));
+        assert!(results[5] == Err(EvalError::NotAFunction));
+        assert!(matches!(results[6], Err(EvalError::NotCode(_))));
+        assert!(results[7] == Err(EvalError::ArgumentCount { min: 1, max: 1, got: 2 }));
+
+        let unsupported = Some(EvalError::Unsupported);
+        assert!(compile_closure(&program[0]).eval(&mut corpus_env()).err() == unsupported);
+        let compiled = corpus_interpreter().compile(&program[..1]);
+        assert!(Vm::new(&compiled).run(&mut corpus_env()).err() == unsupported);
+    }
+
+    #[test]
+    fn let_values_binds_each_value() {
+        let results = run_everywhere(
+            r"
+            (let-values ((q r) (divmod 17 5)) (list q r))
+            (= f (\(x) (values x (add x 1))))
+            (let-values ((a b) (f 1)) (add a b))
+            (let-values ((a b c) (divmod 17 5)) a)
+            (add (values 1 2) 3)
+            (divmod 1 0)
+            (divmod 1 (list))
+            ",
+        );
+
+        assert_eq!(results[0].as_ref().ok().unwrap().to_string(), "(list 3 2)");
+        assert!(results[2] == Ok(Value::Int(3)));
+        assert!(results[3] == Err(EvalError::PatternMismatch { expected: 3, got: Some(2) }));
+        // Multiple values can only go where a pattern takes them apart.
+        assert!(results[4] == Err(EvalError::MultipleValues { count: 2 }));
+        assert!(results[5] == Err(EvalError::DivideByZero));
+        assert!(results[6] == Err(EvalError::NotAnInt));
+    }
+
+    #[test]
+    fn assert_and_error_fail_with_their_message() {
+        let results = run_everywhere(
+            r#"
+            (assert (eq 1 1) "fine")
+            (assert (eq 1 2) "one isn't two")
+            (assert #f 3)
+            (error "bad" 1 (list 2))
+            (assert #f)
+            "#,
+        );
+
+        let message = |text: &str| Rc::new(text.to_owned());
+        assert!(results[0] == Ok(Value::Void));
+        assert!(
+            results[1]
+                == Err(EvalError::AssertionFailed {
+                    message: message("one isn't two"),
+                    span: None,
+                })
+        );
+        assert!(
+            results[2]
+                == Err(EvalError::AssertionFailed {
+                    message: message("3"),
+                    span: None,
+                })
+        );
+        assert_eq!(results[3].as_ref().err().unwrap().to_string(), "bad 1 (list 2)");
+        assert!(results[4] == Err(EvalError::ArgumentCount { min: 2, max: 2, got: 1 }));
+    }
+
+    #[test]
+    fn eval_counts_against_the_limits() {
+        // The call and `eval`, the five nodes of `(list 'add 1 2)` that the
+        // quote reads as, and then the four nodes of `(add 1 2)` itself.
+        let program = parse_program("(eval '(add 1 2))").unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            fuel: Some(11),
+            ..EvalOptions::default()
+        };
+        let result = eval_with(&program[0], &mut env, &mut options).map(Cow::into_owned);
+        assert!(result == Ok(Value::Int(3)));
+        assert_eq!(options.fuel, Some(0));
+
+        options.fuel = Some(10);
+        assert_eq!(eval_with(&program[0], &mut env, &mut options).err(), Some(EvalError::OutOfFuel));
+
+        let program = parse_program(r"(= f (\() (eval '(f)))) (f)").unwrap();
+        let mut options = EvalOptions {
+            max_depth: Some(20),
+            ..EvalOptions::default()
+        };
+        eval_with(&program[0], &mut env, &mut options).ok().unwrap();
+        assert_eq!(
+            eval_with(&program[1], &mut env, &mut options).err(),
+            Some(EvalError::TooDeep { max: 20 })
+        );
+    }
+
+    #[test]
+    fn try_catches_errors() {
+        let program = parse_program(
+            r#"
+            (try (divmod 1 0) (catch (kind message) (eq kind 'dividebyzero)))
+            (try (divmod 1 0) (catch (kind message) message))
+            (try (error "bad" 1 (list 2)) (catch (kind message) (list (eq kind 'user) message)))
+            (try (add 1 2) (catch e 0))
+            (try (try (error "inner") (catch e (error "again"))) (catch (kind message) message))
+            (try (error "inner") (catch e (error "again")))
+            (try (undefined) (catch (kind message) (eq kind 'unbound)))
+            (try (error "ignored") (catch e))
+            "#,
+        )
+        .unwrap();
+        let mut env = corpus_env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(results[0], Ok("void".to_owned()));
+        assert_eq!(results[1], Ok(r#""Divided by zero""#.to_owned()));
+        assert_eq!(results[2], Ok(r#"(list void "bad 1 (list 2)")"#.to_owned()));
+        assert_eq!(results[3], Ok("3".to_owned()));
+        // What goes wrong in a handler isn't caught by its own `try`, but is
+        // by one around it.
+        assert_eq!(results[4], Ok(r#""again""#.to_owned()));
+        assert_eq!(results[5].as_ref().err().unwrap().to_string(), "again");
+        assert_eq!(results[6], Ok("void".to_owned()));
+        assert_eq!(results[7], Ok("void".to_owned()));
+
+        for &src in &["(try 1)", "(try 1 2)", "(try 1 (catch))", "(try 1 (e x))"] {
+            assert!(parse_program(src).is_err(), "{}", src);
+        }
+        let program = parse_program("(tryhard 1)").unwrap();
+        let unbound = EvalError::UnboundVariable(hash_string("tryhard"));
+        assert!(eval(&program[0], &mut env).err() == Some(unbound));
+    }
+
+    #[test]
+    fn limits_escape_try() {
+        let program = parse_program(
+            r"
+            (= loop (\() (loop) (loop)))
+            (try (loop) (catch e 1))
+            ",
+        )
+        .unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            fuel: Some(1000),
+            ..EvalOptions::default()
+        };
+        eval_with(&program[0], &mut env, &mut options).ok().unwrap();
+        let result = eval_with(&program[1], &mut env, &mut options);
+        assert_eq!(result.err(), Some(EvalError::OutOfFuel));
+
+        let mut options = EvalOptions {
+            max_depth: Some(50),
+            ..EvalOptions::default()
+        };
+        let result = eval_with(&program[1], &mut env, &mut options);
+        assert_eq!(result.err(), Some(EvalError::TooDeep { max: 50 }));
+    }
+
+    #[test]
+    fn native_panics_are_caught() {
+        fn explode(_: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+            panic!("boom")
+        }
+        let mut interpreter = prelude::interpreter();
+        interpreter
+            .register(hash_string("explode"), explode)
+            .register_fn(hash_string("shout"), |n: u64| -> u64 { panic!("{} is too loud", n) });
+        let program = parse_program(
+            r"
+            (= f (\(x) (list (explode x))))
+            (f 1)
+            (shout 3)
+            ((if 1 explode) 1)
+            (add 1 2)
+            ",
+        )
+        .unwrap();
+        let mut env: IntMap<_> = interpreter.env();
+        let mut options = EvalOptions {
+            max_depth: Some(10),
+            ..interpreter.options()
+        };
+        let results = program
+            .iter()
+            .map(|form| eval_with(form, &mut env, &mut options).map(Cow::into_owned))
+            .collect::<Vec<_>>();
+
+        let panicked = |name: Option<&str>, message: &str| {
+            let name = name.map(hash_string);
+            Err(EvalError::NativePanic { name, message: message.to_owned() })
+        };
+        assert!(results[1] == panicked(Some("explode"), "boom"));
+        assert!(results[2] == panicked(Some("shout"), "3 is too loud"));
+        assert!(results[3] == panicked(None, "boom"));
+        assert_eq!(results[3].as_ref().unwrap_err().to_string(), "A native panicked: boom");
+        // The same environment and options carry on, at the same depth.
+        assert!(results[4] == Ok(Value::Int(3)));
+        for _ in 0..20 {
+            let result = eval_with(&program[1], &mut env, &mut options).map(Cow::into_owned);
+            assert!(result == panicked(Some("explode"), "boom"));
+        }
+        let nested = parse_program(r"(\() (\() (\() (add 1 2))))").unwrap();
+        assert!(eval_with(&nested[0], &mut env, &mut options).is_ok());
+
+        options.catch_native_panics = false;
+        let unwound = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = eval_with(&program[2], &mut env, &mut options);
+        }));
+        assert_eq!(unwound.unwrap_err().downcast_ref::<String>().unwrap(), "3 is too loud");
+        let result = eval_with(&program[4], &mut env, &mut options).map(Cow::into_owned);
+        assert!(result == Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn thunks_are_forced_once() {
+        use std::cell::Cell;
+
+        thread_local!(static TICKS: Cell<u64> = const { Cell::new(0) });
+
+        fn tick<T>(_: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+            Ok(Value::Int(TICKS.with(|ticks| {
+                ticks.set(ticks.get() + 1);
+                ticks.get()
+            })))
+        }
+
+        let mut interpreter = corpus_interpreter();
+        interpreter.register(hash_string("tick"), tick);
+
+        let program = parse_program(
+            r"
+            (= t (delay (tick)))
+            (tick)
+            (force t)
+            (force t)
+            (tick)
+            (force 5)
+            (eq t t)
+            (eq (delay 1) (delay 1))
+            (= later (delay (add x 1)))
+            ((\(x) (force later)) 41)
+            ((\(x) (force later)) 0)
+            (= deep (\(n) ((if (eq n 50) (\() (force (delay n))) (\() (deep (add n 1)))))))
+            (deep 0)
+            t
+            ",
+        )
+        .unwrap();
+
+        TICKS.with(|ticks| ticks.set(0));
+        let mut env: IntMap<_> = interpreter.env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+
+        // Delaying doesn't tick, and only the first force does.
+        assert_eq!(results[1], Ok("1".to_owned()));
+        assert_eq!(results[2], Ok("2".to_owned()));
+        assert_eq!(results[3], Ok("2".to_owned()));
+        assert_eq!(results[4], Ok("3".to_owned()));
+        assert_eq!(results[5], Ok("5".to_owned()));
+        assert_eq!(results[6], Ok("void".to_owned()));
+        assert_eq!(results[7], Ok("#f".to_owned()));
+        // Scoping is dynamic, so `x` is whatever it is where the thunk is
+        // first forced, and stays that way once it has been.
+        assert_eq!(results[9], Ok("42".to_owned()));
+        assert_eq!(results[10], Ok("42".to_owned()));
+        assert_eq!(results[12], Ok("50".to_owned()));
+        assert_eq!(results[13], Ok("<thunk>".to_owned()));
+
+        for &src in &["(delay)", "(delay 1 2)"] {
+            assert!(parse_program(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn forcing_counts_against_the_limits() {
+        let program = parse_program(
+            r"
+            (= t (delay (add 1 2 3 4 5)))
+            (force t)
+            (force t)
+            ",
+        )
+        .unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            fuel: Some(1000),
+            ..EvalOptions::default()
+        };
+        eval_with(&program[0], &mut env, &mut options).ok().unwrap();
+
+        let mut used = Vec::new();
+        for form in &program[1..] {
+            let before = options.fuel.unwrap();
+            let result = eval_with(form, &mut env, &mut options).map(Cow::into_owned);
+            assert!(result == Ok(Value::Int(15)));
+            used.push(before - options.fuel.unwrap());
+        }
+        // The second force doesn't evaluate the `add` again.
+        assert!(used[1] < used[0], "{:?}", used);
+
+        // A thunk whose expression runs out of fuel isn't kept as forced.
+        let program = parse_program(r"(= t (delay (add 1 2))) (force t) (force t)").unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            fuel: Some(4),
+            ..EvalOptions::default()
+        };
+        eval_with(&program[0], &mut env, &mut options).ok().unwrap();
+        let result = eval_with(&program[1], &mut env, &mut options);
+        assert_eq!(result.err(), Some(EvalError::OutOfFuel));
+        options.fuel = None;
+        let result = eval_with(&program[2], &mut env, &mut options).map(Cow::into_owned);
+        assert!(result == Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn heap_size_counts_shared_values_once() {
+        let program = parse_program(r#"(= xs (list 1 2 3)) (list xs xs) "hello""#).unwrap();
+        let mut env = corpus_env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        let xs = results[0].heap_size();
+        assert!(xs >= 3 * mem::size_of::<Value<u64>>());
+        let outer = results[1].heap_size() - xs;
+        assert!(outer >= 2 * mem::size_of::<Value<u64>>() && outer < xs);
+        assert!(results[2].heap_size() >= "hello".len());
+        assert_eq!(Value::<u64>::Int(1).heap_size(), 0);
+    }
+
+    #[test]
+    fn memory_limit_stops_growing_lists() {
+        let program =
+            parse_program(r"(= grow (\(xs n) (grow (list n xs) (add n 1)))) (grow (list) 0)").unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            max_heap_bytes: Some(4096),
+            ..EvalOptions::default()
+        };
+        eval_with(&program[0], &mut env, &mut options).unwrap();
+        match eval_with(&program[1], &mut env, &mut options) {
+            Err(EvalError::MemoryLimitExceeded { used, limit: 4096 }) => {
+                assert!(used > 4096);
+                assert_eq!(used, options.heap_used);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Without a limit, nothing is counted.
+        let program = parse_program(r#"(= xs (list 1 2 3)) (= s (concat "hello " "world")) (= xs 1)"#);
+        let program = program.unwrap();
+        let mut options = EvalOptions::default();
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+        assert_eq!(options.heap_used, 0);
+
+        // Redefining `xs` doesn't take any more.
+        options.max_heap_bytes = Some(1 << 20);
+        let mut env = corpus_env();
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+        let lower = 3 * mem::size_of::<Value<u64>>() + "hello world".len();
+        assert!(options.heap_used >= lower && options.heap_used < 1024, "{}", options.heap_used);
+    }
+
+    #[test]
+    fn binding_limit_counts_what_evaluation_binds() {
+        let options = || EvalOptions {
+            max_bindings: Some(100),
+            ..EvalOptions::default()
+        };
+
+        // The prelude is already there, so only the new globals count.
+        let program = parse_program(&many_defines(100)).unwrap();
+        let mut env = corpus_env();
+        let mut exact = options();
+        for form in &program {
+            eval_with(form, &mut env, &mut exact).unwrap();
+        }
+        assert_eq!(exact.globals_bound, 100);
+
+        let program = parse_program(&many_defines(101)).unwrap();
+        let mut env = corpus_env();
+        let mut over = options();
+        let results = program
+            .iter()
+            .map(|form| eval_with(form, &mut env, &mut over))
+            .collect::<Vec<_>>();
+        assert!(results[..100].iter().all(Result::is_ok));
+        assert_eq!(results[100].clone().err(), Some(EvalError::TooManyBindings { limit: 100 }));
+
+        // Each call binds `n` and `x` and hasn't returned when the next one
+        // starts, so this runs out after 50 of them.
+        let program = parse_program(
+            r"
+            (= count (\(n) (= x n) (add 1 (count (add n 1)))))
+            (count 0)
+            ",
+        )
+        .unwrap();
+        let mut env = corpus_env();
+        let mut nested = options();
+        eval_with(&program[0], &mut env, &mut nested).unwrap();
+        assert_eq!(
+            eval_with(&program[1], &mut env, &mut nested).err(),
+            Some(EvalError::TooManyBindings { limit: 100 })
+        );
+    }
+
+    #[test]
+    fn bindings_are_released_when_calls_return() {
+        // Each call defines 50 variables, but there's only ever one call at
+        // a time.
+        let defines = many_defines(50).lines().take(50).collect::<Vec<_>>().join(" ");
+        let src = format!(r"(= bind (\() {})) {}", defines, "(bind) ".repeat(10));
+        let program = parse_program(&src).unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            max_bindings: Some(51),
+            ..EvalOptions::default()
+        };
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+        assert_eq!(options.globals_bound, 1);
+    }
+
+    // Counts up from `k` to 30, so `(fact 1)` is 30 factorial.
+    const FACTORIAL: &str = r"
+        (= fact (\(k) ((if (eq k 31) (\() 1) (\() (mul k (fact (add k 1))))))))
+        (fact 1)
+    ";
+
+    #[test]
+    fn big_integers_are_only_made_when_allowed() {
+        let program = parse_program(FACTORIAL).unwrap();
+        let mut env = corpus_env();
+        eval(&program[0], &mut env).unwrap();
+        assert_eq!(eval(&program[1], &mut env).err(), Some(EvalError::IntegerOverflow));
+
+        let mut options = EvalOptions {
+            auto_promote: true,
+            ..EvalOptions::default()
+        };
+        let result = eval_with(&program[1], &mut env, &mut options).unwrap();
+        assert_eq!(result.to_string(), "265252859812191058636308480000000");
+
+        // A big literal can be added to without promoting anything, and a
+        // result that fits is an `Int` again.
+        let program = parse_program(
+            "(add 18446744073709551615 1) (add 18446744073709551616 1) \
+             (mul 18446744073709551616 0)",
+        )
+        .unwrap();
+        let results = program.iter().map(|form| eval(form, &mut env).map(Cow::into_owned));
+        let results = results.collect::<Vec<_>>();
+        assert_eq!(results[0], Err(EvalError::IntegerOverflow));
+        assert_eq!(results[1].as_ref().unwrap().to_string(), "18446744073709551617");
+        assert_eq!(results[2], Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn big_literals_round_trip() {
+        let digits = "1234567890123456789012345678901234567890";
+        let program = parse_program(digits).unwrap();
+        assert!(matches!(program[0], Ast::Lit(Value::BigInt(_))));
+
+        let mut env = corpus_env();
+        assert_eq!(eval(&program[0], &mut env).unwrap().to_string(), digits);
+    }
+
+    #[test]
+    fn ints_and_big_ints_compare_by_value() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let big = |digits| {
+            Value::<u64>::BigInt(Rc::new(bigint::BigInt::from_decimal(digits).unwrap()))
+        };
+        let hash = |value: &Value<u64>| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        assert!(Value::Int(u64::MAX) < big("18446744073709551616"));
+        assert!(big("18446744073709551616") > Value::Int(5));
+        assert!(big("18446744073709551616") < big("18446744073709551617"));
+        assert!(big("18446744073709551616") != Value::Int(0));
+
+        // A `BigInt` small enough to be an `Int` is the same as one.
+        assert!(big("5") == Value::Int(5));
+        assert_eq!(hash(&big("5")), hash(&Value::Int(5)));
+
+        let program = parse_program("(sort (list 18446744073709551616 3 2))").unwrap();
+        let mut env = corpus_env();
+        let sorted = eval(&program[0], &mut env).unwrap().into_owned();
+        assert_eq!(sorted.to_string(), "(list 2 3 18446744073709551616)");
+    }
+
+    #[test]
+    fn natives_can_take_keyword_arguments() {
+        fn sub(args: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+            let names = [hash_string("from"), hash_string("take")];
+            match keyword_args(args, &names)?[..] {
+                [Some(&Value::Int(from)), Some(&Value::Int(take))] => Ok(Value::Int(from - take)),
+                _ => Err(EvalError::ArgumentCount { min: 2, max: 2, got: args.len() }),
+            }
+        }
+
+        let program =
+            parse_program("(sub 5 3) (sub :take 3 :from 5) (sub 5 :take 3) (sub 5 :from 3) (sub 5 :by 3)")
+                .unwrap();
+        let mut env = corpus_env();
+        env.insert(hash_string("sub"), Cow::Owned(Value::InbuiltFunc(sub)));
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).map(Cow::into_owned))
+            .collect::<Vec<_>>();
+
+        assert!(results[0] == Ok(Value::Int(2)));
+        assert!(results[1] == Ok(Value::Int(2)));
+        assert!(results[2] == Ok(Value::Int(2)));
+        assert!(results[3] == Err(EvalError::DuplicateArgument(hash_string("from"))));
+        assert!(results[4] == Err(EvalError::UnknownKeyword(hash_string("by"))));
+    }
+
+    #[test]
+    fn duplicates_are_found_inside_patterns() {
+        let src = r"(\((a b) (c a)) a)";
+        let mut warnings = Vec::new();
+        parse_program_with(src, &ParseOptions::default(), &mut |d| warnings.push(d)).unwrap();
+
+        assert_eq!(
+            warnings,
+            vec![Diagnostic::DuplicateParameter {
+                name: "a".into(),
+                position: 12,
+            }]
+        );
+    }
+
+    // The sizes are for 64-bit targets.
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn ast_and_value_stay_small() {
+        const _: () = assert!(::std::mem::size_of::<Ast<u64>>() == 32);
+        const _: () = assert!(::std::mem::size_of::<Value<u64>>() == 16);
+    }
+
+    #[test]
+    fn dropping_deep_asts_does_not_overflow() {
+        use std::thread;
+
+        // `Ast` isn't `Send`, so each tree is built on the small stack too.
+        let dropper = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let depth = 200_000;
+                let name = hash_string("a");
+
+                let mut calls = Ast::Variable(name);
+                let mut defines = Ast::Lit(Value::Int(0));
+                let mut lambdas = Ast::Lit(Value::Void);
+
+                for _ in 0..depth {
+                    calls = Ast::Call(Rc::new(calls), vec![Ast::Variable(name)].into());
+                    defines = Ast::Define(name, Rc::new(defines));
+                    lambdas = Ast::Lit(Value::Function(Rc::new(Lambda::new(vec![name], vec![lambdas]))));
+                }
+
+                drop(calls);
+                drop(defines);
+                drop(lambdas);
+            })
+            .unwrap();
+
+        dropper.join().unwrap();
+    }
+
+    #[test]
+    fn iter_visits_every_node_in_order() {
+        for src in &[MANY_VARIABLES, NESTED_FUNC, REAL_CODE] {
+            let (program, coverage) =
+                Coverage::parse(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+            let nodes = program.iter().map(|form| form.iter().count()).sum::<usize>();
+            assert_eq!(nodes, coverage.len());
+        }
+
+        let program = parse_program(r"(= f (\(x (= y 1)) (add x y))) (f 2)").unwrap();
+        let nodes = program[0]
+            .iter_with_depth()
+            .map(|(depth, ast)| match *ast {
+                Ast::Lit(Value::Int(n)) => (depth, n.to_string()),
+                Ast::Lit(Value::Function(_)) => (depth, "\\".to_owned()),
+                Ast::Variable(_) => (depth, "var".to_owned()),
+                Ast::Call(..) => (depth, "call".to_owned()),
+                Ast::Define(..) => (depth, "=".to_owned()),
+                _ => (depth, "?".to_owned()),
+            })
+            .collect::<Vec<_>>();
+        let expected = [(0, "="), (1, "\\"), (2, "1"), (2, "call")]
+            .iter()
+            .chain(&[(3, "var"), (3, "var"), (3, "var")])
+            .map(|&(depth, kind)| (depth, kind.to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(nodes, expected);
+    }
+
+    #[test]
+    fn iterating_deep_asts_does_not_overflow() {
+        use std::thread;
+
+        let walker = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let name = hash_string("a");
+
+                let mut calls = Ast::Variable(name);
+                for _ in 0..100_000 {
+                    calls = Ast::Call(Rc::new(calls), vec![Ast::Variable(name)].into());
+                }
+
+                assert_eq!(calls.iter().count(), 200_001);
+                assert_eq!(calls.iter_with_depth().map(|(depth, _)| depth).max(), Some(100_000));
+            })
+            .unwrap();
+
+        walker.join().unwrap();
+    }
+
+    #[test]
+    fn named_asts_hash_to_what_expr_parses() {
+        let src = r"(= f (\(x (= y :k)) (list x y 'z))) (f (curry add 1))";
+        let (program, symbols) =
+            parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+        let named = program
+            .iter()
+            .map(|form| form.clone().map_idents(|id| symbols.name(id).unwrap().to_owned()))
+            .collect::<Vec<Ast<String>>>();
+        match named[0] {
+            Ast::Define(ref name, _) => assert_eq!(name, "f"),
+            _ => panic!("not a define"),
+        }
+
+        let mut rest = src;
+        for form in named {
+            let (expected, remaining) = expr().easy_parse(rest).unwrap();
+            rest = remaining;
+            assert!(same_ast(&form.map_idents(|name| hash_string(&name)), &expected));
+        }
+    }
+
+    #[test]
+    fn u64_hasher_takes_any_key() {
+        use std::collections::HashMap;
+        use std::hash::BuildHasher;
+
+        // Identifiers are hashes already, so they're used as they are.
+        for &id in &[0, 1, hash_string("add"), u64::MAX] {
+            assert_eq!(U64Hasher::default().hash_one(id), id);
+        }
+
+        let mut ids = HashMap::with_hasher(U64Hasher::default());
+        let mut pairs = HashMap::with_hasher(U64Hasher::default());
+        let mut names = HashMap::with_hasher(U64Hasher::default());
+        for i in 0..100u64 {
+            ids.insert(i, i);
+            pairs.insert((i, i / 2), i);
+            names.insert(format!("name{}", i), i);
+        }
+        for i in 0..100u64 {
+            assert_eq!(ids[&i], i);
+            assert_eq!(pairs[&(i, i / 2)], i);
+            assert_eq!(names[&format!("name{}", i)[..]], i);
+        }
+        assert_ne!(U64Hasher::default().hash_one("a"), U64Hasher::default().hash_one("b"));
+    }
+
+    // A keyed hasher that, like some deliberately don't, can't be cloned.
+    // A call's scope only borrows the globals beneath it, so `eval` never
+    // needs another copy of their hasher.
+    struct Keyed(::std::collections::hash_map::RandomState);
+
+    impl ::std::hash::BuildHasher for Keyed {
+        type Hasher = ::std::collections::hash_map::DefaultHasher;
+
+        fn build_hasher(&self) -> Self::Hasher {
+            self.0.build_hasher()
+        }
+    }
+
+    fn keyed_env<'a>() -> ::std::collections::HashMap<u64, Cow<'a, Value<u64>>, Keyed> {
+        let mut env = ::std::collections::HashMap::with_hasher(Keyed(Default::default()));
+        env.extend(corpus_env());
+        env
+    }
+
+    #[test]
+    fn evaluates_with_a_hasher_that_cant_be_cloned() {
+        let program = parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let mut expected_env = corpus_env();
+        let mut env = keyed_env();
+
+        for form in &program {
+            let expected = eval(form, &mut expected_env).map(|v| v.into_owned());
+            let actual = eval(form, &mut env).map(|v| v.into_owned());
+            assert!(same_result(&expected, &actual));
+            let mut options = EvalOptions::default();
+            let actual = eval_with(form, &mut env, &mut options).map(|v| v.into_owned());
+            assert!(same_result(&expected, &actual));
+        }
+    }
+
+    #[test]
+    fn shadowing_is_the_same_with_any_hasher() {
+        let program = parse_program(
+            r"
+            (= x 1)
+            (= show (\() x))
+            (= shadow (\(x) (= y (show)) (= x 3) (list y (show))))
+            (shadow 2)
+            x
+            ",
+        )
+        .unwrap();
+        let mut env = keyed_env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+
+        // Calls see their caller's parameters and defines, and none of them
+        // get out to the globals.
+        assert_eq!(results[3], Value::List(vec![Value::Int(2), Value::Int(3)].into()));
+        assert_eq!(results[4], Value::Int(1));
+        assert!(!env.contains_key(&hash_string("y")));
+    }
+
+    #[test]
+    fn map_idents_renames_one_identifier() {
+        let program = parse_program(r"(= x 1) (= f (\(x (y)) (add x y))) (f x (list 2))").unwrap();
+        let expected = parse_program(r"(= z 1) (= f (\(z (y)) (add z y))) (f z (list 2))").unwrap();
+
+        let (x, z) = (hash_string("x"), hash_string("z"));
+        let mut seen = Vec::new();
+        for (form, expected) in program.into_iter().zip(&expected) {
+            let renamed = form.map_idents(|id| {
+                seen.push(id);
+                if id == x {
+                    z
+                } else {
+                    id
+                }
+            });
+            assert!(same_ast(&renamed, expected));
+        }
+        let names = ["x", "f", "x", "y", "add", "x", "y", "f", "x", "list"];
+        assert_eq!(seen, names.iter().map(|name| hash_string(name)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mapping_deep_asts_does_not_overflow() {
+        use std::thread;
+
+        let mapper = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let name = hash_string("a");
+
+                let mut calls = Ast::Variable(name);
+                let mut lambdas = Ast::Lit(Value::Void);
+                for _ in 0..100_000 {
+                    calls = Ast::Call(Rc::new(calls), vec![Ast::Variable(name)].into());
+                    let lambda = Lambda::new(vec![name], vec![lambdas]);
+                    lambdas = Ast::Lit(Value::Function(Rc::new(lambda)));
+                }
+
+                let calls = calls.map_idents(|id| id == name);
+                assert!(calls.iter().all(|ast| !matches!(*ast, Ast::Variable(false))));
+                assert_eq!(calls.iter().count(), 200_001);
+                assert_eq!(lambdas.map_idents(|_| ()).iter().count(), 100_001);
+            })
+            .unwrap();
+
+        mapper.join().unwrap();
+    }
+
+    #[test]
+    fn cloning_deep_asts_shares_children() {
+        use std::thread;
+
+        let cloner = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(|| {
+                let name = hash_string("a");
+
+                let mut calls = Ast::Variable(name);
+                for _ in 0..200_000 {
+                    calls = Ast::Call(Rc::new(calls), vec![Ast::Variable(name)].into());
+                }
+
+                let copy = calls.clone();
+                match (&calls, &copy) {
+                    (Ast::Call(f1, a1), Ast::Call(f2, a2)) => {
+                        assert!(Rc::ptr_eq(f1, f2));
+                        assert!(Rc::ptr_eq(a1, a2));
+                    }
+                    _ => unreachable!(),
+                }
+
+                // The original must still be intact after the copy is gone.
+                drop(copy);
+                let mut depth = 0;
+                let mut ast = &calls;
+                while let Ast::Call(ref func, _) = *ast {
+                    depth += 1;
+                    ast = func;
+                }
+                assert_eq!(depth, 200_000);
+            })
+            .unwrap();
+
+        cloner.join().unwrap();
+    }
+
+    pub(crate) fn same_ast(a: &Ast<u64>, b: &Ast<u64>) -> bool {
+        match (a, b) {
+            (Ast::Lit(Value::Function(f)), Ast::Lit(Value::Function(g))) => {
+                f.params == g.params
+                    && f.defaults.len() == g.defaults.len()
+                    && f.defaults.iter().zip(g.defaults.iter()).all(|(a, b)| same_ast(a, b))
+                    && f.body.len() == g.body.len()
+                    && f.body.iter().zip(g.body.iter()).all(|(a, b)| same_ast(a, b))
+            }
+            (Ast::Lit(a), Ast::Lit(b)) => same_value(a, b),
+            (Ast::Variable(a), Ast::Variable(b)) => a == b,
+            (Ast::Call(f, xs), Ast::Call(g, ys)) => {
+                same_ast(f, g)
+                    && xs.len() == ys.len()
+                    && xs.iter().zip(ys.iter()).all(|(a, b)| same_ast(a, b))
+            }
+            (Ast::Define(x, a), Ast::Define(y, b)) => x == y && same_ast(a, b),
+            (Ast::Include(a), Ast::Include(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn parse_program_matches_expr() {
+        for &src in &[DEEP_NESTING, MANY_VARIABLES, NESTED_FUNC, REAL_CODE] {
+            let (expected, _) = ::combine::many1::<Vec<_>, _>(expr()).easy_parse(src).unwrap();
+
+            for &detect_collisions in &[true, false] {
+                let options = ParseOptions {
+                    detect_collisions,
+                    ..ParseOptions::default()
+                };
+                let actual = parse_program_with(src, &options, &mut |_| {}).unwrap();
+
+                assert_eq!(expected.len(), actual.len());
+                assert!(expected.iter().zip(&actual).all(|(a, b)| same_ast(a, b)));
+            }
+        }
+    }
+
+    #[test]
+    fn parse_program_reports_syntax_errors() {
+        assert!(parse_program("").unwrap().is_empty());
+        assert!(parse_program("  (add 1 2)  ").is_ok());
+
+        match parse_program("(add 1 2") {
+            Err(ParseError::Syntax { .. }) => {}
+            other => panic!("expected a syntax error, got {:?}", other.map(|p| p.len())),
+        }
+        match parse_program("(add 1 2))") {
+            Err(ParseError::Syntax { .. }) => {}
+            other => panic!("expected a syntax error, got {:?}", other.map(|p| p.len())),
+        }
+    }
+
+    // The language has no comments, so whitespace is all that a program
+    // with no forms can have in it.
+    #[test]
+    fn a_program_can_be_empty() {
+        for &src in &["", "   \n\t "] {
+            assert!(parse_program(src).unwrap().is_empty());
+
+            // Only that, rather than everything an expression could start
+            // with.
+            let message = expr().easy_parse(src).err().unwrap().to_string();
+            let lines = message.lines().skip(1).collect::<Vec<_>>();
+            assert_eq!(lines, ["expected an expression, found end of input"], "{}", message);
+        }
+    }
+
+    // See the `parser` module for why each of these is two expressions.
+    #[test]
+    fn expressions_need_no_whitespace_between_them() {
+        let cases = [
+            ("(f)(g)", "(f) (g)"),
+            ("(f 1)2", "(f 1) 2"),
+            ("#f#f", "#f #f"),
+            ("1a", "1 a"),
+            ("a1", "a 1"),
+            ("\"a\"(f)", "\"a\" (f)"),
+            ("(f)'a", "(f) 'a"),
+        ];
+        for &(tight, spaced) in &cases {
+            let expected = parse_program(spaced).unwrap();
+            let actual = parse_program(tight).unwrap();
+            assert_eq!(actual.len(), 2, "{}", tight);
+            assert!(expected.iter().zip(&actual).all(|(a, b)| same_ast(a, b)), "{}", tight);
+
+            let (first, rest) = expr().easy_parse(tight).unwrap();
+            assert!(same_ast(&first, &expected[0]), "{}", tight);
+            assert!(same_ast(&expr().easy_parse(rest).unwrap().0, &expected[1]), "{}", tight);
+        }
+
+        // And the same goes for the arguments of a call.
+        for &(tight, spaced) in &[("(f(g)(h))", "(f (g) (h))"), ("(f 1(g)#f2)", "(f 1 (g) #f 2)")] {
+            let expected = parse_program(spaced).unwrap();
+            let actual = parse_program(tight).unwrap();
+            assert!(same_ast(&actual[0], &expected[0]), "{}", tight);
+        }
+    }
+
+    #[test]
+    fn empty_application_is_a_syntax_error() {
+        for &src in &["()", "(())", r"(\() ())"] {
+            match parse_program(src) {
+                Err(ParseError::Syntax { ref message, .. }) => {
+                    assert!(message.contains("empty application is not allowed"), "{}", message)
+                }
+                other => panic!("expected a syntax error, got {:?}", other.map(|p| p.len())),
+            }
+            assert!(expr().easy_parse(src).is_err());
+        }
+
+        // Calls with no arguments are still fine.
+        for &src in &["(test)", "((test))", DEEP_NESTING] {
+            let mut env = corpus_env();
+            let program = parse_program(src).unwrap();
+            assert!(eval(&program[0], &mut env).unwrap().into_owned() == Value::InbuiltFunc(callable));
+        }
+    }
+
+    #[test]
+    fn syntax_errors_have_positions() {
+        let src = "(add 1 2)\n  (add 3))";
+        let position = match parse_program_with(src, &ParseOptions::default(), &mut |_| {}) {
+            Err(ParseError::Syntax { position, .. }) => position.unwrap(),
+            other => panic!("expected a syntax error, got {:?}", other.map(|p| p.len())),
+        };
+
+        assert_eq!(position, 19);
+        assert_eq!(line_and_column(src, position), (2, 10));
+        assert_eq!(line_and_column(src, 0), (1, 1));
+        assert_eq!(line_and_column("λ\nλx", 5), (2, 2));
+        assert_eq!(line_and_column("λ", 1), (1, 1));
+    }
+
+    #[test]
+    fn eval_with_runs_out_of_fuel() {
+        // The first call isn't in tail position, so this nests for as long
+        // as it's allowed to.
+        let program = parse_program(r"(= loop (\() (loop) (loop))) (loop)").unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            fuel: Some(1000),
+            ..EvalOptions::default()
+        };
+
+        assert!(eval_with(&program[0], &mut env, &mut options).is_ok());
+        assert_eq!(options.fuel, Some(998));
+        assert_eq!(eval_with(&program[1], &mut env, &mut options).err(), Some(EvalError::OutOfFuel));
+        assert_eq!(options.fuel, Some(0));
+
+        let mut options = EvalOptions {
+            max_depth: Some(50),
+            ..EvalOptions::default()
+        };
+        assert_eq!(
+            eval_with(&program[1], &mut env, &mut options).err(),
+            Some(EvalError::TooDeep { max: 50 })
+        );
+
+        // Without limits, everything else behaves as `eval` does.
+        let program = parse_program(REAL_CODE).unwrap();
+        let mut expected_env = corpus_env();
+        for form in &program {
+            let expected = eval(form, &mut expected_env).map(|v| v.into_owned());
+            let actual = eval_with(form, &mut env, &mut EvalOptions::default()).map(|v| v.into_owned());
+            assert!(same_result(&expected, &actual));
+        }
+    }
+
+    #[test]
+    fn eval_with_profiles_calls() {
+        let program = parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let mut env = corpus_env();
+        let mut options = EvalOptions {
+            profile: Some(Profile::new()),
+            ..EvalOptions::default()
+        };
+        let mut expected_env = corpus_env();
+
+        for form in &program {
+            let expected = eval(form, &mut expected_env).map(|v| v.into_owned());
+            let actual = eval_with(form, &mut env, &mut options).map(|v| v.into_owned());
+            assert!(same_result(&expected, &actual));
+        }
+
+        let entries = options.profile.unwrap().entries();
+        let named = |name| {
+            entries
+                .iter()
+                .find(|entry| entry.name == Some(hash_string(name)))
+                .unwrap_or_else(|| panic!("{} wasn't called", name))
+        };
+
+        // Once each from `increment`, `double` and `addfive`, and ten times
+        // from `rec` counting up to 10.
+        assert_eq!(named("add").calls, 13);
+        assert_eq!(named("add").nodes, 0);
+        assert_eq!(named("rec").calls, 11);
+        assert_eq!(named("eq").calls, 12);
+        assert_eq!(named("increment").calls, 1);
+        // `rec` calls one of these two functions each time, neither of
+        // which has a name.
+        let anonymous = entries.iter().filter(|entry| entry.name.is_none()).collect::<Vec<_>>();
+        assert_eq!(anonymous.iter().map(|entry| entry.calls).sum::<u64>(), 11);
+        assert_eq!(anonymous.len(), 2);
+
+        // `rec` does more work than anything else.
+        assert_eq!(entries[0].name, Some(hash_string("rec")));
+        assert!(entries.windows(2).all(|w| (w[0].nodes, w[0].calls) >= (w[1].nodes, w[1].calls)));
+
+        // Without a profile, nothing is recorded.
+        let mut options = EvalOptions::default();
+        let mut env = corpus_env();
+        for form in &program {
+            eval_with(form, &mut env, &mut options).unwrap();
+        }
+        assert!(options.profile.is_none());
+    }
+
+    #[test]
+    fn parse_errors_instead_of_panicking() {
+        let syntax_error = |src: &[u8]| match parse_bytes(src, &ParseOptions::default(), &mut |_| {}) {
+            Err(ParseError::Syntax { message, .. }) => message,
+            other => panic!("expected a syntax error, got {:?}", other.map(|p| p.len())),
+        };
+
+        assert!(syntax_error(b"(add \xff 1)").contains("Invalid UTF-8 at byte 5"));
+        // Past `u64::MAX` is a `BigInt` rather than an error, up to a point.
+        assert!(parse_program("(add 18446744073709551615 1)").is_ok());
+        assert!(parse_program("(add 18446744073709551616 1)").is_ok());
+        let digits = "9".repeat(bigint::MAX_DIGITS + 1);
+        assert!(parse_program(&digits[1..]).is_ok());
+        assert!(syntax_error(digits.as_bytes()).contains("integer literal is too large"));
+
+        let nest = |depth| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_program(&nest(MAX_NESTING)).is_ok());
+        assert!(syntax_error(nest(MAX_NESTING + 1).as_bytes()).contains("nesting is too deep"));
+        assert!(expr().easy_parse(&nest(MAX_NESTING + 1)[..]).is_err());
+    }
+
+    #[test]
+    fn parsing_recovers_to_report_every_error() {
+        let forms = [
+            "(= a 1)",
+            "(add a %)",
+            "(= b (\\(x)\n  (add x 1)))",
+            "(b a)",
+            "(= c (mul 2 ()))",
+            "(add a c)",
+        ];
+        let src = forms.join("\n");
+        let (program, errors) = parse_recovering(&src, &mut |_| {});
+
+        let good = [forms[0], forms[2], forms[3], forms[5]].join("\n");
+        let expected = parse_program(&good).unwrap();
+        assert_eq!(program.len(), 4);
+        for (a, b) in program.iter().zip(expected.iter()) {
+            assert!(same_ast(a, b));
+        }
+
+        let positions = errors
+            .iter()
+            .map(|error| match *error {
+                ParseError::Syntax { position, ref message } => {
+                    let position = position.unwrap();
+                    assert!(message.starts_with(&format!("Parse error at {}", position)));
+                    line_and_column(&src, position)
+                }
+                ref other => panic!("expected a syntax error, got {}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [(2, 8), (6, 14)]);
+
+        // The first error is the one `parse_program` gives.
+        assert_eq!(Err(errors[0].clone()), parse_program(&src).map(|_| ()));
+
+        // A stray `)` is skipped on its own, and a form that's never closed
+        // up to the next line starting with `(`.
+        let (program, errors) = parse_recovering("(f 1))(g 2)\n(h (i 3)\n  (j 4)\n(k 5)", &mut |_| {});
+        assert_eq!(program.len(), 3);
+        assert_eq!(errors.len(), 2);
+        let (program, errors) = parse_recovering("  \n", &mut |_| {});
+        assert!(program.is_empty() && errors.is_empty());
+
+        // Diagnostics are passed on rather than printed.
+        let mut diagnostics = Vec::new();
+        let (program, errors) = parse_recovering(REAL_CODE, &mut |d| diagnostics.push(d));
+        let mut expected = Vec::new();
+        let real = parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |d| expected.push(d));
+        assert_eq!(program.len(), real.unwrap().len());
+        assert!(errors.is_empty());
+        assert!(!diagnostics.is_empty());
+        assert_eq!(diagnostics, expected);
+    }
+
+    #[test]
+    fn parsing_survives_regression_inputs() {
+        use std::fs;
+        use std::thread;
+
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions/parse");
+        let mut inputs = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!inputs.is_empty());
+
+        // These are too big to keep in the repository.
+        inputs.push(vec![b'1'; 1 << 20]);
+        inputs.push(format!("{}x{}", "(".repeat(50_000), ")".repeat(50_000)).into_bytes());
+        inputs.push(format!("{}{}", "(\\() ".repeat(50_000), ")".repeat(50_000)).into_bytes());
+
+        // Deep inputs mustn't overflow even a small stack.
+        let fuzzer = thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(move || {
+                for input in &inputs {
+                    parse_fuzz(input);
+                }
+            })
+            .unwrap();
+
+        fuzzer.join().unwrap();
+    }
+
+    #[test]
+    fn detects_identifier_collisions() {
+        use parser::{parse_with_state, ParseState};
+        use std::cell::RefCell;
+
+        // Every name of the same length collides under this hash.
+        fn by_length(name: &str) -> u64 {
+            name.len() as u64
+        }
+
+        let parse = |src, detect_collisions| {
+            let options = ParseOptions {
+                detect_collisions,
+                ..ParseOptions::default()
+            };
+            let state = RefCell::new(ParseState::new(src, by_length, &options));
+            parse_with_state(src, &state, &mut |_| {}).map(|p| p.len())
+        };
+
+        let src = "(= ab 1) (= cd 2) (add ab ab)";
+
+        assert_eq!(
+            parse(src, true),
+            Err(ParseError::IdentifierCollision {
+                a: "ab".into(),
+                b: "cd".into(),
+                hash: 2,
+            })
+        );
+
+        // Repeating the same name isn't a collision.
+        assert!(parse("(= ab 1) (add ab ab)", true).is_ok());
+
+        assert_eq!(parse(src, false), Ok(3));
+    }
+
+    #[test]
+    fn reports_duplicate_parameters() {
+        let src = r"(= second (\(a a) a)) (= third (\(a b a) a))";
+        let duplicate = |position| Diagnostic::DuplicateParameter {
+            name: "a".into(),
+            position,
+        };
+
+        let mut warnings = Vec::new();
+        let lenient = ParseOptions::default();
+        let program = parse_program_with(src, &lenient, &mut |d| warnings.push(d)).unwrap();
+        assert_eq!(program.len(), 2);
+        assert_eq!(warnings, vec![duplicate(15), duplicate(38)]);
+
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_program_with(src, &strict, &mut |_| panic!("strict mode doesn't warn"))
+                .map(|p| p.len()),
+            Err(ParseError::Strict(duplicate(15)))
+        );
+    }
+
+    #[test]
+    fn reports_shadowed_builtins() {
+        let src = r"(= add (\(x) x)) (= f (\(if) if)) (= f (\(g) g)) (f add)";
+        let shadows = |name: &str, position| Diagnostic::ShadowsBuiltin {
+            name: name.into(),
+            position,
+        };
+
+        let mut options = corpus_interpreter().parse_options();
+        let mut warnings = Vec::new();
+        let program = parse_program_with(src, &options, &mut |d| warnings.push(d)).unwrap();
+        assert_eq!(program.len(), 4);
+        assert_eq!(warnings, vec![shadows("add", 3), shadows("if", 25)]);
+
+        // Without the interpreter's natives there's nothing to shadow.
+        assert!(parse_program_with(src, &ParseOptions::default(), &mut |_| panic!()).is_ok());
+
+        options.strict = true;
+        assert_eq!(
+            parse_program_with(src, &options, &mut |_| panic!("strict mode doesn't warn"))
+                .map(|p| p.len()),
+            Err(ParseError::Strict(shadows("add", 3)))
+        );
+    }
+
+    #[test]
+    fn parameters_are_only_duplicates_within_one_function() {
+        let src = r"(= f (\(a b) (\(a b) a))) (= g (\(a) a)) (f (\(b) b) 1)";
+
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert!(parse_program_with(src, &strict, &mut |_| {}).is_ok());
+    }
+
+    #[test]
+    fn real_code_only_parses_leniently() {
+        let mut warnings = Vec::new();
+        parse_program_with(REAL_CODE, &ParseOptions::default(), &mut |d| warnings.push(d)).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        match warnings[0] {
+            Diagnostic::DuplicateParameter { ref name, position } => {
+                assert_eq!(name, "a");
+                assert_eq!(&REAL_CODE[position..position + 1], "a");
+            }
+            ref other => panic!("unexpected diagnostic {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn real_code_round_trips_through_serde() {
+        extern crate bincode;
+        extern crate serde_json;
+
+        let program = corpus().into_iter().find(|&(name, _)| name == "REAL_CODE").unwrap().1;
+
+        let json = serde_json::to_string(&program).unwrap();
+        let from_json: Vec<Ast<u64>> = serde_json::from_str(&json).unwrap();
+        let binary = bincode::serialize(&program).unwrap();
+        let from_binary: Vec<Ast<u64>> = bincode::deserialize(&binary).unwrap();
+
+        for copy in &[from_json, from_binary] {
+            assert_eq!(program.len(), copy.len());
+            assert!(program.iter().zip(copy).all(|(a, b)| same_ast(a, b)));
+
+            let mut original_env = corpus_env();
+            let mut copy_env = corpus_env();
+            for (original, copy) in program.iter().zip(copy) {
+                let expected = eval(original, &mut original_env).map(Cow::into_owned);
+                let actual = eval(copy, &mut copy_env).map(Cow::into_owned);
+                assert!(same_result(&expected, &actual));
+            }
+        }
+
+        // Natives can't be named, so they can't be written out.
+        assert!(serde_json::to_string(&Value::InbuiltFunc::<u64>(prelude::add)).is_err());
+        assert!(bincode::serialize(&Ast::Lit(Value::InbuiltFunc::<u64>(prelude::add))).is_err());
+    }
+
+    #[global_allocator]
+    static GLOBAL: instrument::CountingAlloc = instrument::CountingAlloc;
+
+    fn counting_allocations<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+        let (out, counters) = instrument::measure(f);
+        (out, counters.allocations as usize)
+    }
+
+    // The allocations that make up an `Ast` once it's been built.
+    fn heap_blocks(ast: &Ast<u64>) -> usize {
+        match *ast {
+            Ast::Lit(Value::Function(ref lambda)) => {
+                1 + (!lambda.params.is_empty()) as usize
+                    + (!lambda.defaults.is_empty()) as usize
+                    + lambda.defaults.iter().map(heap_blocks).sum::<usize>()
+                    + (!lambda.body.is_empty()) as usize
+                    + lambda.body.iter().map(heap_blocks).sum::<usize>()
+            }
+            Ast::Lit(_) | Ast::Variable(_) => 0,
+            Ast::Call(ref func, ref args) => {
+                2 + heap_blocks(func) + args.iter().map(heap_blocks).sum::<usize>()
+            }
+            Ast::Define(_, ref value) => 1 + heap_blocks(value),
+            Ast::Include(_) => 1,
+        }
+    }
+
+    #[test]
+    fn parsing_only_allocates_the_ast() {
+        for &src in &[DEEP_NESTING, MANY_VARIABLES, NESTED_FUNC, REAL_CODE] {
+            // The first parse on a thread sets up the parser's scratch space.
+            expr().parse(src).unwrap();
+
+            let (parsed, allocations) = counting_allocations(|| expr().parse(src).unwrap());
+            assert_eq!(allocations, heap_blocks(&parsed.0));
+        }
+    }
+}
+
+// Without the `parse` feature there's no parser, so these build programs
+// by hand. Run them with `cargo test --no-default-features`, with or
+// without `--features std`.
+#[cfg(all(test, not(feature = "parse")))]
+mod eval_only_tests {
+    use std::rc::Rc;
+    #[cfg(not(feature = "std"))]
+    use std::prelude::v1::*;
+
+    use {
+        eval, eval_with, hash_string, prelude, Ast, EvalError, EvalOptions, IncludeError, Lambda,
+        Value,
+    };
+
+    fn call(func: &str, args: Vec<Ast<u64>>) -> Ast<u64> {
+        Ast::Call(Rc::new(Ast::Variable(hash_string(func))), args.into())
+    }
+
+    // (add 1 2) (= twice (\(x) (add x x))) (twice 4)
+    fn program() -> Vec<Ast<u64>> {
+        let x = hash_string("x");
+        let body = vec![call("add", vec![Ast::Variable(x), Ast::Variable(x)])];
+        let twice = Ast::Lit(Value::Function(Rc::new(Lambda::new(vec![x], body))));
+        vec![
+            call("add", vec![Ast::Lit(Value::Int(1)), Ast::Lit(Value::Int(2))]),
+            Ast::Define(hash_string("twice"), Rc::new(twice)),
+            call("twice", vec![Ast::Lit(Value::Int(4))]),
+        ]
+    }
+
+    #[test]
+    fn evaluates_hand_built_programs() {
+        let program = program();
+        let mut env = prelude::env();
+        let results = program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(results[0], Value::Int(3));
+        assert_eq!(results[2], Value::Int(8));
+    }
+
+    #[test]
+    fn runs_compiled_programs() {
+        let program = program();
+        let compiled = prelude::interpreter().compile(&program);
+        let mut env = prelude::env();
+        assert_eq!(compiled.run(&mut env), Ok(Value::Int(8)));
+    }
+
+    #[test]
+    fn round_trips_binary_programs() {
+        let program = program();
+        let decoded = program
+            .iter()
+            .map(|form| Ast::from_bytes(&form.to_bytes().unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        let mut env = prelude::env();
+        let results = decoded
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(results[0], Value::Int(3));
+        assert_eq!(results[2], Value::Int(8));
+    }
 
-        b.iter(|| black_box(eval(&program, &mut env)));
+    #[test]
+    fn includes_are_disabled() {
+        let include = Ast::Include("other.lisp".into());
+        let mut env = prelude::env();
+        let result = eval_with(&include, &mut env, &mut EvalOptions::default());
+        assert_eq!(result.err(), Some(EvalError::Include(Box::new(IncludeError::Disabled))));
     }
 
-    #[bench]
-    fn run_nested_func(b: &mut Bencher) {
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn hashes_with_fnv() {
+        use {hash_string_with, Fnv64};
 
-        let (program, _) = expr().easy_parse(NESTED_FUNC).unwrap();
-        let mut env = IntMap::default();
-        b.iter(|| black_box(eval(&program, &mut env)));
+        assert_eq!(hash_string("add"), hash_string_with::<Fnv64>("add"));
     }
 }
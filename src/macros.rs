@@ -0,0 +1,594 @@
+//! User-defined macros, which rewrite a program before it's run.
+//!
+//! `(defmacro name (params) body...)` at the top level of a program defines
+//! a macro. `expand` removes the definition, and replaces every later call
+//! to `name` with what the macro's body returns when it's evaluated with
+//! the call's arguments as code-as-data, that is as lists and symbols
+//! rather than the values they would evaluate to. What it returns is then
+//! turned back into code, and expanded again in case it uses macros too.
+//!
+//! Code is most easily written as data with a quote: `'(f x ,y)` evaluates
+//! to a list of the symbols `f` and `x`, followed by the value of `y`. The
+//! parser turns quotes into calls to `list` as it reads them, so they work
+//! anywhere, not just in macros. Definitions and lambdas become lists with
+//! the symbols `=` and `\` at their head, which can't be written any other
+//! way since they aren't identifiers.
+//!
+//! Scoping is dynamic, so a macro that binds a variable around code it
+//! was given can capture the caller's variable of the same name. Macros are
+//! evaluated with a `gensym` native that returns a symbol no program can
+//! write, which a macro can bind instead by unquoting it in a parameter
+//! list: `'(\(,name) ...)`.
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use {eval, hash_string, prelude, Ast, EvalError, HostFn, IntMap, Lambda, Pattern, Value};
+
+/// The names of the special forms in quoted code, which aren't identifiers
+/// so that no variable can be mistaken for one.
+fn lambda_symbol() -> u64 {
+    hash_string("\\")
+}
+
+fn define_symbol() -> u64 {
+    hash_string("=")
+}
+
+fn quote_symbol() -> u64 {
+    hash_string("'")
+}
+
+// `include` is an identifier, but an include always has a string after it,
+// and a call to a variable called `include` can't.
+fn include_symbol() -> u64 {
+    hash_string("include")
+}
+
+/// The variables that quoted code refers to, as the parser interned them.
+#[cfg(feature = "parse")]
+pub(crate) struct QuoteNames {
+    pub(crate) list: u64,
+    pub(crate) unquote: u64,
+}
+
+/// Turn a quoted expression into code that builds the same data as
+/// `to_data` would give for it, except that `(unquote x)` is replaced with
+/// `x` itself, so it's evaluated. A list pattern `(unquote x)` in a
+/// parameter list is replaced with the variable `x` too.
+#[cfg(feature = "parse")]
+pub(crate) fn quasi(ast: &Ast<u64>, names: &QuoteNames) -> Ast<u64> {
+    let list = |items: Vec<Ast<u64>>| Ast::Call(Rc::new(Ast::Variable(names.list)), items.into());
+    let symbol = |name| Ast::Lit(Value::Symbol(name));
+
+    match *ast {
+        Ast::Call(ref func, ref args) if args.len() == 1 && is_variable(func, names.unquote) => {
+            args[0].clone()
+        }
+        Ast::Call(ref func, ref args) => {
+            let args = args.iter().map(|arg| quasi(arg, names));
+            list(Some(quasi(func, names)).into_iter().chain(args).collect())
+        }
+        Ast::Variable(name) => symbol(name),
+        Ast::Define(name, ref value) => {
+            list(vec![symbol(define_symbol()), symbol(name), quasi(value, names)])
+        }
+        Ast::Include(ref path) => {
+            list(vec![symbol(include_symbol()), Ast::Lit(Value::Str(Rc::new(path.to_string())))])
+        }
+        Ast::Lit(Value::Function(ref lambda)) => {
+            let required = lambda.required();
+            let mut params = lambda.params[..required]
+                .iter()
+                .map(|param| quasi_pattern(param, names))
+                .collect::<Vec<_>>();
+            for (param, default) in lambda.params[required..].iter().zip(lambda.defaults.iter()) {
+                let param = match *param {
+                    Pattern::Name(name) => symbol(name),
+                    Pattern::List(_) => quasi_pattern(param, names),
+                };
+                params.push(list(vec![symbol(define_symbol()), param, quasi(default, names)]));
+            }
+
+            let mut items = vec![symbol(lambda_symbol()), list(params)];
+            items.extend(lambda.body.iter().map(|stmt| quasi(stmt, names)));
+            list(items)
+        }
+        Ast::Lit(ref value @ Value::Symbol(_)) | Ast::Lit(ref value @ Value::List(_)) => {
+            list(vec![symbol(quote_symbol()), Ast::Lit(value.clone())])
+        }
+        Ast::Lit(_) => ast.clone(),
+    }
+}
+
+#[cfg(feature = "parse")]
+fn quasi_pattern(pattern: &Pattern<u64>, names: &QuoteNames) -> Ast<u64> {
+    match *pattern {
+        Pattern::Name(name) => Ast::Lit(Value::Symbol(name)),
+        Pattern::List(ref items) => match **items {
+            [Pattern::Name(unquote), Pattern::Name(name)] if unquote == names.unquote => {
+                Ast::Variable(name)
+            }
+            _ => Ast::Call(
+                Rc::new(Ast::Variable(names.list)),
+                items.iter().map(|item| quasi_pattern(item, names)).collect::<Vec<_>>().into(),
+            ),
+        },
+    }
+}
+
+fn is_variable(ast: &Ast<u64>, name: u64) -> bool {
+    matches!(*ast, Ast::Variable(found) if found == name)
+}
+
+/// Code as a macro sees it. Variables become symbols, and calls, defines,
+/// includes and lambdas become lists. Literal symbols and lists are wrapped
+/// in a quote, so that they come back as literals.
+pub fn to_data(ast: &Ast<u64>) -> Value<u64> {
+    let list = |items: Vec<Value<u64>>| Value::List(Rc::new(items));
+
+    match *ast {
+        Ast::Call(ref func, ref args) => {
+            list(Some(to_data(func)).into_iter().chain(args.iter().map(to_data)).collect())
+        }
+        Ast::Variable(name) => Value::Symbol(name),
+        Ast::Define(name, ref value) => {
+            list(vec![Value::Symbol(define_symbol()), Value::Symbol(name), to_data(value)])
+        }
+        Ast::Include(ref path) => {
+            list(vec![Value::Symbol(include_symbol()), Value::Str(Rc::new(path.to_string()))])
+        }
+        Ast::Lit(Value::Function(ref lambda)) => {
+            let required = lambda.required();
+            let mut params = lambda.params[..required].iter().map(pattern_to_data).collect::<Vec<_>>();
+            for (param, default) in lambda.params[required..].iter().zip(lambda.defaults.iter()) {
+                let name = pattern_to_data(param);
+                params.push(list(vec![Value::Symbol(define_symbol()), name, to_data(default)]));
+            }
+
+            let mut items = vec![Value::Symbol(lambda_symbol()), list(params)];
+            items.extend(lambda.body.iter().map(to_data));
+            list(items)
+        }
+        Ast::Lit(ref value @ Value::Symbol(_)) | Ast::Lit(ref value @ Value::List(_)) => {
+            list(vec![Value::Symbol(quote_symbol()), value.clone()])
+        }
+        Ast::Lit(ref value) => value.clone(),
+    }
+}
+
+fn pattern_to_data(pattern: &Pattern<u64>) -> Value<u64> {
+    match *pattern {
+        Pattern::Name(name) => Value::Symbol(name),
+        Pattern::List(ref items) => Value::List(Rc::new(items.iter().map(pattern_to_data).collect())),
+    }
+}
+
+/// Turn data back into code, the other way round from `to_data`. Anything
+/// that isn't a list or a symbol is a literal. This is how the `eval`
+/// native reads its argument.
+pub fn from_data(value: &Value<u64>) -> Result<Ast<u64>, EvalError<u64>> {
+    let items = match *value {
+        Value::Symbol(name) => return Ok(Ast::Variable(name)),
+        Value::List(ref items) => items,
+        ref value => return Ok(Ast::Lit(value.clone())),
+    };
+    let head = match items.first() {
+        Some(&Value::Symbol(name)) => Some(name),
+        Some(_) => None,
+        None => return Err(EvalError::NotCode("an empty list isn't an expression")),
+    };
+
+    match (head, &items[1..]) {
+        (Some(quote), [value]) if quote == quote_symbol() => Ok(Ast::Lit(value.clone())),
+        (Some(define), [Value::Symbol(name), value]) if define == define_symbol() => {
+            Ok(Ast::Define(*name, Rc::new(from_data(value)?)))
+        }
+        (Some(define), _) if define == define_symbol() => {
+            Err(EvalError::NotCode("a define is a name and a value"))
+        }
+        (Some(include), [Value::Str(path)]) if include == include_symbol() => {
+            Ok(Ast::Include(path.as_str().into()))
+        }
+        (Some(lambda), [Value::List(params), ref body @ ..]) if lambda == lambda_symbol() => {
+            Ok(Ast::Lit(Value::Function(Rc::new(lambda_from_data(params, body)?))))
+        }
+        (Some(lambda), _) if lambda == lambda_symbol() => {
+            Err(EvalError::NotCode("a lambda starts with a list of parameters"))
+        }
+        (_, args) => Ok(Ast::Call(
+            Rc::new(from_data(&items[0])?),
+            args.iter().map(from_data).collect::<Result<Vec<_>, _>>()?.into(),
+        )),
+    }
+}
+
+fn lambda_from_data(params: &[Value<u64>], body: &[Value<u64>]) -> Result<Lambda<u64>, EvalError<u64>> {
+    let mut patterns = Vec::with_capacity(params.len());
+    let mut defaults = Vec::new();
+    for param in params {
+        match *param {
+            Value::List(ref items)
+                if matches!(items.first(), Some(&Value::Symbol(define)) if define == define_symbol()) =>
+            {
+                match items[1..] {
+                    [Value::Symbol(name), ref default] => {
+                        patterns.push(Pattern::Name(name));
+                        defaults.push(from_data(default)?);
+                    }
+                    _ => return Err(EvalError::NotCode("a default is a name and a value")),
+                }
+            }
+            _ if !defaults.is_empty() => {
+                return Err(EvalError::NotCode("required parameters can't come after optional ones"))
+            }
+            ref param => patterns.push(pattern_from_data(param)?),
+        }
+    }
+
+    Ok(Lambda {
+        params: patterns.into(),
+        defaults: defaults.into(),
+        body: body.iter().map(from_data).collect::<Result<Vec<_>, _>>()?.into(),
+    })
+}
+
+fn pattern_from_data(value: &Value<u64>) -> Result<Pattern<u64>, EvalError<u64>> {
+    match *value {
+        Value::Symbol(name) => Ok(Pattern::Name(name)),
+        Value::List(ref items) => Ok(Pattern::List(
+            items.iter().map(pattern_from_data).collect::<Result<Vec<_>, _>>()?.into(),
+        )),
+        _ => Err(EvalError::NotCode("a parameter is a symbol or a list of them")),
+    }
+}
+
+/// What `gensym` counts with. Each `Interpreter` has one, which
+/// `Interpreter::reset` starts again from 0, so expanding the same program
+/// after a reset gives the same symbols. Copies share the count.
+#[derive(Clone, Debug, Default)]
+pub struct Gensyms {
+    next: Arc<AtomicUsize>,
+}
+
+impl Gensyms {
+    pub fn new() -> Self {
+        Gensyms::default()
+    }
+
+    /// Count from 0 again.
+    pub fn reset(&self) {
+        self.next.store(0, Ordering::Relaxed);
+    }
+
+    // A symbol that can't be written in a program, and isn't one of the
+    // names that the passes in `optimize` make up, which start with `#` too.
+    fn next(&self) -> u64 {
+        hash_string(&format!("#gensym{}", self.next.fetch_add(1, Ordering::Relaxed)))
+    }
+}
+
+// A new symbol each time. Only available while expanding macros.
+fn gensym(gensyms: &Gensyms, args: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+    if !args.is_empty() {
+        return Err(EvalError::ArgumentCount {
+            min: 0,
+            max: 0,
+            got: args.len(),
+        });
+    }
+    Ok(Value::Symbol(gensyms.next()))
+}
+
+/// The macros that `expand` has seen defined, which carry over from one
+/// program to the next.
+pub struct Macros {
+    transformers: IntMap<Rc<Lambda<u64>>>,
+    /// How many expansions may be nested inside each other, counting a
+    /// macro call in what another macro returned as nested inside it.
+    pub max_depth: usize,
+    // What macros are evaluated in: the prelude and `gensym`.
+    env: IntMap<Cow<'static, Value<u64>>>,
+}
+
+impl Macros {
+    /// No macros, allowing up to 64 nested expansions, with a `gensym` that
+    /// counts on its own.
+    pub fn new() -> Self {
+        Macros::with_gensyms(Gensyms::new())
+    }
+
+    /// No macros, with a `gensym` that counts with `gensyms`, such as an
+    /// interpreter's so that resetting it starts the count again.
+    pub fn with_gensyms(gensyms: Gensyms) -> Self {
+        let mut env = prelude::env();
+        let gensym = HostFn::new(move |args| gensym(&gensyms, args));
+        env.insert(hash_string("gensym"), Cow::Owned(Value::HostFunc(gensym)));
+        Macros {
+            transformers: IntMap::default(),
+            max_depth: 64,
+            env,
+        }
+    }
+
+    /// Define the macro `name`, replacing any macro that was called that.
+    pub fn define(&mut self, name: u64, transformer: Rc<Lambda<u64>>) {
+        self.transformers.insert(name, transformer);
+    }
+
+    pub fn contains(&self, name: u64) -> bool {
+        self.transformers.contains_key(&name)
+    }
+
+    fn expand(&self, ast: &Ast<u64>, depth: usize) -> Result<Ast<u64>, ExpandError> {
+        match *ast {
+            Ast::Call(ref func, ref args) => match **func {
+                Ast::Variable(name) if self.contains(name) => {
+                    if depth >= self.max_depth {
+                        return Err(ExpandError::TooDeep { max: self.max_depth });
+                    }
+                    let call = Ast::Call(
+                        Rc::new(Ast::Lit(Value::Function(self.transformers[&name].clone()))),
+                        args.iter().map(|arg| Ast::Lit(to_data(arg))).collect::<Vec<_>>().into(),
+                    );
+                    let mut env = self.env.clone();
+                    let data = eval(&call, &mut env).map_err(ExpandError::Eval)?;
+                    let expanded = from_data(&data).map_err(ExpandError::Eval)?;
+                    self.expand(&expanded, depth + 1)
+                }
+                _ => {
+                    let func = self.expand(func, depth)?;
+                    let args = args.iter().map(|arg| self.expand(arg, depth));
+                    Ok(Ast::Call(Rc::new(func), args.collect::<Result<Vec<_>, _>>()?.into()))
+                }
+            },
+            Ast::Define(name, ref value) => Ok(Ast::Define(name, Rc::new(self.expand(value, depth)?))),
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let expand_all = |asts: &[Ast<u64>]| {
+                    asts.iter().map(|ast| self.expand(ast, depth)).collect::<Result<Vec<_>, _>>()
+                };
+                Ok(Ast::Lit(Value::Function(Rc::new(Lambda {
+                    params: lambda.params.clone(),
+                    defaults: expand_all(&lambda.defaults)?.into(),
+                    body: expand_all(&lambda.body)?.into(),
+                }))))
+            }
+            Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => Ok(ast.clone()),
+        }
+    }
+
+    // Define the macro if `ast` is `(defmacro name (params) body...)`, or
+    // `(defmacro name (\(params) body...))` which is the only way to write
+    // a macro without parameters, returning whether it was.
+    fn define_from(&mut self, ast: &Ast<u64>) -> Result<bool, ExpandError> {
+        let args = match *ast {
+            Ast::Call(ref func, ref args) if is_variable(func, hash_string("defmacro")) => args,
+            _ => return Ok(false),
+        };
+
+        let (name, lambda) = match **args {
+            [Ast::Variable(name), ref lambda @ Ast::Lit(Value::Function(_))] => (name, lambda.clone()),
+            [Ast::Variable(name), ref params @ Ast::Call(..), ref body @ ..] => {
+                let params = match to_data(params) {
+                    Value::List(params) => params,
+                    _ => unreachable!(),
+                };
+                let lambda = Lambda {
+                    body: body.into(),
+                    ..lambda_from_data(&params, &[]).map_err(|e| match e {
+                        EvalError::NotCode(message) => ExpandError::Malformed(message),
+                        e => ExpandError::Eval(e),
+                    })?
+                };
+                (name, Ast::Lit(Value::Function(Rc::new(lambda))))
+            }
+            _ => {
+                return Err(ExpandError::Malformed(
+                    "`defmacro` takes a name, a parameter list and a body",
+                ))
+            }
+        };
+
+        // Macros can use the macros defined before them.
+        match self.expand(&lambda, 0)? {
+            Ast::Lit(Value::Function(ref lambda)) => self.define(name, lambda.clone()),
+            _ => unreachable!(),
+        }
+        Ok(true)
+    }
+}
+
+impl Default for Macros {
+    fn default() -> Self {
+        Macros::new()
+    }
+}
+
+impl fmt::Debug for Macros {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = self.transformers.keys().collect::<Vec<_>>();
+        names.sort();
+        f.debug_struct("Macros")
+            .field("names", &names)
+            .field("max_depth", &self.max_depth)
+            .finish()
+    }
+}
+
+/// Expand every macro call in `program`, after defining the macros that
+/// its top-level `defmacro` forms define. The definitions are left out of
+/// what's returned.
+pub fn expand(program: &[Ast<u64>], macros: &mut Macros) -> Result<Vec<Ast<u64>>, ExpandError> {
+    let mut out = Vec::with_capacity(program.len());
+    for form in program {
+        if !macros.define_from(form)? {
+            out.push(macros.expand(form, 0)?);
+        }
+    }
+    Ok(out)
+}
+
+/// Why a program's macros couldn't be expanded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpandError {
+    /// Evaluating a macro failed.
+    Eval(EvalError<u64>),
+    /// A `defmacro` was written wrongly. A macro that returns data that
+    /// isn't code fails with `EvalError::NotCode`.
+    Malformed(&'static str),
+    /// Expanding a macro call would have gone over `Macros::max_depth`.
+    TooDeep { max: usize },
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandError::Eval(ref error) => write!(f, "Expanding a macro failed: {}", error),
+            ExpandError::Malformed(message) => write!(f, "Malformed macro: {}", message),
+            ExpandError::TooDeep { max } => write!(f, "Macros expanded more than {} deep", max),
+        }
+    }
+}
+
+impl error::Error for ExpandError {}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use benches::same_ast;
+    use {eval, parse_program, prelude, EvalError, Value};
+
+    use super::{expand, from_data, to_data, ExpandError, Macros};
+    use Interpreter;
+
+    fn run(src: &str, macros: &mut Macros) -> Result<Vec<Value<u64>>, ExpandError> {
+        let program = expand(&parse_program(src).unwrap(), macros)?;
+        let mut env = prelude::env();
+        Ok(program.iter().map(|form| eval(form, &mut env).ok().unwrap().into_owned()).collect())
+    }
+
+    #[test]
+    fn when_and_unless() {
+        let src = r"
+            (defmacro when (c body) '((if ,c (\() ,body) (\() #f))))
+            (defmacro unless (c body) '(when (if ,c #f) ,body))
+            (= x 5)
+            (when (eq x 5) (add x 1))
+            (unless (eq x 5) (add x 1))
+            (when (eq x 4) (undefined))
+            (unless (eq x 4) (when #f 1))";
+        let results = run(src, &mut Macros::new()).unwrap();
+
+        assert!(results[1] == Value::Int(6));
+        assert!(results[2] == Value::False);
+        // The body isn't evaluated, or the unbound variable would fail.
+        assert!(results[3] == Value::False);
+        assert!(results[4] == Value::False);
+    }
+
+    #[test]
+    fn macros_carry_over_and_are_expanded_away() {
+        let mut macros = Macros::new();
+        run(r"(defmacro twice (x) '(add ,x ,x))", &mut macros).unwrap();
+        assert!(macros.contains(::hash_string("twice")));
+
+        let program = parse_program(r"(\(y) (twice (twice y)))").unwrap();
+        let expanded = expand(&program, &mut macros).unwrap();
+        let expected = parse_program(r"(\(y) (add (add y y) (add y y)))").unwrap();
+        assert!(same_ast(&expanded[0], &expected[0]));
+    }
+
+    #[test]
+    fn runaway_expansion_is_an_error() {
+        let mut macros = Macros::new();
+        macros.max_depth = 10;
+
+        let err = run(r"(defmacro forever (x) '(forever ,x)) (forever 1)", &mut macros).err();
+        assert_eq!(err, Some(ExpandError::TooDeep { max: 10 }));
+        assert_eq!(err.unwrap().to_string(), "Macros expanded more than 10 deep");
+
+        // Nesting is only counted along one chain of expansions.
+        let src = r"(defmacro one (x) '(add 1 ,x)) (list (one 1) (one 2) (one (one (one 3))))";
+        let expected = Value::List(Rc::new(vec![Value::Int(2), Value::Int(3), Value::Int(6)]));
+        assert!(run(src, &mut macros).unwrap()[0] == expected);
+    }
+
+    #[test]
+    fn gensym_keeps_macro_variables_apart() {
+        // `tmp` is bound around `b`, so it hides the caller's `tmp`.
+        let capturing = r"
+            (defmacro either (a b) '((\(tmp) ((if tmp (\() tmp) (\() ,b)))) ,a))
+            (= tmp 7)
+            (either #f tmp)";
+        assert!(run(capturing, &mut Macros::new()).unwrap()[1] == Value::False);
+
+        let hygienic = r"
+            (defmacro either (a b)
+              ((\(tmp) '((\(,tmp) ((if ,tmp (\() ,tmp) (\() ,b)))) ,a)) (gensym)))
+            (= tmp 7)
+            (either #f tmp)
+            (either 3 tmp)";
+        let results = run(hygienic, &mut Macros::new()).unwrap();
+        assert!(results[1] == Value::Int(7));
+        assert!(results[2] == Value::Int(3));
+    }
+
+    #[test]
+    fn gensyms_repeat_after_a_reset() {
+        let mut interpreter = Interpreter::new();
+        let src = r"(defmacro fresh (\() (gensym))) (list (fresh) (fresh))";
+        let program = parse_program(src).unwrap();
+        let expand_with_gensyms = |interpreter: &Interpreter<u64>| {
+            let mut macros = Macros::with_gensyms(interpreter.gensyms().clone());
+            expand(&program, &mut macros).unwrap()
+        };
+
+        let first = expand_with_gensyms(&interpreter);
+        let second = expand_with_gensyms(&interpreter);
+        assert!(!same_ast(&first[0], &second[0]));
+
+        interpreter.reset(&mut prelude::env());
+        let after_reset = expand_with_gensyms(&interpreter);
+        assert!(same_ast(&first[0], &after_reset[0]));
+
+        // Each interpreter counts on its own.
+        interpreter = Interpreter::new();
+        assert!(same_ast(&first[0], &expand_with_gensyms(&interpreter)[0]));
+    }
+
+    #[test]
+    fn code_round_trips_through_data() {
+        let src = r#"(f 'x (= y 1) (include "a") (\(a (b c) (= d 2)) a) #f "s" :k '(g))"#;
+        let program = parse_program(src).unwrap();
+
+        let data = to_data(&program[0]);
+        assert!(same_ast(&program[0], &from_data(&data).unwrap()));
+
+        // Quoting code gives the same data as `to_data`.
+        let quoted = parse_program(&format!("'{}", src)).unwrap();
+        assert!(eval(&quoted[0], &mut prelude::env()).ok().unwrap().equal(&data));
+    }
+
+    #[test]
+    fn bad_macros_are_errors() {
+        let mut macros = Macros::new();
+        let malformed = |src| run(src, &mut Macros::new()).err();
+
+        assert!(matches!(malformed("(defmacro 1 (x) x)"), Some(ExpandError::Malformed(_))));
+        assert!(matches!(malformed("(defmacro m ((= a 1) b) 1)"), Some(ExpandError::Malformed(_))));
+        let not_code = |src| matches!(malformed(src), Some(ExpandError::Eval(EvalError::NotCode(_))));
+        assert!(not_code("(defmacro m (x) (list)) (m 1)"));
+        assert!(not_code(r"(defmacro m (x) '(\(,x) 1)) (m 1)"));
+        assert!(matches!(
+            run("(defmacro m (x) (nothing x)) (m 1)", &mut macros).err(),
+            Some(ExpandError::Eval(_))
+        ));
+    }
+}
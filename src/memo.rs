@@ -0,0 +1,313 @@
+//! Remembering what calls to pure functions returned, so that calling one
+//! again with the same arguments doesn't run it again.
+//!
+//! Nothing is remembered unless `EvalOptions::memoize` names the functions
+//! to remember calls to. Only calls to a lambda through one of those names
+//! are remembered, and only when every argument is data, since functions
+//! are compared by identity and rarely come round again. It's up to the
+//! caller to only name functions whose result depends on nothing but their
+//! arguments: a function that reads a global, or calls a native with side
+//! effects, gives whatever it gave the first time.
+//!
+//! Defining one of the names again forgets what was remembered for it.
+//! When the memo is full, the result that was used least recently makes
+//! way for the new one.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {Arguments, Ast, Value};
+
+/// What `Memo::new` holds at most.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The results remembered by `eval_with`, for the functions it was told
+/// to remember.
+#[derive(Debug)]
+pub struct Memo<Id: Eq + Hash> {
+    names: HashSet<Id>,
+    capacity: usize,
+    // Each result, and when it was last used.
+    results: HashMap<MemoKey<Id>, (Value<Id>, u64)>,
+    // The keys of `results` by when they were last used, oldest first.
+    used: BTreeMap<u64, MemoKey<Id>>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// A call, by the name it was made through, the lambda that ran and its
+// arguments as they were lined up with its parameters, with `None` for an
+// optional parameter that was left to its default.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct MemoKey<Id> {
+    name: Id,
+    func: Value<Id>,
+    args: Vec<Option<Value<Id>>>,
+}
+
+// What `Memo::recall` knows about a call.
+pub(crate) enum Recall<Id> {
+    // It was made before, and returned this.
+    Hit(Value<Id>),
+    // It wasn't, and its result should be remembered under this.
+    Miss(MemoKey<Id>),
+    // It isn't one to remember.
+    Skip,
+}
+
+impl<Id: Clone + Eq + Hash> Memo<Id> {
+    /// Remember calls to the functions called `names`, up to
+    /// `DEFAULT_CAPACITY` of them.
+    pub fn new<I: IntoIterator<Item = Id>>(names: I) -> Self {
+        Memo::with_capacity(names, DEFAULT_CAPACITY)
+    }
+
+    /// Remember calls to the functions called `names`, up to `capacity` of
+    /// them.
+    pub fn with_capacity<I: IntoIterator<Item = Id>>(names: I, capacity: usize) -> Self {
+        Memo {
+            names: names.into_iter().collect(),
+            capacity,
+            results: HashMap::new(),
+            used: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// How many results are remembered.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// How many calls were answered from the memo rather than made.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// How many calls could have been remembered but had to be made.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Forget every result, but keep counting hits and misses.
+    pub fn clear(&mut self) {
+        self.results.clear();
+        self.used.clear();
+    }
+
+    pub(crate) fn recall(
+        &mut self,
+        callee: &Ast<Id>,
+        func: &Value<Id>,
+        args: &Arguments<Cow<Value<Id>>>,
+    ) -> Recall<Id> {
+        let name = match *callee {
+            Ast::Variable(ref name) if self.names.contains(name) => name,
+            _ => return Recall::Skip,
+        };
+        let required = args.required.iter().map(Some);
+        let optional = args.optional.iter().map(Option::as_ref);
+        if !required.clone().chain(optional.clone()).flatten().all(|arg| is_data(arg)) {
+            return Recall::Skip;
+        }
+
+        let key = MemoKey {
+            name: name.clone(),
+            func: func.clone(),
+            args: required.chain(optional).map(|arg| arg.map(|arg| (**arg).clone())).collect(),
+        };
+        match self.results.get_mut(&key) {
+            Some((result, used)) => {
+                self.hits += 1;
+                self.clock += 1;
+                self.used.remove(used);
+                self.used.insert(self.clock, key);
+                *used = self.clock;
+                Recall::Hit(result.clone())
+            }
+            None => {
+                self.misses += 1;
+                Recall::Miss(key)
+            }
+        }
+    }
+
+    pub(crate) fn remember(&mut self, key: MemoKey<Id>, result: &Value<Id>) {
+        if self.capacity == 0 {
+            return;
+        }
+        // A recursive call can finish with the same arguments first.
+        if let Some((_, used)) = self.results.remove(&key) {
+            self.used.remove(&used);
+        }
+        while self.results.len() >= self.capacity {
+            match self.used.pop_first() {
+                Some((_, oldest)) => self.results.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.clock += 1;
+        self.used.insert(self.clock, key.clone());
+        self.results.insert(key, (result.clone(), self.clock));
+    }
+
+    // `name` was defined again, so what it was before can't be called
+    // through it any more.
+    pub(crate) fn forget(&mut self, name: &Id) {
+        if self.names.contains(name) {
+            self.results.retain(|key, _| key.name != *name);
+            self.used.retain(|_, key| key.name != *name);
+        }
+    }
+}
+
+// Whether `value` has no functions, thunks or boxes anywhere in it. What's
+// in a box can change, so a call given one can't be remembered.
+fn is_data<Id>(value: &Value<Id>) -> bool {
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match *value {
+            Value::Function(_)
+            | Value::Partial(_)
+            | Value::Thunk(_)
+            | Value::Box(_)
+            | Value::Foreign(_)
+            | Value::InbuiltFunc(_)
+            | Value::ReentrantFunc(_)
+            | Value::HostFunc(_) => return false,
+            Value::List(ref items) | Value::Values(ref items) => pending.extend(items.iter()),
+            _ => {}
+        }
+    }
+    true
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use instrument::measure;
+    use {eval_with, hash_string, parse_program, prelude, EvalOptions, Value};
+
+    use super::Memo;
+
+    // Counts from `k` up to 20, adding up the two ways to get there, so
+    // `(fib 0)` is the 21st Fibonacci number. Without subtraction it has
+    // to count up rather than down, but it makes just as many calls.
+    const FIB: &str = r"
+        (= fib (\(k)
+          ((if (eq k 20)
+               (\() 1)
+               (\() ((if (eq k 19)
+                         (\() 1)
+                         (\() (add (fib (add k 1)) (fib (add k 2)))))))))))
+        (fib 0)
+    ";
+
+    fn run(src: &str, options: &mut EvalOptions<u64>) -> Vec<Value<u64>> {
+        let program = parse_program(src).unwrap();
+        let mut env = prelude::env();
+        program
+            .iter()
+            .map(|form| eval_with(form, &mut env, options).unwrap().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn memoised_fib_makes_each_call_once() {
+        let (results, plain) = measure(|| run(FIB, &mut EvalOptions::default()));
+        assert_eq!(results[1], Value::Int(10946));
+
+        let mut options = EvalOptions::default();
+        options.memoize(vec![hash_string("fib")]);
+        let (results, memoised) = measure(|| run(FIB, &mut options));
+        assert_eq!(results[1], Value::Int(10946));
+
+        // Each of the 21 values of `k` is worked out once, and those from 2
+        // to 19 are asked for a second time, which the memo answers.
+        let memo = options.memo.as_ref().unwrap();
+        assert_eq!((memo.misses(), memo.hits(), memo.len()), (21, 18, 21));
+        assert!(plain.scopes > 20_000, "{:?}", plain);
+        assert!(memoised.scopes < 100, "{:?}", memoised);
+        assert!(memoised.lookups < plain.lookups / 100, "{:?} {:?}", memoised, plain);
+    }
+
+    #[test]
+    fn redefining_a_function_forgets_its_results() {
+        let mut options = EvalOptions::default();
+        options.memoize(vec![hash_string("double")]);
+
+        let results = run(
+            r"
+            (= double (\(x) (add x x)))
+            (double 2)
+            (double 2)
+            (= other 1)
+            (= double (\(x) (add x x x)))
+            (double 2)
+            ",
+            &mut options,
+        );
+        assert_eq!(results[5], Value::Int(6));
+
+        let memo = options.memo.as_ref().unwrap();
+        assert_eq!((memo.misses(), memo.hits(), memo.len()), (2, 1, 1));
+    }
+
+    #[test]
+    fn only_calls_with_data_arguments_are_remembered() {
+        let mut options = EvalOptions::default();
+        options.memoize(vec![hash_string("first")]);
+
+        run(
+            r"
+            (= first (\(x y) x))
+            (= other (\(x y) x))
+            (first 1 add)
+            (first (list 1 (list add)) 2)
+            (other 1 2)
+            (first (list 1 (list 2)) 2)
+            ",
+            &mut options,
+        );
+
+        let memo = options.memo.as_ref().unwrap();
+        assert_eq!((memo.misses(), memo.hits(), memo.len()), (1, 0, 1));
+    }
+
+    #[test]
+    fn the_least_recently_used_result_is_dropped_first() {
+        let mut options = EvalOptions {
+            memo: Some(Memo::with_capacity(vec![hash_string("double")], 2)),
+            ..EvalOptions::default()
+        };
+
+        run(
+            r"
+            (= double (\(x) (add x x)))
+            (double 1)
+            (double 2)
+            (double 1)
+            (double 3)
+            (double 1)
+            (double 2)
+            ",
+            &mut options,
+        );
+
+        // 3 pushed out 2 rather than 1, which had just been used, so 1 is
+        // still there the second time round but 2 has to be worked out
+        // again.
+        let memo = options.memo.as_ref().unwrap();
+        assert_eq!((memo.misses(), memo.hits(), memo.len()), (4, 2, 2));
+    }
+}
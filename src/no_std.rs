@@ -0,0 +1,32 @@
+//! Stands in for `std` when the crate is built without it, so that the
+//! rest of the crate can keep using `std::` paths. Everything here comes
+//! from `core` and `alloc`, apart from the hash maps, which are
+//! `hashbrown`'s. Not everything here is used, so that it can be used
+//! the way `std` would be.
+
+#![allow(unused_imports)]
+
+pub use core::*;
+
+pub use alloc::{borrow, boxed, fmt, rc, slice, str, string, vec};
+
+pub mod collections {
+    pub use alloc::collections::*;
+    pub use hashbrown::{hash_map, hash_set, HashMap, HashSet};
+}
+
+pub mod sync {
+    pub use alloc::sync::*;
+    pub use core::sync::atomic;
+}
+
+pub mod prelude {
+    /// What the `std` prelude has on top of `core`'s, which each module
+    /// imports for itself.
+    pub mod v1 {
+        pub use alloc::borrow::ToOwned;
+        pub use alloc::boxed::Box;
+        pub use alloc::string::{String, ToString};
+        pub use alloc::vec::Vec;
+    }
+}
@@ -0,0 +1,831 @@
+//! Opt-in source-to-source passes over parsed programs.
+//!
+//! `eval` runs whatever it is handed, so nothing in here happens
+//! automatically. Every pass must leave the observable result of each
+//! top-level form unchanged.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {first_keyword, hash_string, Ast, Lambda, Pattern, Value};
+
+/// Identifier types that passes can mint fresh names in.
+///
+/// Generated names must be impossible to write in source, otherwise renaming
+/// a binder could capture one of the user's variables.
+pub trait Gensym: Sized {
+    fn gensym(n: usize) -> Self;
+}
+
+impl Gensym for u64 {
+    fn gensym(n: usize) -> u64 {
+        // `#` can never appear in a parsed identifier.
+        hash_string(&format!("#{}", n))
+    }
+}
+
+impl Gensym for String {
+    fn gensym(n: usize) -> String {
+        format!("#{}", n)
+    }
+}
+
+// Shared between every call to every pass, so running a pass twice over the
+// same program can't hand out a name that the first run already used.
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn fresh<Id: Gensym>() -> Id {
+    Id::gensym(GENSYM_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Inline calls to small, non-recursive user functions.
+///
+/// A function is a candidate when it is bound exactly once in the whole
+/// program, by a top-level `(= name (\(params) body))`, its body is a single
+/// expression of at most `budget` nodes, and inlining it can't be observed:
+/// the body defines nothing, only calls natives, other candidates or lambdas
+/// it applies on the spot, and never reads a name that some other function
+/// binds locally (scoping is dynamic, so such a read could see the caller's
+/// variables). Calls are only rewritten in top-level forms after the
+/// definition, and only when every argument that isn't a literal or a
+/// variable is used exactly once, in argument order, before the body calls
+/// anything. Programs with an `include` anywhere in them are left alone,
+/// since the included file could bind anything.
+///
+/// Returns the number of call sites that were replaced.
+pub fn inline<Id>(program: &mut [Ast<Id>], budget: usize) -> usize
+where
+    Id: Clone + Eq + Hash + Gensym,
+{
+    if program.iter().any(contains_include) {
+        return 0;
+    }
+
+    let candidates = find_candidates(program, budget);
+    let mut visible = HashMap::new();
+    let mut inlined = 0;
+
+    for (i, form) in program.iter_mut().enumerate() {
+        inlined += rewrite(form, &visible);
+
+        if let Some((name, candidate)) = candidates.iter().find(|&(_, c)| c.index == i) {
+            visible.insert(name.clone(), candidate);
+        }
+    }
+
+    inlined
+}
+
+struct Candidate<Id> {
+    index: usize,
+    params: Box<[Id]>,
+    body: Ast<Id>,
+    callees: HashSet<Id>,
+}
+
+fn find_candidates<Id>(program: &[Ast<Id>], budget: usize) -> HashMap<Id, Candidate<Id>>
+where
+    Id: Clone + Eq + Hash,
+{
+    let mut bindings = HashMap::new();
+    let mut locals = HashSet::new();
+    for form in program {
+        count_bindings(form, false, &mut bindings, &mut locals);
+    }
+
+    let mut candidates = HashMap::new();
+
+    for (index, form) in program.iter().enumerate() {
+        if let Ast::Define(ref name, ref value) = *form {
+            if let Ast::Lit(Value::Function(ref lambda)) = **value {
+                let Lambda { ref params, ref defaults, ref body } = **lambda;
+                if bindings.get(name) != Some(&1) || body.len() != 1 || size(&body[0]) > budget {
+                    continue;
+                }
+                // Calls that leave out an argument would need its default
+                // evaluated in the new scope, which an inlined body doesn't
+                // have.
+                if !defaults.is_empty() {
+                    continue;
+                }
+                // Taking an argument apart can fail, which the inlined body
+                // wouldn't.
+                let params = match params
+                    .iter()
+                    .map(|param| match *param {
+                        Pattern::Name(ref name) => Some(name.clone()),
+                        Pattern::List(_) => None,
+                    })
+                    .collect::<Option<Box<[_]>>>()
+                {
+                    Some(params) => params,
+                    None => continue,
+                };
+
+                let mut check = BodyCheck {
+                    bound: params.iter().cloned().collect(),
+                    locals: &locals,
+                    bindings: &bindings,
+                    callees: HashSet::new(),
+                };
+
+                if check.expr(&body[0]) {
+                    candidates.insert(
+                        name.clone(),
+                        Candidate {
+                            index,
+                            params,
+                            body: body[0].clone(),
+                            callees: check.callees,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    // A candidate can only be inlined if everything it calls can be too. Any
+    // cycle between candidates is recursion, which inlining would unroll
+    // forever, so everything on one is dropped along with its dependents.
+    loop {
+        let before = candidates.len();
+
+        let unresolved = candidates
+            .iter()
+            .filter(|&(_, c)| c.callees.iter().any(|callee| !candidates.contains_key(callee)))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in unresolved {
+            candidates.remove(&name);
+        }
+
+        let recursive = candidates
+            .keys()
+            .filter(|name| reaches(&candidates, name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in recursive {
+            candidates.remove(&name);
+        }
+
+        if candidates.len() == before {
+            return candidates;
+        }
+    }
+}
+
+fn reaches<Id: Eq + Hash>(candidates: &HashMap<Id, Candidate<Id>>, target: &Id) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = candidates[target].callees.iter().collect::<Vec<_>>();
+
+    while let Some(name) = stack.pop() {
+        if name == target {
+            return true;
+        }
+
+        if seen.insert(name) {
+            if let Some(candidate) = candidates.get(name) {
+                stack.extend(&candidate.callees);
+            }
+        }
+    }
+
+    false
+}
+
+// Counts how many times each name is bound anywhere in the program, and
+// collects the names that are bound inside a function (parameters, and
+// defines in a function body) since those are visible to callees.
+fn count_bindings<Id>(
+    ast: &Ast<Id>,
+    in_function: bool,
+    bindings: &mut HashMap<Id, usize>,
+    locals: &mut HashSet<Id>,
+) where
+    Id: Clone + Eq + Hash,
+{
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            for param in lambda.names() {
+                *bindings.entry(param.clone()).or_insert(0) += 1;
+                locals.insert(param.clone());
+            }
+            for stmt in lambda.defaults.iter().chain(lambda.body.iter()) {
+                count_bindings(stmt, true, bindings, locals);
+            }
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        Ast::Call(ref func, ref args) => {
+            count_bindings(func, in_function, bindings, locals);
+            for arg in args.iter() {
+                count_bindings(arg, in_function, bindings, locals);
+            }
+        }
+        Ast::Define(ref name, ref value) => {
+            *bindings.entry(name.clone()).or_insert(0) += 1;
+            if in_function {
+                locals.insert(name.clone());
+            }
+            count_bindings(value, in_function, bindings, locals);
+        }
+    }
+}
+
+fn size<Id>(ast: &Ast<Id>) -> usize {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            1 + lambda.defaults.iter().chain(lambda.body.iter()).map(size).sum::<usize>()
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => 1,
+        Ast::Call(ref func, ref args) => 1 + size(func) + args.iter().map(size).sum::<usize>(),
+        Ast::Define(_, ref value) => 1 + size(value),
+    }
+}
+
+fn is_trivial<Id>(ast: &Ast<Id>) -> bool {
+    matches!(*ast, Ast::Lit(_) | Ast::Variable(_))
+}
+
+fn contains_define<Id>(ast: &Ast<Id>) -> bool {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            lambda.defaults.iter().chain(lambda.body.iter()).any(contains_define)
+        }
+        Ast::Lit(_) | Ast::Variable(_) => false,
+        Ast::Call(ref func, ref args) => contains_define(func) || args.iter().any(contains_define),
+        Ast::Define(..) | Ast::Include(_) => true,
+    }
+}
+
+fn contains_include<Id>(ast: &Ast<Id>) -> bool {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            lambda.defaults.iter().chain(lambda.body.iter()).any(contains_include)
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Define(..) => false,
+        Ast::Call(ref func, ref args) => contains_include(func) || args.iter().any(contains_include),
+        Ast::Include(_) => true,
+    }
+}
+
+// Checks that a candidate's body can be copied into a call site without
+// changing what it observes. `bound` holds the names bound lexically at this
+// point: the candidate's parameters and those of enclosing applied lambdas.
+struct BodyCheck<'a, Id: 'a> {
+    bound: HashSet<Id>,
+    locals: &'a HashSet<Id>,
+    bindings: &'a HashMap<Id, usize>,
+    callees: HashSet<Id>,
+}
+
+impl<'a, Id: Clone + Eq + Hash> BodyCheck<'a, Id> {
+    fn expr(&mut self, ast: &Ast<Id>) -> bool {
+        match *ast {
+            // A lambda that escapes would read our parameters dynamically
+            // wherever it ends up being called.
+            Ast::Lit(Value::Function(..)) | Ast::Define(..) | Ast::Include(_) => false,
+            Ast::Lit(_) => true,
+            Ast::Variable(ref name) => self.bound.contains(name) || !self.locals.contains(name),
+            Ast::Call(ref func, ref args) => {
+                let func_ok = match **func {
+                    // Whether a default runs depends on the call, so those
+                    // aren't worth following.
+                    Ast::Lit(Value::Function(ref lambda)) if lambda.defaults.is_empty() => {
+                        let outer = self.bound.clone();
+                        self.bound.extend(lambda.names().into_iter().cloned());
+                        let ok = lambda.body.iter().all(|stmt| self.expr(stmt));
+                        self.bound = outer;
+                        ok
+                    }
+                    Ast::Variable(ref name) if !self.bound.contains(name) => {
+                        // Anything the program never binds must come from the
+                        // host, and natives can't see the environment.
+                        if self.bindings.contains_key(name) {
+                            self.callees.insert(name.clone());
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+
+                func_ok && args.iter().all(|arg| self.expr(arg))
+            }
+        }
+    }
+}
+
+fn rewrite<Id>(ast: &mut Ast<Id>, visible: &HashMap<Id, &Candidate<Id>>) -> usize
+where
+    Id: Clone + Eq + Hash + Gensym,
+{
+    let mut inlined = 0;
+
+    match *ast {
+        Ast::Lit(Value::Function(ref mut lambda)) => {
+            let mut new_defaults = lambda.defaults.to_vec();
+            let mut new_body = lambda.body.to_vec();
+            for stmt in new_defaults.iter_mut().chain(&mut new_body) {
+                inlined += rewrite(stmt, visible);
+            }
+            if inlined > 0 {
+                *lambda = Rc::new(Lambda {
+                    params: lambda.params.clone(),
+                    defaults: new_defaults.into(),
+                    body: new_body.into(),
+                });
+            }
+        }
+        Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => {}
+        // A freshly parsed program owns all of its nodes, so these only copy
+        // subtrees that are shared with something else.
+        Ast::Call(ref mut func, ref mut args) => {
+            inlined += rewrite(Rc::make_mut(func), visible);
+            for arg in Rc::make_mut(args) {
+                inlined += rewrite(arg, visible);
+            }
+        }
+        Ast::Define(_, ref mut value) => inlined += rewrite(Rc::make_mut(value), visible),
+    }
+
+    let replacement = match *ast {
+        Ast::Call(ref func, ref args) => match **func {
+            Ast::Variable(ref name) => visible
+                .get(name)
+                .and_then(|candidate| instantiate(candidate, args)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    if let Some(mut replacement) = replacement {
+        // The copied body may call other candidates, and since candidates
+        // are never recursive this terminates.
+        inlined += 1 + rewrite(&mut replacement, visible);
+        *ast = replacement;
+    }
+
+    inlined
+}
+
+#[derive(PartialEq)]
+enum Event {
+    Use(usize),
+    Call,
+}
+
+// Records, in evaluation order, each use of a parameter and each point where
+// the body hands control to another function.
+fn events<Id: Eq + Hash>(ast: &Ast<Id>, params: &HashMap<&Id, usize>, out: &mut Vec<Event>) {
+    match *ast {
+        Ast::Lit(_) | Ast::Define(..) => {}
+        Ast::Variable(ref name) => {
+            if let Some(&i) = params.get(name) {
+                out.push(Event::Use(i));
+            }
+        }
+        Ast::Call(ref func, ref args) => match **func {
+            Ast::Lit(Value::Function(ref inner)) => {
+                for arg in args.iter() {
+                    events(arg, params, out);
+                }
+                let params = params
+                    .iter()
+                    .filter(|&(name, _)| !inner.names().contains(name))
+                    .map(|(&name, &i)| (name, i))
+                    .collect();
+                for stmt in inner.body.iter() {
+                    events(stmt, &params, out);
+                }
+            }
+            _ => {
+                events(func, params, out);
+                for arg in args.iter() {
+                    events(arg, params, out);
+                }
+                out.push(Event::Call);
+            }
+        },
+        Ast::Include(_) => out.push(Event::Call),
+    }
+}
+
+fn instantiate<Id>(candidate: &Candidate<Id>, args: &[Ast<Id>]) -> Option<Ast<Id>>
+where
+    Id: Clone + Eq + Hash + Gensym,
+{
+    if args.len() != candidate.params.len()
+        || args.iter().any(contains_define)
+        || first_keyword(args).is_some()
+    {
+        return None;
+    }
+
+    // With duplicate parameter names the last argument wins, as in `eval`.
+    let mut params = HashMap::new();
+    for (i, name) in candidate.params.iter().enumerate() {
+        params.insert(name, i);
+    }
+
+    let mut trace = Vec::new();
+    events(&candidate.body, &params, &mut trace);
+
+    // Literals and variables can be evaluated any number of times, at any
+    // point, without anyone noticing. Anything else has to be evaluated
+    // exactly as often, and in the same order relative to everything else
+    // with an effect, as it would have been before the call.
+    let mut expected = (0..args.len()).filter(|&i| !is_trivial(&args[i]));
+    let mut next = expected.next();
+    let mut called = false;
+
+    for event in &trace {
+        match *event {
+            Event::Call => called = true,
+            Event::Use(i) if is_trivial(&args[i]) => {}
+            Event::Use(i) if !called && next == Some(i) => next = expected.next(),
+            Event::Use(_) => return None,
+        }
+    }
+
+    if next.is_some() {
+        return None;
+    }
+
+    let substitution = params
+        .into_iter()
+        .map(|(name, i)| (name.clone(), args[i].clone()))
+        .collect();
+
+    Some(substitute(&candidate.body, &substitution))
+}
+
+// Copies `ast`, replacing variables according to `substitution`. Parameters
+// of lambdas in the body are renamed to fresh names first, so that free
+// variables of the substituted arguments can't be captured by them.
+fn substitute<Id>(ast: &Ast<Id>, substitution: &HashMap<Id, Ast<Id>>) -> Ast<Id>
+where
+    Id: Clone + Eq + Hash + Gensym,
+{
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            let mut inner = substitution.clone();
+            let required = lambda.required();
+            let mut params = Vec::with_capacity(lambda.params.len());
+            let mut defaults = Vec::with_capacity(lambda.defaults.len());
+            for (i, param) in lambda.params.iter().enumerate() {
+                // A default can only see the parameters before it.
+                if i >= required {
+                    defaults.push(substitute(&lambda.defaults[i - required], &inner));
+                }
+                params.push(param.map(&mut |param: &Id| {
+                    let name = fresh::<Id>();
+                    inner.insert(param.clone(), Ast::Variable(name.clone()));
+                    name
+                }));
+            }
+            let body = lambda
+                .body
+                .iter()
+                .map(|stmt| substitute(stmt, &inner))
+                .collect();
+
+            Ast::Lit(Value::Function(Rc::new(Lambda {
+                params: params.into(),
+                defaults: defaults.into(),
+                body,
+            })))
+        }
+        Ast::Lit(ref value) => Ast::Lit(value.clone()),
+        Ast::Include(ref path) => Ast::Include(path.clone()),
+        Ast::Variable(ref name) => substitution
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Ast::Variable(name.clone())),
+        Ast::Call(ref func, ref args) => Ast::Call(
+            Rc::new(substitute(func, substitution)),
+            args.iter().map(|arg| substitute(arg, substitution)).collect(),
+        ),
+        Ast::Define(ref name, ref value) => {
+            Ast::Define(name.clone(), Rc::new(substitute(value, substitution)))
+        }
+    }
+}
+
+/// Remove top-level defines that nothing can observe.
+///
+/// The roots are the top-level forms that aren't defines, plus any define
+/// whose right-hand side calls something, since that call might have an
+/// effect. A variable read directly by a top-level form refers to the most
+/// recent define of that name before it. A read inside a function body could
+/// happen whenever the function is called, so it also keeps every later
+/// define of that name. Names that are never defined at the top level are
+/// assumed to come from the host. Programs with an `include` anywhere in
+/// them are left alone, since the included file could read anything.
+///
+/// Returns the names of the removed defines, in program order.
+pub fn strip_unused<Id>(program: &mut Vec<Ast<Id>>) -> Vec<Id>
+where
+    Id: Clone + Eq + Hash,
+{
+    if program.iter().any(contains_include) {
+        return Vec::new();
+    }
+
+    let mut defines: HashMap<&Id, Vec<usize>> = HashMap::new();
+    for (i, form) in program.iter().enumerate() {
+        if let Ast::Define(ref name, _) = *form {
+            defines.entry(name).or_default().push(i);
+        }
+    }
+
+    let mut live = vec![false; program.len()];
+    let mut stack = Vec::new();
+
+    for (i, form) in program.iter().enumerate() {
+        let root = match *form {
+            Ast::Define(_, ref value) => calls_anything(value),
+            _ => true,
+        };
+        if root {
+            live[i] = true;
+            stack.push(i);
+        }
+    }
+
+    while let Some(i) = stack.pop() {
+        let mut reads = Vec::new();
+        let form = match program[i] {
+            Ast::Define(_, ref value) => &**value,
+            ref other => other,
+        };
+        collect_reads(form, false, &mut reads);
+
+        for (name, in_function) in reads {
+            let indices = match defines.get(name) {
+                Some(indices) => indices,
+                None => continue,
+            };
+
+            let reached = indices
+                .iter()
+                .rev()
+                .find(|&&j| j < i)
+                .into_iter()
+                .chain(indices.iter().filter(|&&j| in_function && j > i));
+
+            for &j in reached {
+                if !live[j] {
+                    live[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+    }
+
+    // `defines` borrows from `program`, and hashbrown's maps, unlike std's,
+    // may read what they hold when they're dropped.
+    drop(defines);
+
+    let mut removed = Vec::new();
+    let mut index = 0;
+    program.retain(|form| {
+        let keep = live[index];
+        index += 1;
+        if !keep {
+            if let Ast::Define(ref name, _) = *form {
+                removed.push(name.clone());
+            }
+        }
+        keep
+    });
+
+    removed
+}
+
+fn calls_anything<Id>(ast: &Ast<Id>) -> bool {
+    match *ast {
+        Ast::Lit(_) | Ast::Variable(_) => false,
+        Ast::Call(..) | Ast::Include(_) => true,
+        Ast::Define(_, ref value) => calls_anything(value),
+    }
+}
+
+// Every variable read by `ast`, along with whether the read happens inside a
+// function body rather than when `ast` itself is evaluated.
+fn collect_reads<'a, Id>(ast: &'a Ast<Id>, in_function: bool, out: &mut Vec<(&'a Id, bool)>) {
+    match *ast {
+        Ast::Lit(Value::Function(ref lambda)) => {
+            for stmt in lambda.defaults.iter().chain(lambda.body.iter()) {
+                collect_reads(stmt, true, out);
+            }
+        }
+        Ast::Lit(_) | Ast::Include(_) => {}
+        Ast::Variable(ref name) => out.push((name, in_function)),
+        Ast::Call(ref func, ref args) => {
+            collect_reads(func, in_function, out);
+            for arg in args.iter() {
+                collect_reads(arg, in_function, out);
+            }
+        }
+        Ast::Define(_, ref value) => collect_reads(value, in_function, out),
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use combine::Parser;
+
+    use benches::{corpus, corpus_env, same_value};
+    use {eval, expr, hash_string, Ast, Value};
+
+    use super::{inline, strip_unused};
+
+    fn run(program: &[Ast<u64>]) -> Vec<Value<u64>> {
+        let mut env = corpus_env();
+        program
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().into_owned())
+            .collect()
+    }
+
+    fn parse(src: &str) -> Vec<Ast<u64>> {
+        ::combine::many1::<Vec<_>, _>(expr())
+            .easy_parse(src)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn inlining_preserves_corpus_results() {
+        for (name, program) in corpus() {
+            let mut inlined = program.clone();
+            let sites = inline(&mut inlined, 32);
+            if name == "REAL_CODE" {
+                assert!(sites > 0);
+            }
+
+            let (before, after) = (run(&program), run(&inlined));
+            assert_eq!(before.len(), after.len());
+            for (a, b) in before.iter().zip(&after) {
+                assert!(same_value(a, b), "`{}` changed when inlined", name);
+            }
+        }
+    }
+
+    #[test]
+    fn inlines_small_wrappers() {
+        let mut program = parse(
+            r"
+            (= ne (\(a b) (not (eq a b))))
+            (= not (\(a) (if a #f)))
+            (ne 1 2)
+            ",
+        );
+
+        // `ne` is defined before `not`, so its own body is left alone, but
+        // the top-level call gets both layers.
+        assert_eq!(inline(&mut program, 32), 2);
+        match program[2] {
+            Ast::Call(ref func, _) => match **func {
+                Ast::Variable(name) => assert_eq!(name, hash_string("if")),
+                _ => panic!("call to `ne` was not inlined"),
+            },
+            _ => panic!("call to `ne` was not inlined"),
+        }
+    }
+
+    #[test]
+    fn skips_recursion_and_large_bodies() {
+        let mut program = parse(
+            r"
+            (= loop (\(a) (loop a)))
+            (= big (\(a) (add a a a a a a a a)))
+            (loop 1)
+            (big 1)
+            ",
+        );
+
+        assert_eq!(inline(&mut program, 4), 0);
+        assert_eq!(inline(&mut program, 32), 1);
+    }
+
+    #[test]
+    fn renaming_avoids_capture() {
+        // Substituting `x` for `a` naively would produce
+        // `((\(x) (add x x)) 1)`, capturing the caller's `x`.
+        let mut program = parse(
+            r"
+            (= f (\(a) ((\(x) (add x a)) 1)))
+            (= x 10)
+            (f x)
+            ",
+        );
+
+        let expected = run(&program);
+        assert_eq!(inline(&mut program, 32), 1);
+
+        match program[2] {
+            Ast::Call(ref func, ref args) => {
+                match **func {
+                    Ast::Lit(Value::Function(ref lambda)) => {
+                        assert_ne!(*lambda.names()[0], hash_string("x"))
+                    }
+                    _ => panic!("call to `f` was not inlined"),
+                }
+                assert!(args.len() == 1);
+            }
+            _ => panic!("call to `f` was not inlined"),
+        }
+
+        let actual = run(&program);
+        assert!(actual[2] == Value::Int(11));
+        assert!(expected[2] == actual[2]);
+    }
+
+    #[test]
+    fn keeps_evaluation_order_of_arguments() {
+        // Both arguments would be evaluated after `add` has been looked up
+        // but in the wrong order, so this call must be left alone.
+        let mut program = parse(
+            r"
+            (= swap (\(a b) (add b a)))
+            (swap (add 1 2) (add 3 4))
+            ",
+        );
+
+        assert_eq!(inline(&mut program, 32), 0);
+    }
+
+    #[test]
+    fn strips_unreachable_defines() {
+        let src = r"
+            (= one (\() 1))
+            (= two (\() 2))
+            (= three (\() (add (two) 1)))
+            (= four (\() 4))
+            (= five 5)
+            (three)
+            ";
+        let mut program = parse(src);
+        let expected = run(&program);
+
+        let removed = strip_unused(&mut program);
+        assert!(removed == vec![hash_string("one"), hash_string("four"), hash_string("five")]);
+        assert_eq!(program.len(), 3);
+
+        let actual = run(&program);
+        assert!(expected.last() == actual.last());
+        assert!(actual.last() == Some(&Value::Int(3)));
+    }
+
+    #[test]
+    fn strip_respects_redefinition_order() {
+        let mut program = parse(
+            r"
+            (= a 1)
+            (= a 2)
+            a
+            (= b 1)
+            (= f (\() b))
+            (= b 2)
+            (f)
+            ",
+        );
+
+        // The first `a` is overwritten before anything reads it, but both
+        // `b`s are visible to `f` depending on when it's called.
+        assert!(strip_unused(&mut program) == vec![hash_string("a")]);
+        assert_eq!(program.len(), 6);
+    }
+
+    #[test]
+    fn strip_keeps_defines_with_effects() {
+        let mut program = parse(
+            r"
+            (= unused (add 1 2))
+            (= lambda (\() (add 1 2)))
+            ",
+        );
+
+        assert!(strip_unused(&mut program) == vec![hash_string("lambda")]);
+        assert_eq!(program.len(), 1);
+    }
+
+    #[test]
+    fn strip_preserves_corpus_results() {
+        for (name, program) in corpus() {
+            let mut stripped = program.clone();
+            strip_unused(&mut stripped);
+
+            let (before, after) = (run(&program), run(&stripped));
+            assert!(
+                same_value(before.last().unwrap(), after.last().unwrap()),
+                "`{}` changed when stripped",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Where `print` writes to.
+//!
+//! Each thread has its own sink, which starts out as standard output. A
+//! host can swap it for any `io::Write` with `set_output`, or collect what
+//! a call prints with `capture`, which puts the sink back afterwards even
+//! if the call panics. Captures nest, so a native that captures its own
+//! output while a program's is being captured gets only its own.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use {EvalError, Value};
+
+thread_local! {
+    static OUTPUT: RefCell<Box<dyn Write>> = RefCell::new(Box::new(io::stdout()));
+}
+
+/// Make `sink` where `print` writes to on this thread, returning the sink
+/// it replaces.
+pub fn set_output(sink: Box<dyn Write>) -> Box<dyn Write> {
+    OUTPUT.with(|output| output.replace(sink))
+}
+
+/// Call `f`, returning what it gave and the text printed while it ran
+/// instead of writing it to the sink.
+pub fn capture<T, F: FnOnce() -> T>(f: F) -> (T, String) {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let restore = Restore(Some(set_output(Box::new(Buffer(buffer.clone())))));
+    let out = f();
+    drop(restore);
+
+    let text = String::from_utf8_lossy(&buffer.borrow()).into_owned();
+    (out, text)
+}
+
+// Puts back the sink that was there before a capture, when dropped.
+struct Restore(Option<Box<dyn Write>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(previous) = self.0.take() {
+            set_output(previous);
+        }
+    }
+}
+
+// A capture's sink, which the capture reads once it's put back.
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `(print a b ...)` writes its arguments to the sink, separated by spaces
+/// and with a newline after them. Strings are written as they are, and
+/// anything else the way it's displayed. A sink that fails to write is
+/// ignored, since a program has no way to do anything about it.
+pub fn print<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut line = String::new();
+    for (i, value) in variables.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        match **value {
+            Value::Str(ref text) => line.push_str(text),
+            ref value => line.push_str(&value.to_string()),
+        }
+    }
+    line.push('\n');
+
+    OUTPUT.with(|output| {
+        let _ = output.borrow_mut().write_all(line.as_bytes());
+    });
+    Ok(Value::Void)
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::panic;
+
+    use super::{capture, print};
+    use {hash_string, prelude, IntMap, Value};
+
+    #[test]
+    fn captures_what_programs_print() {
+        let interpreter = prelude::interpreter();
+        let mut env = prelude::env();
+        let src = r#"(print "hello" 1 (list 2 "x")) (print) (= x 3)"#;
+        let (result, text) = interpreter.eval_str_captured(src, &mut env);
+        assert!(result.unwrap() == Value::Int(3));
+        assert_eq!(text, "hello 1 (list 2 \"x\")\n\n");
+
+        let src = r#"(print "before") (error "boom") (print "after")"#;
+        let (result, text) = interpreter.eval_str_captured(src, &mut env);
+        assert!(result.is_err());
+        assert_eq!(text, "before\n");
+    }
+
+    #[test]
+    fn captures_nest_and_put_the_sink_back() {
+        let mut interpreter = prelude::interpreter();
+        interpreter.register_fn(hash_string("inner"), |src: String| {
+            let mut env = prelude::env();
+            let (result, text) = prelude::interpreter().eval_str_captured(&src, &mut env);
+            result.unwrap();
+            text
+        });
+
+        let ((), outer) = capture(|| {
+            let src = r#"(print "a") (print (inner "(print 1) (print 2)")) (print "b")"#;
+            let mut env: IntMap<_> = interpreter.env();
+            let (result, text) = interpreter.eval_str_captured(src, &mut env);
+            assert!(result.is_ok());
+            assert_eq!(text, "a\n1\n2\n\nb\n");
+
+            let panicked = panic::catch_unwind(|| {
+                capture(|| {
+                    print::<u64>(&[]).unwrap();
+                    panic!("while capturing");
+                })
+            });
+            assert!(panicked.is_err());
+            print(&[&Value::<u64>::Int(3)]).unwrap();
+        });
+        assert_eq!(outer, "3\n");
+    }
+}
@@ -0,0 +1,900 @@
+//! Reading programs from source, which needs the `parse` feature. It's on
+//! by default, but an embedding that only runs programs built by hand or
+//! decoded with `Ast::from_bytes` can turn it off to drop `combine`.
+//!
+//! Whitespace is never needed between two expressions, whether they're
+//! top-level forms or the arguments of a call. A number or a name ends at
+//! the first character that can't be part of it, and everything else ends
+//! at its own delimiter, so `(f)(g)`, `(f 1)2` and `#f#f` are each two
+//! expressions. So are `1a` and `a1`, since names are only letters, which
+//! means code written out mechanically can leave the whitespace out.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::iter::FromIterator;
+use std::rc::Rc;
+use std::thread::LocalKey;
+
+use combine::error::{Consumed, FastResult, ParseError as CombineError, StreamError};
+use combine::stream::Resetable;
+use combine::{Parser, Positioned, Stream, StreamOnce};
+
+use {
+    hash_string, tokenize, Ast, Diagnostic, ParseError, ParseOptions, Pattern, SymbolTable,
+    TokenKind, Value, MAX_NESTING,
+};
+
+thread_local! {
+    static PARSED_ASTS: RefCell<Vec<Ast<u64>>> = const { RefCell::new(Vec::new()) };
+    static PARSED_PATTERNS: RefCell<Vec<Pattern<u64>>> = const { RefCell::new(Vec::new()) };
+    static PARSED_PARAMS: RefCell<Vec<Param>> = const { RefCell::new(Vec::new()) };
+    static PARSED_PARTS: RefCell<Vec<Part>> = const { RefCell::new(Vec::new()) };
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+// Each level of nesting is a few nested calls into combine, which take tens
+// of kilobytes of stack in a debug build, so a deep enough program would
+// overflow the stack before it failed to parse. Past `MAX_NESTING` we give
+// up, and below it we make sure there's room for another level, moving onto
+// a new stack on the heap if we're running out.
+fn nested<P>(mut inner: P) -> impl Parser<Input = P::Input, Output = P::Output>
+where
+    P: Parser,
+{
+    combine::parser(move |input: &mut P::Input| {
+        let depth = NESTING.with(|nesting| nesting.get());
+
+        if depth >= MAX_NESTING {
+            let message = StreamError::message_static_message("nesting is too deep");
+            let error = <P::Input as StreamOnce>::Error::from_error(input.position(), message);
+            return Err(Consumed::Empty(error.into()));
+        }
+
+        NESTING.with(|nesting| nesting.set(depth + 1));
+        let result = stacker::maybe_grow(64 * 1024, 1024 * 1024, || inner.parse_stream(input));
+        NESTING.with(|nesting| nesting.set(depth));
+        result
+    })
+}
+
+// Fails at the end of the input, where otherwise an expression would fail
+// with a list of every character it could have started with.
+fn not_at_end<I: Stream<Item = char>>() -> impl Parser<Input = I, Output = ()> {
+    combine::parser(|input: &mut I| {
+        let before = input.checkpoint();
+        let at_end = input.uncons().is_err();
+        input.reset(before);
+
+        if at_end {
+            let message = StreamError::message_static_message(
+                "expected an expression, found end of input",
+            );
+            let error = I::Error::from_error(input.position(), message);
+            return Err(Consumed::Empty(error.into()));
+        }
+        Ok(((), Consumed::Empty(())))
+    })
+}
+
+// Parses `item` as many times as possible, like `many`, but moves the
+// results into a single allocation of exactly the right size at the end
+// instead of growing a `Vec` and then copying it into a slice.
+//
+// Items wait on `stack`, which is shared by every list being parsed on this
+// thread. Nested lists always finish before the list containing them, so
+// the items of the innermost unfinished list are always the ones on top.
+//
+// Every list in the grammar ends at `close`, so we stop there without
+// trying `item` at all: building the error for a failed attempt is where
+// combine does most of its allocating.
+fn list<P, C>(
+    close: char,
+    stack: &'static LocalKey<RefCell<Vec<P::Output>>>,
+    mut item: P,
+) -> impl Parser<Input = P::Input, Output = C>
+where
+    P: Parser,
+    P::Input: Stream<Item = char>,
+    P::Output: 'static,
+    C: FromIterator<P::Output>,
+{
+    combine::parser(move |input: &mut P::Input| {
+        let base = stack.with(|stack| stack.borrow().len());
+        let mut consumed = Consumed::Empty(());
+
+        loop {
+            let before = input.checkpoint();
+
+            let at_close = input.uncons().ok() == Some(close);
+            input.reset(before.clone());
+            if at_close {
+                break;
+            }
+
+            match item.parse_lazy(input) {
+                FastResult::ConsumedOk(value) => {
+                    consumed = Consumed::Consumed(());
+                    stack.with(|stack| stack.borrow_mut().push(value));
+                }
+                FastResult::EmptyOk(value) => stack.with(|stack| stack.borrow_mut().push(value)),
+                FastResult::EmptyErr(_) => {
+                    input.reset(before);
+                    break;
+                }
+                FastResult::ConsumedErr(error) => {
+                    stack.with(|stack| stack.borrow_mut().truncate(base));
+                    return Err(Consumed::Consumed(error.into()));
+                }
+            }
+        }
+
+        let items = stack.with(|stack| stack.borrow_mut().drain(base..).collect());
+        Ok((items, consumed))
+    })
+}
+
+// One entry in the parameter list of a function, with its default if it
+// has one.
+enum Param {
+    Required(Pattern<u64>),
+    Optional(u64, Ast<u64>),
+}
+
+// A parameter list, split up the way `Lambda` wants it.
+struct Params {
+    params: Box<[Pattern<u64>]>,
+    defaults: Box<[Ast<u64>]>,
+    // Whether a required parameter came after an optional one.
+    misordered: bool,
+}
+
+impl FromIterator<Param> for Params {
+    fn from_iter<T: IntoIterator<Item = Param>>(iter: T) -> Self {
+        let iter = iter.into_iter();
+        // Sized for the common case of no defaults, so that the parameters
+        // go straight into the slice without reallocating.
+        let mut params = Vec::with_capacity(iter.size_hint().0);
+        let mut defaults = Vec::new();
+        let mut misordered = false;
+
+        for param in iter {
+            match param {
+                Param::Required(pattern) => {
+                    misordered |= !defaults.is_empty();
+                    params.push(pattern);
+                }
+                Param::Optional(name, default) => {
+                    params.push(Pattern::Name(name));
+                    defaults.push(default);
+                }
+            }
+        }
+
+        Params {
+            params: params.into(),
+            defaults: defaults.into(),
+            misordered,
+        }
+    }
+}
+
+// A piece of a string literal: either text, or an expression in braces
+// whose value is written into the string.
+enum Part {
+    Text(String),
+    Interpolated(Ast<u64>),
+}
+
+// A string literal with its `{expr}`s turned into a call to `concat`, which
+// is only made if there's something to interpolate.
+fn interpolate<F: FnOnce() -> u64>(parts: Vec<Part>, concat: F) -> Ast<u64> {
+    if parts.iter().all(|part| matches!(*part, Part::Text(_))) {
+        let text = parts
+            .into_iter()
+            .map(|part| match part {
+                Part::Text(text) => text,
+                Part::Interpolated(_) => unreachable!(),
+            })
+            .collect::<String>();
+        return Ast::Lit(Value::Str(Rc::new(text)));
+    }
+
+    let args = parts
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => Ast::Lit(Value::Str(Rc::new(text))),
+            Part::Interpolated(ast) => ast,
+        })
+        .collect::<Vec<_>>();
+    Ast::Call(Rc::new(Ast::Variable(concat())), args.into())
+}
+
+// A lambda written out as a literal, for the special forms that are calls
+// to a native with code for it to run later.
+fn lambda_lit(params: Box<[Pattern<u64>]>, body: Box<[Ast<u64>]>) -> Ast<u64> {
+    let lambda = ::Lambda {
+        params,
+        defaults: Box::new([]),
+        body,
+    };
+    Ast::Lit(Value::Function(Rc::new(lambda)))
+}
+
+// `nil` reads as a literal wherever a variable could be, so nothing can be
+// bound to it.
+fn bindable(name: &str) -> Result<&str, &'static str> {
+    if name == "nil" {
+        Err("`nil` can't be bound to a value")
+    } else {
+        Ok(name)
+    }
+}
+
+// The bytes of a `#b"..."` literal, given what's between its quotes. There
+// are the same escapes as in strings, and `\xNN` for any byte.
+fn unescape_bytes(text: &str) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => out.push(b'"'),
+                Some('\\') => out.push(b'\\'),
+                Some('n') => out.push(b'\n'),
+                Some('x') => {
+                    let hex = chars.as_str().get(..2).filter(|hex| {
+                        hex.chars().all(|c| c.is_ascii_hexdigit())
+                    });
+                    match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                        Some(byte) => out.push(byte),
+                        None => return Err("`\\x` must be followed by two hex digits"),
+                    }
+                    chars.nth(1);
+                }
+                _ => return Err("unknown escape in a bytes literal"),
+            },
+            c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(out)
+}
+
+/// Parse a whole program into its top-level forms, checking for identifier
+/// collisions. Diagnostics are dropped; pass `parse_program_with` a sink to
+/// see them. A program with nothing but whitespace in it has no forms,
+/// rather than being an error.
+pub fn parse_program(src: &str) -> Result<Vec<Ast<u64>>, ParseError> {
+    parse_program_with(src, &ParseOptions::default(), &mut |_| {})
+}
+
+/// Parse a whole program, passing each diagnostic to `diagnostics` unless
+/// `options.strict` is set.
+pub fn parse_program_with(
+    src: &str,
+    options: &ParseOptions,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> Result<Vec<Ast<u64>>, ParseError> {
+    let state = RefCell::new(ParseState::new(src, hash_string, options));
+    parse_with_state(src, &state, diagnostics)
+}
+
+/// Parse a whole program from bytes like `parse_program_with`, failing if
+/// they aren't UTF-8.
+pub fn parse_bytes(
+    data: &[u8],
+    options: &ParseOptions,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> Result<Vec<Ast<u64>>, ParseError> {
+    let src = ::std::str::from_utf8(data)
+        .map_err(|e| ParseError::Syntax {
+            message: format!("Invalid UTF-8 at byte {}", e.valid_up_to()),
+            position: Some(e.valid_up_to()),
+        })?;
+    parse_program_with(src, options, diagnostics)
+}
+
+/// An entry point for fuzzers, which parses `data` as a whole program and,
+/// if it's UTF-8, as a single expression. Parsing should fail gracefully on
+/// any input, so anything but returning normally is a bug.
+pub fn parse_fuzz(data: &[u8]) {
+    let _ = parse_bytes(data, &ParseOptions::default(), &mut |_| {});
+
+    if let Ok(src) = ::std::str::from_utf8(data) {
+        let _ = expr().easy_parse(src);
+    }
+}
+
+/// Parse a whole program like `parse_program_with`, and also return the
+/// names of all of its identifiers.
+pub fn parse_program_with_symbols(
+    src: &str,
+    options: &ParseOptions,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> Result<(Vec<Ast<u64>>, SymbolTable), ParseError> {
+    let mut state = ParseState::new(src, hash_string, options);
+    state.names.get_or_insert_with(SymbolTable::new);
+
+    let state = RefCell::new(state);
+    let program = parse_with_state(src, &state, diagnostics)?;
+    let symbols = state.into_inner().names.unwrap_or_default();
+    Ok((program, symbols))
+}
+
+/// Parse a whole program like `parse_program`, but carry on past syntax
+/// errors so that every broken form is reported at once, rather than only
+/// the first. A form that fails to parse is left out of the program, and
+/// parsing starts again after it, going by its parentheses. If they never
+/// close, it starts again at the next line beginning with `(`, which is most
+/// likely the next top-level form. A program without errors parses the
+/// same as it does with `parse_program`, and its diagnostics are passed to
+/// `diagnostics` the way `parse_program_with` passes them.
+pub fn parse_recovering(
+    src: &str,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> (Vec<Ast<u64>>, Vec<ParseError>) {
+    let state = RefCell::new(ParseState::new(src, hash_string, &ParseOptions::default()));
+    let mut program = Vec::new();
+    let mut errors = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        pos = src.len() - src[pos..].trim_start().len();
+        if pos == src.len() {
+            break;
+        }
+
+        let rest = &src[pos..];
+        match expr_in(Some(&state)).easy_parse(rest) {
+            Ok((form, after)) => {
+                program.push(form);
+                pos = src.len() - after.len();
+            }
+            Err(e) => {
+                // The message starts with the position, so it's moved to be
+                // relative to the whole source before it's written out.
+                let e = e.map_position(|p| p.translate_position(rest) + pos);
+                errors.push(ParseError::Syntax {
+                    message: e.to_string(),
+                    position: Some(e.position),
+                });
+                pos += skip_form(rest);
+            }
+        }
+
+        if let Some(error) = state.borrow_mut().error.take() {
+            errors.push(error);
+        }
+    }
+
+    for diagnostic in state.borrow_mut().diagnostics.drain(..) {
+        diagnostics(diagnostic);
+    }
+    (program, errors)
+}
+
+// How much of `rest` to skip after the form it starts with fails to parse:
+// to the end of the form, or just past a stray `)` or a character that
+// can't start a token. Always more than nothing, so that parsing moves on.
+fn skip_form(rest: &str) -> usize {
+    let mut depth = 0;
+    for token in tokenize(rest) {
+        match token.kind {
+            TokenKind::Open => depth += 1,
+            TokenKind::Close if depth > 1 => depth -= 1,
+            TokenKind::Close => return token.span.end,
+            TokenKind::Whitespace | TokenKind::Quote | TokenKind::Unquote => {}
+            _ if depth == 0 => return token.span.end,
+            _ => {}
+        }
+    }
+    rest.find("\n(").map_or(rest.len(), |i| i + 1)
+}
+
+pub(crate) fn parse_with_state(
+    src: &str,
+    state: &RefCell<ParseState>,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> Result<Vec<Ast<u64>>, ParseError> {
+    use combine::parser::char::spaces;
+    use combine::{eof, many};
+
+    let (program, _) = (spaces(), many::<Vec<_>, _>(expr_in(Some(state))), eof())
+        .map(|(_, program, _)| program)
+        .easy_parse(src)
+        .map_err(|e| {
+            let e = e.map_position(|p| p.translate_position(src));
+            ParseError::Syntax {
+                message: e.to_string(),
+                position: Some(e.position),
+            }
+        })?;
+
+    let mut state = state.borrow_mut();
+
+    if let Some(error) = state.error.take() {
+        return Err(error);
+    }
+
+    for diagnostic in state.diagnostics.drain(..) {
+        diagnostics(diagnostic);
+    }
+
+    Ok(program)
+}
+
+// What `parse_program` needs to remember while the grammar runs.
+pub(crate) struct ParseState {
+    // Identifiers are slices of the source, so this is enough to find their
+    // position.
+    start: usize,
+    hash: fn(&str) -> u64,
+    // Only kept if we're checking for collisions or the caller wants them.
+    names: Option<SymbolTable>,
+    detect_collisions: bool,
+    strict: bool,
+    builtins: HashSet<u64>,
+    // The parameters seen so far in the parameter list being parsed.
+    params: Vec<u64>,
+    error: Option<ParseError>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseState {
+    pub(crate) fn new(src: &str, hash: fn(&str) -> u64, options: &ParseOptions) -> Self {
+        ParseState {
+            start: src.as_ptr() as usize,
+            hash,
+            names: if options.detect_collisions {
+                Some(SymbolTable::new())
+            } else {
+                None
+            },
+            detect_collisions: options.detect_collisions,
+            strict: options.strict,
+            builtins: options.builtins.clone(),
+            params: Vec::new(),
+            error: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn intern(&mut self, name: &str) -> u64 {
+        use std::collections::hash_map::Entry;
+
+        let hash = (self.hash)(name);
+
+        if let Some(ref mut names) = self.names {
+            match names.names.entry(hash) {
+                Entry::Occupied(entry) => {
+                    if self.detect_collisions && entry.get() != name && self.error.is_none() {
+                        self.error = Some(ParseError::IdentifierCollision {
+                            a: entry.get().clone(),
+                            b: name.to_owned(),
+                            hash,
+                        });
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(name.to_owned());
+                }
+            }
+        }
+
+        hash
+    }
+
+    // A name being bound by a define.
+    fn define(&mut self, name: &str) -> u64 {
+        let hash = self.intern(name);
+
+        if self.builtins.contains(&hash) {
+            self.report(Diagnostic::ShadowsBuiltin {
+                name: name.to_owned(),
+                position: name.as_ptr() as usize - self.start,
+            });
+        }
+
+        hash
+    }
+
+    fn param(&mut self, name: &str) -> u64 {
+        let hash = self.define(name);
+
+        if self.params.contains(&hash) {
+            self.report(Diagnostic::DuplicateParameter {
+                name: name.to_owned(),
+                position: name.as_ptr() as usize - self.start,
+            });
+        }
+        self.params.push(hash);
+
+        hash
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if !self.strict {
+            self.diagnostics.push(diagnostic);
+        } else if self.error.is_none() {
+            self.error = Some(ParseError::Strict(diagnostic));
+        }
+    }
+}
+
+parser! {
+    pub fn expr['a, I]()(I) -> Ast<u64> where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::{satisfy, skip_many};
+
+        skip_many(satisfy(char::is_whitespace))
+            .with(::parser::not_at_end())
+            .with(expr_in(None))
+    }
+}
+
+parser! {
+    fn expr_in['a, 'b, I](state: Option<&'b RefCell<ParseState>>)(I) -> Ast<u64> where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::parser::char::*;
+        use combine::parser::range::*;
+        use combine::error::StreamError;
+        use combine::parser::error;
+        use combine::stream::StreamErrorFor;
+        use combine::*;
+
+        macro_rules! white {
+            ($prs:expr) => {
+                between(
+                    skip_many(satisfy(char::is_whitespace)),
+                    skip_many(satisfy(char::is_whitespace)),
+                    $prs,
+                )
+            };
+        }
+
+        let state = *state;
+        let lambda = char('\\').map(move |_| {
+            if let Some(state) = state {
+                state.borrow_mut().params.clear();
+            }
+        });
+        let eq = char('=');
+        let bytes = between(
+            char('"'),
+            char('"'),
+            recognize(skip_many(choice!(
+                char('\\').with(any()).map(|_| ()),
+                satisfy(|c| c != '"' && c != '\\').map(|_| ())
+            ))),
+        )
+        .and_then(|text: &str| {
+            ::parser::unescape_bytes(text)
+                .map(|bytes| Ast::Lit(::Value::Bytes(::std::rc::Rc::new(bytes))))
+                .map_err(StreamErrorFor::<I>::message_static_message)
+        });
+        // `#f`, or `#b"..."` for bytes.
+        let hash = char('#').with(choice!(
+            char('f').map(|_| Ast::Lit(::Value::False)),
+            char('b').with(bytes)
+        ));
+        let name = || white!(take_while1(|c: char| c.is_alphabetic()));
+        let variable = name().map(move |name| match name {
+            "nil" => Ast::Lit(::Value::Nil),
+            name => Ast::Variable(match state {
+                Some(state) => state.borrow_mut().intern(name),
+                None => hash_string(name),
+            }),
+        });
+        let defined = name()
+            .and_then(|name| {
+                ::parser::bindable(name).map_err(StreamErrorFor::<I>::message_static_message)
+            })
+            .map(move |name| match state {
+                Some(state) => state.borrow_mut().define(name),
+                None => hash_string(name),
+            });
+        let params = white!(between(char('('), char(')'), ::parser::list(')', &::parser::PARSED_PARAMS, param_in(state))))
+            .and_then(|params: ::parser::Params| {
+                if params.misordered {
+                    Err(StreamErrorFor::<I>::message_static_message(
+                        "required parameters can't come after optional ones",
+                    ))
+                } else {
+                    Ok(params)
+                }
+            });
+        let function = (white!(lambda), params, ::parser::list(')', &::parser::PARSED_ASTS, expr_in(state))).map(
+            |(_, ::parser::Params { params, defaults, .. }, body)| {
+                Ast::Lit(::Value::Function(::std::rc::Rc::new(::Lambda { params, defaults, body })))
+            },
+        );
+        let define = (white!(eq), defined, expr_in(state))
+            .map(|(_, a, b)| Ast::Define(a, ::std::rc::Rc::new(b)));
+        // Every `{expr}` in a string is a full expression, which may have
+        // strings of its own.
+        let parts = ::parser::list('"', &::parser::PARSED_PARTS, part_in(state));
+        let lit_str = between(char('"'), char('"'), parts).map(move |parts| {
+            ::parser::interpolate(parts, || match state {
+                Some(state) => state.borrow_mut().intern("concat"),
+                None => hash_string("concat"),
+            })
+        });
+        let intern = move |name| match state {
+            Some(state) => state.borrow_mut().intern(name),
+            None => hash_string(name),
+        };
+        // `'expr` is code as data, and `,expr` inside it is evaluated. See
+        // the `macros` module.
+        let quote = char('\'').with(::parser::nested(expr_in(state))).map(move |ast| {
+            let names = ::macros::QuoteNames {
+                list: intern("list"),
+                unquote: intern("unquote"),
+            };
+            ::macros::quasi(&ast, &names)
+        });
+        let unquote = char(',').with(::parser::nested(expr_in(state))).map(move |ast| {
+            Ast::Call(::std::rc::Rc::new(Ast::Variable(intern("unquote"))), vec![ast].into())
+        });
+        let keyword = char(':')
+            .with(take_while1(|c: char| c.is_alphabetic()))
+            .map(move |name| match state {
+                Some(state) => state.borrow_mut().intern(name),
+                None => hash_string(name),
+            })
+            .map(|name| Ast::Lit(::Value::Keyword(name)));
+        // Anything too long for an `Int` is a `BigInt`, up to
+        // `bigint::MAX_DIGITS`, which is always allowed in a literal.
+        let lit_num = take_while1(|c: char| c.is_ascii_digit()).and_then(|i: &str| {
+            ::int_literal(i).map(Ast::Lit).ok_or_else(|| {
+                StreamErrorFor::<I>::message_static_message("integer literal is too large")
+            })
+        });
+        // Paths have no escapes, so they can't contain a quote. Anything
+        // else starting with `include` is a call.
+        let include = try((
+            white!(string("include")),
+            white!(between(char('"'), char('"'), take_while(|c: char| c != '"'))),
+        )).map(|(_, path): (_, &str)| Ast::Include(path.into()));
+        // `(let-values (pattern expr) body...)` is a call to a lambda with
+        // `pattern` as its only parameter, which takes apart what `expr`
+        // returns as though it were a list.
+        let let_values = (
+            try(white!(string("let-values"))).map(move |_| {
+                if let Some(state) = state {
+                    state.borrow_mut().params.clear();
+                }
+            }),
+            white!(between(char('('), char(')'), (pattern_in(state), expr_in(state)))),
+            ::parser::list(')', &::parser::PARSED_ASTS, expr_in(state)),
+        ).map(|(_, (pattern, value), body)| {
+            let lambda = ::Lambda {
+                params: Box::new([pattern]),
+                defaults: Box::new([]),
+                body,
+            };
+            let lambda = Ast::Lit(::Value::Function(::std::rc::Rc::new(lambda)));
+            Ast::Call(::std::rc::Rc::new(lambda), vec![value].into())
+        });
+        let call = (expr_in(state), ::parser::list(')', &::parser::PARSED_ASTS, expr_in(state))).and_then(
+            |(func, args): (Ast<u64>, ::std::rc::Rc<[Ast<u64>]>)| {
+                let is_keyword = |arg: &Ast<u64>| matches!(*arg, Ast::Lit(::Value::Keyword(_)));
+                let named = &args[::first_keyword(&args).unwrap_or(args.len())..];
+                if named.len() % 2 == 0
+                    && named.chunks(2).all(|pair| is_keyword(&pair[0]) && !is_keyword(&pair[1]))
+                {
+                    Ok(Ast::Call(::std::rc::Rc::new(func), args))
+                } else {
+                    Err(StreamErrorFor::<I>::message_static_message(
+                        "keyword arguments must be `:name value` pairs after any positional arguments",
+                    ))
+                }
+            },
+        );
+        // `()` has no function to call, so rather than let it fall through
+        // every other alternative we reject it outright.
+        let empty = look_ahead(char(')')).with(error::unexpected_any("empty application is not allowed"));
+
+        white!(choice!(
+            hash,
+            lit_num,
+            lit_str,
+            keyword,
+            quote,
+            unquote,
+            variable,
+            ::parser::nested(between(
+                char('('),
+                char(')'),
+                choice!(empty, include, let_values, deferred_in(state), function, define, call)
+            ))
+        ))
+    }
+}
+
+parser! {
+    // The special forms that are calls to a native with code for it to run
+    // later, from after their opening parenthesis. These are parsed apart
+    // from the rest of `expr_in`, which takes far longer to compile with
+    // every alternative it adds.
+    fn deferred_in['a, 'b, I](state: Option<&'b RefCell<ParseState>>)(I) -> Ast<u64> where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::parser::char::*;
+        use combine::error::StreamError;
+        use combine::stream::StreamErrorFor;
+        use combine::*;
+
+        macro_rules! white {
+            ($prs:expr) => {
+                between(
+                    skip_many(satisfy(char::is_whitespace)),
+                    skip_many(satisfy(char::is_whitespace)),
+                    $prs,
+                )
+            };
+        }
+
+        let state = *state;
+        let intern = move |name| match state {
+            Some(state) => state.borrow_mut().intern(name),
+            None => hash_string(name),
+        };
+        // Anything else that starts with one of these names, such as
+        // `(delayed x)`, is a call.
+        let keyword =
+            |name| string(name).skip(not_followed_by(satisfy(|c: char| c.is_alphabetic())));
+
+        // `(delay expr)` is a call to the `delay` native with a lambda that
+        // has `expr` as its body, so `expr` is only evaluated when the thunk
+        // is forced.
+        let delay = (
+            try(white!(keyword("delay"))),
+            ::parser::list(')', &::parser::PARSED_ASTS, expr_in(state)),
+        ).and_then(move |(_, body): (_, Box<[Ast<u64>]>)| {
+            if body.len() != 1 {
+                return Err(StreamErrorFor::<I>::message_static_message(
+                    "`delay` takes exactly one expression",
+                ));
+            }
+            let lambda = ::parser::lambda_lit(Box::new([]), body);
+            Ok(Ast::Call(Rc::new(Ast::Variable(intern("delay"))), vec![lambda].into()))
+        });
+
+        // `(try expr (catch pattern handler...))` is a call to the `try`
+        // native with a lambda that has `expr` as its body, and one that
+        // takes what went wrong as `pattern` and runs `handler`.
+        let catch = (
+            try(white!(keyword("catch"))).map(move |_| {
+                if let Some(state) = state {
+                    state.borrow_mut().params.clear();
+                }
+            }),
+            white!(pattern_in(state)),
+            ::parser::list(')', &::parser::PARSED_ASTS, expr_in(state)),
+        );
+        let try_catch = (
+            try(white!(keyword("try"))),
+            expr_in(state),
+            white!(between(char('('), char(')'), catch)),
+        ).map(move |(_, body, (_, pattern, handler))| {
+            let body = ::parser::lambda_lit(Box::new([]), vec![body].into());
+            let handler = ::parser::lambda_lit(Box::new([pattern]), handler);
+            Ast::Call(Rc::new(Ast::Variable(intern("try"))), vec![body, handler].into())
+        });
+
+        choice!(delay, try_catch)
+    }
+}
+
+parser! {
+    fn param_in['a, 'b, I](state: Option<&'b RefCell<ParseState>>)(I) -> Param where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::parser::char::*;
+        use combine::parser::range::*;
+        use combine::error::StreamError;
+        use combine::stream::StreamErrorFor;
+        use combine::*;
+
+        macro_rules! white {
+            ($prs:expr) => {
+                between(
+                    skip_many(satisfy(char::is_whitespace)),
+                    skip_many(satisfy(char::is_whitespace)),
+                    $prs,
+                )
+            };
+        }
+
+        let state = *state;
+        let name = white!(take_while1(|c: char| c.is_alphabetic()))
+            .and_then(|name| {
+                ::parser::bindable(name).map_err(StreamErrorFor::<I>::message_static_message)
+            })
+            .map(move |name| match state {
+                Some(state) => state.borrow_mut().param(name),
+                None => hash_string(name),
+            });
+        // `(= name default)`, which is told apart from a list pattern by the
+        // `=` that no pattern can start with.
+        let optional = ::parser::nested((
+            try((char('('), white!(char('=')))),
+            name,
+            expr_in(state),
+            char(')'),
+        ));
+
+        choice!(
+            white!(optional).map(|(_, name, default, _)| Param::Optional(name, default)),
+            pattern_in(state).map(Param::Required)
+        )
+    }
+}
+
+parser! {
+    fn part_in['a, 'b, I](state: Option<&'b RefCell<ParseState>>)(I) -> Part where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::parser::char::*;
+        use combine::*;
+
+        // Braces are doubled to write them literally, as in Rust's
+        // `format!`, and anything else special is escaped with a backslash.
+        let text = many1(choice!(
+            try(string("{{")).map(|_| '{'),
+            try(string("}}")).map(|_| '}'),
+            char('\\').with(choice!(char('"'), char('\\'), char('n').map(|_| '\n'))),
+            satisfy(|c| c != '"' && c != '{' && c != '}' && c != '\\')
+        ));
+        let interpolated = ::parser::nested(between(char('{'), char('}'), expr_in(*state)));
+
+        choice!(text.map(Part::Text), interpolated.map(Part::Interpolated))
+    }
+}
+
+parser! {
+    fn pattern_in['a, 'b, I](state: Option<&'b RefCell<ParseState>>)(I) -> Pattern<u64> where [
+         I: combine::Stream<Item = char, Range = &'a str> +
+         combine::RangeStreamOnce
+    ] {
+        use combine::parser::char::*;
+        use combine::parser::range::*;
+        use combine::error::StreamError;
+        use combine::stream::StreamErrorFor;
+        use combine::*;
+
+        let state = *state;
+        let name = take_while1(|c: char| c.is_alphabetic())
+            .and_then(|name| {
+                ::parser::bindable(name).map_err(StreamErrorFor::<I>::message_static_message)
+            })
+            .map(move |name| match state {
+                Some(state) => state.borrow_mut().param(name),
+                None => hash_string(name),
+            });
+        let list = ::parser::nested(between(char('('), char(')'), ::parser::list(')', &::parser::PARSED_PATTERNS, pattern_in(state))));
+        // `,name` in a quoted lambda binds the symbol that `name` holds. It
+        // reads as the list pattern `(unquote name)`, which quoting turns
+        // into the variable.
+        let unquote = char(',').with(take_while1(|c: char| c.is_alphabetic())).map(move |name| {
+            let intern = |name| match state {
+                Some(state) => state.borrow_mut().intern(name),
+                None => hash_string(name),
+            };
+            let names = [Pattern::Name(intern("unquote")), Pattern::Name(intern(name))];
+            Pattern::List(Box::new(names))
+        });
+
+        between(
+            skip_many(satisfy(char::is_whitespace)),
+            skip_many(satisfy(char::is_whitespace)),
+            choice!(name.map(Pattern::Name), list.map(Pattern::List), unquote),
+        )
+    }
+}
@@ -0,0 +1,436 @@
+//! A fixed number of environments that threads take turns running programs
+//! in, for hosts handling requests concurrently.
+//!
+//! Values are `Rc`-based, so the environments are kept as `sync::Env`s and
+//! converted for each run, and programs are given as `sync::Program`s.
+//! Interpreters can't be sent between threads either, so each environment
+//! keeps the options of the one it was built from, and programs run under
+//! those with `eval_with`. An environment is checked out for as long as its
+//! `Checkout` lives, and is put back the way the pool's builder made it, so
+//! one request never sees what another defined.
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::ptr;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use sync::{Env, Program, Value};
+use {EvalError, EvalOptions, IntMap, Interpreter, TruthPolicy};
+
+/// What `InterpreterPool::checkout` does when every environment is checked
+/// out already.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exhausted {
+    /// Wait for one to be checked back in.
+    Wait,
+    /// Fail with `PoolError::Exhausted`.
+    Fail,
+}
+
+/// Why a pooled run failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolError {
+    /// Every environment was checked out, with `Exhausted::Fail`.
+    Exhausted,
+    Eval(EvalError<u64>),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Exhausted => write!(f, "Every interpreter in the pool is in use"),
+            PoolError::Eval(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for PoolError {}
+
+impl From<EvalError<u64>> for PoolError {
+    fn from(e: EvalError<u64>) -> Self {
+        PoolError::Eval(e)
+    }
+}
+
+// An environment, what it's put back to after each checkout, and what its
+// runs are evaluated under.
+struct Slot {
+    baseline: Env<u64>,
+    env: Env<u64>,
+    options: Options,
+}
+
+// The parts of `EvalOptions` that can be sent between threads. Each run
+// gets `EvalOptions` made from them, so it has all the fuel to itself.
+#[derive(Clone, Copy)]
+struct Options {
+    fuel: Option<u64>,
+    max_depth: Option<usize>,
+    max_heap_bytes: Option<usize>,
+    max_bindings: Option<usize>,
+    auto_promote: bool,
+    truth: TruthPolicy<u64>,
+}
+
+impl Options {
+    fn of(options: &EvalOptions<u64>) -> Self {
+        Options {
+            fuel: options.fuel,
+            max_depth: options.max_depth,
+            max_heap_bytes: options.max_heap_bytes,
+            max_bindings: options.max_bindings,
+            auto_promote: options.auto_promote,
+            truth: options.truth,
+        }
+    }
+
+    fn to_eval_options(self) -> EvalOptions<u64> {
+        EvalOptions {
+            fuel: self.fuel,
+            max_depth: self.max_depth,
+            max_heap_bytes: self.max_heap_bytes,
+            max_bindings: self.max_bindings,
+            auto_promote: self.auto_promote,
+            truth: self.truth,
+            ..EvalOptions::default()
+        }
+    }
+}
+
+/// Environments built from interpreters, which can be shared between
+/// threads behind an `Arc`.
+pub struct InterpreterPool {
+    slots: Mutex<Vec<Slot>>,
+    returned: Condvar,
+    size: usize,
+    exhausted: Exhausted,
+}
+
+impl InterpreterPool {
+    /// A pool of `size` environments, each what `reset` gives for an
+    /// interpreter that `builder` returns, such as `prelude::interpreter`,
+    /// so they start from its baseline. Programs run in one under the
+    /// options of its interpreter, so with its truth policy and no limits
+    /// until `limits` sets some. Checking out waits while they're all in
+    /// use; `exhausted` changes that.
+    pub fn new<F: Fn() -> Interpreter<u64>>(size: usize, builder: F) -> Self {
+        let slots = (0..size)
+            .map(|_| {
+                let interpreter = builder();
+                let mut env = IntMap::default();
+                interpreter.reset(&mut env);
+                let baseline = Env::from_local(&env);
+                Slot {
+                    env: baseline.clone(),
+                    baseline,
+                    options: Options::of(&interpreter.options()),
+                }
+            })
+            .collect();
+        InterpreterPool {
+            slots: Mutex::new(slots),
+            returned: Condvar::new(),
+            size,
+            exhausted: Exhausted::Wait,
+        }
+    }
+
+    /// Set what checking out does when every environment is in use.
+    pub fn exhausted(mut self, exhausted: Exhausted) -> Self {
+        self.exhausted = exhausted;
+        self
+    }
+
+    /// Run every program with the `fuel`, `max_depth`, `max_heap_bytes`,
+    /// `max_bindings` and `auto_promote` of `options`, each run getting all
+    /// of the fuel. Environments keep their interpreter's truth policy, and
+    /// the debugger, tracer and the like can't be shared, so they're left
+    /// out.
+    pub fn limits(self, options: &EvalOptions<u64>) -> Self {
+        for slot in self.lock().iter_mut() {
+            slot.options = Options {
+                truth: slot.options.truth,
+                ..Options::of(options)
+            };
+        }
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// How many environments aren't checked out.
+    pub fn available(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Take an environment until the `Checkout` is dropped.
+    pub fn checkout(&self) -> Result<Checkout<'_>, PoolError> {
+        let mut slots = self.lock();
+        loop {
+            if let Some(slot) = slots.pop() {
+                return Ok(Checkout {
+                    pool: self,
+                    slot: Some(slot),
+                });
+            }
+            match self.exhausted {
+                Exhausted::Wait => {
+                    slots = self.returned.wait(slots).unwrap_or_else(|e| e.into_inner())
+                }
+                Exhausted::Fail => return Err(PoolError::Exhausted),
+            }
+        }
+    }
+
+    /// Run `program` with `inputs` in an environment of its own, returning
+    /// the value of its last form.
+    pub fn run(
+        &self,
+        program: &Program,
+        inputs: &[(&str, Value<u64>)],
+    ) -> Result<Value<u64>, PoolError> {
+        self.checkout()?.run(program, inputs)
+    }
+
+    // Nothing is left half changed while the lock is held, so the slots are
+    // fine to use after a thread panics with it.
+    fn lock(&self) -> MutexGuard<'_, Vec<Slot>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An environment checked out of an `InterpreterPool`. Runs in it see what
+/// the runs before them defined, until it's dropped and put back.
+pub struct Checkout<'a> {
+    pool: &'a InterpreterPool,
+    slot: Option<Slot>,
+}
+
+impl<'a> Checkout<'a> {
+    /// Run `program` with each of `inputs` bound under its name, returning
+    /// the value of its last form. What it defines is kept in `env`, unless
+    /// it fails.
+    pub fn run(
+        &mut self,
+        program: &Program,
+        inputs: &[(&str, Value<u64>)],
+    ) -> Result<Value<u64>, PoolError> {
+        let slot = self.slot.as_mut().expect("only taken when dropped");
+        let program = program.to_local();
+        let inputs = inputs
+            .iter()
+            .map(|&(name, ref value)| (name, value.to_local()))
+            .collect::<Vec<_>>();
+
+        // The run gets the bindings borrowed, so that only the ones it
+        // rebinds, and those with boxes it could have changed, need
+        // converting back afterwards.
+        let before: IntMap<_> = slot.env.to_local();
+        let mut env: IntMap<_> = before
+            .iter()
+            .map(|(&name, value)| (name, Cow::Borrowed(&**value)))
+            .collect();
+        let out = program.run_with(&mut env, &inputs, &mut slot.options.to_eval_options())?;
+
+        for (name, value) in env {
+            let unchanged = match (&value, before.get(&name)) {
+                (&Cow::Borrowed(value), Some(old)) => ptr::eq(value, &**old) && !holds_box(value),
+                _ => false,
+            };
+            if !unchanged {
+                slot.env.insert(name, Value::from_local(&value));
+            }
+        }
+        Ok(Value::from_local(&out))
+    }
+
+    pub fn env(&self) -> &Env<u64> {
+        &self.slot.as_ref().expect("only taken when dropped").env
+    }
+}
+
+// Whether there's a box anywhere in `value`, whose contents a run could
+// have set without rebinding anything.
+fn holds_box(value: &::Value<u64>) -> bool {
+    let mut pending = vec![value];
+    while let Some(value) = pending.pop() {
+        match *value {
+            ::Value::Box(_) => return true,
+            ::Value::List(ref items) | ::Value::Values(ref items) => pending.extend(items.iter()),
+            ::Value::Partial(ref partial) => {
+                pending.push(partial.func());
+                pending.extend(partial.args());
+            }
+            ::Value::Thunk(ref thunk) => pending.push(thunk.func()),
+            _ => {}
+        }
+    }
+    false
+}
+
+impl<'a> Drop for Checkout<'a> {
+    fn drop(&mut self) {
+        if let Some(mut slot) = self.slot.take() {
+            slot.env = slot.baseline.clone();
+            self.pool.lock().push(slot);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Exhausted, InterpreterPool, PoolError};
+    use sync::{Program, Value};
+    use {hash_string, prelude, EvalError, EvalOptions, TruthPolicy};
+
+    fn program(src: &str) -> Program {
+        Program::from_local(&prelude::interpreter().prepare(src).unwrap())
+    }
+
+    #[test]
+    fn runs_dont_see_each_others_defines() {
+        let pool = Arc::new(InterpreterPool::new(2, prelude::interpreter));
+        let define = Arc::new(program("(= x n)"));
+        let read = Arc::new(program("x"));
+        // Both threads define `x` before either reads it back.
+        let barrier = Arc::new(Barrier::new(2));
+
+        let workers = (0..2u64)
+            .map(|n| {
+                let (pool, define, read) = (pool.clone(), define.clone(), read.clone());
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut checkout = pool.checkout().unwrap();
+                    checkout.run(&define, &[("n", Value::Int(n))]).unwrap();
+                    barrier.wait();
+                    match checkout.run(&read, &[]) {
+                        Ok(Value::Int(x)) => x,
+                        _ => panic!("x wasn't an int"),
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (n, worker) in workers.into_iter().enumerate() {
+            assert_eq!(worker.join().unwrap(), n as u64);
+        }
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn exhaustion_fails_or_waits() {
+        let add = program("(add 1 2)");
+
+        let failing = InterpreterPool::new(1, prelude::interpreter).exhausted(Exhausted::Fail);
+        let held = failing.checkout().unwrap();
+        assert!(matches!(failing.run(&add, &[]), Err(PoolError::Exhausted)));
+        drop(held);
+        assert!(matches!(failing.run(&add, &[]), Ok(Value::Int(3))));
+
+        let waiting = Arc::new(InterpreterPool::new(1, prelude::interpreter));
+        let held = waiting.checkout().unwrap();
+        let (done, finished) = mpsc::channel();
+        let worker = {
+            let waiting = waiting.clone();
+            thread::spawn(move || {
+                let out = waiting.run(&add, &[]);
+                done.send(matches!(out, Ok(Value::Int(3)))).unwrap();
+            })
+        };
+        assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(held);
+        assert_eq!(finished.recv(), Ok(true));
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn checking_in_clears_defines() {
+        let pool = InterpreterPool::new(1, prelude::interpreter);
+        let mut checkout = pool.checkout().unwrap();
+        checkout.run(&program("(= y 1)"), &[("z", Value::Int(2))]).unwrap();
+        assert!(checkout.env().get(&hash_string("y")).is_some());
+        assert!(checkout.env().get(&hash_string("z")).is_some());
+        drop(checkout);
+
+        let checkout = pool.checkout().unwrap();
+        assert!(checkout.env().get(&hash_string("y")).is_none());
+        assert!(checkout.env().get(&hash_string("z")).is_none());
+        assert!(checkout.env().get(&hash_string("add")).is_some());
+    }
+
+    #[test]
+    fn environments_start_from_the_baseline() {
+        let not = program("(not #f)");
+        let pool = InterpreterPool::new(1, prelude::interpreter);
+        assert!(matches!(pool.run(&not, &[]), Ok(Value::Void)));
+
+        let pool = InterpreterPool::new(1, || {
+            let mut interpreter = prelude::interpreter();
+            let mut env = prelude::env();
+            env.insert(hash_string("answer"), Cow::Owned(::Value::Int(42)));
+            interpreter.mark_baseline(&env);
+            interpreter
+        });
+        assert!(matches!(pool.run(&program("answer"), &[]), Ok(Value::Int(42))));
+    }
+
+    #[test]
+    fn runaway_programs_are_stopped() {
+        let options = EvalOptions {
+            fuel: Some(1000),
+            max_depth: Some(100),
+            ..EvalOptions::default()
+        };
+        let pool = InterpreterPool::new(1, prelude::interpreter).limits(&options);
+        let looping = program(r"(= loop (\() (loop))) (loop)");
+        assert!(matches!(pool.run(&looping, &[]), Err(PoolError::Eval(EvalError::OutOfFuel))));
+        let deep = program(r"(= deep (\(n) (add 1 (deep n)))) (deep 0)");
+        assert!(matches!(
+            pool.run(&deep, &[]),
+            Err(PoolError::Eval(EvalError::TooDeep { max: 100 }))
+        ));
+
+        // Every run has all the fuel to itself.
+        let mut checkout = pool.checkout().unwrap();
+        for _ in 0..10 {
+            assert!(matches!(checkout.run(&program("(add 1 2)"), &[]), Ok(Value::Int(3))));
+        }
+    }
+
+    #[test]
+    fn runs_keep_the_interpreters_truth_policy() {
+        let pool = InterpreterPool::new(1, || {
+            let mut interpreter = prelude::interpreter();
+            interpreter.set_truth(TruthPolicy::CLike);
+            interpreter
+        });
+        assert!(matches!(pool.run(&program("(if 0 1 2)"), &[]), Ok(Value::Int(2))));
+
+        let options = EvalOptions {
+            fuel: Some(1000),
+            ..EvalOptions::default()
+        };
+        let pool = pool.limits(&options);
+        assert!(matches!(pool.run(&program("(if 0 1 2)"), &[]), Ok(Value::Int(2))));
+    }
+
+    #[test]
+    fn changes_inside_boxes_are_kept() {
+        let pool = InterpreterPool::new(1, prelude::interpreter);
+        let mut checkout = pool.checkout().unwrap();
+        checkout.run(&program("(= a (box 1)) (= b (curry identity (box 1)))"), &[]).unwrap();
+        checkout.run(&program("(boxset a 2) (boxset (b) 3)"), &[]).unwrap();
+        let out = checkout.run(&program("(list (unbox a) (unbox (b)))"), &[]);
+        assert_eq!(out.unwrap().to_string(), "(list 2 3)");
+    }
+}
@@ -0,0 +1,1391 @@
+//! The native functions that programs are written against.
+//!
+//! These are used with the `InbuiltFunc` constructor and act as native
+//! functions, similar to how you'd add functions to the global namespace in
+//! Lua. Functions that are simpler to write in the language itself, such as
+//! `not` and `compose`, are kept in `stdlib.lisp` and defined on top of the
+//! natives by `env`. Without the `parse` feature there's no parser to read
+//! them with, so `env` only has the natives.
+
+use std::borrow::Cow;
+#[cfg(all(test, feature = "parse"))]
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::slice;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use convert::ValueType;
+use interpreter::NativeFn;
+use macros::from_data;
+use rng::Rng;
+#[cfg(feature = "std")]
+use output;
+#[cfg(feature = "parse")]
+use {parse_program, Ast};
+use {
+    hash_string, int_literal, BigInt, EvalError, Evaluator, IntMap, Interpreter, Partial,
+    SymbolTable, Thunk, Value,
+};
+
+#[cfg(feature = "parse")]
+const STDLIB: &str = include_str!("stdlib.lisp");
+
+#[cfg(feature = "parse")]
+thread_local! {
+    // Parsed the first time a thread asks for an environment, and shared by
+    // every one after that.
+    static STDLIB_PROGRAM: Rc<[Ast<u64>]> = {
+        #[cfg(test)]
+        STDLIB_PARSES.with(|parses| parses.set(parses.get() + 1));
+        parse_program(STDLIB).expect("the stdlib parses").into()
+    };
+}
+
+#[cfg(all(test, feature = "parse"))]
+thread_local!(static STDLIB_PARSES: Cell<usize> = const { Cell::new(0) });
+
+/// An interpreter with every function in the prelude registered under its
+/// usual name: `add`, `mul`, `eq`, `same`, `if`, `list`, `concat`,
+/// `values`, `divmod`, `assert`, `error`, `sort`, `sortby`, `eval`, `null`,
+/// `random`, `randomseed`, `byteslength`, `bytesref`, `bytesslice`,
+/// `bytesconcat`, `stringtobytes`, `bytestostring`, `numbertostring`,
+/// `stringtonumber`, `stringlength`, `stringconcat`, `substring`, `delay`,
+/// `force`, `box`, `unbox`, `boxset`, `copy`, `try`, `yield`, `typeof`, a
+/// predicate for each type that `typeof` gives, such as `isint` and
+/// `isfunction`, and `curry`, which is also called `partial`. With the
+/// `std` feature there's `print` as well.
+///
+/// The functions from `stdlib.lisp` are its baseline, so `reset` defines
+/// them on top of the natives, though `env` only has the natives.
+pub fn interpreter() -> Interpreter<u64> {
+    #[cfg_attr(not(feature = "parse"), allow(unused_mut))]
+    let mut interpreter = natives();
+    #[cfg(feature = "parse")]
+    {
+        let env = with_stdlib(&interpreter);
+        interpreter.mark_baseline(&env);
+    }
+    interpreter
+}
+
+// An interpreter with only the natives of the prelude registered.
+fn natives() -> Interpreter<u64> {
+    let mut interpreter = Interpreter::new();
+
+    interpreter
+        .register(hash_string("eq"), eq)
+        .register(hash_string("same"), same)
+        .register(hash_string("add"), add)
+        .register(hash_string("mul"), mul)
+        .register_reentrant(hash_string("if"), if_)
+        .register(hash_string("list"), list)
+        .register(hash_string("concat"), concat)
+        .register(hash_string("values"), values)
+        .register(hash_string("divmod"), divmod)
+        .register_reentrant(hash_string("assert"), assert)
+        .register(hash_string("error"), error)
+        .register(hash_string("sort"), sort)
+        .register(hash_string("curry"), curry)
+        .register(hash_string("partial"), curry)
+        .register(hash_string("null"), null)
+        .register(hash_string("byteslength"), bytes_length)
+        .register(hash_string("bytesref"), bytes_ref)
+        .register(hash_string("bytesslice"), bytes_slice)
+        .register(hash_string("bytesconcat"), bytes_concat)
+        .register(hash_string("stringtobytes"), string_to_bytes)
+        .register(hash_string("bytestostring"), bytes_to_string)
+        .register(hash_string("numbertostring"), number_to_string)
+        .register(hash_string("stringtonumber"), string_to_number)
+        .register(hash_string("stringlength"), string_length)
+        .register(hash_string("stringconcat"), string_concat)
+        .register(hash_string("substring"), substring)
+        .register(hash_string("delay"), delay)
+        .register(hash_string("box"), box_)
+        .register(hash_string("unbox"), unbox)
+        .register(hash_string("boxset"), box_set)
+        .register(hash_string("copy"), copy)
+        .register(hash_string("yield"), yield_)
+        .register_reentrant(hash_string("eval"), eval)
+        .register_reentrant(hash_string("sortby"), sort_by)
+        .register_reentrant(hash_string("force"), force)
+        .register_reentrant(hash_string("try"), try_)
+        .register(hash_string("typeof"), type_of);
+    for (&ty, &predicate) in ValueType::ALL.iter().zip(TYPE_PREDICATES.iter()) {
+        interpreter.register(hash_string(&predicate_name(ty)), predicate);
+    }
+    #[cfg(feature = "std")]
+    interpreter.register(hash_string("print"), output::print);
+
+    interpreter
+        .register_rng(hash_string("random"), random)
+        .register_rng(hash_string("randomseed"), random_seed);
+
+    interpreter
+}
+
+/// A global namespace containing the prelude, including the functions
+/// from `stdlib.lisp`: `not`, `identity`, `compose` and `flip`.
+#[cfg(feature = "parse")]
+pub fn env<'a>() -> IntMap<Cow<'a, Value<u64>>> {
+    with_stdlib(&natives())
+}
+
+// The natives that `interpreter` registers, with the stdlib defined on top.
+#[cfg(feature = "parse")]
+fn with_stdlib<'a>(interpreter: &Interpreter<u64>) -> IntMap<Cow<'a, Value<u64>>> {
+    let program = STDLIB_PROGRAM.with(Rc::clone);
+
+    let mut env: IntMap<_> = interpreter.env();
+    for form in program.iter() {
+        ::eval(form, &mut env).expect("the stdlib runs");
+    }
+    env.into_iter()
+        .map(|(name, value)| (name, Cow::Owned(value.into_owned())))
+        .collect()
+}
+
+/// A global namespace containing the prelude. Without the `parse` feature
+/// this is the same as `minimal_env`, since the stdlib can't be parsed.
+#[cfg(not(feature = "parse"))]
+pub fn env<'a>() -> IntMap<Cow<'a, Value<u64>>> {
+    minimal_env()
+}
+
+/// `env` with `now` and `elapsed` added, for timing code from inside a
+/// program. These aren't in `env`, since a program that reads the clock
+/// gives a different result every time it runs.
+#[cfg(feature = "time")]
+pub fn env_with_time<'a>() -> IntMap<Cow<'a, Value<u64>>> {
+    let mut env = env();
+    env.insert(hash_string("now"), Cow::Owned(Value::InbuiltFunc(now)));
+    env.insert(hash_string("elapsed"), Cow::Owned(Value::InbuiltFunc(elapsed)));
+    env
+}
+
+/// A global namespace containing only the natives of the prelude, for when
+/// the stdlib isn't wanted or isn't worth the time it takes to define.
+pub fn minimal_env<'a>() -> IntMap<Cow<'a, Value<u64>>> {
+    natives().env()
+}
+
+/// The names of every function in the prelude, and in the stdlib when there
+/// is one.
+pub fn symbols() -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    let names = [
+        "add", "mul", "eq", "same", "if", "list", "concat", "values", "divmod", "assert", "error",
+        "sort", "curry", "partial", "eval", "sortby", "null", "random", "randomseed",
+        "byteslength", "bytesref", "bytesslice", "bytesconcat", "stringtobytes", "bytestostring",
+        "numbertostring", "stringtonumber", "stringlength", "stringconcat", "substring", "delay",
+        "force", "box", "unbox", "boxset", "copy", "try", "yield", "typeof",
+    ];
+    for name in &names {
+        symbols.insert(name);
+    }
+    for &ty in ValueType::ALL.iter() {
+        symbols.insert(&predicate_name(ty));
+    }
+    #[cfg(feature = "std")]
+    symbols.insert("print");
+    #[cfg(feature = "parse")]
+    for name in &["not", "identity", "compose", "flip"] {
+        symbols.insert(name);
+    }
+    symbols
+}
+
+/// Sums the arguments. A sum too big for an `Int` is a `BigInt`, which
+/// `eval` only allows with `EvalOptions::auto_promote`.
+pub fn add<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut out = Integer::Small(0);
+
+    for v in variables {
+        match **v {
+            Value::Int(_) | Value::BigInt(_) => {
+                out = out.apply(v, u64::checked_add, |a, b| a + b)?;
+            }
+            _ => println!("Tried to add a non-int"),
+        }
+    }
+
+    Ok(out.into_value())
+}
+
+/// Multiplies the arguments, going on to a `BigInt` like `add` does.
+pub fn mul<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut out = Integer::Small(1);
+
+    for v in variables {
+        out = out.apply(v, u64::checked_mul, |a, b| a * b)?;
+    }
+
+    Ok(out.into_value())
+}
+
+// What `add` and `mul` have so far, which stays a `u64` for as long as it
+// fits in one.
+enum Integer {
+    Small(u64),
+    Big(BigInt),
+}
+
+impl Integer {
+    // `self` combined with `value` by `small`, or by `big` once either of
+    // them is too big for a `u64`.
+    fn apply<T>(
+        self,
+        value: &Value<T>,
+        small: fn(u64, u64) -> Option<u64>,
+        big: fn(&BigInt, &BigInt) -> BigInt,
+    ) -> Result<Integer, EvalError<T>> {
+        Ok(match (self, value) {
+            (Integer::Small(a), &Value::Int(b)) => match small(a, b) {
+                Some(out) => Integer::Small(out),
+                None => Integer::Big(big(&BigInt::from(a), &BigInt::from(b))),
+            },
+            (Integer::Small(a), Value::BigInt(b)) => Integer::Big(big(&BigInt::from(a), b)),
+            (Integer::Big(a), &Value::Int(b)) => Integer::Big(big(&a, &BigInt::from(b))),
+            (Integer::Big(a), Value::BigInt(b)) => Integer::Big(big(&a, b)),
+            _ => return Err(EvalError::NotAnInt),
+        })
+    }
+
+    // Back to an `Int` if it fits, which it can after multiplying by 0.
+    fn into_value<T>(self) -> Value<T> {
+        match self {
+            Integer::Small(i) => Value::Int(i),
+            Integer::Big(big) => match big.to_u64() {
+                Some(i) => Value::Int(i),
+                None => Value::BigInt(Rc::new(big)),
+            },
+        }
+    }
+}
+
+/// Checks the arguments for equality. `Void` represents true and `False`
+/// represents false. This is mostly inspired by scheme, where everything is
+/// true except for `#f`. Like scheme's `equal?`, functions that are written
+/// the same way are equal.
+pub fn eq<T: PartialEq>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut iter_vars = variables.iter();
+    if let Some(last) = iter_vars.next() {
+        for v in iter_vars {
+            if !v.equal(last) {
+                return Ok(Value::False);
+            }
+        }
+    }
+
+    Ok(Value::Void)
+}
+
+/// Scheme's `eq?`: the arguments must all be the same value, by
+/// `Value::same`, so two lists holding equal elements aren't the same list.
+pub fn same<T: PartialEq>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut iter_vars = variables.iter();
+    if let Some(last) = iter_vars.next() {
+        for v in iter_vars {
+            if !v.same(last) {
+                return Ok(Value::False);
+            }
+        }
+    }
+
+    Ok(Value::Void)
+}
+
+/// This version of `if` doesn't lazily evaluate its branches, unlike every
+/// other programming language in existence. To do lazy evaluation you make
+/// the `then` and `else` branches return functions and then call the
+/// functions. Without an `else` branch a false condition gives `Void`, as
+/// for anything else done only for its effect, so `(eq (if #f 1) nil)` is
+/// `#f`. Whether the condition is true is up to the caller's `TruthPolicy`.
+pub fn if_<T: Clone>(
+    evaluator: &mut dyn Evaluator<T>,
+    variables: &[&Value<T>],
+) -> Result<Value<T>, EvalError<T>> {
+    let truth = evaluator.truth();
+    match *variables {
+        [cond, then] => Ok(if truth.is_true(cond) { then.clone() } else { Value::Void }),
+        [cond, then, else_] => Ok(if truth.is_true(cond) { then } else { else_ }.clone()),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 3,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(null x)` is `Void` if `x` is `nil`, and `#f` for anything else,
+/// including `Void` and the empty list.
+pub fn null<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Nil] => Ok(Value::Void),
+        [_] => Ok(Value::False),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(typeof v)` is a symbol naming the type of `v`, such as `'int` or
+/// `'function`, to compare with `eq`. `ValueType` lists them all.
+pub fn type_of(variables: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [value] => Ok(Value::Symbol(hash_string(ValueType::of(value).name()))),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+// The predicate for each of `ValueType::ALL`, in the same order, which is
+// what `TYPE` indexes. Names are only letters, so `int?` is `isint`.
+const TYPE_PREDICATES: [NativeFn<u64>; ValueType::ALL.len()] = [
+    is_type::<u64, 0>,
+    is_type::<u64, 1>,
+    is_type::<u64, 2>,
+    is_type::<u64, 3>,
+    is_type::<u64, 4>,
+    is_type::<u64, 5>,
+    is_type::<u64, 6>,
+    is_type::<u64, 7>,
+    is_type::<u64, 8>,
+    is_type::<u64, 9>,
+    is_type::<u64, 10>,
+    is_type::<u64, 11>,
+    is_type::<u64, 12>,
+    is_type::<u64, 13>,
+    is_type::<u64, 14>,
+];
+
+fn predicate_name(ty: ValueType) -> String {
+    format!("is{}", ty.name())
+}
+
+fn is_type<T, const TYPE: usize>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [value] if ValueType::of(value) == ValueType::ALL[TYPE] => Ok(Value::Void),
+        [_] => Ok(Value::False),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// A list of the arguments, in order.
+pub fn list<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    Ok(Value::List(Rc::new(variables.iter().map(|&v| v.clone()).collect())))
+}
+
+/// All of the arguments at once, to be taken apart by `let-values`.
+pub fn values<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    Ok(Value::Values(Rc::new(variables.iter().map(|&v| v.clone()).collect())))
+}
+
+/// `(divmod a b)` is the quotient and the remainder of `a` divided by `b`,
+/// as two values.
+pub fn divmod<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [&Value::Int(_), &Value::Int(0)] => Err(EvalError::DivideByZero),
+        [&Value::Int(a), &Value::Int(b)] => {
+            Ok(Value::Values(Rc::new(vec![Value::Int(a / b), Value::Int(a % b)])))
+        }
+        [_, _] => Err(EvalError::NotAnInt),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(assert cond "message")` is `Void` if `cond` is true under the caller's
+/// `TruthPolicy`, and fails with `message` otherwise.
+pub fn assert<T>(
+    evaluator: &mut dyn Evaluator<T>,
+    variables: &[&Value<T>],
+) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [cond, _] if evaluator.truth().is_true(cond) => Ok(Value::Void),
+        [_, message] => Err(EvalError::AssertionFailed {
+            message: message_text(message),
+            span: None,
+        }),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(error "message" a b)` fails with `message`, handing `a` and `b` back
+/// to the host along with it.
+pub fn error<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [message, ref values @ ..] => Err(EvalError::User {
+            message: message_text(message),
+            values: Rc::new(values.iter().map(|&v| v.clone()).collect()),
+        }),
+        [] => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: usize::MAX,
+            got: 0,
+        }),
+    }
+}
+
+// Messages are meant to be strings, but anything else is shown as it
+// would be printed rather than being another error.
+fn message_text<T>(message: &Value<T>) -> Rc<String> {
+    match *message {
+        Value::Str(ref text) => text.clone(),
+        ref other => Rc::new(other.to_string()),
+    }
+}
+
+/// Joins the arguments into a string, putting strings in as they are and
+/// anything else as it's displayed. String literals with `{expr}` in them
+/// are turned into calls to this.
+pub fn concat<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut out = String::new();
+
+    for v in variables {
+        match **v {
+            Value::Str(ref text) => out.push_str(text),
+            ref v => {
+                let _ = write!(out, "{}", v);
+            }
+        }
+    }
+
+    Ok(Value::Str(Rc::new(out)))
+}
+
+// `(random n)` is an int picked at random from 0 up to but not including
+// `n`, every one as likely as the next. The numbers come from the
+// interpreter's generator, which starts from the same seed unless the host
+// chooses one, so a program gives the same numbers every time it runs
+// unless it seeds the generator itself with `randomseed`.
+fn random(rng: &Rng, variables: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [&Value::Int(0)] => Err(EvalError::EmptyRange),
+        [&Value::Int(n)] => Ok(Value::Int(rng.below(n))),
+        [Value::BigInt(_)] => Err(EvalError::IntegerOverflow),
+        [_] => Err(EvalError::NotAnInt),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+// `(randomseed s)` starts the interpreter's `random` numbers again from
+// `s`, until it's reset.
+fn random_seed(rng: &Rng, variables: &[&Value<u64>]) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [&Value::Int(seed)] => {
+            rng.seed(seed);
+            Ok(Value::Void)
+        }
+        [Value::BigInt(_)] => Err(EvalError::IntegerOverflow),
+        [_] => Err(EvalError::NotAnInt),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(byteslength b)` is how many bytes there are in `b`.
+pub fn bytes_length<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Bytes(bytes)] => Ok(Value::Int(bytes.len() as u64)),
+        [_] => Err(EvalError::NotBytes),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(bytesref b i)` is the byte at index `i` of `b`, counting from 0, as an
+/// int.
+pub fn bytes_ref<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Bytes(bytes), &Value::Int(i)] if i < bytes.len() as u64 => {
+            Ok(Value::Int(u64::from(bytes[i as usize])))
+        }
+        [Value::Bytes(bytes), &Value::Int(i)] => Err(EvalError::OutOfRange {
+            start: i,
+            end: i.saturating_add(1),
+            len: bytes.len(),
+        }),
+        [Value::Bytes(_), _] => Err(EvalError::NotAnInt),
+        [_, _] => Err(EvalError::NotBytes),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(bytesslice b start end)` is the bytes of `b` from index `start` up to
+/// but not including `end`.
+pub fn bytes_slice<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Bytes(bytes), &Value::Int(start), &Value::Int(end)] => {
+            if start <= end && end <= bytes.len() as u64 {
+                Ok(Value::Bytes(Rc::new(bytes[start as usize..end as usize].to_vec())))
+            } else {
+                Err(EvalError::OutOfRange {
+                    start,
+                    end,
+                    len: bytes.len(),
+                })
+            }
+        }
+        [Value::Bytes(_), _, _] => Err(EvalError::NotAnInt),
+        [_, _, _] => Err(EvalError::NotBytes),
+        _ => Err(EvalError::ArgumentCount {
+            min: 3,
+            max: 3,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// Joins the arguments, which must all be bytes, into one.
+pub fn bytes_concat<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut out = Vec::new();
+
+    for v in variables {
+        match **v {
+            Value::Bytes(ref bytes) => out.extend_from_slice(bytes),
+            _ => return Err(EvalError::NotBytes),
+        }
+    }
+
+    Ok(Value::Bytes(Rc::new(out)))
+}
+
+/// `(stringtobytes s)` is the UTF-8 bytes of the string `s`.
+pub fn string_to_bytes<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Str(text)] => Ok(Value::Bytes(Rc::new(text.as_bytes().to_vec()))),
+        [_] => Err(EvalError::NotAString),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(bytestostring b)` is the string that `b` is the UTF-8 bytes of, and
+/// an `InvalidUtf8` error if they aren't UTF-8.
+pub fn bytes_to_string<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Bytes(bytes)] => match ::std::str::from_utf8(bytes) {
+            Ok(text) => Ok(Value::Str(Rc::new(text.to_owned()))),
+            Err(e) => Err(EvalError::InvalidUtf8 { at: e.valid_up_to() }),
+        },
+        [_] => Err(EvalError::NotBytes),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(numbertostring n)` is the int `n` written in decimal, the way it's
+/// displayed.
+pub fn number_to_string<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [n @ Value::Int(_)] | [n @ Value::BigInt(_)] => Ok(Value::Str(Rc::new(n.to_string()))),
+        [_] => Err(EvalError::NotAnInt),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(stringtonumber s)` is the int that `s` is written as, which has to be
+/// an integer literal the parser would accept: only digits, since there are
+/// no negative numbers and so no signs. A number too big for an `Int` is a
+/// `BigInt`, which `eval` only allows with `EvalOptions::auto_promote`.
+/// Anything else is `nil`, rather than an error, so that a program can
+/// check what it's given.
+pub fn string_to_number<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Str(text)] => Ok(int_literal(text).unwrap_or(Value::Nil)),
+        [_] => Err(EvalError::NotAString),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(stringlength s)` is how many characters there are in `s`, which is
+/// fewer than its bytes if any are outside ASCII.
+pub fn string_length<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Str(text)] => Ok(Value::Int(text.chars().count() as u64)),
+        [_] => Err(EvalError::NotAString),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// Joins the arguments, which must all be strings, into one. Unlike
+/// `concat`, anything else is an error rather than displayed.
+pub fn string_concat<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    let mut out = String::new();
+
+    for v in variables {
+        match **v {
+            Value::Str(ref text) => out.push_str(text),
+            _ => return Err(EvalError::NotAString),
+        }
+    }
+
+    Ok(Value::Str(Rc::new(out)))
+}
+
+/// `(substring s start end)` is the characters of `s` from index `start` up
+/// to but not including `end`. These count characters, as `stringlength`
+/// does, rather than bytes, so they can never split a character in two.
+pub fn substring<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Str(text), &Value::Int(start), &Value::Int(end)] => {
+            let len = text.chars().count();
+            if start > end || end > len as u64 {
+                return Err(EvalError::CharsOutOfRange { start, end, len });
+            }
+            // The byte offset of the character at `i`, or of the end.
+            let offset = |i: u64| {
+                text.char_indices().nth(i as usize).map_or(text.len(), |(at, _)| at)
+            };
+            Ok(Value::Str(Rc::new(text[offset(start)..offset(end)].to_owned())))
+        }
+        [Value::Str(_), _, _] => Err(EvalError::NotAnInt),
+        [_, _, _] => Err(EvalError::NotAString),
+        _ => Err(EvalError::ArgumentCount {
+            min: 3,
+            max: 3,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(now)` is how many microseconds have passed on a clock that never goes
+/// backwards, counted from some point before the first call. Only the
+/// difference between two of these means anything.
+#[cfg(feature = "time")]
+pub fn now<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [] => Ok(Value::Int(clock::micros())),
+        _ => Err(EvalError::ArgumentCount {
+            min: 0,
+            max: 0,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(elapsed t)` is how many microseconds have passed since `(now)` gave
+/// `t`. A `t` from the future gives 0.
+#[cfg(feature = "time")]
+pub fn elapsed<T>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [&Value::Int(start)] => Ok(Value::Int(clock::micros().saturating_sub(start))),
+        [_] => Err(EvalError::NotAnInt),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+// `Instant` has no clock to read in the browser, so there it's JavaScript's
+// `performance.now()`, which is monotonic too and needs `wasm` to reach.
+#[cfg(all(feature = "time", target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("on wasm32 the `time` feature needs the `wasm` feature for its clock");
+
+#[cfg(all(feature = "time", not(target_arch = "wasm32")))]
+mod clock {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    pub fn micros() -> u64 {
+        START.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+}
+
+#[cfg(all(feature = "time", target_arch = "wasm32"))]
+mod clock {
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = performance, js_name = now)]
+        fn performance_now() -> f64;
+    }
+
+    pub fn micros() -> u64 {
+        (performance_now() * 1000.0) as u64
+    }
+}
+
+/// `(curry f a b)` is a function that calls `f` with `a` and `b` in front
+/// of whatever arguments it's called with.
+pub fn curry<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [func, ref args @ ..] => match *func {
+            Value::Function(_)
+            | Value::InbuiltFunc(_)
+            | Value::ReentrantFunc(_)
+            | Value::HostFunc(_)
+            | Value::Partial(_) => {
+                let args = args.iter().map(|&v| v.clone()).collect();
+                Ok(Value::Partial(Rc::new(Partial::new(func.clone(), args))))
+            }
+            _ => Err(EvalError::NotAFunction),
+        },
+        [] => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: usize::MAX,
+            got: 0,
+        }),
+    }
+}
+
+/// `(sort list)` is a list of the same elements from smallest to largest,
+/// with equal elements kept in the order they were in. Elements have to be
+/// all ints, all strings, or all lists that can be ordered in turn.
+pub fn sort<T: Clone + PartialEq>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::List(items)] => {
+            let sorted = merge_sort(items.iter().collect(), &mut |a: &&Value<T>, b: &&Value<T>| {
+                a.partial_cmp(b).ok_or(EvalError::Incomparable)
+            })?;
+            Ok(Value::List(Rc::new(sorted.into_iter().cloned().collect())))
+        }
+        [_] => Err(EvalError::NotAList),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(sortby f list)` sorts `list` like `sort` does, but by what `f` gives
+/// for each element rather than by the elements themselves. `f` is called
+/// once per element, in order.
+pub fn sort_by(
+    evaluator: &mut dyn Evaluator<u64>,
+    variables: &[&Value<u64>],
+) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [key, Value::List(items)] => {
+            let mut keyed = Vec::with_capacity(items.len());
+            for item in items.iter() {
+                keyed.push((evaluator.call(key, slice::from_ref(item))?, item));
+            }
+            let sorted = merge_sort(keyed, &mut |a: &(Value<u64>, _), b: &(Value<u64>, _)| {
+                a.0.partial_cmp(&b.0).ok_or(EvalError::Incomparable)
+            })?;
+            Ok(Value::List(Rc::new(sorted.into_iter().map(|(_, item)| item.clone()).collect())))
+        }
+        [_, _] => Err(EvalError::NotAList),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+// A stable sort that gives up on the first comparison that fails, which the
+// sorts in `std` can't do: they may panic if the order they're given isn't
+// total, and an incomparable pair would make it so.
+fn merge_sort<T, E>(
+    mut items: Vec<T>,
+    compare: &mut dyn FnMut(&T, &T) -> Result<Ordering, E>,
+) -> Result<Vec<T>, E> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(items, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut out = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Taking from the left on ties is what keeps the sort stable.
+        if compare(b, a)? == Ordering::Less {
+            out.extend(right.next());
+        } else {
+            out.extend(left.next());
+        }
+    }
+    out.extend(left);
+    out.extend(right);
+    Ok(out)
+}
+
+/// `(eval '(add 1 2))` evaluates quoted code in the caller's environment,
+/// so `(eval '(= x 1))` defines `x`. It counts against the same fuel and
+/// depth limits as the code around it.
+pub fn eval(
+    evaluator: &mut dyn Evaluator<u64>,
+    variables: &[&Value<u64>],
+) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [code] => evaluator.eval(&from_data(code)?),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(delay f)` is a thunk that calls `f` with no arguments the first time
+/// it's forced. The parser turns `(delay expr)` into this with `expr` as
+/// the body of `f`, so that `expr` isn't evaluated until then.
+pub fn delay<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [func] => match *func {
+            Value::Function(_)
+            | Value::InbuiltFunc(_)
+            | Value::ReentrantFunc(_)
+            | Value::HostFunc(_)
+            | Value::Partial(_) => Ok(Value::Thunk(Rc::new(Thunk::new(func.clone())))),
+            _ => Err(EvalError::NotAFunction),
+        },
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(force t)` is what the thunk `t` gives, working it out if this is the
+/// first time it's been forced and remembering it if so, so that its
+/// expression is evaluated at most once. That's counted against the fuel
+/// and depth limits of whatever first forces it. Forcing anything that
+/// isn't a thunk gives it back as it is.
+pub fn force(
+    evaluator: &mut dyn Evaluator<u64>,
+    variables: &[&Value<u64>],
+) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [Value::Thunk(thunk)] => match thunk.value() {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let value = evaluator.call(thunk.func(), &[])?;
+                Ok(thunk.set(value).clone())
+            }
+        },
+        [value] => Ok(value.clone()),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(try f handler)` calls `f` with no arguments, and if that fails, calls
+/// `handler` with what went wrong: a list of a symbol for the kind of error,
+/// as `EvalError::kind` names it, and the message. The parser turns
+/// `(try expr (catch e handler...))` into this, so `e` can be a pattern
+/// such as `(kind message)`. Errors for the limits that `eval_with` sets
+/// aren't caught, and nor is anything that goes wrong in `handler`.
+pub fn try_(
+    evaluator: &mut dyn Evaluator<u64>,
+    variables: &[&Value<u64>],
+) -> Result<Value<u64>, EvalError<u64>> {
+    match *variables {
+        [body, handler] => match evaluator.call(body, &[]) {
+            Err(error) if !error.is_limit() => {
+                let kind = Value::Symbol(hash_string(error.kind()));
+                let message = Value::Str(Rc::new(error.display_opaque().to_string()));
+                evaluator.call(handler, &[Value::List(Rc::new(vec![kind, message]))])
+            }
+            result => result,
+        },
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(yield v)` pauses the program, handing `v` to the host, when it's run
+/// by `eval_resumable`. Whatever the host resumes it with is what the call
+/// gives. Anywhere else it fails with `EvalError::Yielded`.
+pub fn yield_<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [value] => Err(EvalError::Yielded(value.clone())),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(box v)` is a new box holding `v`.
+pub fn box_<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [value] => Ok(Value::Box(Rc::new(RefCell::new(value.clone())))),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(unbox b)` is what the box `b` holds now.
+pub fn unbox<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Box(cell)] => Ok(cell.borrow().clone()),
+        [_] => Err(EvalError::NotABox),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(boxset b v)` makes the box `b` hold `v` instead, for everything that
+/// holds `b`.
+pub fn box_set<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [Value::Box(cell), value] => {
+            *cell.borrow_mut() = value.clone();
+            Ok(Value::Void)
+        }
+        [_, _] => Err(EvalError::NotABox),
+        _ => Err(EvalError::ArgumentCount {
+            min: 2,
+            max: 2,
+            got: variables.len(),
+        }),
+    }
+}
+
+/// `(copy v)` is `v` with every box in it copied, as `Value::deep_clone`
+/// describes, so that writing to a box in the copy doesn't change `v`.
+pub fn copy<T: Clone>(variables: &[&Value<T>]) -> Result<Value<T>, EvalError<T>> {
+    match *variables {
+        [value] => Ok(value.deep_clone()),
+        _ => Err(EvalError::ArgumentCount {
+            min: 1,
+            max: 1,
+            got: variables.len(),
+        }),
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use {
+        eval, eval_with, hash_string, parse_program, EvalError, EvalOptions, IntMap, Interpreter,
+        Value, ValueType,
+    };
+
+    use super::{env, interpreter, minimal_env, symbols, type_of, STDLIB_PARSES, TYPE_PREDICATES};
+
+    #[test]
+    fn stdlib_functions_work() {
+        let src = r"
+            (not #f)
+            (not 1)
+            (identity 5)
+            ((compose (curry add 1) (curry add 2)) 3)
+            ((flip list) 1 2)
+            ";
+        let mut env = env();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).ok().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results, ["void", "#f", "5", "6", "(list 2 1)"]);
+    }
+
+    #[test]
+    fn sorts_lists() {
+        let src = r#"
+            (sort (list 3 1 2 1))
+            (sort (list "b" "ab" "a" ""))
+            (sort (list (list 2 1) (list 1 3) (list 1)))
+            (sortby (\((k v)) k) (list (list 2 "a") (list 1 "b") (list 2 "c") (list 1 "d")))
+            (sort (list))
+            (sort (list 1 "a"))
+            (sortby (\(x) x) (list (list 1) (list "a")))
+            (sort 1)
+            "#;
+        let mut env = env();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(results[0], Ok("(list 1 1 2 3)".to_owned()));
+        assert_eq!(results[1], Ok(r#"(list "" "a" "ab" "b")"#.to_owned()));
+        assert_eq!(results[2], Ok("(list (list 1) (list 1 3) (list 2 1))".to_owned()));
+        // Elements with the same key stay in the order they were in.
+        assert_eq!(
+            results[3],
+            Ok(r#"(list (list 1 "b") (list 1 "d") (list 2 "a") (list 2 "c"))"#.to_owned())
+        );
+        assert_eq!(results[4], Ok("(list)".to_owned()));
+        assert_eq!(results[5], Err(EvalError::Incomparable));
+        assert_eq!(results[6], Err(EvalError::Incomparable));
+        assert_eq!(results[7], Err(EvalError::NotAList));
+    }
+
+    #[test]
+    fn works_on_bytes() {
+        let src = r#"
+            (byteslength #b"abc\x00")
+            (bytesref #b"\xff\x10" 1)
+            (bytesslice #b"hello" 1 3)
+            (bytesslice #b"hello" 0 5)
+            (bytesconcat #b"ab" #b"" #b"\n")
+            (stringtobytes "µ")
+            (bytestostring #b"\xc2\xb5!")
+            (bytesslice #b"hello" 3 6)
+            (bytesslice #b"hello" 3 2)
+            (bytesref #b"" 0)
+            (bytestostring #b"ok\xc2")
+            (bytesconcat #b"a" "b")
+            (stringtobytes #b"a")
+            "#;
+        let mut env = env();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(results[0], Ok("4".to_owned()));
+        assert_eq!(results[1], Ok("16".to_owned()));
+        assert_eq!(results[2], Ok(r#"#b"\x65\x6c""#.to_owned()));
+        assert_eq!(results[3], Ok(r#"#b"\x68\x65\x6c\x6c\x6f""#.to_owned()));
+        assert_eq!(results[4], Ok(r#"#b"\x61\x62\x0a""#.to_owned()));
+        assert_eq!(results[5], Ok(r#"#b"\xc2\xb5""#.to_owned()));
+        assert_eq!(results[6], Ok(r#""µ!""#.to_owned()));
+        assert_eq!(results[7], Err(EvalError::OutOfRange { start: 3, end: 6, len: 5 }));
+        assert_eq!(results[8], Err(EvalError::OutOfRange { start: 3, end: 2, len: 5 }));
+        assert_eq!(results[9], Err(EvalError::OutOfRange { start: 0, end: 1, len: 0 }));
+        assert_eq!(results[10], Err(EvalError::InvalidUtf8 { at: 2 }));
+        assert_eq!(results[11], Err(EvalError::NotBytes));
+        assert_eq!(results[12], Err(EvalError::NotAString));
+    }
+
+    #[test]
+    fn random_numbers_follow_the_seed() {
+        // Runs `src` in a new environment, with `interpreter`'s generator
+        // carrying on from where it was.
+        let run_in = |interpreter: &Interpreter<u64>, src: &str| {
+            let mut env: IntMap<_> = interpreter.env();
+            let program = parse_program(src).unwrap();
+            let results = program.iter().map(|form| eval(form, &mut env).map(|v| v.into_owned()));
+            results.collect::<Vec<_>>()
+        };
+        let run = |src: &str| run_in(&interpreter(), src);
+        let draws = "(list (random 1000000) (random 1000000) (random 1000000) (random 1000000))";
+
+        let first = run(&format!("(randomseed 7) {}", draws));
+        assert_eq!(first, run(&format!("(randomseed 7) {}", draws)));
+        assert_ne!(first, run(&format!("(randomseed 8) {}", draws)));
+
+        // The host can choose the seed, and resetting goes back to it.
+        let mut seeded = interpreter();
+        seeded.with_rng_seed(7);
+        assert_eq!(first[1], run_in(&seeded, draws)[0]);
+        assert_ne!(first[1], run_in(&seeded, draws)[0]);
+        run_in(&seeded, "(randomseed 8)");
+        seeded.reset(&mut IntMap::default());
+        assert_eq!(first[1], run_in(&seeded, draws)[0]);
+
+        // Even with another interpreter's environment as the baseline.
+        seeded.mark_baseline(&env());
+        let mut env = IntMap::default();
+        seeded.reset(&mut env);
+        let program = parse_program(draws).unwrap();
+        assert_eq!(first[1], eval(&program[0], &mut env).map(|v| v.into_owned()));
+
+        // Seeding one interpreter's generator leaves another's alone.
+        let (a, b) = (interpreter(), interpreter());
+        run_in(&b, "(randomseed 7)");
+        assert_eq!(run(draws), run_in(&a, draws));
+
+        // Clones included, and whether it's seeded by a program or reset.
+        let original = seeded.clone();
+        let copy = original.clone();
+        let expected = run_in(&original.clone(), draws);
+        run_in(&copy, "(randomseed 8)");
+        assert_eq!(expected, run_in(&original, draws));
+        run_in(&original, "(randomseed 7)");
+        run_in(&copy, draws);
+        copy.reset(&mut IntMap::default());
+        assert_eq!(first[1], run_in(&original, draws)[0]);
+
+        // Every number in range turns up, and none out of it.
+        let rng = interpreter().rng().clone();
+        let mut seen = [0; 10];
+        for _ in 0..1000 {
+            seen[rng.below(10) as usize] += 1;
+        }
+        assert!(seen.iter().all(|&count| count > 50), "{:?}", seen);
+        assert_eq!(rng.below(1), 0);
+
+        let errors = run("(random 0) (random #f) (random) (random 18446744073709551616) \
+                          (randomseed)");
+        assert_eq!(errors[0], Err(EvalError::EmptyRange));
+        assert_eq!(errors[1], Err(EvalError::NotAnInt));
+        assert_eq!(errors[2], Err(EvalError::ArgumentCount { min: 1, max: 1, got: 0 }));
+        assert_eq!(errors[3], Err(EvalError::IntegerOverflow));
+        assert_eq!(errors[4], Err(EvalError::ArgumentCount { min: 1, max: 1, got: 0 }));
+    }
+
+    #[test]
+    fn only_env_with_time_reads_the_clock() {
+        for env in &[env(), minimal_env()] {
+            assert!(!env.contains_key(&hash_string("now")));
+            assert!(!env.contains_key(&hash_string("elapsed")));
+        }
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn times_code() {
+        use super::env_with_time;
+
+        let src = "(= t (now)) (elapsed t) (elapsed (add t 1000000000)) (now 1) (elapsed #f)";
+        let mut env = env_with_time();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.into_owned()))
+            .collect::<Vec<_>>();
+
+        match results[1] {
+            Ok(Value::Int(micros)) => assert!(micros < 1_000_000, "took {}us", micros),
+            ref other => panic!("{:?}", other),
+        }
+        assert_eq!(results[2], Ok(Value::Int(0)));
+        assert_eq!(results[3], Err(EvalError::ArgumentCount { min: 0, max: 0, got: 1 }));
+        assert_eq!(results[4], Err(EvalError::NotAnInt));
+
+        // Keeping only the names of the usual natives leaves the clock out.
+        let symbols = symbols();
+        let mut env = env_with_time();
+        env.retain(|&id, _| symbols.name(id).is_some());
+        let now = hash_string("now");
+        let program = parse_program("(now)").unwrap();
+        assert_eq!(eval(&program[0], &mut env).err(), Some(EvalError::UnboundVariable(now)));
+    }
+
+    #[test]
+    fn the_minimal_env_has_no_stdlib() {
+        let minimal = minimal_env();
+        assert!(!minimal.contains_key(&hash_string("not")));
+        let natives = interpreter();
+        let registered = natives.natives().len() + natives.reentrant().len() + natives.hosts().len();
+        assert_eq!(minimal.len(), registered);
+        assert!(matches!(env()[&hash_string("not")].as_ref(), Value::Function(_)));
+    }
+
+    #[test]
+    fn stdlib_is_parsed_once() {
+        env();
+        env();
+        assert_eq!(STDLIB_PARSES.with(|parses| parses.get()), 1);
+    }
+
+    #[test]
+    fn everything_in_the_prelude_has_a_name() {
+        let env = env();
+        let symbols = symbols();
+
+        assert_eq!(symbols.len(), env.len());
+        for (id, name) in symbols.iter() {
+            assert!(env.contains_key(&id), "{}", name);
+        }
+    }
+
+    #[test]
+    fn converts_between_strings_and_numbers() {
+        let run = |src: &str| {
+            let program = parse_program(src).unwrap();
+            let mut options = EvalOptions {
+                auto_promote: true,
+                ..EvalOptions::default()
+            };
+            eval_with(&program[0], &mut env(), &mut options).map(|value| value.into_owned())
+        };
+
+        // Round trips, as far as `BigInt` and past where an `Int` ends.
+        let big = "9".repeat(::bigint::MAX_DIGITS);
+        for n in &["0", "7", "18446744073709551615", "18446744073709551616", &big] {
+            let src = format!("(stringtonumber (numbertostring {}))", n);
+            assert_eq!(run(&src), run(n), "{}", n);
+            let src = format!("(numbertostring (stringtonumber \"{}\"))", n);
+            assert_eq!(run(&src), Ok(Value::Str(Rc::new(n.to_string()))));
+        }
+
+        // Anything the parser wouldn't read as an int is `nil`.
+        let too_big = format!("\"{}\"", "9".repeat(::bigint::MAX_DIGITS + 1));
+        for s in &["\"\"", "\"-1\"", "\"+1\"", "\" 1\"", "\"1a\"", "\"١\"", &too_big] {
+            assert_eq!(run(&format!("(stringtonumber {})", s)), Ok(Value::Nil), "{}", s);
+        }
+
+        assert_eq!(run("(numbertostring \"1\")"), Err(EvalError::NotAnInt));
+        assert_eq!(run("(stringtonumber 1)"), Err(EvalError::NotAString));
+    }
+
+    #[test]
+    fn works_on_strings() {
+        let src = r#"
+            (stringlength "")
+            (stringlength "naïve µs")
+            (stringconcat "a" "" "µ" "{(add 1 1)}")
+            (stringconcat)
+            (substring "naïve µs" 2 7)
+            (substring "naïve µs" 0 8)
+            (substring "naïve µs" 8 8)
+            (substring "naïve µs" 3 9)
+            (substring "naïve µs" 3 2)
+            (stringconcat "a" 1)
+            (substring "abc" "a" 1)
+            (stringlength #b"abc")
+            "#;
+        let mut env = env();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).map(|value| value.to_string()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(results[0], Ok("0".to_owned()));
+        assert_eq!(results[1], Ok("8".to_owned()));
+        assert_eq!(results[2], Ok(r#""aµ2""#.to_owned()));
+        assert_eq!(results[3], Ok(r#""""#.to_owned()));
+        assert_eq!(results[4], Ok(r#""ïve µ""#.to_owned()));
+        assert_eq!(results[5], Ok(r#""naïve µs""#.to_owned()));
+        assert_eq!(results[6], Ok(r#""""#.to_owned()));
+        let error = EvalError::CharsOutOfRange { start: 3, end: 9, len: 8 };
+        assert_eq!(results[7], Err(error));
+        let error = EvalError::CharsOutOfRange { start: 3, end: 2, len: 8 };
+        assert_eq!(results[8], Err(error));
+        assert_eq!(results[9], Err(EvalError::NotAString));
+        assert_eq!(results[10], Err(EvalError::NotAnInt));
+        assert_eq!(results[11], Err(EvalError::NotAString));
+    }
+
+    #[test]
+    fn every_value_has_a_type() {
+        let mut natives = interpreter();
+        natives.register_fn(hash_string("double"), |x: u64| x * 2);
+        let mut env: IntMap<_> = natives.env();
+        let src = r#"
+            (list (eq 1 1) #f nil 1 123456789012345678901234567890 (\(x) x) (curry add 1)
+                  add eval double (list) (= k :key) 'sym "s" #b"b" (delay 1) (box 1))
+            "#;
+        let program = parse_program(src).unwrap();
+        let mut values = match eval(&program[0], &mut env).unwrap().into_owned() {
+            Value::List(items) => (*items).clone(),
+            other => panic!("expected a list, got {}", other),
+        };
+        values.push(Value::Values(Rc::new(vec![Value::Int(1), Value::Int(2)])));
+        values.push(Value::foreign_named("handle", 7));
+
+        let expected = [
+            "void", "false", "nil", "int", "int", "function", "function", "builtin", "builtin",
+            "builtin", "list", "keyword", "symbol", "string", "bytes", "thunk", "box", "values",
+            "foreign",
+        ];
+        assert_eq!(values.len(), expected.len());
+        for (value, &name) in values.iter().zip(expected.iter()) {
+            let ty = type_of(&[value]).unwrap();
+            assert!(ty == Value::Symbol(hash_string(name)), "{} is {}", value, ty);
+
+            // Exactly one predicate holds, and it's the one for the type.
+            for (&other, predicate) in ValueType::ALL.iter().zip(TYPE_PREDICATES.iter()) {
+                let holds = predicate(&[value]).unwrap().is_truthy();
+                assert_eq!(holds, other.name() == name, "is{} of {}", other.name(), value);
+            }
+        }
+
+        // Every type is given to some value.
+        for ty in ValueType::ALL.iter() {
+            assert!(expected.contains(&ty.name()), "{:?}", ty);
+        }
+        assert!(type_of(&[]).is_err());
+        assert!(TYPE_PREDICATES[0](&[&Value::Void, &Value::Void]).is_err());
+    }
+
+    #[test]
+    fn types_of_what_closures_return() {
+        let src = r#"
+            (= id (\(x) x))
+            (= make (\(n) (\(x) (add x n))))
+            (list (isint (id 1)) (isfunction (make 1)) (isvoid (id (eq 1 1)))
+                  (isint (id #f)) (isfalse (id #f)) (eq (typeof (id "s")) 'string)
+                  (eq (typeof (make 1)) 'function) (eq (typeof (id (curry add 1))) 'int))
+            "#;
+        let mut env = env();
+        let results = parse_program(src)
+            .unwrap()
+            .iter()
+            .map(|form| eval(form, &mut env).unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results[2], "(list void void void #f void void void #f)");
+    }
+}
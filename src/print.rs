@@ -0,0 +1,599 @@
+//! Printing programs back out as source text.
+
+use std::error;
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+#[cfg(feature = "parse")]
+use {parse_program_with_symbols, ParseError, ParseOptions};
+use {Ast, Lambda, Pattern, Quoted, QuotedBytes, SymbolTable, Value};
+
+/// Why a program couldn't be printed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrintError {
+    /// A literal that there's no syntax for, such as `Void`, a list or a
+    /// native function, or an include of a path with a quote in it.
+    NoSyntax,
+    /// An identifier whose name isn't in the symbol table.
+    UnknownIdentifier(u64),
+}
+
+impl fmt::Display for PrintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PrintError::NoSyntax => write!(f, "Program contains a value with no syntax"),
+            PrintError::UnknownIdentifier(id) => write!(f, "No name for identifier {:x}", id),
+        }
+    }
+}
+
+impl error::Error for PrintError {}
+
+enum Item<'a> {
+    Ast(&'a Ast<u64>),
+    Text(&'a str),
+    Owned(String),
+}
+
+/// Print `ast` on a single line, in a form that parses back to the same
+/// program.
+pub fn to_source(ast: &Ast<u64>, symbols: &SymbolTable) -> Result<String, PrintError> {
+    let name = |id: u64| symbols.name(id).ok_or(PrintError::UnknownIdentifier(id));
+
+    let mut out = String::new();
+    // Uses a worklist rather than recursion, since programs can be deep.
+    let mut pending = vec![Item::Ast(ast)];
+
+    while let Some(item) = pending.pop() {
+        let ast = match item {
+            Item::Text(text) => {
+                out.push_str(text);
+                continue;
+            }
+            Item::Owned(text) => {
+                out.push_str(&text);
+                continue;
+            }
+            Item::Ast(ast) => ast,
+        };
+
+        match *ast {
+            Ast::Lit(Value::Void)
+            | Ast::Lit(Value::InbuiltFunc(_))
+            | Ast::Lit(Value::ReentrantFunc(_))
+            | Ast::Lit(Value::HostFunc(_))
+            | Ast::Lit(Value::List(_))
+            | Ast::Lit(Value::Values(_))
+            | Ast::Lit(Value::Partial(_))
+            | Ast::Lit(Value::Thunk(_))
+            | Ast::Lit(Value::Box(_))
+            | Ast::Lit(Value::Foreign(_)) => return Err(PrintError::NoSyntax),
+            Ast::Lit(Value::False) => out.push_str("#f"),
+            Ast::Lit(Value::Nil) => out.push_str("nil"),
+            Ast::Lit(Value::Int(i)) => out.push_str(&i.to_string()),
+            Ast::Lit(Value::BigInt(ref big)) => out.push_str(&big.to_string()),
+            Ast::Lit(Value::Str(ref text)) => out.push_str(&Quoted(text).to_string()),
+            Ast::Lit(Value::Bytes(ref bytes)) => out.push_str(&QuotedBytes(bytes).to_string()),
+            Ast::Lit(Value::Keyword(id)) => {
+                out.push(':');
+                out.push_str(name(id)?);
+            }
+            Ast::Lit(Value::Symbol(id)) => {
+                out.push('\'');
+                out.push_str(name(id)?);
+            }
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let required = lambda.required();
+                out.push_str("(\\(");
+                out.push_str(&params_source(&lambda.params[..required], &name)?);
+
+                pending.push(Item::Text(")"));
+                for stmt in lambda.body.iter().rev() {
+                    pending.push(Item::Ast(stmt));
+                    pending.push(Item::Text(" "));
+                }
+                pending.push(Item::Text(")"));
+
+                let optional = lambda.params[required..].iter().zip(lambda.defaults.iter());
+                for (i, (param, default)) in optional.enumerate().rev() {
+                    // Only plain names can have a default in source.
+                    let id = match *param {
+                        Pattern::Name(id) => id,
+                        Pattern::List(_) => return Err(PrintError::NoSyntax),
+                    };
+                    pending.push(Item::Text(")"));
+                    pending.push(Item::Ast(default));
+                    pending.push(Item::Text(" "));
+                    pending.push(Item::Text(name(id)?));
+                    pending.push(Item::Text(if required + i > 0 { " (= " } else { "(= " }));
+                }
+            }
+            Ast::Variable(id) => out.push_str(name(id)?),
+            Ast::Call(ref func, ref args) => {
+                if let Some(body) = delayed(func, args, symbols) {
+                    out.push_str("(delay ");
+                    pending.push(Item::Text(")"));
+                    pending.push(Item::Ast(body));
+                    continue;
+                }
+                if let Some((body, handler)) = caught(func, args, symbols) {
+                    out.push_str("(try ");
+                    pending.push(Item::Text("))"));
+                    for stmt in handler.body.iter().rev() {
+                        pending.push(Item::Ast(stmt));
+                        pending.push(Item::Text(" "));
+                    }
+                    let pattern = params_source(&handler.params, &name)?;
+                    pending.push(Item::Owned(format!(" (catch {}", pattern)));
+                    pending.push(Item::Ast(body));
+                    continue;
+                }
+                out.push('(');
+                pending.push(Item::Text(")"));
+                for arg in args.iter().rev() {
+                    pending.push(Item::Ast(arg));
+                    pending.push(Item::Text(" "));
+                }
+                pending.push(Item::Ast(func));
+            }
+            Ast::Define(id, ref value) => {
+                out.push_str("(= ");
+                out.push_str(name(id)?);
+                out.push(' ');
+                pending.push(Item::Text(")"));
+                pending.push(Item::Ast(value));
+            }
+            // Paths can't contain quotes, since there's no way to escape one.
+            Ast::Include(ref path) if path.contains('"') => return Err(PrintError::NoSyntax),
+            Ast::Include(ref path) => {
+                out.push_str("(include \"");
+                out.push_str(path);
+                out.push_str("\")");
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+// What's delayed, if `func` and `args` are the call that the parser turns
+// `(delay expr)` into. Printing that call as it is would give a lambda
+// inside `(delay ...)`, which parses to something else.
+fn delayed<'a>(
+    func: &Ast<u64>,
+    args: &'a [Ast<u64>],
+    symbols: &SymbolTable,
+) -> Option<&'a Ast<u64>> {
+    match (func, args) {
+        (&Ast::Variable(id), [Ast::Lit(Value::Function(lambda))])
+            if symbols.name(id) == Some("delay")
+                && lambda.params.is_empty()
+                && lambda.defaults.is_empty()
+                && lambda.body.len() == 1 =>
+        {
+            Some(&lambda.body[0])
+        }
+        _ => None,
+    }
+}
+
+// What's tried and the lambda that catches what goes wrong, with the one
+// pattern it takes, if `func` and `args` are the call that the parser turns
+// `(try expr (catch pattern handler...))` into.
+fn caught<'a>(
+    func: &Ast<u64>,
+    args: &'a [Ast<u64>],
+    symbols: &SymbolTable,
+) -> Option<(&'a Ast<u64>, &'a Lambda<u64>)> {
+    match (func, args) {
+        (&Ast::Variable(id), [Ast::Lit(Value::Function(body)), Ast::Lit(Value::Function(handler))])
+            if symbols.name(id) == Some("try")
+                && body.params.is_empty()
+                && body.defaults.is_empty()
+                && body.body.len() == 1
+                && handler.params.len() == 1
+                && handler.defaults.is_empty() =>
+        {
+            Some((&body.body[0], handler))
+        }
+        _ => None,
+    }
+}
+
+// The parameters of a function as they're written between its parentheses.
+// Recurses once per level of nesting of list patterns, which the parser
+// limits.
+fn params_source<'a, E>(
+    params: &[Pattern<u64>],
+    name: &dyn Fn(u64) -> Result<&'a str, E>,
+) -> Result<String, E> {
+    let mut out = String::new();
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        match *param {
+            Pattern::Name(id) => out.push_str(name(id)?),
+            Pattern::List(ref items) => {
+                out.push('(');
+                out.push_str(&params_source(items, name)?);
+                out.push(')');
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// How `format_source` lays programs out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatOptions {
+    /// Lines longer than this are broken up where possible. Long names and
+    /// closing parentheses can still go past it.
+    pub max_width: usize,
+    /// How many spaces each level of indentation adds.
+    pub indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            max_width: 80,
+            indent: 2,
+        }
+    }
+}
+
+/// Reformat `src` in a canonical layout, with top-level forms separated by
+/// blank lines.
+///
+/// Anything that fits on the rest of its line is printed as `to_source`
+/// would. Otherwise function bodies and the arguments of calls go on
+/// lines of their own, one level further in, except that arguments which
+/// are all variables or literals are filled in as many to a line as fit.
+/// The output is formatted the same way again and parses to the same
+/// program. The language has no comments, so whitespace is all that's lost.
+#[cfg(feature = "parse")]
+pub fn format_source(src: &str, opts: FormatOptions) -> Result<String, ParseError> {
+    let (program, symbols) =
+        parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {})?;
+
+    let mut formatter = Formatter {
+        symbols: &symbols,
+        opts: &opts,
+        out: String::new(),
+        column: 0,
+    };
+    for (i, form) in program.iter().enumerate() {
+        if i > 0 {
+            formatter.out.push_str("\n\n");
+            formatter.column = 0;
+        }
+        formatter.form(form, 0);
+    }
+    if !program.is_empty() {
+        formatter.out.push('\n');
+    }
+
+    Ok(formatter.out)
+}
+
+#[cfg(feature = "parse")]
+struct Formatter<'a> {
+    symbols: &'a SymbolTable,
+    opts: &'a FormatOptions,
+    out: String,
+    column: usize,
+}
+
+#[cfg(feature = "parse")]
+impl<'a> Formatter<'a> {
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text);
+        self.column += text.chars().count();
+    }
+
+    fn newline(&mut self, indent: usize) {
+        self.out.push('\n');
+        self.out.extend(::std::iter::repeat_n(' ', indent));
+        self.column = indent;
+    }
+
+    fn name(&self, id: u64) -> &'a str {
+        self.symbols.name(id).expect("parsed programs have every name")
+    }
+
+    fn flat(&self, ast: &Ast<u64>) -> String {
+        to_source(ast, self.symbols).expect("parsed programs can always be printed")
+    }
+
+    fn fits(&self, text: &str) -> bool {
+        self.column + text.chars().count() <= self.opts.max_width
+    }
+
+    // Recurses once per level of nesting, which the parser limits.
+    fn form(&mut self, ast: &Ast<u64>, indent: usize) {
+        let flat = self.flat(ast);
+        if self.fits(&flat) {
+            return self.push(&flat);
+        }
+
+        let inner = indent + self.opts.indent;
+        match *ast {
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let required = lambda.required();
+                let lookup = |id| Ok::<_, ()>(self.name(id));
+                let params = params_source(&lambda.params[..required], &lookup)
+                    .expect("names can always be found");
+                self.push(&format!("(\\({}", params));
+                let optional = lambda.params[required..].iter().zip(lambda.defaults.iter());
+                for (i, (param, default)) in optional.enumerate() {
+                    let name = match *param {
+                        Pattern::Name(id) => self.name(id),
+                        Pattern::List(_) => unreachable!("parsed defaults are always for names"),
+                    };
+                    let space = if required + i > 0 { " " } else { "" };
+                    self.push(&format!("{}(= {} ", space, name));
+                    self.form(default, inner);
+                    self.push(")");
+                }
+                self.push(")");
+                for stmt in lambda.body.iter() {
+                    self.newline(inner);
+                    self.form(stmt, inner);
+                }
+                self.push(")");
+            }
+            Ast::Call(ref func, ref args) => {
+                if let Some(body) = delayed(func, args, self.symbols) {
+                    self.push("(delay ");
+                    self.form(body, indent);
+                    return self.push(")");
+                }
+                if let Some((body, handler)) = caught(func, args, self.symbols) {
+                    let lookup = |id| Ok::<_, ()>(self.name(id));
+                    let pattern = params_source(&handler.params, &lookup)
+                        .expect("names can always be found");
+                    self.push("(try ");
+                    self.form(body, inner);
+                    self.newline(inner);
+                    self.push(&format!("(catch {}", pattern));
+                    for stmt in handler.body.iter() {
+                        self.newline(inner + self.opts.indent);
+                        self.form(stmt, inner + self.opts.indent);
+                    }
+                    return self.push("))");
+                }
+                self.push("(");
+                let start = self.out.len();
+                self.form(func, inner);
+                // Arguments don't follow on from a function that had to be
+                // broken up.
+                let mut fill = !self.out[start..].contains('\n');
+
+                let simple = args.iter().all(|arg| match *arg {
+                    Ast::Lit(Value::Function(_)) | Ast::Call(..) | Ast::Define(..) => false,
+                    Ast::Lit(_) | Ast::Variable(_) | Ast::Include(_) => true,
+                });
+                for arg in args.iter() {
+                    if simple && fill {
+                        let flat = format!(" {}", self.flat(arg));
+                        if self.fits(&flat) {
+                            self.push(&flat);
+                            continue;
+                        }
+                    }
+                    self.newline(inner);
+                    self.form(arg, inner);
+                    fill = true;
+                }
+                self.push(")");
+            }
+            Ast::Define(id, ref value) => {
+                let name = self.name(id);
+                self.push(&format!("(= {} ", name));
+                self.form(value, indent);
+                self.push(")");
+            }
+            // Variables and other literals can't be broken up.
+            _ => self.push(&flat),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use benches::{same_ast, MANY_VARIABLES, REAL_CODE};
+    use {
+        hash_string, parse_program_with, parse_program_with_symbols, Ast, ParseOptions, SymbolTable,
+        Value,
+    };
+
+    use super::{format_source, to_source, FormatOptions, PrintError};
+
+    // Formatting must not change what the program means, and formatting the
+    // result again must change nothing.
+    fn check_format(src: &str, opts: FormatOptions) -> String {
+        let formatted = format_source(src, opts.clone()).unwrap();
+        assert_eq!(format_source(&formatted, opts).unwrap(), formatted);
+
+        let parse = |src| parse_program_with(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+        let (before, after) = (parse(src), parse(&formatted));
+        assert_eq!(before.len(), after.len());
+        assert!(before.iter().zip(&after).all(|(a, b)| same_ast(a, b)));
+
+        formatted
+    }
+
+    #[test]
+    fn formats_real_code() {
+        assert_eq!(
+            check_format(REAL_CODE, FormatOptions::default()),
+            r"(= increment (\(a) (add a 1)))
+
+(= someval (increment 2))
+
+(= double (\(someval) (add someval someval)))
+
+(= addfive (\(first second third fourth fifth)
+  (add first second third fourth fifth)))
+
+(= second (\(a a) a))
+
+(= rec (\(a) ((if (eq a 10) (\() 10) (\() (rec (add a 1)))))))
+
+(= ne (\(a b) (not (eq a b))))
+
+(= not (\(a) (if a #f)))
+
+(double 5)
+
+(addfive 1 2 3 4 5)
+
+(second 1 2)
+
+(rec 0)
+
+(ne 1 2)
+
+someval
+"
+        );
+    }
+
+    #[test]
+    fn formats_many_variables() {
+        assert_eq!(
+            check_format(MANY_VARIABLES, FormatOptions::default()),
+            r"((\(a b c d e f g h i j k l m n o p q r s t u v w x y z)
+    (a b c d e f g h i j k l m n o p q r s t u v w x y z)
+    (b c d e f g h i j k l m n o p q r s t u v w x y z)
+    (c d e f g h i j k l m n o p q r s t u v w x y z)
+    (d e f g h i j k l m n o p q r s t u v w x y z)
+    (e f g h i j k l m n o p q r s t u v w x y z)
+    (f g h i j k l m n o p q r s t u v w x y z)
+    (g h i j k l m n o p q r s t u v w x y z)
+    (h i j k l m n o p q r s t u v w x y z)
+    (i j k l m n o p q r s t u v w x y z)
+    (j k l m n o p q r s t u v w x y z)
+    (k l m n o p q r s t u v w x y z)
+    (l m n o p q r s t u v w x y z)
+    (m n o p q r s t u v w x y z)
+    (n o p q r s t u v w x y z)
+    (o p q r s t u v w x y z)
+    (p q r s t u v w x y z)
+    (q r s t u v w x y z)
+    (r s t u v w x y z)
+    (s t u v w x y z)
+    (t u v w x y z)
+    (u v w x y z)
+    (v w x y z)
+    (w x y z)
+    (x y z)
+    (y z)
+    (z))
+  ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore
+  ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore ignore
+  ignore ignore ignore ignore)
+"
+        );
+    }
+
+    #[test]
+    fn wraps_long_lines() {
+        let src = r"(= addfive (\(first second third fourth fifth) (add first second third fourth fifth)))
+            (= rec (\(a) ((if (eq a 10) (\() 10) (\() (rec (add a 1)))))))";
+
+        assert_eq!(
+            check_format(src, FormatOptions { max_width: 30, indent: 4 }),
+            r"(= addfive (\(first second third fourth fifth)
+    (add first second third
+        fourth fifth)))
+
+(= rec (\(a)
+    ((if
+            (eq a 10)
+            (\() 10)
+            (\()
+                (rec
+                    (add a 1)))))))
+"
+        );
+    }
+
+    #[test]
+    fn formatting_reports_parse_errors() {
+        assert!(format_source("(add 1", FormatOptions::default()).is_err());
+        assert_eq!(format_source("  \n ", FormatOptions::default()).unwrap(), "");
+    }
+
+    #[test]
+    fn prints_what_was_parsed() {
+        for &src in &[
+            r"(= f (\(a b) (add a b) (\()))) (f 1 #f) ((f)) (= x 10) (\((a (b)) () c) c)",
+            r"(\((= a 1) (= b (add a 1))) b) (\(a (= b (\() a))) b)",
+            r"(f 1 :b 2 :a (g :c 3)) :a",
+            r#"(f "a {{b}} \"c\" \\ \n") """#,
+            r"(f 'a (list 'b c))",
+            r#"(f #b"\x61\x00\xff" #b"")"#,
+            "(f nil #f)",
+            r"(delay (f x)) (delay (\() 1)) (delayed x)",
+            r"(try (f x) (catch e (g e) e)) (try 1 (catch (kind message))) (tryit x)",
+        ] {
+            let (program, symbols) =
+                parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+            let printed = program
+                .iter()
+                .map(|form| to_source(form, &symbols).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(printed.join(" "), src);
+        }
+    }
+
+    #[test]
+    fn formats_defaults() {
+        let opts = FormatOptions {
+            max_width: 30,
+            indent: 2,
+        };
+        assert_eq!(
+            check_format(r"(\(first (= second (add first first first))) second)", opts),
+            "(\\(first (= second (add first\n    first first)))\n  second)\n"
+        );
+    }
+
+    #[test]
+    fn formats_delay() {
+        let opts = FormatOptions {
+            max_width: 20,
+            indent: 2,
+        };
+        assert_eq!(
+            check_format("(= later (delay (add first second third)))", opts),
+            "(= later (delay (add\n  first second third)))\n"
+        );
+    }
+
+    #[test]
+    fn formats_try() {
+        let opts = FormatOptions {
+            max_width: 30,
+            indent: 2,
+        };
+        assert_eq!(
+            check_format("(try (divmod first second) (catch (kind message) (list kind)))", opts),
+            "(try (divmod first second)\n  (catch (kind message)\n    (list kind)))\n"
+        );
+    }
+
+    #[test]
+    fn refuses_what_cant_be_parsed() {
+        let symbols = SymbolTable::new();
+        assert_eq!(to_source(&Ast::Lit(Value::Void), &symbols), Err(PrintError::NoSyntax));
+        assert_eq!(
+            to_source(&Ast::Variable(hash_string("a")), &symbols),
+            Err(PrintError::UnknownIdentifier(hash_string("a")))
+        );
+    }
+}
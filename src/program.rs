@@ -0,0 +1,118 @@
+//! Programs parsed once and run many times, for hosts that run the same
+//! script against different inputs, such as a server handling requests.
+//!
+//! `Interpreter::prepare` parses a program into a `Program`, which keeps
+//! its forms and the names of its identifiers. Each `Program::run` binds
+//! the inputs it's given and evaluates the forms as they are, so the cost
+//! of parsing is only paid once. A program that should be optimised first
+//! can be parsed and rewritten by hand and then given to `Program::new`.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+use std::rc::Rc;
+
+use {eval, eval_with, hash_string, Ast, EvalError, EvalOptions, SymbolTable, Value};
+
+/// A parsed program. Cloning one shares its forms rather than copying them.
+#[derive(Clone)]
+pub struct Program {
+    forms: Rc<[Ast<u64>]>,
+    symbols: Rc<SymbolTable>,
+}
+
+impl Program {
+    /// A program of `forms`, whose identifiers are named in `symbols`.
+    pub fn new(forms: Vec<Ast<u64>>, symbols: SymbolTable) -> Self {
+        Program {
+            forms: forms.into(),
+            symbols: Rc::new(symbols),
+        }
+    }
+
+    pub fn forms(&self) -> &[Ast<u64>] {
+        &self.forms
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Bind each of `inputs` in `env` under its name, then evaluate the
+    /// forms in it, returning the value of the last or `Void` if there are
+    /// none. Like `eval`, whatever the program defines, the inputs
+    /// included, is left in `env`.
+    pub fn run<'a, S: BuildHasher>(
+        &'a self,
+        env: &mut HashMap<u64, Cow<'a, Value<u64>>, S>,
+        inputs: &[(&str, Value<u64>)],
+    ) -> Result<Value<u64>, EvalError<u64>> {
+        for (name, value) in inputs {
+            env.insert(hash_string(name), Cow::Owned(value.clone()));
+        }
+
+        let mut out = Value::Void;
+        for form in self.forms.iter() {
+            out = eval(form, env)?.into_owned();
+        }
+        Ok(out)
+    }
+
+    /// `run`, evaluating each form with `eval_with` under `options`, such
+    /// as to give the program `fuel` or a `TruthPolicy`. Fuel and the other
+    /// limits count across all the forms.
+    pub fn run_with<'a, S: BuildHasher>(
+        &'a self,
+        env: &mut HashMap<u64, Cow<'a, Value<u64>>, S>,
+        inputs: &[(&str, Value<u64>)],
+        options: &mut EvalOptions<u64>,
+    ) -> Result<Value<u64>, EvalError<u64>> {
+        for (name, value) in inputs {
+            env.insert(hash_string(name), Cow::Owned(value.clone()));
+        }
+
+        let mut out = Value::Void;
+        for form in self.forms.iter() {
+            out = eval_with(form, env, options)?.into_owned();
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use {hash_string, prelude, EvalError, IntMap, Value};
+
+    #[test]
+    fn runs_many_times_with_different_inputs() {
+        let interpreter = prelude::interpreter();
+        let program = interpreter
+            .prepare(r"(= double (\(x) (add x x))) (double (add n 1))")
+            .unwrap();
+        assert!(program.symbols().name(hash_string("double")) == Some("double"));
+
+        let copy = program.clone();
+        assert!(Rc::ptr_eq(&program.forms, &copy.forms));
+
+        for n in 0..10 {
+            let mut env: IntMap<_> = interpreter.env();
+            let out = copy.run(&mut env, &[("n", Value::Int(n))]).unwrap();
+            assert!(out == Value::Int((n + 1) * 2));
+            assert!(*env[&hash_string("n")] == Value::Int(n));
+        }
+
+        let mut env: IntMap<_> = interpreter.env();
+        let missing = program.run(&mut env, &[]);
+        assert!(missing == Err(EvalError::UnboundVariable(hash_string("n"))));
+    }
+
+    #[test]
+    fn empty_programs_give_void() {
+        let program = prelude::interpreter().prepare("").unwrap();
+        assert!(program.run(&mut prelude::env(), &[]) == Ok(Value::Void));
+    }
+}
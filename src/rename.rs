@@ -0,0 +1,501 @@
+//! Renaming a variable everywhere that refers to the same binding of it.
+//!
+//! Scoping is dynamic, but renaming follows the code as it's written, the
+//! way `analysis::free_variables` does: a read refers to the parameter of
+//! the innermost function around it that has one by that name, or to a
+//! define in that function that runs before it, and otherwise to whatever
+//! the top level binds. A function's body can see everything the scopes
+//! around it define, even after it. A rename that would make a read refer
+//! to a different binding than before, either because something between
+//! it and its binding already has the new name or because the binding
+//! would take over reads of the new name, is refused, and the program is
+//! left as it was. Code inside an `include` or a quote isn't looked at.
+
+use std::rc::Rc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {hash_string, Ast, Lambda, Pattern, SymbolTable, Value};
+
+/// Which binding of a name to rename.
+#[derive(Clone)]
+pub enum RenameScope<Id> {
+    /// The top level's, which is also what every read that no function
+    /// binds refers to, builtins included.
+    Global,
+    /// The one in this function, as a parameter or a define in its body.
+    /// The function is recognised by its address, so this has to be the
+    /// same `Rc` that's in the program, such as one found with
+    /// `Coverage::node_at_offset`.
+    Function(Rc<Lambda<Id>>),
+}
+
+/// Why a rename was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenameConflict {
+    /// The binding already binds the new name too, as another parameter or
+    /// a define in the same scope.
+    Clashes,
+    /// A read being renamed is inside a function that binds the new name,
+    /// which would take it over.
+    Shadowed,
+    /// A read of the new name that refers to something else would refer to
+    /// the renamed binding instead.
+    Captures,
+}
+
+/// What `rename` did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenameReport {
+    /// How many reads, defines and parameters were renamed.
+    pub renamed: usize,
+    /// Why nothing was renamed, if the rename was refused.
+    pub refused: Option<RenameConflict>,
+}
+
+/// Rename the binding of `target` that `scope` picks out, and everything
+/// that refers to it, to `new_name`.
+pub fn rename<Id: Clone + PartialEq>(
+    program: &mut [Ast<Id>],
+    target: Id,
+    new_name: Id,
+    scope: RenameScope<Id>,
+) -> RenameReport {
+    let mut renamer = Renamer {
+        target,
+        new_name,
+        scope,
+        frames: Vec::new(),
+        report: RenameReport::default(),
+    };
+    if renamer.target == renamer.new_name {
+        return renamer.report;
+    }
+
+    let renamed = program.iter().map(|form| renamer.walk(form)).collect::<Vec<_>>();
+    if renamer.report.refused.is_some() {
+        renamer.report.renamed = 0;
+        return renamer.report;
+    }
+    for (form, renamed) in program.iter_mut().zip(renamed) {
+        if let Some(renamed) = renamed {
+            *form = renamed;
+        }
+    }
+    renamer.report
+}
+
+/// `rename` for a program with hashed identifiers, by name, adding the new
+/// name to `symbols`.
+pub fn rename_named(
+    program: &mut [Ast<u64>],
+    symbols: &mut SymbolTable,
+    target: &str,
+    new_name: &str,
+    scope: RenameScope<u64>,
+) -> RenameReport {
+    let report = rename(program, hash_string(target), hash_string(new_name), scope);
+    if report.refused.is_none() {
+        symbols.insert(new_name);
+    }
+    report
+}
+
+// Whether a function binds a name, so far in its body and anywhere in it.
+#[derive(Clone, Copy, Default)]
+struct Bound {
+    so_far: bool,
+    anywhere: bool,
+}
+
+// A function that the walk is inside.
+struct Frame {
+    is_target: bool,
+    target: Bound,
+    new_name: Bound,
+}
+
+struct Renamer<Id> {
+    target: Id,
+    new_name: Id,
+    scope: RenameScope<Id>,
+    frames: Vec<Frame>,
+    report: RenameReport,
+}
+
+impl<Id: Clone + PartialEq> Renamer<Id> {
+    // The innermost function around the walk that binds `name` where it is
+    // now, or `None` if it's bound at the top level or not at all. Only the
+    // innermost function is part way through; the walk is inside the body
+    // of any other, which sees everything it binds.
+    fn binder(&self, name: &Id) -> Option<usize> {
+        let last = self.frames.len().checked_sub(1)?;
+        (0..self.frames.len()).rev().find(|&i| {
+            let frame = &self.frames[i];
+            let bound = if *name == self.target {
+                frame.target
+            } else {
+                frame.new_name
+            };
+            if i == last {
+                bound.so_far
+            } else {
+                bound.anywhere
+            }
+        })
+    }
+
+    fn is_target(&self, binder: Option<usize>) -> bool {
+        match binder {
+            Some(i) => self.frames[i].is_target,
+            None => matches!(self.scope, RenameScope::Global),
+        }
+    }
+
+    fn refuse(&mut self, conflict: RenameConflict) {
+        self.report.refused.get_or_insert(conflict);
+    }
+
+    // Whether a read of `name` here is renamed.
+    fn read(&mut self, name: &Id) -> bool {
+        if *name == self.new_name {
+            // The renamed binding would be found first if it's in a function
+            // between here and what this read refers to now.
+            let binder = self.binder(name);
+            let target = self.frames.iter().position(|frame| frame.is_target);
+            let captured = match target {
+                Some(target) if !self.frames[target].target.anywhere => false,
+                Some(target) => binder.is_none_or(|binder| binder < target),
+                None => binder.is_none() && matches!(self.scope, RenameScope::Global),
+            };
+            if captured {
+                self.refuse(RenameConflict::Captures);
+            }
+            return false;
+        }
+        if *name != self.target {
+            return false;
+        }
+
+        let binder = self.binder(name);
+        if !self.is_target(binder) {
+            return false;
+        }
+        let shadowed = match (binder, self.binder(&self.new_name)) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(binder), Some(shadow)) => shadow >= binder,
+        };
+        if shadowed {
+            self.refuse(RenameConflict::Shadowed);
+        }
+        self.report.renamed += 1;
+        true
+    }
+
+    // Whether a define of `name` here is renamed, marking it as bound in
+    // the scope it's in.
+    fn define(&mut self, name: &Id) -> bool {
+        if *name == self.new_name {
+            match self.frames.last_mut() {
+                Some(frame) => frame.new_name.so_far = true,
+                None if matches!(self.scope, RenameScope::Global) => {
+                    self.refuse(RenameConflict::Clashes)
+                }
+                None => {}
+            }
+            return false;
+        }
+        if *name != self.target {
+            return false;
+        }
+
+        let renamed = match self.frames.last_mut() {
+            Some(frame) => {
+                frame.target.so_far = true;
+                frame.is_target
+            }
+            None => matches!(self.scope, RenameScope::Global),
+        };
+        if renamed {
+            self.report.renamed += 1;
+        }
+        renamed
+    }
+
+    fn param(&mut self, param: &Pattern<Id>) -> Option<Pattern<Id>> {
+        let frame = self.frames.last_mut().expect("parameters are in a function");
+        let names = param.names();
+        frame.target.so_far |= names.contains(&&self.target);
+        frame.new_name.so_far |= names.contains(&&self.new_name);
+        if !frame.is_target || !names.contains(&&self.target) {
+            return None;
+        }
+
+        self.report.renamed += 1;
+        let (target, new_name) = (&self.target, &self.new_name);
+        Some(param.map(&mut |name| if name == target { new_name.clone() } else { name.clone() }))
+    }
+
+    // The renamed copy of `ast`, or `None` if nothing in it is renamed.
+    // Recurses once per level of nesting, which the parser limits.
+    fn walk(&mut self, ast: &Ast<Id>) -> Option<Ast<Id>> {
+        match *ast {
+            Ast::Variable(ref name) => {
+                if self.read(name) {
+                    Some(Ast::Variable(self.new_name.clone()))
+                } else {
+                    None
+                }
+            }
+            Ast::Define(ref name, ref value) => {
+                let renamed_value = self.walk(value);
+                let renamed = self.define(name);
+                if renamed_value.is_none() && !renamed {
+                    return None;
+                }
+                let name = if renamed {
+                    self.new_name.clone()
+                } else {
+                    name.clone()
+                };
+                let value = renamed_value.map_or_else(|| value.clone(), Rc::new);
+                Some(Ast::Define(name, value))
+            }
+            Ast::Call(ref func, ref args) => {
+                let renamed_func = self.walk(func);
+                let renamed_args = args.iter().map(|arg| self.walk(arg)).collect::<Vec<_>>();
+                if renamed_func.is_none() && renamed_args.iter().all(Option::is_none) {
+                    return None;
+                }
+                let func = renamed_func.map_or_else(|| func.clone(), Rc::new);
+                let args = args
+                    .iter()
+                    .zip(renamed_args)
+                    .map(|(arg, renamed)| renamed.unwrap_or_else(|| arg.clone()))
+                    .collect::<Vec<_>>();
+                Some(Ast::Call(func, args.into()))
+            }
+            Ast::Lit(Value::Function(ref lambda)) => {
+                let renamed = self.function(lambda)?;
+                Some(Ast::Lit(Value::Function(Rc::new(renamed))))
+            }
+            Ast::Lit(_) | Ast::Include(_) => None,
+        }
+    }
+
+    fn function(&mut self, lambda: &Rc<Lambda<Id>>) -> Option<Lambda<Id>> {
+        let is_target = match self.scope {
+            RenameScope::Function(ref target) => Rc::ptr_eq(target, lambda),
+            RenameScope::Global => false,
+        };
+        let binds = |name: &Id| Bound {
+            so_far: false,
+            anywhere: lambda.params.iter().any(|param| param.names().contains(&name))
+                || lambda.defaults.iter().chain(lambda.body.iter()).any(|ast| defines(ast, name)),
+        };
+        let frame = Frame {
+            is_target,
+            target: binds(&self.target),
+            new_name: binds(&self.new_name),
+        };
+        if is_target && frame.new_name.anywhere {
+            self.refuse(RenameConflict::Clashes);
+        }
+        self.frames.push(frame);
+
+        let required = lambda.required();
+        let mut params = lambda.params[..required]
+            .iter()
+            .map(|param| self.param(param))
+            .collect::<Vec<_>>();
+        // Each default can use the parameters before it.
+        let mut defaults = Vec::with_capacity(lambda.defaults.len());
+        for (param, default) in lambda.params[required..].iter().zip(lambda.defaults.iter()) {
+            defaults.push(self.walk(default));
+            params.push(self.param(param));
+        }
+        let body = lambda.body.iter().map(|stmt| self.walk(stmt)).collect::<Vec<_>>();
+
+        self.frames.pop();
+        let unchanged = params.iter().all(Option::is_none)
+            && defaults.iter().all(Option::is_none)
+            && body.iter().all(Option::is_none);
+        if unchanged {
+            return None;
+        }
+
+        fn keep<T: Clone>(old: &[T], new: Vec<Option<T>>) -> Box<[T]> {
+            old.iter()
+                .zip(new)
+                .map(|(old, new)| new.unwrap_or_else(|| old.clone()))
+                .collect()
+        }
+        Some(Lambda {
+            params: keep(&lambda.params, params),
+            defaults: keep(&lambda.defaults, defaults),
+            body: keep(&lambda.body, body),
+        })
+    }
+}
+
+// Whether `ast` defines `name` in the scope it's in, rather than in a
+// function inside it.
+fn defines<Id: PartialEq>(ast: &Ast<Id>, name: &Id) -> bool {
+    match *ast {
+        Ast::Define(ref defined, ref value) => defined == name || defines(value, name),
+        Ast::Call(ref func, ref args) => {
+            defines(func, name) || args.iter().any(|arg| defines(arg, name))
+        }
+        Ast::Variable(_) | Ast::Lit(_) | Ast::Include(_) => false,
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::{rename, rename_named, RenameConflict, RenameReport, RenameScope};
+    use analysis::alpha_eq;
+    use benches::{corpus_env, REAL_CODE};
+    use print::to_source;
+    use {
+        eval, hash_string, parse_program_with_symbols, Ast, Coverage, ParseOptions, SymbolTable,
+        Value,
+    };
+
+    // The program, with what's needed to find things in it by offset and
+    // print it out.
+    fn parse(src: &str) -> (Vec<Ast<u64>>, Coverage, SymbolTable) {
+        let options = ParseOptions::default();
+        let (program, coverage) = Coverage::parse(src, &options, &mut |_| {}).unwrap();
+        let (_, symbols) = parse_program_with_symbols(src, &options, &mut |_| {}).unwrap();
+        (program, coverage, symbols)
+    }
+
+    fn source(program: &[Ast<u64>], symbols: &SymbolTable) -> String {
+        let forms = program.iter().map(|form| to_source(form, symbols).unwrap());
+        forms.collect::<Vec<_>>().join(" ")
+    }
+
+    // The function that the parameter at `offset` belongs to.
+    fn function_at(program: &[Ast<u64>], coverage: &Coverage, offset: usize) -> RenameScope<u64> {
+        match *coverage.node_at_offset(program, offset).unwrap().node() {
+            Ast::Lit(Value::Function(ref lambda)) => RenameScope::Function(lambda.clone()),
+            _ => panic!("no function at {}", offset),
+        }
+    }
+
+    #[test]
+    fn renames_only_the_same_binding() {
+        let src = r"(= x 1) (= f (\(x) (add x 1))) (add x (f x)) (\(q) (list q (\(q) q)))";
+        let (program, coverage, mut symbols) = parse(src);
+
+        let mut global = program.clone();
+        let report = rename_named(&mut global, &mut symbols, "x", "y", RenameScope::Global);
+        assert_eq!(report, RenameReport { renamed: 3, refused: None });
+        assert_eq!(
+            source(&global, &symbols),
+            r"(= y 1) (= f (\(x) (add x 1))) (add y (f y)) (\(q) (list q (\(q) q)))"
+        );
+
+        let mut param = program.clone();
+        let scope = function_at(&program, &coverage, src.find("(x)").unwrap() + 1);
+        let report = rename_named(&mut param, &mut symbols, "x", "z", scope);
+        assert_eq!(report.renamed, 2);
+        assert_eq!(
+            source(&param, &symbols),
+            r"(= x 1) (= f (\(z) (add z 1))) (add x (f x)) (\(q) (list q (\(q) q)))"
+        );
+        // Renaming a parameter changes nothing but the names.
+        assert!(program.iter().zip(&param).all(|(a, b)| alpha_eq(a, b)));
+        assert!(!alpha_eq(&program[0], &global[0]));
+
+        // The inner `q` is a different binding from the outer one.
+        let mut outer = program.clone();
+        let scope = function_at(&program, &coverage, src.find("(q)").unwrap() + 1);
+        assert_eq!(rename_named(&mut outer, &mut symbols, "q", "r", scope).renamed, 2);
+        assert_eq!(source(&outer[3..], &symbols), r"(\(r) (list r (\(q) q)))");
+
+        // A define in a body binds the reads after it, and reads before it
+        // are of whatever is outside.
+        let src = r"(\() (list y (= y 1) y (\() y)))";
+        let (program, coverage, mut symbols) = parse(src);
+        let mut local = program.clone();
+        let scope = function_at(&program, &coverage, 1);
+        assert_eq!(rename_named(&mut local, &mut symbols, "y", "w", scope).renamed, 3);
+        assert_eq!(source(&local, &symbols), r"(\() (list y (= w 1) w (\() w)))");
+        let mut global = program.clone();
+        rename_named(&mut global, &mut symbols, "y", "w", RenameScope::Global);
+        assert_eq!(source(&global, &symbols), r"(\() (list w (= y 1) y (\() y)))");
+    }
+
+    #[test]
+    fn refuses_renames_that_change_what_reads_refer_to() {
+        let src = r"(= x 1) (= f (\(y) (add x y))) (= g (\(x (= n add)) (add x n)))";
+        let (program, coverage, _) = parse(src);
+        let refused = |target, new_name, scope| {
+            let mut renamed = program.clone();
+            let report = rename(&mut renamed, hash_string(target), hash_string(new_name), scope);
+            assert_eq!(report.renamed, 0);
+            report.refused
+        };
+
+        // `f` reads the global `x` from inside a function that binds `y`.
+        assert_eq!(refused("x", "y", RenameScope::Global), Some(RenameConflict::Shadowed));
+        // Two globals, or the global and a builtin, would become one.
+        assert_eq!(refused("x", "f", RenameScope::Global), Some(RenameConflict::Clashes));
+        assert_eq!(refused("x", "add", RenameScope::Global), Some(RenameConflict::Captures));
+        // In `g`, `x` can't be renamed to the other parameter, or to the
+        // builtin its body uses.
+        let g = || function_at(&program, &coverage, src.find("(x").unwrap() + 1);
+        assert_eq!(refused("x", "n", g()), Some(RenameConflict::Clashes));
+        assert_eq!(refused("x", "add", g()), Some(RenameConflict::Captures));
+        // Renaming to a name nothing uses is fine.
+        let mut renamed = program.clone();
+        let report = rename(&mut renamed, hash_string("x"), hash_string("v"), g());
+        assert_eq!(report, RenameReport { renamed: 2, refused: None });
+    }
+
+    #[test]
+    fn renamed_real_code_runs_the_same() {
+        let (program, coverage, mut symbols) = parse(REAL_CODE);
+        let rec = REAL_CODE.find("(= rec (\\ (a)").unwrap() + 11;
+        let scope = function_at(&program, &coverage, rec);
+        let mut renamed = program.clone();
+        for &(target, new_name) in &[("second", "snd"), ("someval", "val"), ("not", "negate")] {
+            let scope = RenameScope::Global;
+            let report = rename_named(&mut renamed, &mut symbols, target, new_name, scope);
+            assert!(report.renamed > 1 && report.refused.is_none(), "{:?}", report);
+        }
+        assert_eq!(rename_named(&mut renamed, &mut symbols, "a", "n", scope).renamed, 3);
+
+        let printed = source(&renamed, &symbols);
+        assert!(printed.contains(r"(= snd (\(a a) a))"));
+        assert!(printed.contains(r"(\(first second third fourth fifth)"));
+        assert!(printed.contains(r"(\(someval) (add someval someval))"));
+        assert!(printed.contains(r"(\(n) ((if (eq n 10)"));
+
+        let run = |program: &[Ast<u64>]| {
+            let mut env = corpus_env();
+            let values = program.iter().map(|form| eval(form, &mut env).map(|v| v.to_string()));
+            values.collect::<Result<Vec<_>, _>>().unwrap()
+        };
+        assert_eq!(run(&renamed), run(&program));
+    }
+
+    #[test]
+    fn renames_named_identifiers() {
+        let (program, _, symbols) = parse(r"(= x 1) (= f (\(x) x)) (f x)");
+        let mut named = program
+            .into_iter()
+            .map(|form| form.map_idents(|id| symbols.name(id).unwrap().to_owned()))
+            .collect::<Vec<_>>();
+        let report = rename(&mut named, "x".to_owned(), "y".to_owned(), RenameScope::Global);
+        assert_eq!(report.renamed, 2);
+        assert!(matches!(named[0], Ast::Define(ref name, _) if name == "y"));
+        let read = match named[2] {
+            Ast::Call(_, ref args) => args[0].clone(),
+            _ => panic!("not a call"),
+        };
+        assert!(matches!(read, Ast::Variable(ref name) if name == "y"));
+    }
+}
@@ -0,0 +1,307 @@
+//! The interactive interpreter behind `src/bin/repl.rs`, kept separate from
+//! the terminal handling so that it can be driven by tests.
+//!
+//! Input is read a line at a time, and lines are collected until
+//! `is_complete` says every parenthesis and string is closed. Everything
+//! entered runs in the same environment, which starts out as the prelude
+//! and its stdlib.
+
+use std::borrow::Cow;
+use std::io::{self, BufRead, Write};
+
+use report::{self, render_error};
+use snapshot::sorted_by_name;
+use {
+    is_complete, parse_program_with_symbols, prelude, CompleteStatus, EnvSnapshot, EvalOptions,
+    IntMap, Interpreter, ParseOptions, SymbolTable, Value,
+};
+
+pub const PROMPT: &str = "> ";
+pub const CONTINUATION_PROMPT: &str = ". ";
+
+/// How deeply calls can nest before an input is stopped, the same as the
+/// `run` binary's default, so that runaway recursion is an error rather
+/// than overflowing the stack.
+pub const MAX_DEPTH: usize = 1000;
+
+// What errors are reported as coming from.
+const SOURCE_NAME: &str = "<repl>";
+
+const HELP: &str = "\
+Enter expressions to evaluate them. Input continues over several lines
+until every parenthesis and string is closed.
+
+Commands:
+  :help  Show this message
+  :env   List every variable that's defined
+  :diff  List what's been defined or redefined since the start
+  :quit  Exit";
+
+pub struct Repl {
+    interpreter: Interpreter<u64>,
+    env: IntMap<Cow<'static, Value<u64>>>,
+    // The environment before anything was entered, for `:diff`.
+    start: EnvSnapshot,
+    symbols: SymbolTable,
+    options: ParseOptions,
+    pending: String,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let interpreter = prelude::interpreter();
+        let mut env = IntMap::default();
+        interpreter.reset(&mut env);
+
+        Repl {
+            start: EnvSnapshot::new(&env),
+            env,
+            symbols: prelude::symbols(),
+            options: interpreter.parse_options(),
+            interpreter,
+            pending: String::new(),
+        }
+    }
+
+    /// The prompt to show before the next line, which depends on whether
+    /// the last one finished an expression.
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        }
+    }
+
+    /// Handle one line of input, writing whatever it prints to `out`.
+    /// Returns `false` once the user has asked to quit.
+    pub fn feed<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        if self.pending.is_empty() && line.trim_start().starts_with(':') {
+            return self.command(line.trim(), out);
+        }
+
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        // Invalid input is parsed anyway, for the parser's error message.
+        if let CompleteStatus::Incomplete { .. } = is_complete(&self.pending) {
+            return Ok(true);
+        }
+
+        let src = ::std::mem::take(&mut self.pending);
+        if !src.trim().is_empty() {
+            self.evaluate(&src, out)?;
+        }
+
+        Ok(true)
+    }
+
+    fn command<W: Write>(&mut self, command: &str, out: &mut W) -> io::Result<bool> {
+        match command {
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":env" => {
+                let bindings = self.env.iter().map(|(&id, value)| (id, value));
+                for (name, value) in sorted_by_name(bindings, &self.symbols) {
+                    writeln!(out, "{} = {}", name, value)?;
+                }
+            }
+            ":diff" => {
+                let diff = self.start.diff(&EnvSnapshot::new(&self.env));
+                write!(out, "{}", diff.display_with(&self.symbols))?;
+            }
+            ":quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command {}, try :help", command)?,
+        }
+
+        Ok(true)
+    }
+
+    fn evaluate<W: Write>(&mut self, src: &str, out: &mut W) -> io::Result<()> {
+        let mut warnings = Vec::new();
+        let parsed = parse_program_with_symbols(src, &self.options, &mut |d| warnings.push(d));
+        for warning in warnings {
+            writeln!(out, "warning: {}", warning)?;
+        }
+
+        let (program, symbols) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => return write!(out, "{}", render_error(&e.into(), src, SOURCE_NAME)),
+        };
+        for (_, name) in symbols.iter() {
+            self.symbols.insert(name);
+        }
+        if program.is_empty() {
+            return Ok(());
+        }
+
+        let options = EvalOptions {
+            max_depth: Some(MAX_DEPTH),
+            ..self.interpreter.options()
+        };
+        let mut env: IntMap<Cow<Value<u64>>> = ::std::mem::take(&mut self.env);
+        let result = report::run_program(&program, src, &mut env, options);
+        // Values can borrow from the program, which only lives as long as
+        // this input, so those are copied out before it goes away.
+        self.env = env
+            .into_iter()
+            .map(|(name, value)| (name, Cow::Owned(value.into_owned())))
+            .collect();
+
+        match result {
+            Ok(value) => writeln!(out, "{}", value),
+            Err(e) => write!(out, "{}", render_error(&e, src, SOURCE_NAME)),
+        }
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a session reading lines from `input` until it ends or the user
+/// quits, writing prompts and results to `output`.
+pub fn run<R: BufRead, W: Write>(input: R, mut output: W) -> io::Result<()> {
+    let mut repl = Repl::new();
+    let mut lines = input.lines();
+
+    loop {
+        write!(output, "{}", repl.prompt())?;
+        output.flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return writeln!(output),
+        };
+        if !repl.feed(&line, &mut output)? {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+
+    fn transcript(input: &str) -> String {
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn evaluates_in_a_persistent_environment() {
+        let input = r"(= inc (\(n) (add n 1)))
+(= x
+  (inc
+    41))
+x
+(inc x) (inc (inc x))
+:quit
+(this is never read)";
+
+        assert_eq!(
+            transcript(input),
+            "> <function>\n> . . 42\n> 42\n> 44\n> "
+        );
+    }
+
+    #[test]
+    fn the_stdlib_is_defined() {
+        let input = "(not #f)\n(identity 1)";
+
+        assert_eq!(transcript(input), "> void\n> 1\n> \n");
+    }
+
+    #[test]
+    fn strings_continue_over_lines() {
+        let input = "(concat \"a (\n) b\"\n  \"c\")\n\"x";
+
+        assert_eq!(transcript(input), "> . . \"a (\\n) bc\"\n> . \n");
+    }
+
+    #[test]
+    fn reports_errors_and_carries_on() {
+        let input = "(1 2)\n(add 1))\n(= if 1)\n:what\n(add 1 1)";
+
+        assert_eq!(
+            transcript(input),
+            "> error: Attempted to call a non-function\n --> <repl>:1:1\n  |\n1 | (1 2)\n  | ^^^^^\n\
+             > error: Unexpected `)`, Expected `end of input`\n --> <repl>:1:8\n  |\n\
+             1 | (add 1))\n  |        ^\n\
+             > warning: `if` shadows a builtin function (at byte 3)\n1\n\
+             > Unknown command :what, try :help\n\
+             > 2\n\
+             > \n"
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_input_by_name() {
+        let input = "(= double (\\(n) (mul n 2)))\n(add 1\n  (dubble 2))";
+
+        assert_eq!(
+            transcript(input),
+            "> <function>\n> . error: Variable does not exist: `dubble`\n --> <repl>:2:4\n  |\n\
+             2 |   (dubble 2))\n  |    ^^^^^^\n> \n"
+        );
+    }
+
+    #[test]
+    fn runaway_recursion_is_an_error() {
+        // Enough stack for `MAX_DEPTH` nested calls in a debug build.
+        let output = ::std::thread::Builder::new()
+            .stack_size(64 * 1024 * 1024)
+            .spawn(|| transcript("(= loop (\\() (add 1 (loop))))\n(loop)\n(add 1 1)"))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        // The call that failed is in an earlier input, so there's no source
+        // to show it in.
+        assert_eq!(
+            output,
+            "> <function>\n> <repl>: error: Calls nested more than 1000 deep\n> 2\n> \n"
+        );
+    }
+
+    #[test]
+    fn lists_the_environment() {
+        let input = "(= x 10)\n(= x (add x 1))\n:env";
+
+        assert_eq!(
+            transcript(input),
+            "> 10\n> 11\n> add = <native>\nassert = <native>\nbox = <native>\n\
+             boxset = <native>\nbytesconcat = <native>\nbyteslength = <native>\n\
+             bytesref = <native>\nbytesslice = <native>\nbytestostring = <native>\n\
+             compose = <function>\n\
+             concat = <native>\ncopy = <native>\ncurry = <native>\ndelay = <native>\n\
+             divmod = <native>\n\
+             eq = <native>\nerror = <native>\neval = <native>\nflip = <function>\n\
+             force = <native>\nidentity = <function>\n\
+             if = <native>\nisbox = <native>\nisbuiltin = <native>\nisbytes = <native>\n\
+             isfalse = <native>\nisforeign = <native>\nisfunction = <native>\n\
+             isint = <native>\niskeyword = <native>\nislist = <native>\nisnil = <native>\n\
+             isstring = <native>\nissymbol = <native>\nisthunk = <native>\n\
+             isvalues = <native>\nisvoid = <native>\n\
+             list = <native>\nmul = <native>\nnot = <function>\nnull = <native>\nnumbertostring = <native>\n\
+             partial = <native>\nprint = <native>\nrandom = <native>\nrandomseed = <native>\n\
+             same = <native>\nsort = <native>\nsortby = <native>\n\
+             stringconcat = <native>\nstringlength = <native>\nstringtobytes = <native>\n\
+             stringtonumber = <native>\nsubstring = <native>\ntry = <native>\n\
+             typeof = <native>\nunbox = <native>\n\
+             values = <native>\nx = 11\nyield = <native>\n> \n"
+        );
+    }
+
+    #[test]
+    fn lists_what_changed() {
+        let input = "(= x 10)\n(= y x)\n(= if 1)\n:diff";
+
+        assert_eq!(
+            transcript(input),
+            "> 10\n> 10\n> warning: `if` shadows a builtin function (at byte 3)\n1\n\
+             > ~ if = <native> -> 1\n+ x = 10\n+ y = 10\n> \n"
+        );
+    }
+}
@@ -0,0 +1,522 @@
+//! Reports of errors shown against the source they came from, in the style
+//! of rustc:
+//!
+//! ```text
+//! error: Variable does not exist: `incremnt`
+//!  --> real.lisp:4:13
+//!   |
+//! 4 | (= someval (incremnt 2))
+//!   |             ^^^^^^^^
+//!   |
+//!   = help: a variable with a similar name exists: `increment`
+//! ```
+//!
+//! Evaluation errors don't carry a position, so `run_source` works out
+//! which node each one came from while running the program.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+use coverage::{Coverage, Span};
+use debugger::{Debugger, Debugging, EnvView, Step};
+use {
+    eval_with, hash_string, line_and_column, Ast, Diagnostic, EvalError, EvalOptions, IntMap,
+    ParseError, ParseOptions, Value,
+};
+
+/// Something that went wrong with a program, along with where it went
+/// wrong if that's known.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    /// `span` is the node that the error came from, and `suggestion` a
+    /// hint at how to fix it.
+    Eval {
+        error: EvalError<u64>,
+        span: Option<Span>,
+        suggestion: Option<String>,
+    },
+}
+
+impl Error {
+    /// The source that the error is about, if it's known.
+    pub fn span(&self, src: &str) -> Option<Span> {
+        match *self {
+            Error::Parse(ParseError::Syntax {
+                position: Some(position),
+                ..
+            }) => {
+                // Points at the character that couldn't be parsed, or just
+                // past the end of the source.
+                let len = src
+                    .get(position..)
+                    .and_then(|rest| rest.chars().next())
+                    .map_or(0, char::len_utf8);
+                Some(Span {
+                    start: position,
+                    end: position + len,
+                })
+            }
+            Error::Parse(ParseError::Strict(Diagnostic::DuplicateParameter {
+                ref name,
+                position,
+            }))
+            | Error::Parse(ParseError::Strict(Diagnostic::ShadowsBuiltin { ref name, position })) => {
+                Some(Span {
+                    start: position,
+                    end: position + name.len(),
+                })
+            }
+            Error::Parse(_) => None,
+            Error::Eval { span, .. } => span,
+        }
+    }
+
+    /// How the error could be fixed, if there's an obvious way.
+    pub fn suggestion(&self) -> Option<&str> {
+        match *self {
+            Error::Parse(ParseError::Strict(Diagnostic::DuplicateParameter { .. })) => {
+                Some("give each parameter a different name")
+            }
+            Error::Parse(ParseError::Strict(Diagnostic::ShadowsBuiltin { .. })) => {
+                Some("choose a name that isn't a builtin")
+            }
+            Error::Parse(_) => None,
+            Error::Eval { ref suggestion, .. } => suggestion.as_ref().map(|s| &s[..]),
+        }
+    }
+
+    // What went wrong, without the byte offsets that the report shows as a
+    // line and column instead.
+    fn message(&self, span: Option<(Span, &str)>) -> String {
+        match (self, span) {
+            (&Error::Parse(ParseError::Syntax { ref message, .. }), Some(_)) => message
+                .lines()
+                .filter(|line| !line.is_empty() && !line.starts_with("Parse error at"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            (
+                &Error::Parse(ParseError::Strict(Diagnostic::DuplicateParameter {
+                    ref name, ..
+                })),
+                Some(_),
+            ) => {
+                format!("Parameter `{}` is bound more than once", name)
+            }
+            (
+                &Error::Parse(ParseError::Strict(Diagnostic::ShadowsBuiltin { ref name, .. })),
+                Some(_),
+            ) => {
+                format!("`{}` shadows a builtin function", name)
+            }
+            (
+                &Error::Eval {
+                    error: EvalError::UnboundVariable(_),
+                    ..
+                },
+                Some((span, src)),
+            ) => format!("Variable does not exist: `{}`", &src[span.start..span.end]),
+            (
+                &Error::Eval {
+                    error: EvalError::AssertionFailed { ref message, .. },
+                    ..
+                },
+                Some((span, src)),
+            ) => format!("Assertion `{}` failed: {}", &src[span.start..span.end], message),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "{}", e),
+            Error::Eval { ref error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+/// Render `err` as a report of where it happened in `src`, which was read
+/// from `filename`. Errors that aren't tied to any part of the source are
+/// reported on a single line.
+pub fn render_error(err: &Error, src: &str, filename: &str) -> String {
+    let span = match err.span(src) {
+        Some(span) => span,
+        None => return format!("{}: error: {}\n", filename, err),
+    };
+
+    let (line, column) = line_and_column(src, span.start);
+    let line_start = src[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let text = src[line_start..].lines().next().unwrap_or("");
+    // Spans that go over several lines are only underlined on the first.
+    let underline = src[span.start..span.end.min(line_start + text.len()).max(span.start)]
+        .chars()
+        .count()
+        .max(1);
+
+    let gutter = " ".repeat(line.to_string().len());
+    let mut out = format!(
+        "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+        err.message(Some((span, src))),
+        gutter,
+        filename,
+        line,
+        column,
+        gutter,
+        line,
+        text,
+        gutter,
+        " ".repeat(column - 1),
+        "^".repeat(underline),
+    );
+    if let Some(suggestion) = err.suggestion() {
+        out.push_str(&format!(
+            "{} |\n{} = help: {}\n",
+            gutter, gutter, suggestion
+        ));
+    }
+    out
+}
+
+/// Parse and evaluate `src` in a copy of `env`, returning the value of the
+/// last form. An error from evaluation is given the span of the innermost
+/// node that failed.
+pub fn run_source(src: &str, env: &IntMap<Cow<'static, Value<u64>>>) -> Result<Value<u64>, Error> {
+    run_source_with(src, env, EvalOptions::default(), &mut |_| {})
+}
+
+/// `run_source`, evaluating with `options`, such as to give the program
+/// `fuel`, and passing parse diagnostics to `diagnostics`. Their debugger
+/// is replaced with the one that finds where errors came from.
+pub fn run_source_with(
+    src: &str,
+    env: &IntMap<Cow<'static, Value<u64>>>,
+    options: EvalOptions<u64>,
+    diagnostics: &mut dyn FnMut(Diagnostic),
+) -> Result<Value<u64>, Error> {
+    let (program, coverage) = Coverage::parse(src, &ParseOptions::default(), diagnostics)?;
+    let mut variables: IntMap<Cow<Value<u64>>> = env.clone();
+    run_located(&program, Some(&coverage), src, &mut variables, options)
+}
+
+/// Evaluate `program`, which was parsed from `src`, in `env` itself, so
+/// that what it defines is still there afterwards, and return the value of
+/// its last form. Errors are located the way `run_source_with` locates
+/// them, unless the spans of the program's nodes can't be found, in which
+/// case they're reported without one.
+pub fn run_program<'a>(
+    program: &'a [Ast<u64>],
+    src: &str,
+    env: &mut IntMap<Cow<'a, Value<u64>>>,
+    options: EvalOptions<u64>,
+) -> Result<Value<u64>, Error> {
+    let coverage = Coverage::of(program, src).ok();
+    run_located(program, coverage.as_ref(), src, env, options)
+}
+
+fn run_located<'a>(
+    program: &'a [Ast<u64>],
+    coverage: Option<&Coverage>,
+    src: &str,
+    variables: &mut IntMap<Cow<'a, Value<u64>>>,
+    mut options: EvalOptions<u64>,
+) -> Result<Value<u64>, Error> {
+    let failed = Rc::new(Cell::new(None));
+    options.debugger = Some(Debugging::new(Locator {
+        failed: failed.clone(),
+    }));
+
+    let mut out = Value::Void;
+    for form in program {
+        match eval_with(form, variables, &mut options) {
+            Ok(value) => out = value.into_owned(),
+            Err(error) => {
+                let span = coverage.and_then(|coverage| {
+                    let id = coverage.node_at(failed.get()?)?;
+                    Some(coverage.span(id))
+                });
+                let suggestion = match error {
+                    EvalError::UnboundVariable(_) => span.and_then(|span| {
+                        similar_name(&src[span.start..span.end], src, variables).map(|name| {
+                            format!("a variable with a similar name exists: `{}`", name)
+                        })
+                    }),
+                    _ => None,
+                };
+                // Assertions are the one error that knows where it's from.
+                let error = match error {
+                    EvalError::AssertionFailed {
+                        message,
+                        span: None,
+                    } => EvalError::AssertionFailed { message, span },
+                    error => error,
+                };
+                return Err(Error::Eval {
+                    error,
+                    span,
+                    suggestion,
+                });
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+// Writes down the address of the first node to fail, which is the
+// innermost, since every node it was part of fails after it. A failed
+// `assert` is put down to the expression it was given rather than the call.
+struct Locator {
+    failed: Rc<Cell<Option<usize>>>,
+}
+
+impl Debugger<u64> for Locator {
+    fn on_enter(&mut self, _: &Ast<u64>, _: &EnvView<u64>) -> Step {
+        Step::Continue
+    }
+
+    fn on_exit(&mut self, node: &Ast<u64>, result: Result<&Value<u64>, &EvalError<u64>>) {
+        if result.is_err() && self.failed.get().is_none() {
+            let node = match (node, result) {
+                (Ast::Call(_, args), Err(EvalError::AssertionFailed { .. })) if !args.is_empty() => {
+                    &args[0]
+                }
+                _ => node,
+            };
+            self.failed.set(Some(node as *const Ast<u64> as usize));
+        }
+    }
+}
+
+// The closest name to `name` out of those written in `src` that are bound
+// in `variables`, if any is only a couple of edits away.
+fn similar_name<'a>(
+    name: &str,
+    src: &'a str,
+    variables: &IntMap<Cow<Value<u64>>>,
+) -> Option<&'a str> {
+    let mut names = src
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|&word| {
+            !word.is_empty() && word != name && variables.contains_key(&hash_string(word))
+        })
+        .map(|word| (edit_distance(name, word), word))
+        .filter(|&(distance, _)| distance <= 2)
+        .collect::<Vec<_>>();
+    names.sort();
+    names.first().map(|&(_, word)| word)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (diagonal + (ca != cb) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use benches::REAL_CODE;
+    use std::rc::Rc;
+
+    use {hash_string, parse_program, prelude, EvalError, ParseError, Value};
+
+    use super::{edit_distance, render_error, run_source, Error};
+
+    #[test]
+    fn renders_an_unbalanced_paren() {
+        let src = "(= x 1)\n(= y (add x 2)))\n";
+        let err = Error::from(parse_program(src).err().unwrap());
+
+        assert_eq!(
+            render_error(&err, src, "unbalanced.lisp"),
+            "error: Unexpected `)`, Expected `end of input`
+ --> unbalanced.lisp:2:16
+  |
+2 | (= y (add x 2)))
+  |                ^
+"
+        );
+    }
+
+    #[test]
+    fn renders_an_undefined_variable() {
+        let src = REAL_CODE.replace("(increment 2)", "(incremnt 2)");
+        let err = run_source(&src, &prelude::env()).err().unwrap();
+
+        assert_eq!(
+            render_error(&err, &src, "real.lisp"),
+            "error: Variable does not exist: `incremnt`
+ --> real.lisp:4:13
+  |
+4 | (= someval (incremnt 2))
+  |             ^^^^^^^^
+  |
+  = help: a variable with a similar name exists: `increment`
+"
+        );
+    }
+
+    #[test]
+    fn renders_errors_without_spans_plainly() {
+        let err = Error::Parse(ParseError::IdentifierCollision {
+            a: "a".to_owned(),
+            b: "b".to_owned(),
+            hash: 1,
+        });
+        assert_eq!(
+            render_error(&err, "(a b)", "prog.lisp"),
+            "prog.lisp: error: Identifiers `a` and `b` have the same hash (0x1)\n"
+        );
+
+        let err = Error::Eval {
+            error: EvalError::UnboundVariable(hash_string("x")),
+            span: None,
+            suggestion: None,
+        };
+        assert_eq!(
+            render_error(&err, "x", "prog.lisp"),
+            format!(
+                "prog.lisp: error: Variable does not exist: {:?}\n",
+                hash_string("x")
+            )
+        );
+    }
+
+    #[test]
+    fn points_past_the_end_of_unfinished_source() {
+        let src = "(add 1\n  (add 2";
+        let err = Error::from(parse_program(src).err().unwrap());
+
+        let report = render_error(&err, src, "short.lisp");
+        assert!(report.contains(" --> short.lisp:2:9\n"), "{}", report);
+        assert!(
+            report.ends_with("2 |   (add 2\n  |         ^\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn locates_the_call_that_failed() {
+        let src = "(= f (\\(x) (add x (1 2))))\n(f 1)";
+        let err = run_source(src, &prelude::env()).err().unwrap();
+
+        let span = err.span(src).unwrap();
+        assert_eq!(&src[span.start..span.end], "(1 2)");
+        assert_eq!(err.suggestion(), None);
+    }
+
+    #[test]
+    fn locates_failures_inside_a_delay() {
+        assert!(run_source("(delay 1)", &prelude::env()).is_ok());
+
+        let src = "(= later (delay (add 1 (1 2))))
+(force later)";
+        let err = run_source(src, &prelude::env()).err().unwrap();
+        let span = err.span(src).unwrap();
+        assert_eq!(&src[span.start..span.end], "(1 2)");
+    }
+
+    #[test]
+    fn locates_failures_inside_a_try() {
+        assert!(run_source("(try 1 (catch e 2))", &prelude::env()) == Ok(Value::Int(1)));
+
+        let src = "(try (add 1 (1 2))\n  (catch e (error e)))";
+        let err = run_source(src, &prelude::env()).err().unwrap();
+        let span = err.span(src).unwrap();
+        assert_eq!(&src[span.start..span.end], "(1 2)");
+    }
+
+    #[test]
+    fn renders_the_expression_of_a_failed_assertion() {
+        let src = "(= x 1)\n(assert (eq x 1) \"x starts at 1\")\n\
+                   (assert (eq x 2) \"x should be 2\")";
+        let err = run_source(src, &prelude::env()).err().unwrap();
+
+        let span = err.span(src).unwrap();
+        assert_eq!(&src[span.start..span.end], "(eq x 2)");
+        assert!(matches!(
+            err,
+            Error::Eval {
+                error: EvalError::AssertionFailed { span: Some(at), .. },
+                ..
+            } if at == span
+        ));
+        assert_eq!(
+            render_error(&err, src, "test.lisp"),
+            "error: Assertion `(eq x 2)` failed: x should be 2
+ --> test.lisp:3:9
+  |
+3 | (assert (eq x 2) \"x should be 2\")
+  |         ^^^^^^^^
+"
+        );
+    }
+
+    #[test]
+    fn errors_raised_in_nested_calls_reach_the_top() {
+        let src = r#"
+            (= check (\(n) ((if (eq n 0) (\() (error "n can't be" n)) (\() n)))))
+            (= outer (\(n) (add 1 (check n))))
+            (outer 0)"#;
+        let err = run_source(src, &prelude::env()).err().unwrap();
+
+        let span = err.span(src).unwrap();
+        assert_eq!(&src[span.start..span.end], r#"(error "n can't be" n)"#);
+        match err {
+            Error::Eval { ref error, .. } => assert!(
+                *error
+                    == EvalError::User {
+                        message: Rc::new("n can't be".to_owned()),
+                        values: Rc::new(vec![Value::Int(0)]),
+                    }
+            ),
+            _ => panic!("{}", err),
+        }
+        assert_eq!(
+            render_error(&err, src, "test.lisp").lines().next(),
+            Some("error: n can't be 0")
+        );
+
+        let src = src.replace("(outer 0)", "(outer 3)");
+        assert!(run_source(&src, &prelude::env()) == Ok(Value::Int(4)));
+    }
+
+    #[test]
+    fn an_empty_program_is_void() {
+        for &src in &["", "   \n\t "] {
+            assert!(run_source(src, &prelude::env()) == Ok(Value::Void));
+        }
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(edit_distance("incremnt", "increment"), 1);
+        assert_eq!(edit_distance("abc", "abc"), 0);
+        assert_eq!(edit_distance("", "ab"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
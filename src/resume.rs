@@ -0,0 +1,221 @@
+//! Programs that pause partway through and carry on later, for hosts such
+//! as games that run a script a little at a time.
+//!
+//! `eval_resumable` runs a program on the bytecode VM, whose stack is its
+//! own rather than Rust's, so it can stop between any two instructions and
+//! keep everything it needs to carry on in a `Continuation`. It stops when
+//! the program calls `yield`, and when it runs out of fuel if
+//! `EvalOptions::suspend_on_fuel` is set. Like the VM, it can't call the
+//! natives that evaluate code, such as `try`, and it doesn't look at any
+//! option but those two.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+
+use bytecode::{self, Exit, Instr, Pause, Paused};
+use {Ast, EvalError, EvalOptions, Value};
+
+/// How far a resumable program got.
+pub enum Outcome<Id> {
+    /// It finished, giving the value of its last form.
+    Completed(Value<Id>),
+    /// It paused, giving the value it yielded, or `Void` if it ran out of
+    /// fuel.
+    Suspended(Continuation<Id>, Value<Id>),
+}
+
+/// Where a paused program carries on from. Each can be resumed once.
+pub struct Continuation<Id> {
+    paused: Option<Paused<Id>>,
+    out_of_fuel: bool,
+}
+
+impl<Id: Clone + Debug + Eq + Hash> Continuation<Id> {
+    /// Whether the program paused because it ran out of fuel, rather than
+    /// because it yielded.
+    pub fn out_of_fuel(&self) -> bool {
+        self.out_of_fuel
+    }
+
+    /// Carry on running the program in `env`, with `reply` as what the
+    /// `yield` it paused at gives. A program that ran out of fuel carries on
+    /// with what it was about to do and `reply` is ignored, so `options`
+    /// should be given more fuel first. Resuming a second time fails with
+    /// `AlreadyResumed`.
+    pub fn resume<'a, S: BuildHasher>(
+        &mut self,
+        reply: Value<Id>,
+        env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+        options: &mut EvalOptions<Id>,
+    ) -> Result<Outcome<Id>, EvalError<Id>> {
+        let mut paused = self.paused.take().ok_or(EvalError::AlreadyResumed)?;
+        if !self.out_of_fuel {
+            paused.reply(reply);
+        }
+        carry_on(paused, env, options)
+    }
+}
+
+/// Run `program` like `eval` runs each of its forms in turn, until it
+/// finishes or pauses. Every name is looked up in `env` when it's used,
+/// so the host can change what they're bound to between pauses.
+pub fn eval_resumable<'a, Id, S>(
+    program: &[Ast<Id>],
+    env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+    options: &mut EvalOptions<Id>,
+) -> Result<Outcome<Id>, EvalError<Id>>
+where
+    Id: Clone + Debug + Eq + Hash,
+    S: BuildHasher,
+{
+    let compiled = bytecode::compile(program, &HashMap::new(), options.truth);
+    carry_on(Paused::new(compiled), env, options)
+}
+
+fn carry_on<'a, Id, S>(
+    mut paused: Paused<Id>,
+    env: &mut HashMap<Id, Cow<'a, Value<Id>>, S>,
+    options: &mut EvalOptions<Id>,
+) -> Result<Outcome<Id>, EvalError<Id>>
+where
+    Id: Clone + Debug + Eq + Hash,
+    S: BuildHasher,
+{
+    let (value, out_of_fuel) = match paused.run(env, options)? {
+        Exit::Returned(value) => return Ok(Outcome::Completed(value)),
+        Exit::Yielded(value) => (value, false),
+        Exit::OutOfFuel => (Value::Void, true),
+    };
+    let continuation = Continuation {
+        paused: Some(paused),
+        out_of_fuel,
+    };
+    Ok(Outcome::Suspended(continuation, value))
+}
+
+// Fuel counts expressions, as it does for `eval_with`.
+impl<Id: Eq + Hash> Pause<Id> for EvalOptions<Id> {
+    fn step(&mut self, instr: &Instr<Id>) -> Result<bool, EvalError<Id>> {
+        match self.fuel {
+            Some(_) if !instr.is_expression() => Ok(false),
+            Some(0) if self.suspend_on_fuel => Ok(true),
+            Some(0) => Err(EvalError::OutOfFuel),
+            Some(ref mut fuel) => {
+                *fuel -= 1;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn yields(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::rc::Rc;
+
+    use super::{eval_resumable, Continuation, Outcome};
+    use {eval, parse_program, prelude, EvalError, EvalOptions, Value};
+
+    fn suspended(outcome: Outcome<u64>) -> (Continuation<u64>, Value<u64>) {
+        match outcome {
+            Outcome::Suspended(continuation, value) => (continuation, value),
+            Outcome::Completed(value) => panic!("completed with {}", value),
+        }
+    }
+
+    fn completed(outcome: Outcome<u64>) -> Value<u64> {
+        match outcome {
+            Outcome::Completed(value) => value,
+            Outcome::Suspended(_, value) => panic!("suspended with {}", value),
+        }
+    }
+
+    #[test]
+    fn yields_are_given_the_replies() {
+        let program = parse_program(
+            r"
+            (= a (yield 1))
+            (= b (yield (add a 1)))
+            (= f (\(x) (yield (list x b))))
+            (add (f a) 100)
+            ",
+        )
+        .unwrap();
+        let mut env = prelude::env();
+        let mut options = EvalOptions::default();
+
+        let (mut first, yielded) =
+            suspended(eval_resumable(&program, &mut env, &mut options).unwrap());
+        assert!(yielded == Value::Int(1));
+        let outcome = first.resume(Value::Int(10), &mut env, &mut options).unwrap();
+        let (mut second, yielded) = suspended(outcome);
+        assert!(yielded == Value::Int(11));
+        let outcome = second.resume(Value::Int(20), &mut env, &mut options).unwrap();
+        let (mut third, yielded) = suspended(outcome);
+        assert!(!third.out_of_fuel());
+        assert!(yielded == Value::List(Rc::new(vec![Value::Int(10), Value::Int(20)])));
+        let outcome = third.resume(Value::Int(30), &mut env, &mut options).unwrap();
+        assert!(completed(outcome) == Value::Int(130));
+
+        let unpaused = eval(&program[0], &mut env);
+        assert!(unpaused.unwrap_err() == EvalError::Yielded(Value::Int(1)));
+    }
+
+    #[test]
+    fn continuations_resume_once() {
+        let program = parse_program("(add (yield 1) 1)").unwrap();
+        let mut env = prelude::env();
+        let mut options = EvalOptions::default();
+
+        let (mut continuation, _) =
+            suspended(eval_resumable(&program, &mut env, &mut options).unwrap());
+        let outcome = continuation.resume(Value::Int(4), &mut env, &mut options).unwrap();
+        assert!(completed(outcome) == Value::Int(5));
+        let again = continuation.resume(Value::Int(4), &mut env, &mut options);
+        assert!(matches!(again, Err(EvalError::AlreadyResumed)));
+    }
+
+    #[test]
+    fn running_out_of_fuel_suspends() {
+        let program = parse_program(
+            r"
+            (= double (\(x) (add x x)))
+            (double (double (double 1)))
+            ",
+        )
+        .unwrap();
+        let mut env = prelude::env();
+        let mut options = EvalOptions {
+            fuel: Some(3),
+            suspend_on_fuel: true,
+            ..EvalOptions::default()
+        };
+
+        let mut pauses = 0;
+        let mut outcome = eval_resumable(&program, &mut env, &mut options).unwrap();
+        let value = loop {
+            match outcome {
+                Outcome::Completed(value) => break value,
+                Outcome::Suspended(mut continuation, yielded) => {
+                    assert!(continuation.out_of_fuel() && yielded == Value::Void);
+                    pauses += 1;
+                    options.fuel = Some(3);
+                    outcome = continuation.resume(Value::Nil, &mut env, &mut options).unwrap();
+                }
+            }
+        };
+        assert!(value == Value::Int(8));
+        assert!(pauses > 3);
+
+        options.fuel = Some(3);
+        options.suspend_on_fuel = false;
+        let failed = eval_resumable(&program, &mut env, &mut options);
+        assert!(matches!(failed, Err(EvalError::OutOfFuel)));
+    }
+}
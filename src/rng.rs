@@ -0,0 +1,51 @@
+//! The generator behind the prelude's `random`. Each `Interpreter` has one
+//! of its own, so a program seeding it doesn't change the numbers another
+//! interpreter's programs get, and `Interpreter::reset` starts it again
+//! from the interpreter's seed.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// SplitMix64, which is small, fine with any seed including 0, and has no
+/// state but a counter. Copies share the counter, so the closures that
+/// `random` and `randomseed` are registered as see what the other did.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: Arc<AtomicU64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            state: Arc::new(AtomicU64::new(seed)),
+        }
+    }
+
+    /// Start the numbers again from `seed`, so the same seed always gives
+    /// the same numbers after it.
+    pub fn seed(&self, seed: u64) {
+        self.state.store(seed, Ordering::Relaxed);
+    }
+
+    fn next(&self) -> u64 {
+        let mut z = self.state.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which mustn't be 0, every one as likely as the
+    /// next. Numbers from the top of the range that would make some
+    /// remainders likelier than others are thrown away.
+    pub fn below(&self, n: u64) -> u64 {
+        let limit = u64::MAX - u64::MAX % n;
+        loop {
+            let z = self.next();
+            if z < limit {
+                return z % n;
+            }
+        }
+    }
+}
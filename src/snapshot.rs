@@ -0,0 +1,231 @@
+//! Copies of environments, for seeing what running something changed.
+//!
+//! An `EnvSnapshot` keeps what every name was bound to when it was taken,
+//! and `EnvSnapshot::diff` lists what another snapshot added, removed and
+//! rebound. Values are compared with `==`, so a function is rebound if
+//! it's a different function, however alike their code is, and a list
+//! only if its elements changed.
+//!
+//! Environments are hash maps, which keep no order worth showing, so
+//! everything that lists bindings, here, in `debugger::EnvView` and in the
+//! REPL's `:env`, puts them in order of name with `sorted_by_name` just
+//! before showing them. A listing is then the same whatever order the
+//! names were bound in.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {IntMap, SymbolTable, Value};
+
+/// What every name in an environment was bound to.
+#[derive(Clone, Debug, Default)]
+pub struct EnvSnapshot {
+    bindings: IntMap<Value<u64>>,
+}
+
+impl EnvSnapshot {
+    pub fn new<S: BuildHasher>(env: &HashMap<u64, Cow<Value<u64>>, S>) -> Self {
+        let bindings = env.iter().map(|(&name, value)| (name, (**value).clone()));
+        EnvSnapshot {
+            bindings: bindings.collect(),
+        }
+    }
+
+    pub fn get(&self, name: u64) -> Option<&Value<u64>> {
+        self.bindings.get(&name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bindings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Every name and what it was bound to, as `sorted_by_name` gives them.
+    pub fn bindings(&self, symbols: &SymbolTable) -> Vec<(String, &Value<u64>)> {
+        sorted_by_name(self.bindings.iter().map(|(&name, value)| (name, value)), symbols)
+    }
+
+    /// What changed between this snapshot and `later`.
+    pub fn diff(&self, later: &EnvSnapshot) -> EnvDiff {
+        let mut diff = EnvDiff::default();
+        for (&name, before) in &self.bindings {
+            match later.bindings.get(&name) {
+                None => diff.removed.push((name, before.clone())),
+                Some(after) if after != before => {
+                    diff.rebound.push((name, before.clone(), after.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for (&name, after) in &later.bindings {
+            if !self.bindings.contains_key(&name) {
+                diff.added.push((name, after.clone()));
+            }
+        }
+
+        diff.added.sort_by_key(|binding| binding.0);
+        diff.removed.sort_by_key(|binding| binding.0);
+        diff.rebound.sort_by_key(|binding| binding.0);
+        diff
+    }
+}
+
+/// `bindings` with their names from `symbols`, in order of name. A name
+/// that isn't in `symbols` is shown as `SymbolTable::display_name` shows
+/// it.
+pub fn sorted_by_name<V, I>(bindings: I, symbols: &SymbolTable) -> Vec<(String, V)>
+where
+    I: IntoIterator<Item = (u64, V)>,
+{
+    let named = bindings.into_iter().map(|(id, value)| (symbols.display_name(id), value));
+    let mut named = named.collect::<Vec<_>>();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    named
+}
+
+/// The names that one environment bound differently from another, each
+/// in order of identifier.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnvDiff {
+    /// Names that are only bound in the later one, and their values.
+    pub added: Vec<(u64, Value<u64>)>,
+    /// Names that are only bound in the earlier one, and their values.
+    pub removed: Vec<(u64, Value<u64>)>,
+    /// Names that are bound to something else in the later one, and their
+    /// values before and after.
+    pub rebound: Vec<(u64, Value<u64>, Value<u64>)>,
+}
+
+impl EnvDiff {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.rebound.is_empty()
+    }
+
+    /// Something that displays this a line per name in order of name, with
+    /// names from `symbols`: `+ x = 1` for an added binding, `- x = 1` for a
+    /// removed one and `~ x = 1 -> 2` for a rebound one. A name that isn't
+    /// in `symbols` is shown as its identifier in hex, after a `#`.
+    pub fn display_with<'a>(&'a self, symbols: &'a SymbolTable) -> DisplayDiff<'a> {
+        DisplayDiff {
+            diff: self,
+            symbols,
+        }
+    }
+}
+
+/// An `EnvDiff` displayed with `EnvDiff::display_with`.
+pub struct DisplayDiff<'a> {
+    diff: &'a EnvDiff,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> fmt::Display for DisplayDiff<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |id| self.symbols.display_name(id);
+        let mut lines = Vec::new();
+        for &(id, ref value) in &self.diff.added {
+            lines.push((name(id), format!("+ {} = {}", name(id), value)));
+        }
+        for &(id, ref value) in &self.diff.removed {
+            lines.push((name(id), format!("- {} = {}", name(id), value)));
+        }
+        for &(id, ref before, ref after) in &self.diff.rebound {
+            lines.push((name(id), format!("~ {} = {} -> {}", name(id), before, after)));
+        }
+        lines.sort();
+
+        for (_, line) in lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+    use std::rc::Rc;
+
+    use super::{EnvDiff, EnvSnapshot};
+    use {hash_string, prelude, IntMap, SymbolTable, Value};
+
+    #[test]
+    fn lists_what_a_program_changed() {
+        let interpreter = prelude::interpreter();
+        let mut env = prelude::env();
+        let (_, before, _) = interpreter.eval_str_diffed("(= x 1)", &mut env).unwrap();
+        assert!(before.added == vec![(hash_string("x"), Value::Int(1))]);
+
+        let src = r#"(= y 2) (= x (add x 1)) (= z "z") (= y 2)"#;
+        let (value, diff, symbols) = interpreter.eval_str_diffed(src, &mut env).unwrap();
+        assert!(value == Value::Int(2));
+        let z = Value::Str(Rc::new("z".to_owned()));
+        let mut added = vec![(hash_string("y"), Value::Int(2)), (hash_string("z"), z)];
+        added.sort_by_key(|binding| binding.0);
+        let expected = EnvDiff {
+            added,
+            removed: vec![],
+            rebound: vec![(hash_string("x"), Value::Int(1), Value::Int(2))],
+        };
+        assert!(diff == expected);
+        assert_eq!(diff.display_with(&symbols).to_string(), "~ x = 1 -> 2\n+ y = 2\n+ z = \"z\"\n");
+
+        let mut fewer = env.clone();
+        fewer.remove(&hash_string("y"));
+        let removed = EnvSnapshot::new(&env).diff(&EnvSnapshot::new(&fewer));
+        assert!(removed.removed == vec![(hash_string("y"), Value::Int(2))]);
+        // Without a name for `y`, it's shown as its identifier.
+        let shown = removed.display_with(&Default::default()).to_string();
+        assert_eq!(shown, format!("- #{:x} = 2\n", hash_string("y")));
+    }
+
+    #[test]
+    fn listings_dont_depend_on_the_order_of_binding() {
+        let mut symbols = SymbolTable::new();
+        let names = (0..40).map(|i| symbols.insert(&format!("v{}", i))).collect::<Vec<_>>();
+        let build = |names: &mut dyn Iterator<Item = &u64>| {
+            let mut env = IntMap::default();
+            for &name in names {
+                env.insert(name, Cow::Owned(Value::Int(name % 7)));
+            }
+            EnvSnapshot::new(&env)
+        };
+        let forwards = build(&mut names.iter());
+        let backwards = build(&mut names.iter().rev());
+        let shown = |snapshot: &EnvSnapshot| {
+            let bindings = snapshot.bindings(&symbols).into_iter();
+            bindings.map(|(name, value)| format!("{} = {}", name, value)).collect::<Vec<_>>()
+        };
+        assert_eq!(shown(&forwards), shown(&backwards));
+        let mut sorted = shown(&forwards);
+        sorted.sort();
+        assert_eq!(shown(&forwards), sorted);
+
+        // Both change the same way, and show it the same way.
+        let empty = EnvSnapshot::default();
+        let (added, removed) = (empty.diff(&forwards), backwards.diff(&empty));
+        assert!(added == empty.diff(&backwards));
+        assert!(removed == forwards.diff(&empty));
+        let shown = added.display_with(&symbols).to_string();
+        assert!(shown.starts_with("+ v0 = "));
+        assert!(shown.lines().map(|line| &line[2..]).eq(sorted.iter().map(String::as_str)));
+    }
+
+    #[test]
+    fn pure_programs_change_nothing() {
+        let interpreter = prelude::interpreter();
+        let mut env = prelude::env();
+        let src = r"((\(n) (= m (add n 1))) 1) (list 1 2)";
+        let (value, diff, _) = interpreter.eval_str_diffed(src, &mut env).unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(value.to_string(), "(list 1 2)");
+    }
+}
@@ -0,0 +1,4 @@
+(= not (\(x) (if x #f)))
+(= identity (\(x) x))
+(= compose (\(f g) (curry (\(f g x) (f (g x))) f g)))
+(= flip (\(f) (curry (\(f a b) (f b a)) f)))
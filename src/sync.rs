@@ -0,0 +1,465 @@
+//! Copies of programs and values that can be shared between threads.
+//!
+//! The evaluator works on `Rc`-based values, so these are for getting
+//! programs and data to and from it: a program or environment is converted
+//! once, shared behind an `Arc`, and each thread converts it back to run it
+//! with `eval`. Native functions are plain function pointers, so they're
+//! shared as they are, and so are host functions, whose closures are
+//! `Send` and `Sync`. Functions are copied by each conversion, which means
+//! a copy is never `same` as the original. A thunk is copied without what
+//! it was forced to, so each copy works its value out again when it's
+//! forced. A box is copied with what it holds at the time, and becomes a new
+//! box when it's converted back, so writes to one copy aren't seen by any
+//! other. Where a box turns up inside itself, the copy holds `nil` instead.
+//! A foreign value's object is only known to be `Any`, which needn't be
+//! `Send`, so it's copied as `nil` too.
+//!
+//! Conversions recurse once per level of nesting, which is fine for
+//! anything the parser accepts.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use {
+    eval, BigInt, BytesPreview, EvalError, HostFn, NativeFn, Pattern, Quoted, ReentrantFn,
+    SymbolTable,
+};
+
+#[derive(Clone)]
+pub enum Ast<Ident> {
+    Lit(Value<Ident>),
+    Variable(Ident),
+    Call(Arc<Ast<Ident>>, Arc<[Ast<Ident>]>),
+    Define(Ident, Arc<Ast<Ident>>),
+    Include(Arc<str>),
+}
+
+#[derive(Clone)]
+pub enum Value<Ident> {
+    Void,
+    False,
+    Int(u64),
+    Function(Arc<Lambda<Ident>>),
+    InbuiltFunc(NativeFn<Ident>),
+    ReentrantFunc(ReentrantFn<Ident>),
+    HostFunc(HostFn<Ident>),
+    List(Arc<Vec<Value<Ident>>>),
+    Values(Arc<Vec<Value<Ident>>>),
+    Keyword(Ident),
+    Symbol(Ident),
+    Partial(Arc<Value<Ident>>, Arc<[Value<Ident>]>),
+    Str(Arc<str>),
+    BigInt(Arc<BigInt>),
+    Bytes(Arc<[u8]>),
+    Nil,
+    Thunk(Arc<Value<Ident>>),
+    Box(Arc<Value<Ident>>),
+}
+
+pub struct Lambda<Ident> {
+    pub params: Box<[Pattern<Ident>]>,
+    pub defaults: Box<[Ast<Ident>]>,
+    pub body: Box<[Ast<Ident>]>,
+}
+
+impl<Id: Clone> Ast<Id> {
+    pub fn from_local(ast: &::Ast<Id>) -> Self {
+        match *ast {
+            ::Ast::Lit(ref value) => Ast::Lit(Value::from_local(value)),
+            ::Ast::Variable(ref name) => Ast::Variable(name.clone()),
+            ::Ast::Call(ref func, ref args) => Ast::Call(
+                Arc::new(Ast::from_local(func)),
+                args.iter().map(Ast::from_local).collect::<Vec<_>>().into(),
+            ),
+            ::Ast::Define(ref name, ref value) => {
+                Ast::Define(name.clone(), Arc::new(Ast::from_local(value)))
+            }
+            ::Ast::Include(ref path) => Ast::Include(Arc::from(&**path)),
+        }
+    }
+
+    pub fn to_local(&self) -> ::Ast<Id> {
+        match *self {
+            Ast::Lit(ref value) => ::Ast::Lit(value.to_local()),
+            Ast::Variable(ref name) => ::Ast::Variable(name.clone()),
+            Ast::Call(ref func, ref args) => ::Ast::Call(
+                Rc::new(func.to_local()),
+                args.iter().map(Ast::to_local).collect::<Vec<_>>().into(),
+            ),
+            Ast::Define(ref name, ref value) => {
+                ::Ast::Define(name.clone(), Rc::new(value.to_local()))
+            }
+            Ast::Include(ref path) => ::Ast::Include(Rc::from(&**path)),
+        }
+    }
+}
+
+impl<Id: Clone> Value<Id> {
+    pub fn from_local(value: &::Value<Id>) -> Self {
+        Value::from_local_in(value, &mut Vec::new())
+    }
+
+    // `from_local`, where `open` is the boxes whose contents are being
+    // copied.
+    fn from_local_in(value: &::Value<Id>, open: &mut Vec<*const ()>) -> Self {
+        let mut copy = |value| Value::from_local_in(value, open);
+        match *value {
+            ::Value::Void => Value::Void,
+            ::Value::False => Value::False,
+            ::Value::Nil => Value::Nil,
+            ::Value::Int(i) => Value::Int(i),
+            ::Value::BigInt(ref big) => Value::BigInt(Arc::new((**big).clone())),
+            ::Value::Function(ref lambda) => Value::Function(Arc::new(Lambda {
+                params: lambda.params.clone(),
+                defaults: lambda.defaults.iter().map(Ast::from_local).collect(),
+                body: lambda.body.iter().map(Ast::from_local).collect(),
+            })),
+            ::Value::InbuiltFunc(func) => Value::InbuiltFunc(func),
+            ::Value::ReentrantFunc(func) => Value::ReentrantFunc(func),
+            ::Value::HostFunc(ref func) => Value::HostFunc(func.clone()),
+            ::Value::List(ref items) => Value::List(Arc::new(items.iter().map(copy).collect())),
+            ::Value::Values(ref items) => Value::Values(Arc::new(items.iter().map(copy).collect())),
+            ::Value::Keyword(ref name) => Value::Keyword(name.clone()),
+            ::Value::Symbol(ref name) => Value::Symbol(name.clone()),
+            ::Value::Str(ref text) => Value::Str(Arc::from(&***text)),
+            ::Value::Bytes(ref bytes) => Value::Bytes(Arc::from(&***bytes)),
+            ::Value::Partial(ref partial) => Value::Partial(
+                Arc::new(copy(partial.func())),
+                partial.args().iter().map(copy).collect::<Vec<_>>().into(),
+            ),
+            ::Value::Thunk(ref thunk) => Value::Thunk(Arc::new(copy(thunk.func()))),
+            ::Value::Box(ref cell) => {
+                let ptr = Rc::as_ptr(cell) as *const ();
+                if open.contains(&ptr) {
+                    return Value::Box(Arc::new(Value::Nil));
+                }
+                open.push(ptr);
+                let held = Value::from_local_in(&cell.borrow(), open);
+                open.pop();
+                Value::Box(Arc::new(held))
+            }
+            ::Value::Foreign(_) => Value::Nil,
+        }
+    }
+
+    pub fn to_local(&self) -> ::Value<Id> {
+        match *self {
+            Value::Void => ::Value::Void,
+            Value::False => ::Value::False,
+            Value::Nil => ::Value::Nil,
+            Value::Int(i) => ::Value::Int(i),
+            Value::BigInt(ref big) => ::Value::BigInt(Rc::new((**big).clone())),
+            Value::Function(ref lambda) => ::Value::Function(Rc::new(::Lambda {
+                params: lambda.params.clone(),
+                defaults: lambda.defaults.iter().map(Ast::to_local).collect(),
+                body: lambda.body.iter().map(Ast::to_local).collect(),
+            })),
+            Value::InbuiltFunc(func) => ::Value::InbuiltFunc(func),
+            Value::ReentrantFunc(func) => ::Value::ReentrantFunc(func),
+            Value::HostFunc(ref func) => ::Value::HostFunc(func.clone()),
+            Value::List(ref items) => ::Value::List(Rc::new(items.iter().map(Value::to_local).collect())),
+            Value::Values(ref items) => ::Value::Values(Rc::new(items.iter().map(Value::to_local).collect())),
+            Value::Keyword(ref name) => ::Value::Keyword(name.clone()),
+            Value::Symbol(ref name) => ::Value::Symbol(name.clone()),
+            Value::Str(ref text) => ::Value::Str(Rc::new(text.to_string())),
+            Value::Bytes(ref bytes) => ::Value::Bytes(Rc::new(bytes.to_vec())),
+            Value::Partial(ref func, ref args) => ::Value::Partial(Rc::new(::Partial::new(
+                func.to_local(),
+                args.iter().map(Value::to_local).collect(),
+            ))),
+            Value::Thunk(ref func) => ::Value::Thunk(Rc::new(::Thunk::new(func.to_local()))),
+            Value::Box(ref held) => ::Value::Box(Rc::new(RefCell::new(held.to_local()))),
+        }
+    }
+}
+
+impl<Ident> Value<Ident> {
+    // Like `::Value::heap_size_in`, for values shared behind an `Arc`.
+    fn heap_size_in(&self, seen: &mut HashSet<*const ()>) -> usize {
+        const COUNTS: usize = 2 * mem::size_of::<usize>();
+
+        let mut size = 0;
+        let mut pending = vec![self];
+        while let Some(value) = pending.pop() {
+            match *value {
+                Value::List(ref items) | Value::Values(ref items)
+                    if seen.insert(Arc::as_ptr(items) as *const ()) =>
+                {
+                    size += COUNTS + mem::size_of::<Vec<Value<Ident>>>();
+                    size += items.capacity() * mem::size_of::<Value<Ident>>();
+                    pending.extend(items.iter());
+                }
+                Value::Str(ref text)
+                    if seen.insert(Arc::as_ptr(text) as *const u8 as *const ()) =>
+                {
+                    size += COUNTS + text.len();
+                }
+                Value::BigInt(ref big)
+                    if seen.insert(Arc::as_ptr(big) as *const ()) =>
+                {
+                    size += COUNTS + mem::size_of::<BigInt>() + big.heap_size();
+                }
+                Value::Bytes(ref bytes)
+                    if seen.insert(Arc::as_ptr(bytes) as *const u8 as *const ()) =>
+                {
+                    size += COUNTS + bytes.len();
+                }
+                Value::Function(ref lambda)
+                    if seen.insert(Arc::as_ptr(lambda) as *const ()) =>
+                {
+                    size += COUNTS + mem::size_of::<Lambda<Ident>>();
+                    size += lambda.params.len() * mem::size_of::<Pattern<Ident>>();
+                    let nodes = lambda.defaults.len() + lambda.body.len();
+                    size += nodes * mem::size_of::<Ast<Ident>>();
+                }
+                Value::Partial(ref func, ref args) => {
+                    if seen.insert(Arc::as_ptr(func) as *const ()) {
+                        size += COUNTS + mem::size_of::<Value<Ident>>();
+                        pending.push(func);
+                    }
+                    if seen.insert(Arc::as_ptr(args) as *const Value<Ident> as *const ()) {
+                        size += COUNTS + args.len() * mem::size_of::<Value<Ident>>();
+                        pending.extend(args.iter());
+                    }
+                }
+                Value::Thunk(ref func) | Value::Box(ref func)
+                    if seen.insert(Arc::as_ptr(func) as *const ()) =>
+                {
+                    size += COUNTS + mem::size_of::<Value<Ident>>();
+                    pending.push(func);
+                }
+                _ => {}
+            }
+        }
+        size
+    }
+}
+
+impl<Ident> fmt::Display for Value<Ident> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Void => write!(f, "void"),
+            Value::False => write!(f, "#f"),
+            Value::Nil => write!(f, "nil"),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::BigInt(ref big) => write!(f, "{}", big),
+            Value::Function(_) | Value::Partial(..) => write!(f, "<function>"),
+            Value::InbuiltFunc(_) | Value::ReentrantFunc(_) | Value::HostFunc(_) => {
+                write!(f, "<native>")
+            }
+            Value::Keyword(_) => write!(f, "<keyword>"),
+            Value::Symbol(_) => write!(f, "<symbol>"),
+            Value::Thunk(_) => write!(f, "<thunk>"),
+            Value::Box(ref held) => write!(f, "(box {})", held),
+            Value::Str(ref text) => write!(f, "{}", Quoted(text)),
+            Value::Bytes(ref bytes) => write!(f, "{}", BytesPreview(bytes)),
+            Value::List(ref items) => {
+                write!(f, "(list")?;
+                for item in items.iter() {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
+            Value::Values(ref items) => {
+                write!(f, "(values")?;
+                for item in items.iter() {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// A global namespace that can be shared between threads, such as the
+/// prelude.
+#[derive(Clone)]
+pub struct Env<Id: Eq + Hash> {
+    bindings: HashMap<Id, Value<Id>>,
+}
+
+impl<Id: Clone + fmt::Debug + Eq + Hash> Env<Id> {
+    pub fn from_local<S: BuildHasher>(env: &HashMap<Id, Cow<::Value<Id>>, S>) -> Self {
+        Env {
+            bindings: env
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::from_local(value)))
+                .collect(),
+        }
+    }
+
+    pub fn to_local<'a, S: BuildHasher + Default>(&self) -> HashMap<Id, Cow<'a, ::Value<Id>>, S> {
+        self.bindings
+            .iter()
+            .map(|(name, value)| (name.clone(), Cow::Owned(value.to_local())))
+            .collect()
+    }
+
+    pub fn get(&self, name: &Id) -> Option<&Value<Id>> {
+        self.bindings.get(name)
+    }
+
+    pub fn insert(&mut self, name: Id, value: Value<Id>) {
+        self.bindings.insert(name, value);
+    }
+
+    /// Roughly how many bytes the bindings keep on the heap, counting what
+    /// values share once, the way `Value::heap_size` does for a value that
+    /// isn't shared between threads.
+    pub fn heap_size(&self) -> usize {
+        let mut seen = HashSet::new();
+        let values = self.bindings.values().map(|value| value.heap_size_in(&mut seen));
+        self.bindings.capacity() * mem::size_of::<(Id, Value<Id>)>() + values.sum::<usize>()
+    }
+
+    /// Run `program` in a copy of this environment, returning the value of
+    /// its last form or `Void` if it's empty. Whatever it defines is thrown
+    /// away afterwards.
+    pub fn run(&self, program: &[Ast<Id>]) -> Result<Value<Id>, EvalError<Id>> {
+        let program = program.iter().map(Ast::to_local).collect::<Vec<_>>();
+        let mut env: HashMap<_, _> = self.to_local();
+
+        let mut out = Value::Void;
+        for form in &program {
+            out = Value::from_local(eval(form, &mut env)?.as_ref());
+        }
+
+        Ok(out)
+    }
+}
+
+/// A `Program` that can be shared between threads. Each thread converts it
+/// back once with `to_local`, and can then run that as often as it likes.
+#[derive(Clone)]
+pub struct Program {
+    forms: Arc<[Ast<u64>]>,
+    symbols: Arc<SymbolTable>,
+}
+
+impl Program {
+    pub fn from_local(program: &::Program) -> Self {
+        Program {
+            forms: program.forms().iter().map(Ast::from_local).collect(),
+            symbols: Arc::new(program.symbols().clone()),
+        }
+    }
+
+    pub fn to_local(&self) -> ::Program {
+        let forms = self.forms.iter().map(Ast::to_local).collect();
+        ::Program::new(forms, (*self.symbols).clone())
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use {hash_string, parse_program, prelude};
+
+    use super::{Ast, Env, Program, Value};
+
+    #[test]
+    fn shares_the_prelude_between_threads() {
+        let prelude = Arc::new(Env::from_local(&prelude::env()));
+
+        let workers = (0..8u64)
+            .map(|i| {
+                let src = format!(r"(= n {}) (= double (\(x) (add x x))) (double (add n 1))", i);
+                let program = parse_program(&src)
+                    .unwrap()
+                    .iter()
+                    .map(Ast::from_local)
+                    .collect::<Vec<_>>();
+                let prelude = prelude.clone();
+
+                thread::spawn(move || prelude.run(&program).ok())
+            })
+            .collect::<Vec<_>>();
+
+        for (i, worker) in workers.into_iter().enumerate() {
+            match worker.join().unwrap() {
+                Some(Value::Int(n)) => assert_eq!(n, (i as u64 + 1) * 2),
+                Some(other) => panic!("thread {} returned {}", i, other),
+                None => panic!("thread {} failed", i),
+            }
+        }
+
+        // Nothing a thread defined made it back into the shared prelude.
+        assert!(prelude.get(&hash_string("n")).is_none());
+        assert!(prelude.get(&hash_string("add")).is_some());
+    }
+
+    #[test]
+    fn shares_a_prepared_program_between_threads() {
+        let prelude = Arc::new(Env::from_local(&prelude::env()));
+        let src = r"(= double (\(x) (add x x))) (double n)";
+        let program = Program::from_local(&prelude::interpreter().prepare(src).unwrap());
+
+        let workers = (0..4u64)
+            .map(|i| {
+                let (prelude, program) = (prelude.clone(), program.clone());
+                thread::spawn(move || {
+                    let program = program.to_local();
+                    let mut total = 0;
+                    for n in 0..10 {
+                        let mut env: ::IntMap<_> = prelude.to_local();
+                        match program.run(&mut env, &[("n", ::Value::Int(i * 10 + n))]) {
+                            Ok(::Value::Int(doubled)) => total += doubled,
+                            _ => return None,
+                        }
+                    }
+                    Some(total)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (i, worker) in workers.into_iter().enumerate() {
+            let expected = (0..10).map(|n| (i as u64 * 10 + n) * 2).sum::<u64>();
+            assert_eq!(worker.join().unwrap(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn functions_survive_the_round_trip() {
+        let program = parse_program(r"(\(a b) (add a b) (= c 1))").unwrap();
+        let local = match program[0] {
+            ::Ast::Lit(ref value) => value.clone(),
+            _ => unreachable!(),
+        };
+
+        let shared = Value::from_local(&local);
+        assert!(shared.to_local().equal(&local));
+        assert!(shared.to_local() != local);
+    }
+
+    #[test]
+    fn heap_size_counts_shared_values_once() {
+        let list = || Value::List(Arc::new(vec![Value::Int(1), Value::Int(2), Value::Int(3)]));
+        let mut shared = Env::from_local(&prelude::minimal_env());
+        let mut separate = shared.clone();
+
+        let xs = list();
+        shared.insert(hash_string("a"), xs.clone());
+        shared.insert(hash_string("b"), xs);
+        separate.insert(hash_string("a"), list());
+        separate.insert(hash_string("b"), list());
+
+        // What one list takes, beside a binding that takes nothing.
+        let mut one = Env::from_local(&::IntMap::default());
+        let mut int = one.clone();
+        one.insert(hash_string("a"), list());
+        int.insert(hash_string("a"), Value::Int(1));
+        let size = one.heap_size() - int.heap_size();
+        assert!(size >= 3 * ::std::mem::size_of::<Value<u64>>());
+        assert_eq!(separate.heap_size() - shared.heap_size(), size);
+    }
+}
@@ -0,0 +1,411 @@
+//! Random programs for property tests.
+//!
+//! Generated programs only use the identifiers in `symbols`, and only ever
+//! call a function written out in place, with exactly as many arguments as
+//! it has parameters. Function values can be defined and passed around but
+//! are never called through a variable, so no program can recurse and
+//! every one of them finishes without needing a step limit. Variables are
+//! chosen at random, so some programs read names that aren't bound.
+//!
+//! `generate_program` is for profiling rather than testing. It writes
+//! programs as large as they're asked to be, with functions that are called
+//! by name and can recurse, that always run without an error.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use print::to_source;
+use {hash_string, Ast, Lambda, SymbolTable, Value};
+
+use std::rc::Rc;
+
+const NAMES: &[&str] = &["a", "b", "c", "x", "y", "z", "foo", "bar"];
+
+/// The names of every identifier that generated programs use.
+pub fn symbols() -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for name in NAMES {
+        symbols.insert(name);
+    }
+    symbols
+}
+
+/// A random expression nested at most `depth` calls, defines or functions
+/// deep.
+pub fn arbitrary_ast<R: Rng + ?Sized>(depth: usize, rng: &mut R) -> Ast<u64> {
+    let name = |rng: &mut R| hash_string(NAMES[rng.gen_range(0..NAMES.len())]);
+    let function = |rng: &mut R, params| {
+        let params = (0..params).map(|_| name(rng)).collect::<Vec<_>>();
+        let body = (0..rng.gen_range(0..3))
+            .map(|_| arbitrary_ast(depth - 1, rng))
+            .collect::<Vec<_>>();
+        Value::Function(Rc::new(Lambda::new(params, body)))
+    };
+
+    if depth == 0 || rng.gen_bool(0.3) {
+        return match rng.gen_range(0..3) {
+            0 => Ast::Lit(Value::Int(rng.gen_range(0..1000))),
+            1 => Ast::Lit(Value::False),
+            _ => Ast::Variable(name(rng)),
+        };
+    }
+
+    match rng.gen_range(0..3) {
+        0 => {
+            let arity = rng.gen_range(0..4);
+            let func = function(rng, arity);
+            let args = (0..arity)
+                .map(|_| arbitrary_ast(depth - 1, rng))
+                .collect::<Vec<_>>();
+            Ast::Call(Rc::new(Ast::Lit(func)), args.into())
+        }
+        1 => Ast::Define(name(rng), Rc::new(arbitrary_ast(depth - 1, rng))),
+        _ => {
+            let arity = rng.gen_range(0..4);
+            Ast::Lit(function(rng, arity))
+        }
+    }
+}
+
+/// The source of a random program of a few top-level forms, one per line.
+pub fn arbitrary_source<R: Rng + ?Sized>(rng: &mut R) -> String {
+    let symbols = symbols();
+
+    (0..rng.gen_range(1..5))
+        .map(|_| {
+            let depth = rng.gen_range(0..6);
+            to_source(&arbitrary_ast(depth, rng), &symbols).expect("generated an unprintable program")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The shape of the programs that `generate_program` writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgramSpec {
+    /// How many top-level defines there are, about half of them functions
+    /// and the rest values. One expression that uses them follows.
+    pub defines: usize,
+    /// The average number of arguments a call is given, and of parameters
+    /// a function takes.
+    pub arity: usize,
+    /// How many calls deep each expression nests, at most.
+    pub depth: usize,
+    /// The share of the functions that call themselves, from 0 to 1.
+    pub recursive: f64,
+    /// How many names parameters are chosen from. Each define has a name
+    /// of its own on top of these.
+    pub identifiers: usize,
+    /// The same seed and spec always give the same program.
+    pub seed: u64,
+}
+
+impl Default for ProgramSpec {
+    fn default() -> Self {
+        ProgramSpec {
+            defines: 100,
+            arity: 2,
+            depth: 4,
+            recursive: 0.2,
+            identifiers: 16,
+            seed: 0,
+        }
+    }
+}
+
+// Generated programs only have ints in them, and `add` can overflow, so the
+// generator keeps track of how large each expression can get. Arguments
+// must fit in `PARAM_BOUND`, and everything else in `VALUE_BOUND`.
+const PARAM_BOUND: u64 = 1 << 16;
+const VALUE_BOUND: u64 = 1 << 32;
+
+// Every function only calls the ones defined before it, so without a limit
+// the time a call takes can double with each define. These are roughly how
+// many nodes may be evaluated for the body of a function and for each
+// top-level form.
+const FUNCTION_COST: u64 = 500;
+const FORM_COST: u64 = 2000;
+
+struct Function {
+    name: String,
+    params: usize,
+    // Recursive functions take a counter first, which they count up to this
+    // before returning.
+    counter: Option<u64>,
+    bound: u64,
+    cost: u64,
+}
+
+struct Generator {
+    spec: ProgramSpec,
+    rng: StdRng,
+    // The values defined so far, with how large each can be.
+    values: Vec<(String, u64)>,
+    functions: Vec<Function>,
+    // The parameters of the function being written, if any.
+    params: Vec<String>,
+}
+
+/// The source of a program shaped by `spec`, one top-level form per line.
+///
+/// It only uses names it defines and the prelude's `add`, `eq` and `if`,
+/// and calls every function with as many arguments as it takes, so it
+/// parses and runs without an error against `prelude::env`. Functions that
+/// call themselves always stop after a few calls.
+pub fn generate_program(spec: ProgramSpec) -> String {
+    let mut generator = Generator {
+        spec,
+        rng: StdRng::seed_from_u64(spec.seed),
+        values: Vec::new(),
+        functions: Vec::new(),
+        params: Vec::new(),
+    };
+
+    let mut forms = Vec::with_capacity(spec.defines + 1);
+    for i in 0..spec.defines {
+        let name = generated_name('g', i);
+        let form = if generator.rng.gen_bool(0.5) {
+            generator.function(name)
+        } else {
+            let mut cost = FORM_COST;
+            let (src, bound) = generator.expr(spec.depth, VALUE_BOUND, &mut cost);
+            let form = format!("(= {} {})", name, src);
+            generator.values.push((name, bound));
+            form
+        };
+        forms.push(form);
+    }
+    let mut cost = FORM_COST;
+    forms.push(generator.expr(spec.depth, VALUE_BOUND, &mut cost).0);
+
+    forms.join("\n")
+}
+
+// Names can only have letters in them, so `i` is written in base 26 with
+// `a` to `z` for digits. No prelude name starts with `g` or `x`.
+fn generated_name(prefix: char, mut i: usize) -> String {
+    let mut name = prefix.to_string();
+    loop {
+        name.push((b'a' + (i % 26) as u8) as char);
+        i /= 26;
+        if i == 0 {
+            return name;
+        }
+    }
+}
+
+impl Generator {
+    fn arity(&mut self) -> usize {
+        self.rng.gen_range(0..=self.spec.arity * 2)
+    }
+
+    fn function(&mut self, name: String) -> String {
+        let params = self.arity().min(self.spec.identifiers);
+        let mut pool = (0..self.spec.identifiers).collect::<Vec<_>>();
+        self.params = (0..params)
+            .map(|_| {
+                let i = self.rng.gen_range(0..pool.len());
+                generated_name('x', pool.swap_remove(i))
+            })
+            .collect();
+
+        let mut cost = FUNCTION_COST;
+        let (body, bound) = self.expr(self.spec.depth, VALUE_BOUND, &mut cost);
+        let body_cost = FUNCTION_COST - cost;
+        let list = self.params.iter().map(|param| format!(" {}", param)).collect::<String>();
+        self.params.clear();
+
+        let (src, counter, cost) = if self.rng.gen_bool(self.spec.recursive) {
+            let limit = self.rng.gen_range(1..4);
+            let again = format!("({} (add n 1){})", name, list);
+            let src = format!(
+                r"(= {name} (\(n{list}) ((if (eq n {limit}) (\() {body}) (\() {again})))))",
+                name = name,
+                list = list,
+                limit = limit,
+                body = body,
+                again = again,
+            );
+            // Each call before the last evaluates about a dozen nodes
+            // besides the arguments.
+            let cost = body_cost + (limit + 1) * (12 + params as u64);
+            (src, Some(limit), cost)
+        } else {
+            let src = format!(r"(= {} (\({}) {}))", name, list.trim_start(), body);
+            (src, None, body_cost)
+        };
+
+        self.functions.push(Function {
+            name,
+            params,
+            counter,
+            bound,
+            cost,
+        });
+        src
+    }
+
+    // An expression that's never larger than `bound` and that takes about
+    // `cost` nodes at most to evaluate, along with how large it can be.
+    fn expr(&mut self, depth: usize, bound: u64, cost: &mut u64) -> (String, u64) {
+        *cost = cost.saturating_sub(1);
+        if depth == 0 || *cost == 0 || self.rng.gen_bool(0.3) {
+            return self.leaf(bound);
+        }
+
+        match self.rng.gen_range(0..3) {
+            0 => {
+                let args = self.arity().max(1);
+                let mut src = String::from("(add");
+                let mut total = 0;
+                for _ in 0..args {
+                    let (arg, arg_bound) = self.expr(depth - 1, bound / args as u64, cost);
+                    src.push(' ');
+                    src.push_str(&arg);
+                    total += arg_bound;
+                }
+                src.push(')');
+                (src, total)
+            }
+            1 => {
+                let (a, _) = self.expr(depth - 1, VALUE_BOUND, cost);
+                let (b, _) = self.expr(depth - 1, VALUE_BOUND, cost);
+                let (then, then_bound) = self.expr(depth - 1, bound, cost);
+                let (else_, else_bound) = self.expr(depth - 1, bound, cost);
+                let src = format!("(if (eq {} {}) {} {})", a, b, then, else_);
+                (src, then_bound.max(else_bound))
+            }
+            _ => self.call(depth, bound, cost).unwrap_or_else(|| self.leaf(bound)),
+        }
+    }
+
+    // A call to one of the functions defined so far that fits, if any do.
+    fn call(&mut self, depth: usize, bound: u64, cost: &mut u64) -> Option<(String, u64)> {
+        let budget = *cost;
+        let fits = self
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|&(_, f)| f.bound <= bound && f.cost <= budget)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if fits.is_empty() {
+            return None;
+        }
+
+        let (name, params, counter, bound) = {
+            let f = &self.functions[fits[self.rng.gen_range(0..fits.len())]];
+            *cost -= f.cost;
+            (f.name.clone(), f.params, f.counter, f.bound)
+        };
+        let mut src = format!("({}", name);
+        if let Some(limit) = counter {
+            src.push_str(&format!(" {}", self.rng.gen_range(0..=limit)));
+        }
+        for _ in 0..params {
+            src.push(' ');
+            src.push_str(&self.expr(depth - 1, PARAM_BOUND, cost).0);
+        }
+        src.push(')');
+        Some((src, bound))
+    }
+
+    fn leaf(&mut self, bound: u64) -> (String, u64) {
+        let mut names = self
+            .values
+            .iter()
+            .filter(|&&(_, value_bound)| value_bound <= bound)
+            .map(|&(ref name, value_bound)| (name, value_bound))
+            .collect::<Vec<_>>();
+        if PARAM_BOUND <= bound {
+            names.extend(self.params.iter().map(|name| (name, PARAM_BOUND)));
+        }
+
+        if names.is_empty() || self.rng.gen_bool(0.3) {
+            let value = self.rng.gen_range(0..1000.min(bound + 1));
+            (value.to_string(), value)
+        } else {
+            let (name, value_bound) = names[self.rng.gen_range(0..names.len())];
+            (name.clone(), value_bound)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use benches::{corpus_interpreter, same_ast, same_result};
+    use bytecode::Vm;
+    use closure::compile_closure;
+    use print::to_source;
+    use analysis::metrics;
+    use {eval, parse_program, parse_program_with, prelude, IntMap, ParseOptions};
+
+    use super::{arbitrary_ast, arbitrary_source, generate_program, symbols, ProgramSpec};
+
+    // Each case has its own seed, so a failure can be reproduced on its own.
+    const CASES: u64 = 500;
+
+    #[test]
+    fn printed_programs_parse_to_themselves() {
+        let symbols = symbols();
+
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let ast = arbitrary_ast(6, &mut rng);
+
+            let src = to_source(&ast, &symbols).unwrap();
+            let parsed = parse_program_with(&src, &ParseOptions::default(), &mut |_| {}).unwrap();
+            assert_eq!(parsed.len(), 1, "seed {}: {}", seed, src);
+            assert!(same_ast(&ast, &parsed[0]), "seed {}: {}", seed, src);
+        }
+    }
+
+    #[test]
+    fn generated_programs_run_the_same_everywhere() {
+        let interpreter = corpus_interpreter();
+
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let src = arbitrary_source(&mut rng);
+            let program = parse_program_with(&src, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+            let mut tree_env: IntMap<_> = interpreter.env();
+            let mut closure_env: IntMap<_> = interpreter.env();
+            let mut vm_env: IntMap<_> = interpreter.env();
+            for form in &program {
+                let expected = eval(form, &mut tree_env).map(|v| v.into_owned());
+
+                let actual = compile_closure(form).eval(&mut closure_env);
+                assert!(same_result(&expected, &actual), "seed {}: {}", seed, src);
+
+                let compiled = interpreter.compile(::std::slice::from_ref(form));
+                let actual = Vm::new(&compiled).run(&mut vm_env);
+                assert!(same_result(&expected, &actual), "seed {}: {}", seed, src);
+            }
+        }
+    }
+
+    #[test]
+    fn generates_large_programs_that_run() {
+        let spec = ProgramSpec {
+            defines: 500,
+            ..ProgramSpec::default()
+        };
+        let src = generate_program(spec);
+        assert_eq!(src, generate_program(spec));
+
+        let program = parse_program(&src).unwrap();
+        let nodes = program.iter().map(|form| metrics(form).nodes).sum::<usize>();
+        assert!(nodes >= 10_000, "only {} nodes", nodes);
+
+        let mut env = prelude::env();
+        for form in &program {
+            if let Err(e) = eval(form, &mut env) {
+                panic!("{}", e);
+            }
+        }
+    }
+}
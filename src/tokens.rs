@@ -0,0 +1,384 @@
+//! Splitting source into tokens, for tools such as syntax highlighters that
+//! want to know what each piece of a program is without parsing it.
+//!
+//! `tokenize` never fails and never stops early: anything it doesn't
+//! recognise is an `Error` token, and the spans of the tokens it gives
+//! cover the whole source in order, with no gaps and no overlaps, so an
+//! editor can colour every byte. Whitespace is a token of its own for the
+//! same reason. A string is a single token, `{expr}`s and all, and so is a
+//! keyword with its colon. Words that start special forms, like `include`
+//! and `let-values`, are names, as they are when they're called.
+//!
+//! `is_complete` uses the tokens to tell whether some input is finished,
+//! which is much quicker than parsing it when it isn't, for a REPL deciding
+//! whether to wait for another line.
+
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use coverage::Span;
+use ParseError;
+
+/// What a token is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    /// `(`
+    Open,
+    /// `)`
+    Close,
+    /// A run of digits.
+    Int,
+    /// `#f`
+    False,
+    /// A bytes literal, from `#b"` to its closing quote.
+    Bytes,
+    /// A string literal, from its opening quote to its closing one.
+    Str,
+    /// `:name`
+    Keyword,
+    /// A run of letters, or `let-values`.
+    Name,
+    /// `\`, which starts a function.
+    Lambda,
+    /// `=`, which starts a definition.
+    Define,
+    /// `'`
+    Quote,
+    /// `,`
+    Unquote,
+    /// A run of whitespace.
+    Whitespace,
+    /// A character that can't start a token, or a string or bytes literal
+    /// that's never closed, which runs to the end of the source.
+    Error,
+}
+
+/// A piece of source and what it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// The tokens of `src`, in order.
+pub fn tokenize<'a>(src: &'a str) -> impl Iterator<Item = Token> + 'a {
+    Tokens { src, pos: 0 }
+}
+
+struct Tokens<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        let rest = &self.src[self.pos..];
+        let first = rest.chars().next()?;
+        let run = |f: fn(char) -> bool| rest.find(|c| !f(c)).unwrap_or(rest.len());
+
+        let (kind, len) = match first {
+            '(' => (TokenKind::Open, 1),
+            ')' => (TokenKind::Close, 1),
+            '\\' => (TokenKind::Lambda, 1),
+            '=' => (TokenKind::Define, 1),
+            '\'' => (TokenKind::Quote, 1),
+            ',' => (TokenKind::Unquote, 1),
+            '"' => literal(TokenKind::Str, rest, string_end(rest)),
+            _ if rest.starts_with("#f") => (TokenKind::False, 2),
+            _ if rest.starts_with("#b\"") => literal(TokenKind::Bytes, rest, bytes_end(rest)),
+            ':' if rest[1..].starts_with(char::is_alphabetic) => {
+                let name = rest[1..].find(|c: char| !c.is_alphabetic());
+                (TokenKind::Keyword, 1 + name.unwrap_or(rest.len() - 1))
+            }
+            _ if rest.starts_with("let-values") => (TokenKind::Name, 10),
+            c if c.is_ascii_digit() => (TokenKind::Int, run(|c| c.is_ascii_digit())),
+            c if c.is_alphabetic() => (TokenKind::Name, run(char::is_alphabetic)),
+            c if c.is_whitespace() => (TokenKind::Whitespace, run(char::is_whitespace)),
+            c => (TokenKind::Error, c.len_utf8()),
+        };
+
+        let start = self.pos;
+        self.pos += len;
+        Some(Token {
+            kind,
+            span: Span {
+                start,
+                end: self.pos,
+            },
+        })
+    }
+}
+
+// A literal that's closed at `end`, or else an error to the end of `rest`.
+fn literal(kind: TokenKind, rest: &str, end: Option<usize>) -> (TokenKind, usize) {
+    match end {
+        Some(end) => (kind, end),
+        None => (TokenKind::Error, rest.len()),
+    }
+}
+
+// Where the string that `rest` starts with ends, just after its closing
+// quote. Interpolated expressions can have strings of their own, and since
+// strings and interpolations always alternate, counting the open strings
+// is enough to tell which one a quote closes.
+fn string_end(rest: &str) -> Option<usize> {
+    let mut open = 1;
+    let mut in_string = true;
+    let mut chars = rest.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // An escape, or a doubled brace.
+            '\\' if in_string => {
+                chars.next();
+            }
+            '{' | '}' if in_string && rest[i + 1..].starts_with(c) => {
+                chars.next();
+            }
+            '"' if in_string => {
+                open -= 1;
+                if open == 0 {
+                    return Some(i + 1);
+                }
+                in_string = false;
+            }
+            '{' if in_string => in_string = false,
+            '"' => {
+                open += 1;
+                in_string = true;
+            }
+            '}' => in_string = true,
+            _ => {}
+        }
+    }
+    None
+}
+
+// Where the bytes literal that `rest` starts with ends, just after its
+// closing quote.
+fn bytes_end(rest: &str) -> Option<usize> {
+    let mut chars = rest.char_indices().skip(3);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether some input is ready to be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompleteStatus {
+    /// Every delimiter is closed.
+    Complete,
+    /// Some parentheses, or a string or bytes literal, are still open, and
+    /// more input could close them.
+    Incomplete { open_delims: usize },
+    /// No more input could make this parse, because a parenthesis is closed
+    /// that was never opened or there's a character that can't start a
+    /// token. This isn't every way input can be wrong, only the ones that
+    /// can be seen in the tokens.
+    Invalid { error: ParseError },
+}
+
+/// Check whether `src` is complete, without parsing it.
+pub fn is_complete(src: &str) -> CompleteStatus {
+    let mut open = 0;
+    for token in tokenize(src) {
+        let text = &src[token.span.start..token.span.end];
+        let message = match token.kind {
+            TokenKind::Open => {
+                open += 1;
+                continue;
+            }
+            TokenKind::Close if open > 0 => {
+                open -= 1;
+                continue;
+            }
+            TokenKind::Close => "Unexpected `)`".to_owned(),
+            // A literal that's still open is always the last token.
+            TokenKind::Error if text.starts_with('"') || text.starts_with("#b\"") => {
+                return CompleteStatus::Incomplete {
+                    open_delims: open + 1,
+                };
+            }
+            TokenKind::Error => format!("Unexpected `{}`", text),
+            _ => continue,
+        };
+        let error = ParseError::Syntax {
+            message,
+            position: Some(token.span.start),
+        };
+        return CompleteStatus::Invalid { error };
+    }
+
+    if open == 0 {
+        CompleteStatus::Complete
+    } else {
+        CompleteStatus::Incomplete { open_delims: open }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use super::{is_complete, tokenize, CompleteStatus, TokenKind};
+    use ParseError;
+    use benches::{DEEP_NESTING, LITERALS, MANY_VARIABLES, NESTED_FUNC, REAL_CODE, TAIL_RECURSION};
+
+    fn pieces(src: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(src).map(|token| (token.kind, &src[token.span.start..token.span.end])).collect()
+    }
+
+    #[test]
+    fn splits_real_code() {
+        use super::TokenKind::*;
+
+        let expected = vec![
+            (Whitespace, "\n"),
+            (Open, "("),
+            (Define, "="),
+            (Whitespace, " "),
+            (Name, "increment"),
+            (Whitespace, " "),
+            (Open, "("),
+            (Lambda, "\\"),
+            (Open, "("),
+            (Name, "a"),
+            (Close, ")"),
+            (Whitespace, "\n  "),
+            (Open, "("),
+            (Name, "add"),
+            (Whitespace, " "),
+            (Name, "a"),
+            (Whitespace, " "),
+            (Int, "1"),
+            (Close, ")"),
+            (Close, ")"),
+            (Close, ")"),
+            (Whitespace, "\n"),
+        ];
+        assert_eq!(pieces(REAL_CODE)[..expected.len()], expected[..]);
+
+        let second = tokenize(REAL_CODE).nth(4).unwrap();
+        assert_eq!((second.span.start, second.span.end), (4, 13));
+    }
+
+    #[test]
+    fn tokens_tile_the_source() {
+        let many = ::benches::many_defines(100);
+        let srcs = [
+            DEEP_NESTING,
+            MANY_VARIABLES,
+            NESTED_FUNC,
+            REAL_CODE,
+            LITERALS,
+            TAIL_RECURSION,
+            &many,
+        ];
+        for src in &srcs {
+            let mut end = 0;
+            for token in tokenize(src) {
+                assert_eq!(token.span.start, end);
+                assert!(token.span.end > token.span.start);
+                assert!(token.kind != TokenKind::Error, "{:?} in {:?}", token, src);
+                end = token.span.end;
+            }
+            assert_eq!(end, src.len());
+        }
+    }
+
+    #[test]
+    fn splits_every_kind_of_literal() {
+        use super::TokenKind::*;
+
+        let src = r#"'(f ,x :key #f #b"a\"b" "say {(g "{y}}}")}!" let-values 12)"#;
+        let expected = vec![
+            (Quote, "'"),
+            (Open, "("),
+            (Name, "f"),
+            (Whitespace, " "),
+            (Unquote, ","),
+            (Name, "x"),
+            (Whitespace, " "),
+            (Keyword, ":key"),
+            (Whitespace, " "),
+            (False, "#f"),
+            (Whitespace, " "),
+            (Bytes, r#"#b"a\"b""#),
+            (Whitespace, " "),
+            (Str, r#""say {(g "{y}}}")}!""#),
+            (Whitespace, " "),
+            (Name, "let-values"),
+            (Whitespace, " "),
+            (Int, "12"),
+            (Close, ")"),
+        ];
+        assert_eq!(pieces(src), expected);
+    }
+
+    #[test]
+    fn garbage_gives_error_tokens() {
+        use super::TokenKind::*;
+
+        let src = "(a % 1)#x:€ \"open {";
+        let expected = vec![
+            (Open, "("),
+            (Name, "a"),
+            (Whitespace, " "),
+            (Error, "%"),
+            (Whitespace, " "),
+            (Int, "1"),
+            (Close, ")"),
+            (Error, "#"),
+            (Name, "x"),
+            (Error, ":"),
+            (Error, "€"),
+            (Whitespace, " "),
+            (Error, "\"open {"),
+        ];
+        assert_eq!(pieces(src), expected);
+        assert_eq!(pieces(r#"#b"never"#), vec![(Error, r#"#b"never"#)]);
+        assert!(pieces("").is_empty());
+
+        // Every pair of bytes, as far as they make a string.
+        for a in 0..=255u8 {
+            for b in 0..=255u8 {
+                let src = String::from_utf8_lossy(&[a, b]).into_owned();
+                let text = pieces(&src).into_iter().map(|(_, text)| text).collect::<String>();
+                assert_eq!(text, src);
+            }
+        }
+    }
+
+    #[test]
+    fn tells_when_input_is_complete() {
+        assert_eq!(is_complete("(add 1"), CompleteStatus::Incomplete { open_delims: 1 });
+        assert_eq!(is_complete("((\\(a"), CompleteStatus::Incomplete { open_delims: 3 });
+        assert_eq!(is_complete("(= x 1)\n(add x\n  2) x"), CompleteStatus::Complete);
+        assert_eq!(is_complete(REAL_CODE), CompleteStatus::Complete);
+        assert_eq!(is_complete(""), CompleteStatus::Complete);
+
+        let error = ParseError::Syntax {
+            message: "Unexpected `)`".to_owned(),
+            position: Some(7),
+        };
+        assert_eq!(is_complete("(add 1))"), CompleteStatus::Invalid { error });
+        let error = ParseError::Syntax {
+            message: "Unexpected `%`".to_owned(),
+            position: Some(3),
+        };
+        assert_eq!(is_complete("(a %"), CompleteStatus::Invalid { error });
+
+        assert_eq!(is_complete("\"abc"), CompleteStatus::Incomplete { open_delims: 1 });
+        let open = is_complete("(print \"{(f \"x\")}\nand ");
+        assert_eq!(open, CompleteStatus::Incomplete { open_delims: 2 });
+        let closed = is_complete("(print \"a ) (\" #b\")\")");
+        assert_eq!(closed, CompleteStatus::Complete);
+    }
+}
@@ -0,0 +1,182 @@
+//! A log of what `eval_with` does, for finding out where a slow program
+//! spends its time.
+//!
+//! Tracing is off unless `EvalOptions::trace` is given somewhere to send
+//! events, and costs a check per call, define and variable read when it's
+//! off. Calls that fail have no `Return`.
+
+use std::fmt::{self, Write};
+#[cfg(not(feature = "std"))]
+use std::prelude::v1::*;
+
+use SymbolTable;
+
+/// One thing that evaluation did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent<Id> {
+    /// Counts up from 0 over every event sent to the same `Tracer`.
+    pub seq: u64,
+    pub kind: TraceKind<Id>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceKind<Id> {
+    /// A function was called, through the variable `name` if it has one.
+    /// Top-level calls have depth 1.
+    Call { name: Option<Id>, depth: usize },
+    /// The call at `depth` finished.
+    Return { depth: usize },
+    Define { name: Id },
+    /// A variable was read, whether or not it was bound.
+    Lookup { name: Id },
+}
+
+/// Where `eval_with` sends trace events.
+pub struct Tracer<Id> {
+    sink: Box<dyn FnMut(TraceEvent<Id>)>,
+    seq: u64,
+}
+
+impl<Id> Tracer<Id> {
+    pub fn new<F: FnMut(TraceEvent<Id>) + 'static>(sink: F) -> Self {
+        Tracer {
+            sink: Box::new(sink),
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn emit(&mut self, kind: TraceKind<Id>) {
+        let seq = self.seq;
+        self.seq += 1;
+        (self.sink)(TraceEvent { seq, kind });
+    }
+}
+
+impl<Id> fmt::Debug for Tracer<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer").field("seq", &self.seq).finish()
+    }
+}
+
+/// Lay `events` out as a tree of calls, with everything that happened
+/// during a call indented under it. Identifiers are shown by name if
+/// `symbols` has them, and as their hash otherwise.
+pub fn render_trace(events: &[TraceEvent<u64>], symbols: &SymbolTable) -> String {
+    let name = |id: u64| match symbols.name(id) {
+        Some(name) => name.to_owned(),
+        None => format!("#{:x}", id),
+    };
+
+    let mut out = String::new();
+    // How deep the innermost call that hasn't returned is.
+    let mut depth = 0;
+    for event in events {
+        let (indent, line) = match event.kind {
+            TraceKind::Call { name: callee, depth: call } => {
+                depth = call;
+                let callee = callee.map_or_else(|| "<anonymous>".to_owned(), name);
+                (call - 1, format!("call {}", callee))
+            }
+            TraceKind::Return { depth: call } => {
+                depth = call - 1;
+                (depth, "return".to_owned())
+            }
+            TraceKind::Define { name: defined } => (depth, format!("define {}", name(defined))),
+            TraceKind::Lookup { name: read } => (depth, format!("lookup {}", name(read))),
+        };
+        let _ = writeln!(out, "{:indent$}{}", "", line, indent = indent * 2);
+    }
+    out
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use benches::{corpus_env, NESTED_FUNC, REAL_CODE};
+    use {eval_with, parse_program, parse_program_with_symbols, EvalOptions, ParseOptions};
+
+    use super::{render_trace, TraceEvent, TraceKind};
+
+    fn trace(src: &str) -> Vec<TraceEvent<u64>> {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut options = EvalOptions::default();
+        let sink = events.clone();
+        options.trace(move |event| sink.borrow_mut().push(event));
+
+        let mut env = corpus_env();
+        for form in &parse_program(src).unwrap() {
+            eval_with(form, &mut env, &mut options).ok().unwrap();
+        }
+
+        events.take()
+    }
+
+    #[test]
+    fn traces_nested_calls_in_order() {
+        let events = trace(NESTED_FUNC);
+
+        assert!(events.iter().enumerate().all(|(i, event)| event.seq == i as u64));
+
+        let calls = events
+            .iter()
+            .filter_map(|event| match event.kind {
+                TraceKind::Call { ref name, depth } => Some((true, name.is_some(), depth)),
+                TraceKind::Return { depth } => Some((false, false, depth)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Each lambda is called from the end of the one before it, so it
+        // replaces that call rather than nesting inside it.
+        let expected = (1..=11)
+            .flat_map(|_| vec![(true, false, 1), (false, false, 1)])
+            .collect::<Vec<_>>();
+        assert_eq!(calls, expected);
+    }
+
+    #[test]
+    fn renders_a_call_tree() {
+        let src = r"(= double (\(x) (add x x))) (double 2)";
+        let (_, symbols) =
+            parse_program_with_symbols(src, &ParseOptions::default(), &mut |_| {}).unwrap();
+
+        assert_eq!(
+            render_trace(&trace(src), &symbols),
+            "define double
+lookup double
+call double
+  lookup add
+  lookup x
+  lookup x
+  call add
+  return
+return
+"
+        );
+    }
+
+    #[test]
+    fn nothing_is_traced_when_off() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut options = EvalOptions::default();
+        let sink = events.clone();
+        options.trace(move |event| sink.borrow_mut().push(event));
+
+        let program = parse_program(REAL_CODE).unwrap();
+        let run = |options: &mut EvalOptions<u64>| {
+            let mut env = corpus_env();
+            for form in &program {
+                eval_with(form, &mut env, options).ok().unwrap();
+            }
+        };
+
+        run(&mut options);
+        let traced = events.borrow().len();
+        assert!(traced > 0);
+
+        options.trace = None;
+        run(&mut options);
+        assert_eq!(events.borrow().len(), traced);
+    }
+}
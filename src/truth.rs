@@ -0,0 +1,135 @@
+//! Which values conditionals treat as true.
+//!
+//! `if`, `assert` and everything else that branches on a value ask the
+//! `TruthPolicy` they're evaluated under, and so does `not`, which the
+//! stdlib writes with `if`. It's `SchemeLike` unless a host says otherwise,
+//! with `EvalOptions::truth` or `Interpreter::set_truth`.
+//!
+//! The policy is passed along with the evaluation rather than kept
+//! anywhere else, so two programs can run under different policies at
+//! once. `eval_with` evaluates under the one in its options, and gives it
+//! to reentrant natives through `Evaluator::truth`, which is how `if` and
+//! `assert` see it. Natives have no way to change it, so a program sees the
+//! same policy from start to finish. `eval` always evaluates under
+//! `SchemeLike`.
+
+use std::fmt;
+
+use Value;
+
+/// Which values count as true.
+#[derive(Default)]
+pub enum TruthPolicy<Id> {
+    /// Only `#f` and `nil` are false, as in Scheme where everything but
+    /// `#f` is true. This is the default.
+    #[default]
+    SchemeLike,
+    /// `#f`, `nil`, `0` and the empty string are false, as in C where
+    /// zero is false.
+    CLike,
+    /// Whatever the function says.
+    Custom(fn(&Value<Id>) -> bool),
+}
+
+impl<Id> TruthPolicy<Id> {
+    /// Whether `value` counts as true under this policy.
+    pub fn is_true(&self, value: &Value<Id>) -> bool {
+        match *self {
+            TruthPolicy::SchemeLike => !matches!(*value, Value::False | Value::Nil),
+            TruthPolicy::CLike => match *value {
+                Value::False | Value::Nil | Value::Int(0) => false,
+                Value::Str(ref s) => !s.is_empty(),
+                _ => true,
+            },
+            TruthPolicy::Custom(f) => f(value),
+        }
+    }
+}
+
+// Derived, these would need `Id` to be `Clone` and `Debug` too.
+impl<Id> Clone for TruthPolicy<Id> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Id> Copy for TruthPolicy<Id> {}
+
+impl<Id> fmt::Debug for TruthPolicy<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TruthPolicy::SchemeLike => f.write_str("SchemeLike"),
+            TruthPolicy::CLike => f.write_str("CLike"),
+            TruthPolicy::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "parse"))]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::TruthPolicy;
+    use {eval_with, parse_program, prelude, EvalOptions, IntMap, Value};
+
+    // What `src` gives as its last form under `policy`.
+    fn run(src: &str, policy: TruthPolicy<u64>) -> Value<u64> {
+        let program = parse_program(src).unwrap();
+        let mut env = prelude::env();
+        let mut options = EvalOptions {
+            truth: policy,
+            ..EvalOptions::default()
+        };
+        let mut out = Value::Void;
+        for form in &program {
+            out = eval_with(form, &mut env, &mut options).unwrap().into_owned();
+        }
+        out
+    }
+
+    #[test]
+    fn the_same_program_branches_by_policy() {
+        let src = r#"(list (if 0 1 2) (if "" 1 2) (if (list) 1 2) (not "") (not 0))"#;
+        assert_eq!(run(src, TruthPolicy::SchemeLike).to_string(), "(list 1 1 1 #f #f)");
+        assert_eq!(run(src, TruthPolicy::CLike).to_string(), "(list 2 2 1 void void)");
+
+        // `is_truthy` doesn't know what it's being evaluated under.
+        assert!(Value::<u64>::Int(0).is_truthy());
+    }
+
+    #[test]
+    fn custom_policies_can_make_void_false() {
+        fn void_is_false(value: &Value<u64>) -> bool {
+            !matches!(*value, Value::Void | Value::False | Value::Nil)
+        }
+        let src = "(if (print 1) 1 2)";
+        assert_eq!(run(src, TruthPolicy::SchemeLike), Value::Int(1));
+        assert_eq!(run(src, TruthPolicy::Custom(void_is_false)), Value::Int(2));
+        assert_eq!(run("(not (if #f 1))", TruthPolicy::Custom(void_is_false)), Value::Void);
+
+        fn empty_symbols_are_false(value: &Value<&str>) -> bool {
+            !matches!(*value, Value::Symbol("") | Value::False | Value::Nil)
+        }
+        let policy = TruthPolicy::Custom(empty_symbols_are_false);
+        assert!(!policy.is_true(&Value::Symbol("")));
+        assert!(policy.is_true(&Value::Symbol("a")));
+    }
+
+    #[test]
+    fn interpreters_keep_their_policy() {
+        let mut interpreter = prelude::interpreter();
+        interpreter.set_truth(TruthPolicy::CLike);
+        let mut env: IntMap<Cow<_>> = interpreter.env();
+        let (value, _) = interpreter.eval_str_captured("(if 0 1 2)", &mut env);
+        assert_eq!(value.unwrap(), Value::Int(2));
+
+        let program = parse_program(r#"(assert "" "empty")"#).unwrap();
+        let mut env: IntMap<_> = interpreter.env();
+        let error = eval_with(&program[0], &mut env, &mut interpreter.options()).unwrap_err();
+        assert_eq!(error.to_string(), "Assertion failed: empty");
+
+        let compiled = interpreter.compile(&parse_program("(if 0 1 2)").unwrap());
+        let mut env: IntMap<_> = interpreter.env();
+        assert_eq!(compiled.run(&mut env).unwrap(), Value::Int(2));
+    }
+}
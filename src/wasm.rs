@@ -0,0 +1,79 @@
+//! Bindings for running programs from JavaScript, for use in the browser.
+//!
+//! Programs run against the prelude, stdlib and all, in a fresh
+//! environment every time. They're given `FUEL` and may nest calls
+//! `MAX_DEPTH` deep, so a program that never finishes fails rather than
+//! hanging the page.
+
+use wasm_bindgen::prelude::*;
+
+use {eval_with, parse_program_with, prelude, EvalOptions, IntMap};
+
+/// How many expressions a program may evaluate.
+pub const FUEL: u64 = 10_000_000;
+
+/// How deeply a program's calls may nest. This is lower than `run`'s
+/// default, since the stack is small in the browser.
+pub const MAX_DEPTH: usize = 200;
+
+/// The reason `src` doesn't parse, or `None` if it does.
+#[wasm_bindgen]
+pub fn parse_check(src: &str) -> Option<String> {
+    let options = prelude::interpreter().parse_options();
+    parse_program_with(src, &options, &mut |_| {})
+        .err()
+        .map(|e| e.to_string())
+}
+
+/// Run `src` and return the value of its last form written out with
+/// `Display`, or the reason it failed to parse or run.
+#[wasm_bindgen]
+pub fn run(src: &str) -> String {
+    let interpreter = prelude::interpreter();
+    let program = match parse_program_with(src, &interpreter.parse_options(), &mut |_| {}) {
+        Ok(program) => program,
+        Err(e) => return e.to_string(),
+    };
+
+    let mut env: IntMap<_> = prelude::env();
+    let mut options = EvalOptions {
+        fuel: Some(FUEL),
+        max_depth: Some(MAX_DEPTH),
+        ..interpreter.options()
+    };
+    let mut out = String::new();
+    for form in &program {
+        match eval_with(form, &mut env, &mut options) {
+            Ok(value) => out = value.to_string(),
+            Err(e) => return e.to_string(),
+        }
+    }
+
+    out
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::{parse_check, run};
+
+    #[wasm_bindgen_test]
+    fn runs_a_simple_program() {
+        assert_eq!(parse_check(r"(= inc (\(n) (add n 1))) (inc 41)"), None);
+        assert_eq!(run(r"(= inc (\(n) (add n 1))) (inc 41)"), "42");
+        assert_eq!(run("(not #f)"), "void");
+    }
+
+    #[wasm_bindgen_test]
+    fn reports_errors() {
+        assert!(parse_check("(add 1").is_some());
+        assert_eq!(run("(if #f)"), "Expected 2 to 3 arguments, got 1");
+    }
+
+    #[wasm_bindgen_test]
+    fn stops_programs_that_never_finish() {
+        assert_eq!(run(r"(= f (\() (f))) (f)"), "Ran out of fuel");
+        assert_eq!(run(r"(= f (\() (add 1 (f)))) (f)"), "Calls nested more than 200 deep");
+    }
+}
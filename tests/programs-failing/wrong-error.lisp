@@ -0,0 +1,2 @@
+(1)
+; expect: 1
@@ -0,0 +1,2 @@
+(add 1 1)
+; expect: 3
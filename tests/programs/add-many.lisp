@@ -0,0 +1,2 @@
+(add 1 2 3 4 5)
+; expect: 15
@@ -0,0 +1,2 @@
+(add)
+; expect: 0
@@ -0,0 +1,4 @@
+(= x 1)
+((\() (= x 2)))
+x
+; expect: 1
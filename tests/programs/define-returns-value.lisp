@@ -0,0 +1,2 @@
+(= x 42)
+; expect: 42
@@ -0,0 +1,4 @@
+(= x 40)
+(= y 2)
+(add x y)
+; expect: 42
@@ -0,0 +1,3 @@
+(= first (\((a b)) a))
+(first (list 1 2 3))
+; expect: error: Expected a list of 2 elements to destructure, got 3
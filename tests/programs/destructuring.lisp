@@ -0,0 +1,4 @@
+; A list argument can be taken apart by the parameter list.
+(= f (\((a (b c)) d) (add a b c d)))
+(f (list 1 (list 2 3)) 4)
+; expect: 10
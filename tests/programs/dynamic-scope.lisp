@@ -0,0 +1,7 @@
+; Functions see the variables of their caller, not of where they were
+; written.
+(= x 1)
+(= getx (\() x))
+(= x 2)
+(getx)
+; expect: 2
@@ -0,0 +1,2 @@
+((\()))
+; expect: void
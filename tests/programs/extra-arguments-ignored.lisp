@@ -0,0 +1,2 @@
+((\(a) a) 1 2)
+; expect: 1
@@ -0,0 +1,2 @@
+(if 0 1 2)
+; expect: 1
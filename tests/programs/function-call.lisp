@@ -0,0 +1,3 @@
+(= inc (\(n) (add n 1)))
+(inc 41)
+; expect: 42
@@ -0,0 +1,3 @@
+(= twice (\(f x) (f (f x))))
+(twice (\(n) (add n 10)) 22)
+; expect: 42
@@ -0,0 +1,2 @@
+(same (\(x) x) (\(x) x))
+; expect: #f
@@ -0,0 +1,2 @@
+(if #f)
+; expect: error: Expected 2 to 3 arguments, got 1
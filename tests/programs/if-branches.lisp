@@ -0,0 +1 @@
+(if (eq 1 1) 10 20)
@@ -0,0 +1,2 @@
+(add (add 1 2) (add 3 (add 4 5)))
+; expect: 15
@@ -0,0 +1,2 @@
+(1 2)
+; expect: error: Attempted to call a non-function
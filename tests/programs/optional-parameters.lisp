@@ -0,0 +1,5 @@
+; A parameter with a default can be left out, and its default can use the
+; parameters before it.
+(= f (\(a (= b (add a 1))) (add a b)))
+(add (f 1) (f 1 10))
+; expect: 14
@@ -0,0 +1,4 @@
+(= x 1)
+(= x (add x 1))
+x
+; expect: 2
@@ -0,0 +1,5 @@
+; Expressions in braces are written into the string, and doubled braces
+; are written as they are.
+(= x 5)
+"{{x}} is {x}, and doubled it's {(add x x)}"
+; expect: "{{x}} is 5, and doubled it's 10"
@@ -0,0 +1,2 @@
+(eq (\(x) x) (\(x) x))
+; expect: void
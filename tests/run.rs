@@ -0,0 +1,90 @@
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_run"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn prints_the_last_value() {
+    let output = run(&["tests/scripts/answer.lisp"]);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn warns_about_suspicious_code() {
+    let output = run(&["tests/scripts/duplicate.lisp"]);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "tests/scripts/duplicate.lisp: warning: Parameter `x` is bound more than once (at byte 14)\n"
+    );
+}
+
+#[test]
+fn reports_where_parsing_failed() {
+    let output = run(&["tests/scripts/unbalanced.lisp"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "error: Unexpected `)`, Expected `end of input`
+ --> tests/scripts/unbalanced.lisp:3:13
+  |
+3 |   (add 1 2)))
+  |             ^
+"
+    );
+}
+
+#[test]
+fn reports_where_running_failed() {
+    let output = run(&["tests/scripts/misspelled.lisp"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "error: Variable does not exist: `dubble`
+ --> tests/scripts/misspelled.lisp:2:7
+  |
+2 | (= n (dubble 20))
+  |       ^^^^^^
+  |
+  = help: a variable with a similar name exists: `double`
+"
+    );
+}
+
+#[test]
+fn stops_when_out_of_fuel() {
+    let output = run(&["--fuel", "500", "tests/scripts/loop.lisp"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("error: Ran out of fuel\n --> tests/scripts/loop.lisp:1:14\n"));
+
+    let output = run(&["--max-depth", "10", "tests/scripts/loop.lisp"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("error: Calls nested more than 10 deep\n --> tests/scripts/loop.lisp:1:14\n"));
+}
+
+#[test]
+fn rejects_bad_arguments() {
+    for args in &[&[][..], &["--fuel"], &["--fuel", "lots", "a.lisp"], &["a.lisp", "b.lisp"]] {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Usage: run"), "{:?}", args);
+    }
+}
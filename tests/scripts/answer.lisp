@@ -0,0 +1,3 @@
+(= double (\(x) (add x x)))
+(= n (double 20))
+(add n 2)
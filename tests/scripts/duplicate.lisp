@@ -0,0 +1,2 @@
+(= first (\(x x) x))
+(first 1 2)
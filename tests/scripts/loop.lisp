@@ -0,0 +1,2 @@
+(= loop (\() (loop) (loop)))
+(loop)
@@ -0,0 +1,3 @@
+(= double (\(x) (add x x)))
+(= n (dubble 20))
+(add n 2)
@@ -0,0 +1,3 @@
+(= double (\(x) (add x x)))
+(double
+  (add 1 2)))